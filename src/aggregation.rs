@@ -2,17 +2,54 @@
 //!
 //! This module provides the foundational types and functionality for aggregating
 //! time-series data. It defines:
-//! - Generic aggregation functions (Sum, Avg, Min, Max, Count)
-//! - Time window specifications (None, Fixed, Sliding)
+//! - Generic aggregation functions (Sum, Avg, Min, Max, Count, Quantile, StdDev,
+//!   Variance, First, Last, CountDistinct, Rate, Delta)
+//! - Time window specifications (None, Fixed, Sliding, Aligned, Calendar, Session)
+//! - Aggregate expressions with explicit columns and output names
+//! - Row filters, see [`filter`]
+//! - Post-aggregation HAVING filters, ordering, limits and per-window top-k
+//! - Gap filling of windowed series, see [`fill`]
+//! - In-process aggregation of record batches, see [`operator`], and
+//!   mergeable partial state, see [`partial`]
+//! - Exponential smoothing, see [`smoothing`], anomaly detection on
+//!   windowed series, see [`anomaly`], and forecasts, see [`forecast`]
+//! - Arithmetic and statistics across aligned series, see [`series`]
+//! - Step-aligned range queries returning a regular series matrix, see
+//!   [`matrix`]
 //! - Grouping operations
-//! - SQL query generation
+//! - SQL query generation in the DuckDB and Postgres dialects
 //!
 //! This framework is used by more specific aggregation implementations, such as
 //! the metric-specific aggregation in `crate::metrics::aggregation`.
 
+pub mod anomaly;
+pub mod calendar;
+pub mod fill;
+pub mod filter;
+pub mod forecast;
+pub mod hll;
+pub mod matrix;
+pub mod operator;
+pub mod partial;
+pub mod series;
+pub mod sketch;
+pub mod smoothing;
+
 use std::time::Duration;
+use chrono_tz::Tz;
 use serde::{Serialize, Deserialize};
+use arrow_schema::{DataType, Field, Schema};
+use crate::aggregation::calendar::CalendarUnit;
+use crate::aggregation::fill::{fill_sql, FillStrategy};
+use crate::aggregation::filter::{Filter, SqlDialect};
 use std::fmt::{Display, Formatter};
+use tonic::Status;
+
+/// Maximum number of windows emitted by gap filling.
+pub const MAX_FILL_WINDOWS: usize = 100_000;
+
+/// Maximum number of sliding windows an event belongs to, `window / slide`.
+pub const MAX_SLIDING_WINDOWS: u64 = 1_000;
 
 /// Time window for aggregation
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -21,11 +58,31 @@ pub enum TimeWindow {
     None,
     /// Fixed time window (e.g., 5 minutes, 1 hour)
     Fixed(Duration),
-    /// Sliding time window with window size and slide interval
+    /// Sliding time window with window size and slide interval. Windows
+    /// start every `slide`, so each event belongs to all windows overlapping
+    /// it
     Sliding {
         window: Duration,
         slide: Duration,
     },
+    /// Fixed time window whose boundaries fall on `origin + k * size`, with
+    /// `origin` in Unix seconds
+    Aligned {
+        size: Duration,
+        origin: i64,
+    },
+    /// Calendar window (day, week, month, quarter) in an IANA timezone,
+    /// starting `offset` after local midnight
+    Calendar {
+        unit: CalendarUnit,
+        timezone: Tz,
+        offset: Duration,
+    },
+    /// Session window per metric: consecutive events less than `gap` apart
+    /// form one session spanning from the first event to `gap` after the last
+    Session {
+        gap: Duration,
+    },
 }
 
 /// Generic aggregation functions that can be applied to time-series data
@@ -41,6 +98,22 @@ pub enum AggregateFunction {
     Min,
     /// Find the maximum value
     Max,
+    /// Estimate the q-quantile (0 <= q <= 1) using a mergeable sketch
+    Quantile(f64),
+    /// Sample standard deviation
+    StdDev,
+    /// Sample variance
+    Variance,
+    /// Value with the earliest timestamp
+    First,
+    /// Value with the latest timestamp
+    Last,
+    /// Approximate number of distinct values using HyperLogLog
+    CountDistinct,
+    /// Change per second between the first and last value
+    Rate,
+    /// Change between the first and last value
+    Delta,
 }
 
 impl Display for AggregateFunction {
@@ -51,10 +124,30 @@ impl Display for AggregateFunction {
             AggregateFunction::Avg => write!(f, "AVG"),
             AggregateFunction::Min => write!(f, "MIN"),
             AggregateFunction::Max => write!(f, "MAX"),
+            AggregateFunction::Quantile(q) => write!(f, "QUANTILE({})", q),
+            AggregateFunction::StdDev => write!(f, "STDDEV"),
+            AggregateFunction::Variance => write!(f, "VARIANCE"),
+            AggregateFunction::First => write!(f, "FIRST"),
+            AggregateFunction::Last => write!(f, "LAST"),
+            AggregateFunction::CountDistinct => write!(f, "COUNT_DISTINCT"),
+            AggregateFunction::Rate => write!(f, "RATE"),
+            AggregateFunction::Delta => write!(f, "DELTA"),
         }
     }
 }
 
+/// A single aggregate expression: a function applied to a column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateExpr {
+    /// The aggregation function to apply
+    pub function: AggregateFunction,
+    /// The column to aggregate, or `*` to count rows
+    pub column: String,
+    /// Name of the output column, see [`output_name`](Self::output_name)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
 /// Grouping specification for aggregation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupBy {
@@ -62,96 +155,684 @@ pub struct GroupBy {
     pub time_column: Option<String>,
 }
 
-/// Result of an aggregation operation
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AggregateResult {
-    pub value: f64,
-    pub timestamp: i64,
+/// Ordering on an output column of an aggregation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderBy {
+    /// A group by column, `window_start`, `window_end` or an aggregate
+    /// output name
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
 }
 
+/// Keeps the `k` top rows per time window, ranked by an output column
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopK {
+    pub k: usize,
+    pub by: OrderBy,
+}
+
+/// Clauses applied to the result of an aggregation
+///
+/// They are applied in SQL order: `fill` emits the missing windows,
+/// `having` filters the aggregated rows, `top_k` ranks the remaining groups
+/// within each window, then the result is sorted by `order_by` and truncated
+/// to `limit` rows.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResultOptions {
+    /// Emit every window between the start and end of the time range, filled
+    /// with this strategy; requires a time window and both range bounds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<FillStrategy>,
+    /// Filter on the output columns, like SQL `HAVING`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub having: Option<Filter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order_by: Vec<OrderBy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<TopK>,
+}
+
+/// Name of the column counting the rows aggregated into each row of an
+/// aggregation result, after the aggregate columns.
+pub const ROW_COUNT_COLUMN: &str = "row_count";
+
 impl TimeWindow {
     /// Calculates the window boundaries for a given timestamp
+    ///
+    /// For sliding windows this is the latest window containing the
+    /// timestamp, see [`windows`](Self::windows) for all of them. For session
+    /// windows it is the extent of a session consisting of this event only.
     pub fn window_bounds(&self, timestamp: i64) -> (i64, i64) {
         match *self {
             TimeWindow::None => (i64::MIN, i64::MAX),
             TimeWindow::Fixed(duration) => {
-                let window_size = duration.as_secs() as i64;
-                let window_start = (timestamp / window_size) * window_size;
+                let window_size = duration.as_secs().max(1) as i64;
+                let window_start = timestamp.div_euclid(window_size) * window_size;
                 (window_start, window_start + window_size)
             },
             TimeWindow::Sliding { window, slide } => {
                 let window_size = window.as_secs() as i64;
-                let slide_size = slide.as_secs() as i64;
-                let current_slide = (timestamp / slide_size) * slide_size;
+                let slide_size = slide.as_secs().max(1) as i64;
+                let current_slide = timestamp.div_euclid(slide_size) * slide_size;
                 (current_slide, current_slide + window_size)
             }
+            TimeWindow::Aligned { size, origin } => {
+                let size = size.as_secs().max(1) as i64;
+                let window_start = origin + (timestamp - origin).div_euclid(size) * size;
+                (window_start, window_start + size)
+            }
+            TimeWindow::Calendar { unit, timezone, offset } => {
+                calendar::window_bounds(unit, timezone, offset, timestamp)
+            }
+            TimeWindow::Session { gap } => (timestamp, timestamp + gap.as_secs().max(1) as i64),
         }
     }
 
-    /// Generates SQL expressions for window boundaries
+    /// Checks that the window can be assigned to events.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a sliding window slides further
+    /// than its size, which would skip events, or an event would belong to
+    /// more than [`MAX_SLIDING_WINDOWS`] windows.
+    pub fn validate(&self) -> Result<(), Status> {
+        if let TimeWindow::Sliding { window, slide } = *self {
+            let window_size = window.as_secs().max(1);
+            let slide_size = slide.as_secs().max(1);
+            if slide_size > window_size {
+                return Err(Status::invalid_argument(format!(
+                    "Sliding window slide of {}s exceeds its size of {}s",
+                    slide_size, window_size
+                )));
+            }
+            if window_size.div_ceil(slide_size) > MAX_SLIDING_WINDOWS {
+                return Err(Status::invalid_argument(format!(
+                    "Sliding window of {}s sliding by {}s assigns an event to more than {} windows",
+                    window_size, slide_size, MAX_SLIDING_WINDOWS
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the boundaries of every window containing a timestamp.
+    ///
+    /// Sliding windows start at multiples of `slide`, so an event belongs to
+    /// about `window / slide` windows; all other windows assign an event to
+    /// exactly one window, see [`window_bounds`](Self::window_bounds).
+    pub fn windows(&self, timestamp: i64) -> Vec<(i64, i64)> {
+        match *self {
+            TimeWindow::Sliding { window, slide } => {
+                let window_size = window.as_secs().max(1) as i64;
+                let slide_size = slide.as_secs().max(1) as i64;
+                let last = timestamp.div_euclid(slide_size) * slide_size;
+                let first = ((timestamp - window_size).div_euclid(slide_size) + 1) * slide_size;
+                (first..=last)
+                    .step_by(slide_size as usize)
+                    .map(|start| (start, start + window_size))
+                    .collect()
+            }
+            _ => vec![self.window_bounds(timestamp)],
+        }
+    }
+
+    /// Returns every window containing a timestamp in `[from, to)`, in
+    /// order of their start.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` for [`TimeWindow::None`] and
+    /// session windows, which have no fixed boundaries, or if the range
+    /// spans more than [`MAX_FILL_WINDOWS`] windows.
+    pub fn windows_between(&self, from: i64, to: i64) -> Result<Vec<(i64, i64)>, Status> {
+        let mut windows = Vec::new();
+        if from >= to {
+            return Ok(windows);
+        }
+        let (mut start, mut end) = match self {
+            TimeWindow::None | TimeWindow::Session { .. } => {
+                return Err(Status::invalid_argument("Only windows with fixed boundaries can be enumerated"));
+            }
+            _ => self.windows(from)[0],
+        };
+        while start < to {
+            if windows.len() == MAX_FILL_WINDOWS {
+                return Err(Status::invalid_argument(format!(
+                    "Time range spans more than {} windows",
+                    MAX_FILL_WINDOWS
+                )));
+            }
+            windows.push((start, end));
+            (start, end) = match *self {
+                TimeWindow::Sliding { slide, .. } => {
+                    let slide = slide.as_secs().max(1) as i64;
+                    (start + slide, end + slide)
+                }
+                _ => self.window_bounds(end),
+            };
+        }
+        Ok(windows)
+    }
+
+    /// Generates SQL expressions for window boundaries in the DuckDB dialect
     pub fn to_sql(&self) -> Option<String> {
+        self.to_sql_between(None, None, SqlDialect::DuckDb)
+    }
+
+    /// Like [`to_sql`](Self::to_sql), but in `dialect` and only valid for
+    /// timestamps in `[from, to)`, which keeps the SQL of calendar windows
+    /// short.
+    ///
+    /// Returns `None` for sliding windows in the Postgres dialect, which
+    /// repeat rows with a join, see [`source_sql`](Self::source_sql).
+    pub fn to_sql_between(&self, from: Option<i64>, to: Option<i64>, dialect: SqlDialect) -> Option<String> {
         match *self {
             TimeWindow::None => None,
             TimeWindow::Fixed(duration) => {
-                let window_size = duration.as_secs();
+                let window_size = duration.as_secs().max(1);
                 Some(format!(
-                    "(timestamp / {}) * {} as window_start, 
-                    ((timestamp / {}) + 1) * {} as window_end",
-                    window_size, window_size, window_size, window_size
+                    "CAST(FLOOR(timestamp / {s}.0) AS BIGINT) * {s} as window_start,
+                    (CAST(FLOOR(timestamp / {s}.0) AS BIGINT) + 1) * {s} as window_end",
+                    s = window_size
                 ))
             },
+            TimeWindow::Sliding { .. } if dialect == SqlDialect::Postgres => None,
             TimeWindow::Sliding { window, slide } => {
-                let window_size = window.as_secs();
-                let slide_size = slide.as_secs();
+                // One row per overlapping window; both lists have the same
+                // length, so DuckDB unnests them side by side
+                let window_size = window.as_secs().max(1);
+                let slide_size = slide.as_secs().max(1);
+                let starts = format!(
+                    "generate_series((CAST(FLOOR((timestamp - {w}) / {s}.0) AS BIGINT) + 1) * {s}, \
+                    CAST(FLOOR(timestamp / {s}.0) AS BIGINT) * {s}, {s})",
+                    w = window_size,
+                    s = slide_size
+                );
                 Some(format!(
-                    "(timestamp / {}) * {} as window_start,
-                    ((timestamp / {}) * {} + {}) as window_end",
-                    slide_size, slide_size, slide_size, slide_size, window_size
+                    "UNNEST({starts}) as window_start,
+                    UNNEST(list_transform({starts}, start -> start + {w})) as window_end",
+                    starts = starts,
+                    w = window_size
                 ))
             }
+            TimeWindow::Aligned { size, origin } => {
+                let size = size.as_secs().max(1);
+                Some(format!(
+                    "({o} + CAST(FLOOR((timestamp - {o}) / {s}.0) AS BIGINT) * {s}) as window_start,
+                    ({o} + (CAST(FLOOR((timestamp - {o}) / {s}.0) AS BIGINT) + 1) * {s}) as window_end",
+                    o = origin,
+                    s = size
+                ))
+            }
+            TimeWindow::Calendar { unit, timezone, offset } => {
+                Some(calendar::window_sql(
+                    unit,
+                    timezone,
+                    offset,
+                    "timestamp",
+                    from.unwrap_or(i64::MIN),
+                    to.unwrap_or(i64::MAX),
+                    dialect,
+                ))
+            }
+            // Sessions depend on neighbouring rows, see `source_sql`
+            TimeWindow::Session { .. } => None,
+        }
+    }
+
+    /// Generates a SQL source that adds `window_start` and `window_end`
+    /// columns to the rows of `table`.
+    ///
+    /// Rows are repeated for every window they belong to. Sessions are formed
+    /// per combination of the `partition_by` columns, typically `metric_id`.
+    /// The SQL is only valid for rows of `table` within `[from, to)`.
+    /// Returns `None` for [`TimeWindow::None`].
+    pub fn source_sql(
+        &self,
+        table: &str,
+        partition_by: &[String],
+        from: Option<i64>,
+        to: Option<i64>,
+        dialect: SqlDialect,
+    ) -> Option<String> {
+        match *self {
+            TimeWindow::Sliding { window, slide } if dialect == SqlDialect::Postgres => {
+                // Postgres cannot unnest lists side by side: join every row
+                // with the index of each window it may belong to, at most
+                // `MAX_SLIDING_WINDOWS` after `validate`
+                let window_size = window.as_secs().max(1);
+                let slide_size = slide.as_secs().max(1);
+                let indices: Vec<String> = (0..window_size.div_ceil(slide_size)).map(|i| format!("({})", i)).collect();
+                let start = format!(
+                    "(CAST(FLOOR(timestamp / {s}.0) AS BIGINT) - slide_index) * {s}",
+                    s = slide_size
+                );
+                Some(format!(
+                    "(SELECT *, {start} as window_start, {start} + {w} as window_end \
+                    FROM {table} CROSS JOIN (VALUES {indices}) AS slides(slide_index) \
+                    WHERE {start} > timestamp - {w}) AS windowed",
+                    start = start,
+                    w = window_size,
+                    table = table,
+                    indices = indices.join(", ")
+                ))
+            }
+            TimeWindow::Session { gap } => {
+                let partition = if partition_by.is_empty() {
+                    String::new()
+                } else {
+                    format!("PARTITION BY {} ", partition_by.join(", "))
+                };
+                let sessions = if partition_by.is_empty() {
+                    "PARTITION BY session_id".to_string()
+                } else {
+                    format!("PARTITION BY {}, session_id", partition_by.join(", "))
+                };
+                Some(format!(
+                    "(SELECT *, MIN(timestamp) OVER ({sessions}) as window_start, \
+                    MAX(timestamp) OVER ({sessions}) + {gap} as window_end \
+                    FROM (SELECT *, SUM(session_break) OVER ({partition}ORDER BY timestamp) as session_id \
+                    FROM (SELECT *, CASE WHEN timestamp - LAG(timestamp) OVER ({partition}ORDER BY timestamp) < {gap} \
+                    THEN 0 ELSE 1 END as session_break FROM {table}) AS breaks) AS sessions) AS windowed",
+                    sessions = sessions,
+                    partition = partition,
+                    gap = gap.as_secs().max(1),
+                    table = table
+                ))
+            }
+            _ => self
+                .to_sql_between(from, to, dialect)
+                .map(|window_sql| format!("(SELECT *, {} FROM {}) AS windowed", window_sql, table)),
         }
     }
 }
 
 impl AggregateFunction {
-    /// Generates SQL for the aggregation function
+    /// Generates SQL for the aggregation function in the DuckDB dialect
+    ///
+    /// Order-dependent functions (`First`, `Last`, `Rate`, `Delta`) order by
+    /// the `timestamp` column; see [`to_sql_with_time`](Self::to_sql_with_time).
     pub fn to_sql(&self, column: &str) -> String {
+        self.to_sql_with_time(column, "timestamp", SqlDialect::DuckDb)
+    }
+
+    /// Generates SQL for the aggregation function in `dialect`, ordering by
+    /// `time_column`.
+    ///
+    /// `Rate` is the change between the first and last value divided by the
+    /// seconds between them, and is NULL if they share a timestamp. In the
+    /// Postgres dialect, `First` and `Last` take the head of an ordered
+    /// `ARRAY_AGG`, quantiles use `PERCENTILE_CONT` and distinct counts are
+    /// exact.
+    pub fn to_sql_with_time(&self, column: &str, time_column: &str, dialect: SqlDialect) -> String {
+        // The non-null value at the earliest or latest time
+        let at = |latest: bool| match dialect {
+            SqlDialect::DuckDb => format!("{}({}, {})", if latest { "ARG_MAX" } else { "ARG_MIN" }, column, time_column),
+            SqlDialect::Postgres => format!(
+                "(ARRAY_AGG({c} ORDER BY {t}{d}) FILTER (WHERE {c} IS NOT NULL AND {t} IS NOT NULL))[1]",
+                c = column,
+                t = time_column,
+                d = if latest { " DESC" } else { "" }
+            ),
+        };
+        let delta = || format!("({} - {})", at(true), at(false));
         match self {
             AggregateFunction::Sum => format!("SUM({})", column),
             AggregateFunction::Avg => format!("AVG({})", column),
             AggregateFunction::Min => format!("MIN({})", column),
             AggregateFunction::Max => format!("MAX({})", column),
             AggregateFunction::Count => format!("COUNT({})", column),
+            AggregateFunction::Quantile(q) => match dialect {
+                SqlDialect::DuckDb => format!("QUANTILE_CONT({}, {})", column, q),
+                SqlDialect::Postgres => format!("PERCENTILE_CONT({}) WITHIN GROUP (ORDER BY {})", q, column),
+            },
+            AggregateFunction::StdDev => format!("STDDEV_SAMP({})", column),
+            AggregateFunction::Variance => format!("VAR_SAMP({})", column),
+            AggregateFunction::First => at(false),
+            AggregateFunction::Last => at(true),
+            AggregateFunction::CountDistinct => match dialect {
+                SqlDialect::DuckDb => format!("APPROX_COUNT_DISTINCT({})", column),
+                SqlDialect::Postgres => format!("COUNT(DISTINCT {})", column),
+            },
+            AggregateFunction::Rate => format!(
+                "({} / NULLIF(MAX({t}) - MIN({t}), 0))",
+                delta(),
+                t = time_column
+            ),
+            AggregateFunction::Delta => delta(),
+        }
+    }
+
+    /// Checks the parameters of the function.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a quantile is outside `[0, 1]`.
+    pub fn validate(&self) -> Result<(), Status> {
+        match self {
+            AggregateFunction::Quantile(q) if !(0.0..=1.0).contains(q) => Err(
+                Status::invalid_argument(format!("Quantile must be between 0 and 1, got {}", q)),
+            ),
+            _ => Ok(()),
         }
     }
 }
 
+impl AggregateExpr {
+    /// Creates an expression applying `function` to `column`.
+    pub fn new(function: AggregateFunction, column: impl Into<String>) -> Self {
+        Self {
+            function,
+            column: column.into(),
+            alias: None,
+        }
+    }
+
+    /// Sets the name of the output column.
+    pub fn with_alias(mut self, alias: impl Into<String>) -> Self {
+        self.alias = Some(alias.into());
+        self
+    }
+
+    /// Returns the name of the output column.
+    ///
+    /// Without an alias this is the lowercase function and column joined by
+    /// an underscore, e.g. `sum_value`, `quantile_0_99_latency` or `count`
+    /// for `COUNT(*)`.
+    pub fn output_name(&self) -> String {
+        if let Some(alias) = &self.alias {
+            return alias.clone();
+        }
+        let function = self.function.to_string().to_lowercase()
+            .replace(['(', ')'], "_")
+            .replace('.', "_")
+            .trim_end_matches('_')
+            .to_string();
+        if self.column == "*" {
+            function
+        } else {
+            format!("{}_{}", function, self.column)
+        }
+    }
+
+    /// Generates the SQL select item of the expression in `dialect`,
+    /// ordering order-dependent functions by `time_column`.
+    pub fn to_sql(&self, time_column: &str, dialect: SqlDialect) -> String {
+        let expr = match (self.function, self.column.as_str()) {
+            (AggregateFunction::Count, "*") => "COUNT(*)".to_string(),
+            (function, column) => function.to_sql_with_time(column, time_column, dialect),
+        };
+        format!("{} AS {}", expr, self.output_name())
+    }
+}
+
+/// Checks an aggregation spec against the schema of the aggregated table.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the spec is empty, a function has
+/// invalid parameters, an aggregated or grouped column is not in `schema`,
+/// `*` is used with a function other than `Count`, or output names are not
+/// unique identifiers.
+pub fn validate_aggregates(
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    schema: &Schema,
+) -> Result<(), Status> {
+    if aggregates.is_empty() {
+        return Err(Status::invalid_argument("At least one aggregate expression is required"));
+    }
+
+    let has_column = |name: &str| schema.fields().iter().any(|f| f.name() == name);
+    let grouped = group_by.columns.iter().chain(group_by.time_column.iter());
+    for column in grouped {
+        if !has_column(column) {
+            return Err(Status::invalid_argument(format!("Unknown group by column {}", column)));
+        }
+    }
+
+    let mut names: Vec<String> = group_by.columns.iter().chain(group_by.time_column.iter()).cloned().collect();
+    for aggregate in aggregates {
+        aggregate.function.validate()?;
+        match (aggregate.function, aggregate.column.as_str()) {
+            (AggregateFunction::Count, "*") => {}
+            (function, "*") => {
+                return Err(Status::invalid_argument(format!("{} cannot be applied to *", function)));
+            }
+            (_, column) if !has_column(column) => {
+                return Err(Status::invalid_argument(format!("Unknown aggregate column {}", column)));
+            }
+            _ => {}
+        }
+
+        let name = aggregate.output_name();
+        if !is_identifier(&name) {
+            return Err(Status::invalid_argument(format!("Invalid aggregate alias {}", name)));
+        }
+        if names.contains(&name) {
+            return Err(Status::invalid_argument(format!("Duplicate output column {}", name)));
+        }
+        names.push(name);
+    }
+    Ok(())
+}
+
+impl OrderBy {
+    /// Orders by `column` in ascending order.
+    pub fn asc(column: impl Into<String>) -> Self {
+        Self { column: column.into(), descending: false }
+    }
+
+    /// Orders by `column` in descending order.
+    pub fn desc(column: impl Into<String>) -> Self {
+        Self { column: column.into(), descending: true }
+    }
+
+    /// Renders the ordering as SQL, with nulls last in either direction.
+    fn to_sql(&self) -> String {
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        format!("{} {} NULLS LAST", self.column, direction)
+    }
+}
+
+impl ResultOptions {
+    /// Returns whether no clause is set.
+    pub fn is_empty(&self) -> bool {
+        self.fill.is_none() && self.having.is_none() && self.order_by.is_empty() && self.limit.is_none() && self.top_k.is_none()
+    }
+
+    /// Checks the clauses against the output schema of the aggregation, see
+    /// [`aggregate_output_schema`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the `having` filter is invalid,
+    /// an ordering refers to an unknown output column or `k` is zero.
+    pub fn validate(&self, output: &Schema) -> Result<(), Status> {
+        if let Some(having) = &self.having {
+            having.validate(output)?;
+        }
+        let top_k = self.top_k.as_ref().map(|top_k| &top_k.by);
+        for order in self.order_by.iter().chain(top_k) {
+            if output.field_with_name(&order.column).is_err() {
+                return Err(Status::invalid_argument(format!("Unknown output column {}", order.column)));
+            }
+        }
+        if self.top_k.as_ref().is_some_and(|top_k| top_k.k == 0) {
+            return Err(Status::invalid_argument("Top-k requires k > 0"));
+        }
+        Ok(())
+    }
+
+    /// Wraps an aggregation query with the clauses in `dialect`. Top-k
+    /// partitions by `partition`, the columns identifying a window; in the
+    /// Postgres dialect, which has no `QUALIFY`, rows are ranked in a
+    /// subquery and the output `columns` selected from it.
+    fn apply_sql(&self, query: String, partition: &[&str], columns: &[String], dialect: SqlDialect) -> Result<String, Status> {
+        if self.having.is_none() && self.order_by.is_empty() && self.limit.is_none() && self.top_k.is_none() {
+            return Ok(query);
+        }
+
+        let having = match &self.having {
+            Some(having) => format!(" WHERE {}", having.to_sql(dialect)?),
+            None => String::new(),
+        };
+        let mut wrapped = match &self.top_k {
+            None => format!("SELECT * FROM ({}) AS aggregated{}", query, having),
+            Some(top_k) => {
+                let partition = if partition.is_empty() {
+                    String::new()
+                } else {
+                    format!("PARTITION BY {} ", partition.join(", "))
+                };
+                let rank = format!("ROW_NUMBER() OVER ({}ORDER BY {})", partition, top_k.by.to_sql());
+                match dialect {
+                    SqlDialect::DuckDb => format!(
+                        "SELECT * FROM ({}) AS aggregated{} QUALIFY {} <= {}",
+                        query, having, rank, top_k.k
+                    ),
+                    SqlDialect::Postgres => format!(
+                        "SELECT {} FROM (SELECT *, {} AS top_k_rank FROM ({}) AS aggregated{}) AS ranked \
+                        WHERE top_k_rank <= {}",
+                        columns.join(", "),
+                        rank,
+                        query,
+                        having,
+                        top_k.k
+                    ),
+                }
+            }
+        };
+        if !self.order_by.is_empty() {
+            let order: Vec<String> = self.order_by.iter().map(|o| o.to_sql()).collect();
+            wrapped.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        }
+        if let Some(limit) = self.limit {
+            wrapped.push_str(&format!(" LIMIT {}", limit));
+        }
+        Ok(wrapped)
+    }
+}
+
+/// Returns the schema of the rows produced by an aggregation query.
+///
+/// The group by columns and the time column keep their source types and are
+/// followed by `window_start` and `window_end` if `windowed`. Counts are
+/// `Int64` and all other aggregates `Float64`.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if a group by column is not in
+/// `schema`, see [`validate_aggregates`].
+pub fn aggregate_output_schema(
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    windowed: bool,
+    schema: &Schema,
+) -> Result<Schema, Status> {
+    let mut fields = Vec::new();
+    for column in group_by.columns.iter().chain(group_by.time_column.iter()) {
+        let field = schema
+            .field_with_name(column)
+            .map_err(|_| Status::invalid_argument(format!("Unknown group by column {}", column)))?;
+        fields.push(field.clone());
+    }
+    if windowed {
+        fields.push(Field::new("window_start", DataType::Int64, false));
+        fields.push(Field::new("window_end", DataType::Int64, false));
+    }
+    for aggregate in aggregates {
+        let data_type = match aggregate.function {
+            AggregateFunction::Count | AggregateFunction::CountDistinct => DataType::Int64,
+            _ => DataType::Float64,
+        };
+        fields.push(Field::new(aggregate.output_name(), data_type, true));
+    }
+    Ok(Schema::new(fields))
+}
+
+/// Returns whether a name can be used in SQL without quoting.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Builds a SQL query for aggregation.
 ///
 /// This is the core query builder used by specific aggregation implementations.
 /// It provides a flexible way to build SQL queries for different types of
-/// time-series data aggregation.
+/// time-series data aggregation. All aggregate expressions are computed in a
+/// single pass, one output column each, after the group by columns.
 ///
 /// # Arguments
 ///
 /// * `table_name` - The source table name
-/// * `function` - The aggregation function to apply
+/// * `aggregates` - The aggregate expressions to compute
 /// * `group_by` - The grouping specification
-/// * `columns` - The columns to aggregate
-/// * `from_timestamp` - Optional start of the time range
-/// * `to_timestamp` - Optional end of the time range
+/// * `from_timestamp` - Optional start of the time range, inclusive
+/// * `to_timestamp` - Optional end of the time range, exclusive
+/// * `filter` - Optional filter on the rows of the source table
+/// * `options` - HAVING, ORDER BY, LIMIT and top-k clauses on the result.
+///   Without a window, top-k ranks per value of the time column, if any
 ///
 /// # Returns
 ///
-/// A SQL query string for the specified aggregation
+/// A SQL query string in the DuckDB dialect for the specified aggregation
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if a filter cannot be rendered, see
+/// [`Filter::to_sql`].
 pub fn build_aggregate_query(
     table_name: &str,
-    function: AggregateFunction,
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    from_timestamp: Option<i64>,
+    to_timestamp: Option<i64>,
+    filter: Option<&Filter>,
+    options: &ResultOptions,
+) -> Result<String, Status> {
+    build_windowed_aggregate_query(
+        table_name,
+        aggregates,
+        group_by,
+        TimeWindow::None,
+        from_timestamp,
+        to_timestamp,
+        filter,
+        options,
+        SqlDialect::DuckDb,
+    )
+}
+
+/// Builds a SQL query for aggregation per time window in `dialect`.
+///
+/// Like [`build_aggregate_query`], but additionally selects and groups by the
+/// `window_start` and `window_end` of `window`, see [`TimeWindow::source_sql`].
+/// Session windows are formed per combination of the group by columns. The
+/// filter and the time range `[from, to)` are applied before rows are
+/// assigned to windows, so sessions only consist of matching rows. Top-k ranks groups within each window.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the window is invalid, see
+/// [`TimeWindow::validate`], or a filter cannot be rendered.
+#[allow(clippy::too_many_arguments)]
+pub fn build_windowed_aggregate_query(
+    table_name: &str,
+    aggregates: &[AggregateExpr],
     group_by: &GroupBy,
-    _columns: &[&str],
+    window: TimeWindow,
     from_timestamp: Option<i64>,
     to_timestamp: Option<i64>,
-) -> String {
+    filter: Option<&Filter>,
+    options: &ResultOptions,
+    dialect: SqlDialect,
+) -> Result<String, Status> {
+    window.validate()?;
     let mut query = String::new();
     
     // Build SELECT clause
@@ -168,29 +849,44 @@ pub fn build_aggregate_query(
     if let Some(time_col) = &group_by.time_column {
         query.push_str(&format!("{}, ", time_col));
     }
-    
-    // Add aggregation function
-    match function {
-        AggregateFunction::Sum => query.push_str("SUM(value)"),
-        AggregateFunction::Avg => query.push_str("AVG(value)"),
-        AggregateFunction::Count => query.push_str("COUNT(*)"),
-        AggregateFunction::Min => query.push_str("MIN(value)"),
-        AggregateFunction::Max => query.push_str("MAX(value)"),
+
+    // Add window bounds if present, computed by the source of the rows
+    // within the range
+    let mut predicates = Vec::new();
+    if let Some(filter) = filter {
+        predicates.push(filter.to_sql(dialect)?);
     }
-    
-    // Add FROM clause
-    query.push_str(&format!(" FROM {}", table_name));
-    
-    // Add WHERE clause for timestamp range
     if let Some(from_ts) = from_timestamp {
-        query.push_str(&format!(" WHERE timestamp >= {}", from_ts));
-        if let Some(to_ts) = to_timestamp {
-            query.push_str(&format!(" AND timestamp <= {}", to_ts));
-        }
+        predicates.push(format!("timestamp >= {}", from_ts));
+    }
+    if let Some(to_ts) = to_timestamp {
+        predicates.push(format!("timestamp < {}", to_ts));
+    }
+    let source = if predicates.is_empty() {
+        table_name.to_string()
+    } else {
+        format!("(SELECT * FROM {} WHERE {}) AS filtered", table_name, predicates.join(" AND "))
+    };
+    let window_source = window.source_sql(
+        &source,
+        &group_by.columns,
+        from_timestamp,
+        from_timestamp.and(to_timestamp).map(|to| to.saturating_add(1)),
+        dialect,
+    );
+    if window_source.is_some() {
+        query.push_str("window_start, window_end, ");
     }
     
+    // Add aggregate expressions
+    let exprs: Vec<String> = aggregates.iter().map(|a| a.to_sql("timestamp", dialect)).collect();
+    query.push_str(&exprs.join(", "));
+    
+    // Add FROM clause
+    query.push_str(&format!(" FROM {}", window_source.as_deref().unwrap_or(&source)));
+    
     // Add GROUP BY clause
-    if !group_by.columns.is_empty() || group_by.time_column.is_some() {
+    if !group_by.columns.is_empty() || group_by.time_column.is_some() || window_source.is_some() {
         query.push_str(" GROUP BY ");
         let mut group_cols = Vec::new();
         
@@ -202,9 +898,35 @@ pub fn build_aggregate_query(
         if let Some(time_col) = &group_by.time_column {
             group_cols.push(time_col.as_str());
         }
+
+        if window_source.is_some() {
+            group_cols.extend(["window_start", "window_end"]);
+        }
         
         query.push_str(&group_cols.join(", "));
     }
-    
-    query
+
+    // Emit missing windows before the clauses refer to them
+    if let Some(strategy) = options.fill {
+        let (Some(from), Some(to)) = (from_timestamp, to_timestamp) else {
+            return Err(Status::invalid_argument("Gap filling requires a start and end of the time range"));
+        };
+        if window_source.is_none() {
+            return Err(Status::invalid_argument("Gap filling requires a time window"));
+        }
+        query = fill_sql(&query, aggregates, group_by, &window.windows_between(from, to)?, strategy, dialect)?;
+    }
+
+    // Apply the clauses on the aggregated rows
+    let partition: Vec<&str> = if window_source.is_some() {
+        vec!["window_start", "window_end"]
+    } else {
+        group_by.time_column.iter().map(|c| c.as_str()).collect()
+    };
+    let mut columns: Vec<String> = group_by.columns.iter().chain(group_by.time_column.iter()).cloned().collect();
+    if window_source.is_some() {
+        columns.extend(["window_start".to_string(), "window_end".to_string()]);
+    }
+    columns.extend(aggregates.iter().map(|a| a.output_name()));
+    options.apply_sql(query, &partition, &columns, dialect)
 } 
\ No newline at end of file
@@ -0,0 +1,153 @@
+//! Online anomaly detectors for windowed series.
+//!
+//! An [`AnomalyDetector`] scores each value of a series against the values
+//! before it, as a signed deviation in units of the expected spread, and
+//! flags values whose score exceeds its threshold in magnitude:
+//!
+//! - `ZScore`: the mean and sample standard deviation of the previous
+//!   `window` values
+//! - `Ewma`: an exponentially weighted mean and variance, i.e. EWMA control
+//!   bands of `threshold` standard deviations
+//! - `HoltWinters`: the one-step forecast of a seasonal model and the
+//!   standard deviation of its past residuals, see [`HoltWinters`]
+//!
+//! Detectors do not score values until they saw enough history: two values
+//! for `ZScore` and `Ewma`, and two seasons for `HoltWinters`, whose second
+//! season also provides the first residuals (three values with a season of
+//! one window). A spread of zero is floored to a tiny fraction of the
+//! expected value, so any deviation from a constant series scores high.
+
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use super::smoothing::{HoltWinters, HoltWintersParams};
+
+/// Smallest spread relative to the expected value, or to 1 near zero.
+const MIN_RELATIVE_SPREAD: f64 = 1e-9;
+
+/// How the values of a series are scored.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyDetector {
+    ZScore { window: usize, threshold: f64 },
+    Ewma { alpha: f64, threshold: f64 },
+    HoltWinters {
+        #[serde(flatten)]
+        params: HoltWintersParams,
+        threshold: f64,
+    },
+}
+
+/// The score of one value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Observation {
+    /// Value the detector expected
+    pub expected: f64,
+    /// Deviation from the expected value in units of the expected spread
+    pub score: f64,
+    /// Whether the score exceeds the threshold in magnitude
+    pub anomaly: bool,
+}
+
+impl AnomalyDetector {
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the threshold is not positive,
+    /// a z-score window has fewer than two values, an EWMA alpha is not in
+    /// `(0, 1]` or Holt-Winters parameters are invalid.
+    pub fn validate(&self) -> Result<(), Status> {
+        let threshold = match *self {
+            AnomalyDetector::ZScore { window, threshold } => {
+                if window < 2 {
+                    return Err(Status::invalid_argument("Z-score window must hold at least two values"));
+                }
+                threshold
+            }
+            AnomalyDetector::Ewma { alpha, threshold } => {
+                if !(alpha > 0.0 && alpha <= 1.0) {
+                    return Err(Status::invalid_argument("EWMA alpha must be in (0, 1]"));
+                }
+                threshold
+            }
+            AnomalyDetector::HoltWinters { params, threshold } => {
+                params.validate()?;
+                threshold
+            }
+        };
+        if !(threshold > 0.0 && threshold.is_finite()) {
+            return Err(Status::invalid_argument("Anomaly threshold must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Returns the state of a new series.
+    pub fn start(&self) -> Result<DetectorState, Status> {
+        self.validate()?;
+        Ok(match *self {
+            AnomalyDetector::ZScore { window, threshold } => {
+                DetectorState::ZScore { window, threshold, history: VecDeque::with_capacity(window) }
+            }
+            AnomalyDetector::Ewma { alpha, threshold } => {
+                DetectorState::Ewma { alpha, threshold, count: 0, mean: 0.0, variance: 0.0 }
+            }
+            AnomalyDetector::HoltWinters { params, threshold } => {
+                DetectorState::HoltWinters { model: HoltWinters::new(params)?, threshold }
+            }
+        })
+    }
+}
+
+/// State of a detector for one series.
+#[derive(Debug, Clone)]
+pub enum DetectorState {
+    ZScore { window: usize, threshold: f64, history: VecDeque<f64> },
+    Ewma { alpha: f64, threshold: f64, count: usize, mean: f64, variance: f64 },
+    HoltWinters { model: HoltWinters, threshold: f64 },
+}
+
+impl DetectorState {
+    /// Scores the next value of the series, then adds it to the history.
+    /// Returns `None` while the detector lacks history.
+    pub fn observe(&mut self, value: f64) -> Option<Observation> {
+        match self {
+            DetectorState::ZScore { window, threshold, history } => {
+                let observation = (history.len() >= 2).then(|| {
+                    let count = history.len() as f64;
+                    let mean = history.iter().sum::<f64>() / count;
+                    let variance = history.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1.0);
+                    score(value, mean, variance.sqrt(), *threshold)
+                });
+                if history.len() == *window {
+                    history.pop_front();
+                }
+                history.push_back(value);
+                observation
+            }
+            DetectorState::Ewma { alpha, threshold, count, mean, variance } => {
+                let observation = (*count >= 2).then(|| score(value, *mean, variance.sqrt(), *threshold));
+                if *count == 0 {
+                    *mean = value;
+                } else {
+                    let deviation = value - *mean;
+                    let increment = *alpha * deviation;
+                    *mean += increment;
+                    *variance = (1.0 - *alpha) * (*variance + deviation * increment);
+                }
+                *count += 1;
+                observation
+            }
+            DetectorState::HoltWinters { model, threshold } => {
+                let expected = model.forecast(1);
+                let spread = model.residual_stddev();
+                model.update(value);
+                Some(score(value, expected?, spread?, *threshold))
+            }
+        }
+    }
+}
+
+fn score(value: f64, expected: f64, spread: f64, threshold: f64) -> Observation {
+    let spread = spread.max(MIN_RELATIVE_SPREAD * expected.abs().max(1.0));
+    let score = (value - expected) / spread;
+    Observation { expected, score, anomaly: score.abs() > threshold }
+}
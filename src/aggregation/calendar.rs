@@ -0,0 +1,290 @@
+//! Calendar-aligned time windows in an IANA timezone.
+//!
+//! Calendar windows start at local midnight of a day, a week (starting
+//! Monday), a month or a quarter, optionally shifted by an offset such as a
+//! business day starting at 06:00. Boundaries are returned as Unix seconds,
+//! so a day window spans 23 or 25 hours across a daylight saving change.
+//!
+//! Local boundaries that occur twice resolve to the earlier instant, and
+//! boundaries that fall into a daylight saving gap resolve to the end of the
+//! gap. The SQL rendered by [`window_sql`] follows the same rules; as the
+//! bundled DuckDB has no timezone database, the UTC offsets of the zone
+//! between [`SQL_FROM_YEAR`] and [`SQL_TO_YEAR`] are rendered into the query.
+//! The offset changes of a zone are computed once and cached, and only those
+//! around the queried time range are rendered.
+
+use chrono::{Datelike, Duration as ChronoDuration, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use super::filter::SqlDialect;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// First year whose offset changes are rendered into SQL.
+pub const SQL_FROM_YEAR: i32 = 1970;
+
+/// Year from which on SQL uses the last known offset of a zone.
+pub const SQL_TO_YEAR: i32 = 2100;
+
+/// Calendar unit of a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalendarUnit {
+    Day,
+    /// ISO week, starting on Monday
+    Week,
+    Month,
+    Quarter,
+}
+
+impl CalendarUnit {
+    /// Returns the first day of the unit containing `date`.
+    fn truncate(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            CalendarUnit::Day => date,
+            CalendarUnit::Week => date - ChronoDuration::days(date.weekday().num_days_from_monday() as i64),
+            CalendarUnit::Month => date.with_day(1).unwrap_or(date),
+            CalendarUnit::Quarter => {
+                let month = (date.month0() / 3) * 3 + 1;
+                NaiveDate::from_ymd_opt(date.year(), month, 1).unwrap_or(date)
+            }
+        }
+    }
+
+    /// Returns the first day of the next unit after the one starting at `start`.
+    fn advance(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            CalendarUnit::Day => start + ChronoDuration::days(1),
+            CalendarUnit::Week => start + ChronoDuration::days(7),
+            CalendarUnit::Month => start.checked_add_months(chrono::Months::new(1)).unwrap_or(start),
+            CalendarUnit::Quarter => start.checked_add_months(chrono::Months::new(3)).unwrap_or(start),
+        }
+    }
+
+    fn sql_part(&self) -> &'static str {
+        match self {
+            CalendarUnit::Day => "day",
+            CalendarUnit::Week => "week",
+            CalendarUnit::Month => "month",
+            CalendarUnit::Quarter => "quarter",
+        }
+    }
+
+    fn sql_interval(&self, dialect: SqlDialect) -> &'static str {
+        match (self, dialect) {
+            (CalendarUnit::Day, SqlDialect::DuckDb) => "INTERVAL 1 DAY",
+            (CalendarUnit::Week, SqlDialect::DuckDb) => "INTERVAL 7 DAY",
+            (CalendarUnit::Month, SqlDialect::DuckDb) => "INTERVAL 1 MONTH",
+            (CalendarUnit::Quarter, SqlDialect::DuckDb) => "INTERVAL 3 MONTH",
+            (CalendarUnit::Day, SqlDialect::Postgres) => "INTERVAL '1 day'",
+            (CalendarUnit::Week, SqlDialect::Postgres) => "INTERVAL '7 days'",
+            (CalendarUnit::Month, SqlDialect::Postgres) => "INTERVAL '1 month'",
+            (CalendarUnit::Quarter, SqlDialect::Postgres) => "INTERVAL '3 months'",
+        }
+    }
+}
+
+/// Returns the calendar window containing `timestamp`.
+///
+/// `offset` shifts the window boundaries from local midnight.
+pub fn window_bounds(unit: CalendarUnit, timezone: Tz, offset: Duration, timestamp: i64) -> (i64, i64) {
+    let offset = ChronoDuration::seconds(offset.as_secs() as i64);
+    let local = match timezone.timestamp_opt(timestamp, 0) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.naive_local(),
+        LocalResult::None => return (timestamp, timestamp + 1),
+    };
+
+    let start = unit.truncate((local - offset).date());
+    let end = unit.advance(start);
+    let to_utc = |date: NaiveDate| local_to_utc(timezone, date.and_time(chrono::NaiveTime::MIN) + offset);
+    (to_utc(start), to_utc(end))
+}
+
+/// Resolves a local time to Unix seconds.
+fn local_to_utc(timezone: Tz, local: NaiveDateTime) -> i64 {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.timestamp(),
+        LocalResult::None => {
+            // Inside a gap: find the transition, the first instant after it
+            let naive = local.and_utc().timestamp();
+            let before = utc_offset(timezone, naive - 86_400);
+            let after = utc_offset(timezone, naive + 86_400);
+            let (mut lo, mut hi) = (naive - after, naive - before);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if utc_offset(timezone, mid) == after {
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+            lo
+        }
+    }
+}
+
+/// UTC offset of the zone at `timestamp`, in seconds.
+fn utc_offset(timezone: Tz, timestamp: i64) -> i64 {
+    match timezone.timestamp_opt(timestamp, 0) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => dt.offset().fix().local_minus_utc() as i64,
+        LocalResult::None => 0,
+    }
+}
+
+/// Offset changes of a zone as `(instant, offset before, offset after)`,
+/// after an initial offset.
+#[derive(Debug)]
+struct Transitions {
+    initial: i64,
+    changes: Vec<(i64, i64, i64)>,
+}
+
+impl Transitions {
+    /// Returns the offset before and the changes of the zone that matter for
+    /// local times within `margin` seconds of `[from, to)`.
+    fn within(&self, from: i64, to: i64, margin: i64) -> (i64, &[(i64, i64, i64)]) {
+        let (from, to) = (from.saturating_sub(margin), to.saturating_add(margin));
+        let first = self.changes.partition_point(|(instant, _, _)| *instant < from);
+        let last = self.changes.partition_point(|(instant, _, _)| *instant < to);
+        let initial = match first {
+            0 => self.initial,
+            i => self.changes[i - 1].2,
+        };
+        (initial, &self.changes[first..last.max(first)])
+    }
+}
+
+/// Returns the offset changes of a zone, computed once per zone.
+fn transitions(timezone: Tz) -> Arc<Transitions> {
+    static CACHE: OnceLock<Mutex<HashMap<Tz, Arc<Transitions>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(transitions) = cache.lock().ok().and_then(|cache| cache.get(&timezone).cloned()) {
+        return transitions;
+    }
+    let transitions = Arc::new(compute_transitions(timezone));
+    if let Ok(mut cache) = cache.lock() {
+        cache.insert(timezone, transitions.clone());
+    }
+    transitions
+}
+
+fn compute_transitions(timezone: Tz) -> Transitions {
+    let from = NaiveDate::from_ymd_opt(SQL_FROM_YEAR, 1, 1).unwrap_or_default().and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+    let to = NaiveDate::from_ymd_opt(SQL_TO_YEAR, 1, 1).unwrap_or_default().and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+    let initial = utc_offset(timezone, from);
+
+    let mut changes = Vec::new();
+    let mut current = initial;
+    let mut day = from;
+    while day < to {
+        let next = day + 86_400;
+        let offset = utc_offset(timezone, next);
+        if offset != current {
+            let (mut lo, mut hi) = (day, next);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if utc_offset(timezone, mid) == current {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            changes.push((lo, current, offset));
+            current = offset;
+        }
+        day = next;
+    }
+    Transitions { initial, changes }
+}
+
+/// Renders the local time of the Unix seconds `column`.
+fn utc_to_local_sql(column: &str, initial: i64, changes: &[(i64, i64, i64)]) -> String {
+    if changes.is_empty() {
+        return format!("({} + {})", column, initial);
+    }
+    let mut sql = String::from("(CASE");
+    for (instant, before, _) in changes {
+        let _ = write!(sql, " WHEN {c} < {t} THEN {c} + {o}", c = column, t = instant, o = before);
+    }
+    let last = changes.last().map_or(initial, |(_, _, after)| *after);
+    let _ = write!(sql, " ELSE {} + {} END)", column, last);
+    sql
+}
+
+/// Renders Unix seconds of the local time `local`, matching [`local_to_utc`].
+fn local_to_utc_sql(local: &str, initial: i64, changes: &[(i64, i64, i64)]) -> String {
+    if changes.is_empty() {
+        return format!("({} - {})", local, initial);
+    }
+    let mut sql = String::from("(CASE");
+    for (instant, before, after) in changes {
+        let _ = write!(sql, " WHEN {l} < {t} THEN {l} - {o}", l = local, t = instant + before, o = before);
+        if after > before {
+            let _ = write!(sql, " WHEN {} < {} THEN {}", local, instant + after, instant);
+        }
+    }
+    let last = changes.last().map_or(initial, |(_, _, after)| *after);
+    let _ = write!(sql, " ELSE {} - {} END)", local, last);
+    sql
+}
+
+/// Renders `window_start` and `window_end` select expressions in `dialect`
+/// for the Unix seconds in `column`, valid for values in `[from, to)`.
+///
+/// Each bound binds its local time in a scalar subquery, so the offset
+/// changes are rendered once per conversion. Only changes within a window
+/// of the range are rendered.
+pub fn window_sql(
+    unit: CalendarUnit,
+    timezone: Tz,
+    offset: Duration,
+    column: &str,
+    from: i64,
+    to: i64,
+    dialect: SqlDialect,
+) -> String {
+    // Windows of the range start and end within a unit and the offset of it,
+    // and local times are within a day of UTC
+    let margin = 93 * 86_400 + 2 * 86_400 + offset.as_secs().min(i64::MAX as u64 / 2) as i64;
+    let transitions = transitions(timezone);
+    let (initial, changes) = transitions.within(from, to, margin);
+    let offset = offset.as_secs();
+    let local = utc_to_local_sql(column, initial, changes);
+    let truncated = match dialect {
+        SqlDialect::DuckDb => format!(
+            "date_trunc('{}', make_timestamp(CAST({} - {} AS BIGINT) * 1000000))",
+            unit.sql_part(),
+            local,
+            offset
+        ),
+        // Units start at midnight, so only the local date matters
+        SqlDialect::Postgres => format!(
+            "date_trunc('{}', CAST(DATE '1970-01-01' + CAST(FLOOR(({} - {}) / 86400.0) AS INTEGER) AS TIMESTAMP))",
+            unit.sql_part(),
+            local,
+            offset
+        ),
+    };
+    let epoch = |timestamp: String| match dialect {
+        SqlDialect::DuckDb => format!("CAST(epoch({}) AS BIGINT)", timestamp),
+        SqlDialect::Postgres => format!("CAST(EXTRACT(EPOCH FROM {}) AS BIGINT)", timestamp),
+    };
+    // Postgres requires an alias for every subquery in FROM
+    let alias = match dialect {
+        SqlDialect::DuckDb => "",
+        SqlDialect::Postgres => " AS local",
+    };
+    let bound = |local: String| format!(
+        "(SELECT {} FROM (SELECT {} AS local_time){})",
+        local_to_utc_sql("local_time", initial, changes),
+        local,
+        alias
+    );
+    format!(
+        "{} as window_start, {} as window_end",
+        bound(format!("{} + {}", epoch(truncated.clone()), offset)),
+        bound(format!("{} + {}", epoch(format!("{} + {}", truncated, unit.sql_interval(dialect))), offset))
+    )
+}
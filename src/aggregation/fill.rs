@@ -0,0 +1,205 @@
+//! Gap filling and interpolation for windowed series.
+//!
+//! Windowed aggregation only produces windows that contain data. With a
+//! [`FillStrategy`], every window in `[from, to)` is emitted per group, see
+//! [`TimeWindow::windows_between`](super::TimeWindow::windows_between), and windows without a value are filled
+//! from their neighbours. [`fill_series`] fills a series in memory and
+//! [`fill_sql`] wraps an aggregation query to do the same in SQL. Both treat
+//! a missing or null aggregate as a gap and never extrapolate: `Previous`
+//! leaves leading gaps null and `Linear` leaves leading and trailing gaps
+//! null.
+
+use super::filter::SqlDialect;
+use super::{AggregateExpr, GroupBy, ROW_COUNT_COLUMN};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tonic::Status;
+
+/// How windows without a value are filled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FillStrategy {
+    /// Emit the window with a null value
+    Null,
+    /// Use a constant value
+    Constant(f64),
+    /// Carry the latest earlier value forward
+    Previous,
+    /// Interpolate linearly between the surrounding values by window start
+    Linear,
+}
+
+/// One window of a gap-filled series.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilledWindow {
+    pub window_start: i64,
+    pub window_end: i64,
+    /// The value of the window, `None` for a gap that could not be filled
+    pub value: Option<f64>,
+}
+
+/// Fills a series over `windows`, as returned by
+/// [`TimeWindow::windows_between`](super::TimeWindow::windows_between).
+///
+/// `values` maps window starts to aggregated values; NaN counts as missing
+/// and values of windows not in `windows` are ignored.
+pub fn fill_series(values: &HashMap<i64, f64>, windows: &[(i64, i64)], strategy: FillStrategy) -> Vec<FilledWindow> {
+    let known: Vec<Option<f64>> = windows
+        .iter()
+        .map(|(start, _)| values.get(start).copied().filter(|value| !value.is_nan()))
+        .collect();
+
+    let mut filled = Vec::with_capacity(windows.len());
+    let mut previous: Option<(i64, f64)> = None;
+    for (i, &(window_start, window_end)) in windows.iter().enumerate() {
+        let value = match (known[i], strategy) {
+            (Some(value), _) => Some(value),
+            (None, FillStrategy::Null) => None,
+            (None, FillStrategy::Constant(value)) => Some(value),
+            (None, FillStrategy::Previous) => previous.map(|(_, value)| value),
+            (None, FillStrategy::Linear) => {
+                let next = (i + 1..windows.len()).find_map(|j| known[j].map(|value| (windows[j].0, value)));
+                match (previous, next) {
+                    (Some((t0, v0)), Some((t1, v1))) => {
+                        Some(v0 + (v1 - v0) * (window_start - t0) as f64 / (t1 - t0) as f64)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if let Some(value) = known[i] {
+            previous = Some((window_start, value));
+        }
+        filled.push(FilledWindow { window_start, window_end, value });
+    }
+    filled
+}
+
+/// Wraps a windowed aggregation query so it emits every window of
+/// `windows` for every group, filling the aggregate columns with
+/// `strategy` in `dialect`.
+///
+/// The query must select the group by columns, `window_start`,
+/// `window_end` and the aggregates, as built by
+/// [`build_windowed_aggregate_query`](super::build_windowed_aggregate_query).
+/// Groups are the combinations of group by values present in the result.
+/// The row count column is not filled with `strategy`: missing windows
+/// count zero rows. Postgres has no `IGNORE NULLS`, so the closest values
+/// are found through running counts of the windows with a value.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if `group_by` has a time column, or
+/// the constant of `strategy` is not finite.
+pub fn fill_sql(
+    query: &str,
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    windows: &[(i64, i64)],
+    strategy: FillStrategy,
+    dialect: SqlDialect,
+) -> Result<String, Status> {
+    if group_by.time_column.is_some() {
+        return Err(Status::invalid_argument("Gap filling cannot be combined with a time column"));
+    }
+    if let FillStrategy::Constant(value) = strategy {
+        if !value.is_finite() {
+            return Err(Status::invalid_argument(format!("Fill value {} is not finite", value)));
+        }
+    }
+
+    let values: Vec<String> = windows.iter().map(|(start, end)| format!("({}, {})", start, end)).collect();
+    let windows_sql = if values.is_empty() {
+        // An empty grid, keeping the column types
+        "SELECT 0::BIGINT AS window_start, 0::BIGINT AS window_end WHERE FALSE".to_string()
+    } else {
+        format!("SELECT * FROM (VALUES {}) AS bounds(window_start, window_end)", values.join(", "))
+    };
+
+    let columns = &group_by.columns;
+    let (grid, partition) = if columns.is_empty() {
+        ("SELECT * FROM windows".to_string(), String::new())
+    } else {
+        (
+            format!("SELECT * FROM (SELECT DISTINCT {} FROM aggregated) AS group_keys CROSS JOIN windows", columns.join(", ")),
+            format!("PARTITION BY {} ", columns.join(", ")),
+        )
+    };
+    let preceding = format!("OVER ({}ORDER BY window_start ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)", partition);
+    let following = format!("OVER ({}ORDER BY window_start ROWS BETWEEN CURRENT ROW AND UNBOUNDED FOLLOWING)", partition);
+    let keys: String = columns.iter().map(|column| format!("{}, ", column)).collect();
+    // The value of `expr` in the closest window at or before, and at or
+    // after, each window where the aggregate `name` has a value. In
+    // Postgres, the windows sharing a count of values up to or from them
+    // hold at most one value, the closest one
+    let previous = |expr: &str, name: &str| match dialect {
+        SqlDialect::DuckDb => format!("LAST_VALUE({} IGNORE NULLS) {}", expr, preceding),
+        SqlDialect::Postgres => format!("MAX({}) OVER (PARTITION BY {}{}_known_before)", expr, keys, name),
+    };
+    let next = |expr: &str, name: &str| match dialect {
+        SqlDialect::DuckDb => format!("FIRST_VALUE({} IGNORE NULLS) {}", expr, following),
+        SqlDialect::Postgres => format!("MAX({}) OVER (PARTITION BY {}{}_known_after)", expr, keys, name),
+    };
+    let double = match dialect {
+        SqlDialect::DuckDb => "DOUBLE",
+        SqlDialect::Postgres => "DOUBLE PRECISION",
+    };
+
+    let mut select: Vec<String> = columns.clone();
+    select.extend(["window_start".to_string(), "window_end".to_string()]);
+    for aggregate in aggregates {
+        let name = aggregate.output_name();
+        let filled = match strategy {
+            // Missing windows have no rows, whatever the strategy
+            _ if name == ROW_COUNT_COLUMN => format!("COALESCE({}, 0)", name),
+            FillStrategy::Null => name.clone(),
+            FillStrategy::Constant(value) => format!("COALESCE({}, {:?})", name, value),
+            FillStrategy::Previous => previous(&name, &name),
+            FillStrategy::Linear => {
+                let known_start = format!("CASE WHEN {} IS NOT NULL THEN window_start END", name);
+                format!(
+                    "CASE WHEN {v} IS NOT NULL THEN {v} \
+                    ELSE {pv} + ({nv} - {pv}) * CAST(window_start - {pt} AS {double}) / ({nt} - {pt}) END",
+                    v = name,
+                    pv = previous(&name, &name),
+                    nv = next(&name, &name),
+                    pt = previous(&known_start, &name),
+                    nt = next(&known_start, &name),
+                    double = double,
+                )
+            }
+        };
+        select.push(format!("{} AS {}", filled, name));
+    }
+
+    // Null group keys are groups of their own, which an equi-join would not
+    // match
+    let mut conditions: Vec<String> = columns.iter()
+        .map(|column| format!("grid.{c} IS NOT DISTINCT FROM aggregated.{c}", c = column))
+        .collect();
+    conditions.extend(["grid.window_start = aggregated.window_start".to_string(), "grid.window_end = aggregated.window_end".to_string()]);
+    let mut joined = vec!["grid.*".to_string()];
+    joined.extend(aggregates.iter().map(|aggregate| format!("aggregated.{}", aggregate.output_name())));
+    let mut source = format!(
+        "(SELECT {} FROM grid LEFT JOIN aggregated ON {}) AS joined",
+        joined.join(", "),
+        conditions.join(" AND ")
+    );
+    if dialect == SqlDialect::Postgres && matches!(strategy, FillStrategy::Previous | FillStrategy::Linear) {
+        let mut counts = vec!["*".to_string()];
+        for aggregate in aggregates {
+            let name = aggregate.output_name();
+            counts.push(format!("COUNT({v}) {p} AS {v}_known_before", v = name, p = preceding));
+            counts.push(format!("COUNT({v}) {f} AS {v}_known_after", v = name, f = following));
+        }
+        source = format!("(SELECT {} FROM {}) AS counted", counts.join(", "), source);
+    }
+    Ok(format!(
+        "WITH aggregated AS ({}), windows AS ({}), grid AS ({}) SELECT {} FROM {}",
+        query,
+        windows_sql,
+        grid,
+        select.join(", "),
+        source
+    ))
+}
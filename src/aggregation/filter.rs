@@ -0,0 +1,410 @@
+//! Typed filter expressions for aggregation and table queries.
+//!
+//! A [`Filter`] is a serializable expression tree of column comparisons,
+//! `IN` lists, Prometheus-style label matchers and boolean connectives. The
+//! same filter can be rendered to the SQL of a backend, see
+//! [`Filter::to_sql`], or evaluated against a record batch in memory, see
+//! [`Filter::evaluate`]. Both follow SQL semantics: comparisons with null are
+//! unknown, and rows are selected only if the filter is true.
+//!
+//! Column names must be plain identifiers and literals are escaped, so a
+//! filter received from a client cannot inject SQL.
+
+use arrow::compute::kernels::boolean::{and_kleene, not, or_kleene};
+use arrow::compute::kernels::cmp::{eq, gt, gt_eq, lt, lt_eq, neq};
+use arrow::compute::kernels::regexp::regexp_is_match_scalar;
+use arrow::compute::{cast, filter_record_batch};
+use arrow_array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, Scalar, StringArray};
+use arrow_schema::{DataType, Schema};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tonic::Status;
+use super::is_identifier;
+
+/// SQL dialect a filter or aggregation query is rendered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SqlDialect {
+    /// DuckDB, used by the DuckDB backend
+    #[default]
+    DuckDb,
+    /// PostgreSQL-compatible SQL, used by the ADBC backend
+    Postgres,
+}
+
+/// Comparison operator of a [`Filter::Compare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Operator of a label matcher, as in Prometheus selectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchOp {
+    /// `label = value`
+    Eq,
+    /// `label != value`
+    Ne,
+    /// `label =~ regex`, matching the whole value
+    Regex,
+    /// `label !~ regex`, matching the whole value
+    NotRegex,
+}
+
+impl MatchOp {
+    /// Whether a single value matches `pattern` under this operator.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the regular expression is
+    /// invalid.
+    pub fn is_match(&self, pattern: &str, value: &str) -> Result<bool, Status> {
+        Ok(match self {
+            MatchOp::Eq => value == pattern,
+            MatchOp::Ne => value != pattern,
+            MatchOp::Regex => anchored_regex(pattern)?.is_match(value),
+            MatchOp::NotRegex => !anchored_regex(pattern)?.is_match(value),
+        })
+    }
+}
+
+/// A literal value compared against a column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Literal {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+/// A filter expression over the columns of a table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Compares a column with a literal
+    Compare {
+        column: String,
+        op: CompareOp,
+        value: Literal,
+    },
+    /// True if a column equals any of the values
+    In {
+        column: String,
+        values: Vec<Literal>,
+    },
+    /// Matches a string column, such as `metric_id`, against a value or an
+    /// anchored regular expression
+    Match {
+        label: String,
+        op: MatchOp,
+        value: String,
+    },
+    /// True if all filters are true; an empty list is true
+    And(Vec<Filter>),
+    /// True if any filter is true; an empty list is false
+    Or(Vec<Filter>),
+    /// Negates a filter
+    Not(Box<Filter>),
+}
+
+impl Literal {
+    /// Renders the literal as SQL, escaping strings.
+    fn to_sql(&self) -> Result<String, Status> {
+        match self {
+            Literal::Bool(b) => Ok(if *b { "TRUE" } else { "FALSE" }.to_string()),
+            Literal::Int(i) => Ok(i.to_string()),
+            Literal::Float(f) if f.is_finite() => Ok(format!("{:?}", f)),
+            Literal::Float(f) => Err(Status::invalid_argument(format!("Filter value {} is not finite", f))),
+            Literal::String(s) => Ok(quote(s)),
+        }
+    }
+
+    /// Checks that the literal can be compared with a column of `data_type`.
+    fn check_type(&self, column: &str, data_type: &DataType) -> Result<(), Status> {
+        let compatible = match self {
+            Literal::Bool(_) => *data_type == DataType::Boolean,
+            Literal::Int(_) | Literal::Float(_) => data_type.is_numeric(),
+            Literal::String(_) => matches!(data_type, DataType::Utf8 | DataType::LargeUtf8),
+        };
+        if compatible {
+            Ok(())
+        } else {
+            Err(Status::invalid_argument(format!(
+                "Cannot compare column {} of type {} with {:?}",
+                column, data_type, self
+            )))
+        }
+    }
+
+    /// Returns the type both sides of a comparison with a column of
+    /// `data_type` are cast to.
+    fn comparison_type(&self, data_type: &DataType) -> DataType {
+        match self {
+            Literal::Bool(_) => DataType::Boolean,
+            Literal::Int(_) if data_type.is_integer() => DataType::Int64,
+            Literal::Int(_) | Literal::Float(_) => DataType::Float64,
+            Literal::String(_) => DataType::Utf8,
+        }
+    }
+
+    /// Returns the literal as a scalar of `data_type`.
+    fn to_scalar(&self, data_type: &DataType) -> Result<Scalar<ArrayRef>, Status> {
+        let array: ArrayRef = match self {
+            Literal::Bool(b) => Arc::new(BooleanArray::from(vec![*b])),
+            Literal::Int(i) => Arc::new(Int64Array::from(vec![*i])),
+            Literal::Float(f) => Arc::new(Float64Array::from(vec![*f])),
+            Literal::String(s) => Arc::new(StringArray::from(vec![s.as_str()])),
+        };
+        let array = cast(&array, data_type)
+            .map_err(|e| Status::invalid_argument(format!("Invalid filter value {:?}: {}", self, e)))?;
+        Ok(Scalar::new(array))
+    }
+}
+
+impl Filter {
+    /// Creates a filter comparing `column` with `value`.
+    pub fn compare(column: impl Into<String>, op: CompareOp, value: Literal) -> Self {
+        Filter::Compare {
+            column: column.into(),
+            op,
+            value,
+        }
+    }
+
+    /// Creates a filter matching `column` against `value` with `op`.
+    pub fn matches(column: impl Into<String>, op: MatchOp, value: impl Into<String>) -> Self {
+        Filter::Match {
+            label: column.into(),
+            op,
+            value: value.into(),
+        }
+    }
+
+    /// Creates a filter selecting rows whose `metric_id` fully matches a
+    /// regular expression.
+    pub fn metric_id_regex(pattern: impl Into<String>) -> Self {
+        Self::matches("metric_id", MatchOp::Regex, pattern)
+    }
+
+    /// Combines two filters into one that is true if both are.
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    /// Checks the filter against the schema of the filtered table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a column is not in `schema`, a
+    /// literal cannot be compared with its column, a label matcher is
+    /// applied to a non-string column or a regular expression is invalid.
+    pub fn validate(&self, schema: &Schema) -> Result<(), Status> {
+        match self {
+            Filter::Compare { column, value, .. } => value.check_type(column, schema_type(schema, column)?),
+            Filter::In { column, values } => {
+                let data_type = schema_type(schema, column)?;
+                for value in values {
+                    value.check_type(column, data_type)?;
+                }
+                Ok(())
+            }
+            Filter::Match { label, op, value } => {
+                Literal::String(value.clone()).check_type(label, schema_type(schema, label)?)?;
+                if matches!(op, MatchOp::Regex | MatchOp::NotRegex) {
+                    anchored_regex(value)?;
+                }
+                Ok(())
+            }
+            Filter::And(filters) | Filter::Or(filters) => {
+                for filter in filters {
+                    filter.validate(schema)?;
+                }
+                Ok(())
+            }
+            Filter::Not(filter) => filter.validate(schema),
+        }
+    }
+
+    /// Renders the filter as a SQL boolean expression.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a column is not a plain
+    /// identifier or a value is not finite.
+    pub fn to_sql(&self, dialect: SqlDialect) -> Result<String, Status> {
+        match self {
+            Filter::Compare { column, op, value } => {
+                let op = match op {
+                    CompareOp::Eq => "=",
+                    CompareOp::Ne => "<>",
+                    CompareOp::Lt => "<",
+                    CompareOp::Le => "<=",
+                    CompareOp::Gt => ">",
+                    CompareOp::Ge => ">=",
+                };
+                Ok(format!("{} {} {}", identifier(column)?, op, value.to_sql()?))
+            }
+            Filter::In { values, .. } if values.is_empty() => Ok("FALSE".to_string()),
+            Filter::In { column, values } => {
+                let mut rendered = Vec::with_capacity(values.len());
+                for value in values {
+                    rendered.push(value.to_sql()?);
+                }
+                Ok(format!("{} IN ({})", identifier(column)?, rendered.join(", ")))
+            }
+            Filter::Match { label, op, value } => {
+                let label = identifier(label)?;
+                let matched = match dialect {
+                    SqlDialect::DuckDb => format!("regexp_full_match({}, {})", label, quote(value)),
+                    SqlDialect::Postgres => format!("{} ~ {}", label, quote(&format!("^(?:{})$", value))),
+                };
+                Ok(match op {
+                    MatchOp::Eq => format!("{} = {}", label, quote(value)),
+                    MatchOp::Ne => format!("{} <> {}", label, quote(value)),
+                    MatchOp::Regex => matched,
+                    MatchOp::NotRegex => format!("NOT {}", matched),
+                })
+            }
+            Filter::And(filters) if filters.is_empty() => Ok("TRUE".to_string()),
+            Filter::Or(filters) if filters.is_empty() => Ok("FALSE".to_string()),
+            Filter::And(filters) | Filter::Or(filters) => {
+                let connective = if matches!(self, Filter::And(_)) { " AND " } else { " OR " };
+                let mut parts = Vec::with_capacity(filters.len());
+                for filter in filters {
+                    parts.push(format!("({})", filter.to_sql(dialect)?));
+                }
+                Ok(parts.join(connective))
+            }
+            Filter::Not(filter) => Ok(format!("NOT ({})", filter.to_sql(dialect)?)),
+        }
+    }
+
+    /// Evaluates the filter for every row of a batch.
+    ///
+    /// The result is null where SQL would evaluate the filter to unknown.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the filter does not match the
+    /// schema of the batch, see [`validate`](Self::validate).
+    pub fn evaluate(&self, batch: &RecordBatch) -> Result<BooleanArray, Status> {
+        match self {
+            Filter::Compare { column: name, op, value } => {
+                let array = batch_column(batch, name)?;
+                value.check_type(name, array.data_type())?;
+                let data_type = value.comparison_type(array.data_type());
+                let array = cast(array, &data_type).map_err(compute_error)?;
+                let scalar = value.to_scalar(&data_type)?;
+                let compare = match op {
+                    CompareOp::Eq => eq,
+                    CompareOp::Ne => neq,
+                    CompareOp::Lt => lt,
+                    CompareOp::Le => lt_eq,
+                    CompareOp::Gt => gt,
+                    CompareOp::Ge => gt_eq,
+                };
+                compare(&array, &scalar).map_err(compute_error)
+            }
+            Filter::In { column, values } => {
+                let equals = values.iter().map(|value| Filter::Compare {
+                    column: column.clone(),
+                    op: CompareOp::Eq,
+                    value: value.clone(),
+                });
+                Filter::Or(equals.collect()).evaluate(batch)
+            }
+            Filter::Match { label, op, value } => {
+                let array = batch_column(batch, label)?;
+                Literal::String(value.clone()).check_type(label, array.data_type())?;
+                let strings = cast(array, &DataType::Utf8).map_err(compute_error)?;
+                let strings = strings
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| Status::internal(format!("Failed to read column {}", label)))?;
+                let scalar = Scalar::new(StringArray::from(vec![value.as_str()]));
+                match op {
+                    MatchOp::Eq => eq(strings, &scalar),
+                    MatchOp::Ne => neq(strings, &scalar),
+                    MatchOp::Regex | MatchOp::NotRegex => {
+                        let pattern = anchored_regex(value)?;
+                        let matched = regexp_is_match_scalar(strings, pattern.as_str(), None);
+                        if *op == MatchOp::Regex { matched } else { matched.and_then(|m| not(&m)) }
+                    }
+                }
+                .map_err(compute_error)
+            }
+            Filter::And(filters) => {
+                let mut result = BooleanArray::from(vec![true; batch.num_rows()]);
+                for filter in filters {
+                    result = and_kleene(&result, &filter.evaluate(batch)?).map_err(compute_error)?;
+                }
+                Ok(result)
+            }
+            Filter::Or(filters) => {
+                let mut result = BooleanArray::from(vec![false; batch.num_rows()]);
+                for filter in filters {
+                    result = or_kleene(&result, &filter.evaluate(batch)?).map_err(compute_error)?;
+                }
+                Ok(result)
+            }
+            Filter::Not(filter) => not(&filter.evaluate(batch)?).map_err(compute_error),
+        }
+    }
+
+    /// Returns the rows of a batch for which the filter is true.
+    pub fn apply(&self, batch: &RecordBatch) -> Result<RecordBatch, Status> {
+        filter_record_batch(batch, &self.evaluate(batch)?)
+            .map_err(|e| Status::internal(format!("Failed to filter batch: {}", e)))
+    }
+}
+
+fn schema_type<'a>(schema: &'a Schema, column: &str) -> Result<&'a DataType, Status> {
+    schema
+        .field_with_name(column)
+        .map(|field| field.data_type())
+        .map_err(|_| Status::invalid_argument(format!("Unknown filter column {}", column)))
+}
+
+fn batch_column<'a>(batch: &'a RecordBatch, column: &str) -> Result<&'a ArrayRef, Status> {
+    batch
+        .column_by_name(column)
+        .ok_or_else(|| Status::invalid_argument(format!("Unknown filter column {}", column)))
+}
+
+/// Checks that a column name can be used in SQL unquoted.
+fn identifier(name: &str) -> Result<&str, Status> {
+    if is_identifier(name) {
+        Ok(name)
+    } else {
+        Err(Status::invalid_argument(format!("Invalid filter column {}", name)))
+    }
+}
+
+/// Renders a string literal, doubling embedded quotes.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Compiles a label matcher regex, anchored at both ends as in Prometheus.
+fn anchored_regex(pattern: &str) -> Result<Regex, Status> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| Status::invalid_argument(format!("Invalid filter regex '{}': {}", pattern, e)))
+}
+
+fn compute_error(e: arrow::error::ArrowError) -> Status {
+    Status::invalid_argument(format!("Failed to evaluate filter: {}", e))
+}
@@ -0,0 +1,297 @@
+//! Forecasts of windowed series.
+//!
+//! A [`ForecastSpec`] projects each series of an aggregation result
+//! `horizon` windows past its last window, with a prediction interval at
+//! the requested confidence:
+//!
+//! - `LinearTrend`: a least squares line through the values, with the
+//!   prediction interval of the regression
+//! - `HoltWinters`: the additive seasonal model of [`HoltWinters`], with the
+//!   interval widening with the horizon as derived by Hyndman et al. for the
+//!   equivalent state space model
+//!
+//! Intervals assume normally distributed errors. Series are the values of
+//! consecutive result rows; windows without rows are skipped unless the
+//! aggregation fills them, see [`ResultOptions::fill`](super::ResultOptions::fill).
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use arrow::compute::{cast, take};
+use arrow::row::{OwnedRow, RowConverter, SortField};
+use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, UInt32Array};
+use arrow_schema::{DataType, Field, Schema};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use super::smoothing::{HoltWinters, HoltWintersParams};
+use super::{AggregateExpr, TimeWindow};
+
+/// Maximum number of windows forecast per series.
+pub const MAX_FORECAST_WINDOWS: usize = 10_000;
+
+/// Model fitted to each series.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForecastModel {
+    LinearTrend,
+    HoltWinters(HoltWintersParams),
+}
+
+/// How series are forecast.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ForecastSpec {
+    /// Number of future windows
+    pub horizon: usize,
+    pub model: ForecastModel,
+    /// Probability that a value falls within its prediction interval, 0.95
+    /// by default
+    #[serde(default = "default_confidence")]
+    pub confidence: f64,
+}
+
+fn default_confidence() -> f64 {
+    0.95
+}
+
+/// The forecast of one future window.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ForecastPoint {
+    pub value: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl ForecastSpec {
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the horizon is zero or above
+    /// [`MAX_FORECAST_WINDOWS`], the confidence is not in `(0, 1)` or the
+    /// model parameters are invalid.
+    pub fn validate(&self) -> Result<(), Status> {
+        if self.horizon == 0 || self.horizon > MAX_FORECAST_WINDOWS {
+            return Err(Status::invalid_argument(format!(
+                "Forecast horizon must be between 1 and {} windows",
+                MAX_FORECAST_WINDOWS
+            )));
+        }
+        if !(self.confidence > 0.0 && self.confidence < 1.0) {
+            return Err(Status::invalid_argument("Forecast confidence must be in (0, 1)"));
+        }
+        match self.model {
+            ForecastModel::LinearTrend => Ok(()),
+            ForecastModel::HoltWinters(params) => params.validate(),
+        }
+    }
+
+    /// Forecasts the `horizon` values following a series, `None` if the
+    /// series is too short to fit the model: three values for a linear
+    /// trend, and two seasons for Holt-Winters (three values with a season
+    /// of one window).
+    pub fn forecast(&self, values: &[f64]) -> Option<Vec<ForecastPoint>> {
+        let z = normal_quantile(0.5 + self.confidence / 2.0);
+        let point = |value: f64, stddev: f64| ForecastPoint { value, lower: value - z * stddev, upper: value + z * stddev };
+        match self.model {
+            ForecastModel::LinearTrend => {
+                if values.len() < 3 {
+                    return None;
+                }
+                let n = values.len() as f64;
+                let mean_x = (n - 1.0) / 2.0;
+                let mean_y = values.iter().sum::<f64>() / n;
+                let sxx = values.iter().enumerate().map(|(x, _)| (x as f64 - mean_x).powi(2)).sum::<f64>();
+                let sxy = values.iter().enumerate().map(|(x, y)| (x as f64 - mean_x) * (y - mean_y)).sum::<f64>();
+                let slope = sxy / sxx;
+                let intercept = mean_y - slope * mean_x;
+                let sse = values.iter()
+                    .enumerate()
+                    .map(|(x, y)| (y - intercept - slope * x as f64).powi(2))
+                    .sum::<f64>();
+                let residual_stddev = (sse / (n - 2.0)).sqrt();
+                Some((1..=self.horizon)
+                    .map(|step| {
+                        let x = n - 1.0 + step as f64;
+                        let stddev = residual_stddev * (1.0 + 1.0 / n + (x - mean_x).powi(2) / sxx).sqrt();
+                        point(intercept + slope * x, stddev)
+                    })
+                    .collect())
+            }
+            ForecastModel::HoltWinters(params) => {
+                let model = HoltWinters::fit(params, values).ok()?;
+                let residual_stddev = model.residual_stddev()?;
+                let HoltWintersParams { alpha, beta, gamma, season } = params;
+                let mut variance = 0.0;
+                Some((1..=self.horizon)
+                    .map(|step| {
+                        // Weight of the error `step` windows back in the forecast
+                        let j = step - 1;
+                        if j > 0 {
+                            let seasonal = if j % season == 0 { gamma * (1.0 - alpha) } else { 0.0 };
+                            variance += (alpha * (1.0 + j as f64 * beta) + seasonal).powi(2);
+                        }
+                        Some(point(model.forecast(step)?, residual_stddev * (1.0 + variance).sqrt()))
+                    })
+                    .collect::<Option<Vec<_>>>()?)
+            }
+        }
+    }
+}
+
+/// Forecasts the series of an aggregation result.
+///
+/// The columns before `window_start` identify a series, and the result has
+/// them, `window_start`, `window_end`, the `step` of the window from 1 to
+/// the horizon and, per aggregate, the forecast value under its output name
+/// and the interval bounds under the name suffixed with `_lower` and
+/// `_upper`. Series are ordered by their identifying columns. Values are
+/// null for aggregates whose series is too short, see
+/// [`ForecastSpec::forecast`].
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the spec is invalid or the window
+/// is not fixed, aligned or calendar.
+pub fn forecast_batch(
+    batch: &RecordBatch,
+    aggregates: &[AggregateExpr],
+    window: TimeWindow,
+    spec: &ForecastSpec,
+) -> Result<RecordBatch, Status> {
+    spec.validate()?;
+    validate_window(window)?;
+    let schema = batch.schema();
+    let keys = schema.index_of("window_start")
+        .map_err(|_| Status::internal("Missing aggregation column window_start"))?;
+    let column = |name: &str| {
+        let column = batch.column_by_name(name)
+            .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", name)))?;
+        cast(column, &DataType::Float64).map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))
+    };
+    let starts = batch.column(keys).as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_start"))?;
+    let ends = batch.column_by_name("window_end")
+        .and_then(|column| column.as_any().downcast_ref::<Int64Array>())
+        .ok_or_else(|| Status::internal("Failed to read column window_end"))?;
+
+    // Row indices of each series, in window order
+    let mut series: BTreeMap<Option<OwnedRow>, Vec<usize>> = BTreeMap::new();
+    if keys == 0 {
+        series.insert(None, (0..batch.num_rows()).collect());
+    } else {
+        let fields = schema.fields()[..keys].iter().map(|f| SortField::new(f.data_type().clone())).collect();
+        let converter = RowConverter::new(fields)
+            .map_err(|e| Status::internal(format!("Failed to group series: {}", e)))?;
+        let rows = converter.convert_columns(&batch.columns()[..keys])
+            .map_err(|e| Status::internal(format!("Failed to group series: {}", e)))?;
+        for (index, row) in rows.iter().enumerate() {
+            series.entry(Some(row.owned())).or_default().push(index);
+        }
+    }
+    for indices in series.values_mut() {
+        indices.sort_by_key(|&index| starts.value(index));
+    }
+
+    let values = aggregates.iter()
+        .map(|aggregate| {
+            let values = column(&aggregate.output_name())?;
+            values.as_any().downcast_ref::<Float64Array>().cloned()
+                .ok_or_else(|| Status::internal("Failed to read aggregate column"))
+        })
+        .collect::<Result<Vec<_>, Status>>()?;
+
+    let mut first_rows = Vec::new();
+    let mut window_starts = Vec::new();
+    let mut window_ends = Vec::new();
+    let mut steps = Vec::new();
+    let mut forecasts: Vec<[Vec<Option<f64>>; 3]> = vec![Default::default(); aggregates.len()];
+    for indices in series.values() {
+        let Some(&last) = indices.last() else { continue };
+        let mut end = ends.value(last);
+        for step in 1..=spec.horizon {
+            let bounds = window.window_bounds(end);
+            first_rows.push(indices[0] as u32);
+            window_starts.push(bounds.0);
+            window_ends.push(bounds.1);
+            steps.push(step as i64);
+            end = bounds.1;
+        }
+        for (values, columns) in values.iter().zip(forecasts.iter_mut()) {
+            let series: Vec<f64> = indices.iter()
+                .filter(|&&index| values.is_valid(index))
+                .map(|&index| values.value(index))
+                .collect();
+            let points = spec.forecast(&series);
+            for step in 0..spec.horizon {
+                let point = points.as_ref().map(|points| points[step]);
+                columns[0].push(point.map(|p| p.value));
+                columns[1].push(point.map(|p| p.lower));
+                columns[2].push(point.map(|p| p.upper));
+            }
+        }
+    }
+
+    let first_rows = UInt32Array::from(first_rows);
+    let mut fields: Vec<Field> = schema.fields()[..keys].iter().map(|f| f.as_ref().clone()).collect();
+    let mut columns = batch.columns()[..keys].iter()
+        .map(|column| take(column, &first_rows, None))
+        .collect::<Result<Vec<ArrayRef>, _>>()
+        .map_err(|e| Status::internal(format!("Failed to build forecast: {}", e)))?;
+    fields.push(Field::new("window_start", DataType::Int64, false));
+    fields.push(Field::new("window_end", DataType::Int64, false));
+    fields.push(Field::new("step", DataType::Int64, false));
+    columns.push(Arc::new(Int64Array::from(window_starts)));
+    columns.push(Arc::new(Int64Array::from(window_ends)));
+    columns.push(Arc::new(Int64Array::from(steps)));
+    for (aggregate, values) in aggregates.iter().zip(forecasts) {
+        let name = aggregate.output_name();
+        for (suffix, values) in ["", "_lower", "_upper"].into_iter().zip(values) {
+            fields.push(Field::new(format!("{}{}", name, suffix), DataType::Float64, true));
+            columns.push(Arc::new(Float64Array::from(values)));
+        }
+    }
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+        .map_err(|e| Status::internal(format!("Failed to build forecast: {}", e)))
+}
+
+/// # Errors
+///
+/// Returns `Status::invalid_argument` unless the window is fixed, aligned or
+/// calendar, whose windows follow each other.
+pub fn validate_window(window: TimeWindow) -> Result<(), Status> {
+    if !matches!(window, TimeWindow::Fixed(_) | TimeWindow::Aligned { .. } | TimeWindow::Calendar { .. }) {
+        return Err(Status::invalid_argument("Forecasts require a fixed, aligned or calendar window"));
+    }
+    Ok(())
+}
+
+/// Inverse of the standard normal distribution function, by Acklam's
+/// rational approximation, accurate to about 1e-9.
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1, 2.209460984245205e2, -2.759285104469687e2,
+        1.38357751867269e2, -3.066479806614716e1, 2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1, 1.615858368580409e2, -1.556989798598866e2,
+        6.680131188771972e1, -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3, -3.223964580411365e-1, -2.400758277161838,
+        -2.549732539343734, 4.374664141464968, 2.938163982698783,
+    ];
+    const D: [f64; 4] = [7.784695709041462e-3, 3.224671290700398e-1, 2.445134137142996, 3.754408661907416];
+    const LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
@@ -0,0 +1,281 @@
+//! Step-aligned range queries returning a regular series matrix.
+//!
+//! A [`RangeQuery`] aggregates a time range in fixed windows of `step`,
+//! aligned to multiples of the step, and returns exactly one row per step
+//! for every series, null where a series has no data. Series are the
+//! groups of the query, or a single series without group by columns.
+//!
+//! If the range spans more than `max_points` steps, the step is widened to
+//! the smallest multiple of the requested step that fits, so a query never
+//! returns more than `max_points` rows per series. The step used is that of
+//! the returned windows.
+//!
+//! The [`MatrixLayout`] selects the shape of the result:
+//!
+//! - `Long`: the group by columns, `window_start`, `window_end`, the
+//!   aggregates and [`ROW_COUNT_COLUMN`], ordered by series and window
+//! - `Wide`: `window_start`, `window_end` and one column per series and
+//!   aggregate, named like a Prometheus series, e.g.
+//!   `avg_value{metric_id="cpu"}`, or by the aggregate alone without group
+//!   by columns
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use arrow::compute::{cast, take};
+use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch, StringArray, UInt32Array};
+use arrow_schema::{DataType, Field, Schema};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use super::filter::Filter;
+use super::{AggregateExpr, GroupBy, TimeWindow, MAX_FILL_WINDOWS, ROW_COUNT_COLUMN};
+
+/// Default maximum number of steps of a range query.
+pub const DEFAULT_MAX_POINTS: usize = 11_000;
+
+/// Shape of the result of a [`RangeQuery`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatrixLayout {
+    /// One row per series and step
+    #[default]
+    Long,
+    /// One row per step, with a column per series and aggregate
+    Wide,
+}
+
+/// Aggregation of `[start, end]` in windows of `step`, one row per step
+/// and series.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeQuery {
+    pub aggregates: Vec<AggregateExpr>,
+    /// Columns identifying a series; a single series if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    pub start: i64,
+    pub end: i64,
+    pub step: Duration,
+    /// Maximum number of steps before the step is widened
+    #[serde(default = "default_max_points")]
+    pub max_points: usize,
+    #[serde(default)]
+    pub layout: MatrixLayout,
+}
+
+fn default_max_points() -> usize {
+    DEFAULT_MAX_POINTS
+}
+
+/// The steps of a range query after alignment and widening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepGrid {
+    /// Start of the first window, a multiple of the step
+    pub start: i64,
+    /// Step in seconds
+    pub step: i64,
+    /// Number of windows, up to the one containing the end of the range
+    pub points: usize,
+}
+
+impl StepGrid {
+    /// End of the last window.
+    pub fn end(&self) -> i64 {
+        self.start + self.points as i64 * self.step
+    }
+}
+
+impl RangeQuery {
+    /// Grouping of the aggregation of the query.
+    pub fn group_by(&self) -> GroupBy {
+        GroupBy { columns: self.labels.clone(), time_column: None }
+    }
+
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if there is no aggregate, the
+    /// step is shorter than a second, the start, end or step is outside the
+    /// range of dates chrono represents, the end is before the start, or
+    /// `max_points` is zero or above [`MAX_FILL_WINDOWS`]. The bounds keep
+    /// the arithmetic of [`grid`](Self::grid) from overflowing.
+    pub fn validate(&self) -> Result<(), Status> {
+        if self.aggregates.is_empty() {
+            return Err(Status::invalid_argument("Range query requires at least one aggregate"));
+        }
+        if self.step.as_secs() == 0 {
+            return Err(Status::invalid_argument("Step must be at least one second"));
+        }
+        let max_timestamp = DateTime::<Utc>::MAX_UTC.timestamp();
+        if self.step.as_secs() > max_timestamp as u64 {
+            return Err(Status::invalid_argument(format!("Step must be at most {} seconds", max_timestamp)));
+        }
+        for timestamp in [self.start, self.end] {
+            if DateTime::from_timestamp(timestamp, 0).is_none() {
+                return Err(Status::invalid_argument(format!("Timestamp {} is out of range", timestamp)));
+            }
+        }
+        if self.end < self.start {
+            return Err(Status::invalid_argument("End timestamp must not be before start timestamp"));
+        }
+        if self.max_points == 0 || self.max_points > MAX_FILL_WINDOWS {
+            return Err(Status::invalid_argument(format!(
+                "max_points must be between 1 and {}",
+                MAX_FILL_WINDOWS
+            )));
+        }
+        Ok(())
+    }
+
+    /// Aligns the range to the step, widening the step to a multiple of the
+    /// requested one until the range spans at most `max_points` windows.
+    ///
+    /// The query must be [validated](Self::validate).
+    pub fn grid(&self) -> StepGrid {
+        let requested = self.step.as_secs().max(1) as i64;
+        let mut step = requested;
+        loop {
+            let start = self.start.div_euclid(step) * step;
+            let points = (self.end.div_euclid(step) * step - start) / step + 1;
+            if points as usize <= self.max_points {
+                if step != requested {
+                    tracing::debug!("Widened range query step from {}s to {}s", requested, step);
+                }
+                return StepGrid { start, step, points: points as usize };
+            }
+            step *= (points as usize).div_ceil(self.max_points) as i64;
+        }
+    }
+
+    /// Fixed windows of the step of `grid`.
+    pub fn window(&self, grid: &StepGrid) -> TimeWindow {
+        TimeWindow::Fixed(Duration::from_secs(grid.step as u64))
+    }
+
+    /// Arranges an aggregation result, with the columns of
+    /// `aggregate_metrics`, on the steps of `grid` in the layout of the
+    /// query.
+    ///
+    /// Windows outside the grid are dropped and missing windows are null,
+    /// with a row count of zero.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a series has several rows for
+    /// the same window, and `Status::internal` if a column is missing.
+    pub fn arrange(&self, batch: &RecordBatch, grid: &StepGrid) -> Result<RecordBatch, Status> {
+        let column = |name: &str| {
+            batch.column_by_name(name)
+                .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", name)))
+        };
+        let read = |name: &str, data_type: &DataType| {
+            cast(column(name)?, data_type).map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))
+        };
+        let labels = self.labels.iter()
+            .map(|label| read(label, &DataType::Utf8))
+            .collect::<Result<Vec<_>, Status>>()?;
+        let labels: Vec<&StringArray> = labels.iter()
+            .map(|column| column.as_any().downcast_ref::<StringArray>())
+            .collect::<Option<_>>()
+            .ok_or_else(|| Status::internal("Failed to read label column"))?;
+        let starts = read("window_start", &DataType::Int64)?;
+        let starts = starts.as_any().downcast_ref::<Int64Array>()
+            .ok_or_else(|| Status::internal("Failed to read column window_start"))?;
+
+        // Rows of each series by step, keyed by label values
+        let mut series: BTreeMap<Vec<Option<String>>, Vec<Option<u32>>> = BTreeMap::new();
+        for row in 0..batch.num_rows() {
+            let offset = starts.value(row) - grid.start;
+            if offset < 0 || offset % grid.step != 0 || offset / grid.step >= grid.points as i64 {
+                continue;
+            }
+            let key: Vec<Option<String>> = labels.iter()
+                .map(|column| column.is_valid(row).then(|| column.value(row).to_string()))
+                .collect();
+            let rows = series.entry(key).or_insert_with(|| vec![None; grid.points]);
+            let step = (offset / grid.step) as usize;
+            if rows[step].replace(row as u32).is_some() {
+                return Err(Status::invalid_argument("Several rows of a series fall in the same step"));
+            }
+        }
+
+        let window_starts: Vec<i64> = (0..grid.points as i64).map(|i| grid.start + i * grid.step).collect();
+        let gather = |name: &str, rows: &UInt32Array| -> Result<ArrayRef, Status> {
+            take(column(name)?.as_ref(), rows, None)
+                .map_err(|e| Status::internal(format!("Failed to arrange column {}: {}", name, e)))
+        };
+        let mut fields = Vec::new();
+        let mut columns: Vec<ArrayRef> = Vec::new();
+        match self.layout {
+            MatrixLayout::Long => {
+                let first_rows: UInt32Array = series.values()
+                    .flat_map(|rows| {
+                        let first = rows.iter().flatten().next().copied();
+                        std::iter::repeat_n(first, rows.len())
+                    })
+                    .collect();
+                let rows: UInt32Array = series.values().flatten().copied().collect();
+                for label in &self.labels {
+                    fields.push(batch.schema().field_with_name(label).map_err(|e| Status::internal(e.to_string()))?.clone());
+                    columns.push(gather(label, &first_rows)?);
+                }
+                let starts: Vec<i64> = series.values().flat_map(|_| window_starts.iter().copied()).collect();
+                push_windows(&mut fields, &mut columns, starts, grid.step);
+                for aggregate in &self.aggregates {
+                    let name = aggregate.output_name();
+                    fields.push(nullable_field(batch, &name)?);
+                    columns.push(gather(&name, &rows)?);
+                }
+                let counts = read(ROW_COUNT_COLUMN, &DataType::Int64)?;
+                let counts = counts.as_any().downcast_ref::<Int64Array>()
+                    .ok_or_else(|| Status::internal("Failed to read row count column"))?;
+                let counts: Int64Array = rows.iter()
+                    .map(|row| Some(row.map_or(0, |row| counts.value(row as usize))))
+                    .collect();
+                fields.push(Field::new(ROW_COUNT_COLUMN, DataType::Int64, false));
+                columns.push(Arc::new(counts));
+            }
+            MatrixLayout::Wide => {
+                push_windows(&mut fields, &mut columns, window_starts, grid.step);
+                for (key, rows) in &series {
+                    let rows: UInt32Array = rows.iter().copied().collect();
+                    for aggregate in &self.aggregates {
+                        let name = aggregate.output_name();
+                        let field = nullable_field(batch, &name)?;
+                        fields.push(field.with_name(self.series_name(&name, key)));
+                        columns.push(gather(&name, &rows)?);
+                    }
+                }
+            }
+        }
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .map_err(|e| Status::internal(format!("Failed to build range query result: {}", e)))
+    }
+
+    /// Name of the wide column of an aggregate of a series.
+    fn series_name(&self, aggregate: &str, key: &[Option<String>]) -> String {
+        if self.labels.is_empty() {
+            return aggregate.to_string();
+        }
+        let labels: Vec<String> = self.labels.iter()
+            .zip(key)
+            .map(|(label, value)| format!("{}={:?}", label, value.as_deref().unwrap_or_default()))
+            .collect();
+        format!("{}{{{}}}", aggregate, labels.join(","))
+    }
+}
+
+fn push_windows(fields: &mut Vec<Field>, columns: &mut Vec<ArrayRef>, starts: Vec<i64>, step: i64) {
+    let ends: Vec<i64> = starts.iter().map(|start| start + step).collect();
+    fields.push(Field::new("window_start", DataType::Int64, false));
+    fields.push(Field::new("window_end", DataType::Int64, false));
+    columns.push(Arc::new(Int64Array::from(starts)));
+    columns.push(Arc::new(Int64Array::from(ends)));
+}
+
+fn nullable_field(batch: &RecordBatch, name: &str) -> Result<Field, Status> {
+    let schema = batch.schema();
+    let field = schema.field_with_name(name).map_err(|e| Status::internal(e.to_string()))?;
+    Ok(field.clone().with_nullable(true))
+}
@@ -0,0 +1,819 @@
+//! In-process aggregation of record batches.
+//!
+//! [`AggregateOperator`] computes the result of
+//! [`build_windowed_aggregate_query`](super::build_windowed_aggregate_query)
+//! without a database, so batches can be aggregated as they stream in, e.g.
+//! through Flight `DoExchange`. Batches are filtered, cast and split into
+//! groups and windows with Arrow compute kernels, and each slice is reduced
+//! into mergeable per-window state.
+//!
+//! The results follow the SQL semantics of the query builder: the filter is
+//! applied with the time range before rows are assigned to windows, sessions
+//! are formed per combination of the group by columns, the time range
+//! `[from, to)` excludes its end, null values are ignored and nulls form
+//! their own group. They differ
+//! in three ways:
+//! - distinct counts are exact, where DuckDB estimates them, unless the
+//!   operator keeps sketches
+//! - rows with a null timestamp are not assigned to any window
+//! - the [`ResultOptions`](super::ResultOptions) clauses are not applied, and
+//!   rows are ordered by group and window
+//!
+//! Instead of results, an operator can return its state as partial state,
+//! which is merged across operators, see [`partial`](super::partial).
+//!
+//! Exact quantiles and distinct counts keep every value of a window. An
+//! operator aggregating an unbounded stream keeps a [`QuantileSketch`] and
+//! a [`HyperLogLog`] per window instead, see
+//! [`with_sketches`](AggregateOperator::with_sketches), so its memory only
+//! grows with the number of groups and windows.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use arrow::compute::kernels::cmp::{gt_eq, lt};
+use arrow::compute::{and, cast, filter_record_batch, max, min, sum, take};
+use arrow::row::{OwnedRow, RowConverter, SortField};
+use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, Scalar, StringArray, UInt32Array};
+use arrow_schema::{DataType, Schema, SchemaRef};
+use futures::{Stream, StreamExt};
+use tonic::Status;
+use super::filter::Filter;
+use super::hll::HyperLogLog;
+use super::partial::{partial_batch, partial_schema, PartialState};
+use super::sketch::QuantileSketch;
+use super::{aggregate_output_schema, validate_aggregates, AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+
+/// Key of the state of a group in a window; for session windows the window
+/// is a single timestamp until sessions are formed in
+/// [`finish`](AggregateOperator::finish).
+type StateKey = (usize, i64, i64);
+
+/// Aggregates record batches in memory with the semantics of the SQL query
+/// builder, see the [module documentation](self).
+pub struct AggregateOperator {
+    aggregates: Vec<AggregateExpr>,
+    group_by: GroupBy,
+    window: TimeWindow,
+    from_timestamp: Option<i64>,
+    to_timestamp: Option<i64>,
+    filter: Option<Filter>,
+    schema: SchemaRef,
+    /// Each aggregated column once, `*` for the row count
+    columns: Vec<String>,
+    /// Converters of the columns counted distinct, by column
+    distinct: Vec<Option<RowConverter>>,
+    /// Whether the values of a column are kept for quantiles, by column
+    quantiles: Vec<bool>,
+    /// Whether quantiles and distinct counts are kept as sketches
+    sketches: bool,
+    /// Converter of the group by and time columns, if any
+    keys: Option<RowConverter>,
+    groups: Vec<Option<OwnedRow>>,
+    group_index: HashMap<Option<OwnedRow>, usize>,
+    /// Converter of the group by columns that partition sessions
+    partitions: Option<RowConverter>,
+    partition_index: HashMap<Option<OwnedRow>, usize>,
+    /// Session partition per group
+    partition_of: Vec<usize>,
+    states: HashMap<StateKey, WindowState>,
+}
+
+impl AggregateOperator {
+    /// Creates an operator over batches with `schema`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the spec is invalid for
+    /// `schema`, see [`validate_aggregates`], the window is invalid, see
+    /// [`TimeWindow::validate`], a function other than a count is applied to
+    /// a non-numeric column, or a window is used without a `timestamp`
+    /// column.
+    pub fn try_new(
+        schema: SchemaRef,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+    ) -> Result<Self, Status> {
+        validate_aggregates(aggregates, group_by, &schema)?;
+        window.validate()?;
+        let mut columns: Vec<String> = Vec::new();
+        let mut distinct: Vec<Option<RowConverter>> = Vec::new();
+        let mut quantiles = Vec::new();
+        for aggregate in aggregates {
+            let i = match columns.iter().position(|column| *column == aggregate.column) {
+                Some(i) => i,
+                None => {
+                    columns.push(aggregate.column.clone());
+                    distinct.push(None);
+                    quantiles.push(false);
+                    columns.len() - 1
+                }
+            };
+            let data_type = schema.field_with_name(&aggregate.column).ok().map(|f| f.data_type().clone());
+            match (aggregate.function, data_type) {
+                (AggregateFunction::Count, _) => {}
+                (AggregateFunction::CountDistinct, Some(data_type)) => {
+                    distinct[i] = Some(converter(vec![data_type])?);
+                }
+                (function, Some(data_type)) if !data_type.is_numeric() => {
+                    return Err(Status::invalid_argument(format!(
+                        "{} cannot be applied to column {} of type {}",
+                        function, aggregate.column, data_type
+                    )));
+                }
+                (AggregateFunction::Quantile(_), _) => quantiles[i] = true,
+                _ => {}
+            }
+        }
+
+        let key_columns: Vec<&String> = group_by.columns.iter().chain(group_by.time_column.iter()).collect();
+        let key_types = |columns: &[&String]| -> Result<Option<RowConverter>, Status> {
+            if columns.is_empty() {
+                return Ok(None);
+            }
+            let types = columns.iter()
+                .map(|column| schema.field_with_name(column).map(|f| f.data_type().clone()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            converter(types).map(Some)
+        };
+        let keys = key_types(&key_columns)?;
+        let partitions = key_types(&group_by.columns.iter().collect::<Vec<_>>())?;
+        if !matches!(window, TimeWindow::None) && schema.field_with_name("timestamp").is_err() {
+            return Err(Status::invalid_argument("Windowed aggregation requires a timestamp column"));
+        }
+
+        Ok(Self {
+            aggregates: aggregates.to_vec(),
+            group_by: group_by.clone(),
+            window,
+            from_timestamp: None,
+            to_timestamp: None,
+            filter: None,
+            schema,
+            columns,
+            distinct,
+            quantiles,
+            sketches: false,
+            keys,
+            groups: Vec::new(),
+            group_index: HashMap::new(),
+            partitions,
+            partition_index: HashMap::new(),
+            partition_of: Vec::new(),
+            states: HashMap::new(),
+        })
+    }
+
+    /// Restricts the aggregation to rows with a timestamp in `[from, to)`,
+    /// as in the query builder.
+    pub fn with_range(mut self, from_timestamp: Option<i64>, to_timestamp: Option<i64>) -> Self {
+        self.from_timestamp = from_timestamp;
+        self.to_timestamp = to_timestamp;
+        self
+    }
+
+    /// Estimates quantiles and distinct counts from sketches, like partial
+    /// state, instead of keeping every value, which bounds the state of each
+    /// group and window. Used for streams of unknown length.
+    pub fn with_sketches(mut self) -> Self {
+        self.sketches = true;
+        self
+    }
+
+    /// Aggregates only the rows matching `filter`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the filter is invalid for the
+    /// schema of the operator, see [`Filter::validate`].
+    pub fn with_filter(mut self, filter: Filter) -> Result<Self, Status> {
+        filter.validate(&self.schema)?;
+        self.filter = Some(filter);
+        Ok(self)
+    }
+
+    /// Returns the schema of the result, see [`aggregate_output_schema`].
+    pub fn output_schema(&self) -> Result<Schema, Status> {
+        aggregate_output_schema(&self.aggregates, &self.group_by, self.windowed(), &self.schema)
+    }
+
+    fn windowed(&self) -> bool {
+        !matches!(self.window, TimeWindow::None)
+    }
+
+    /// Adds the rows of a batch to the aggregation.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the batch does not have the
+    /// columns of the operator's schema.
+    pub fn push(&mut self, batch: &RecordBatch) -> Result<(), Status> {
+        let mut batch = match &self.filter {
+            Some(filter) => filter.apply(batch)?,
+            None => batch.clone(),
+        };
+        let has_time = batch.schema().field_with_name("timestamp").is_ok();
+        let mut timestamps = match has_time {
+            true => Some(int64_column(&batch, "timestamp")?),
+            false => None,
+        };
+
+        if self.from_timestamp.is_some() || self.to_timestamp.is_some() {
+            let times = timestamps.as_ref()
+                .ok_or_else(|| Status::invalid_argument("Time range requires a timestamp column"))?;
+            let mut mask = None;
+            if let Some(from) = self.from_timestamp {
+                mask = Some(gt_eq(times, &Scalar::new(Int64Array::from(vec![from]))).map_err(kernel_error)?);
+            }
+            if let Some(to) = self.to_timestamp {
+                let before = lt(times, &Scalar::new(Int64Array::from(vec![to]))).map_err(kernel_error)?;
+                mask = Some(match mask {
+                    Some(mask) => and(&mask, &before).map_err(kernel_error)?,
+                    None => before,
+                });
+            }
+            if let Some(mask) = mask {
+                batch = filter_record_batch(&batch, &mask).map_err(kernel_error)?;
+                timestamps = Some(int64_column(&batch, "timestamp")?);
+            }
+        }
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+
+        let groups = self.group_rows(&batch)?;
+        let mut buckets: HashMap<StateKey, Vec<u32>> = HashMap::new();
+        for (row, group) in groups.into_iter().enumerate() {
+            let windows = match (&self.window, &timestamps) {
+                (TimeWindow::None, _) => vec![(0, 0)],
+                (_, Some(timestamps)) if timestamps.is_valid(row) => match self.window {
+                    TimeWindow::Session { .. } => vec![(timestamps.value(row), timestamps.value(row))],
+                    window => window.windows(timestamps.value(row)),
+                },
+                _ => continue,
+            };
+            for (start, end) in windows {
+                buckets.entry((group, start, end)).or_default().push(row as u32);
+            }
+        }
+
+        let arrays = self.columns.iter()
+            .map(|column| ColumnArrays::new(&batch, column))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, rows) in buckets {
+            let indices = UInt32Array::from(rows);
+            let state = self.states.entry(key).or_insert_with(|| WindowState::new(arrays.len()));
+            state.update(&indices, timestamps.as_ref(), &arrays, &self.distinct, &self.quantiles, self.sketches)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the group index of every row, adding new groups.
+    fn group_rows(&mut self, batch: &RecordBatch) -> Result<Vec<usize>, Status> {
+        let Some(keys) = &self.keys else {
+            if self.groups.is_empty() {
+                self.groups.push(None);
+                self.partition_of.push(0);
+            }
+            return Ok(vec![0; batch.num_rows()]);
+        };
+        let columns = |names: &mut dyn Iterator<Item = &String>| {
+            names.map(|name| column(batch, name)).collect::<Result<Vec<_>, _>>()
+        };
+        let key_columns = columns(&mut self.group_by.columns.iter().chain(self.group_by.time_column.iter()))?;
+        let rows = keys.convert_columns(&key_columns).map_err(kernel_error)?;
+        let partitions = match &self.partitions {
+            Some(partitions) if matches!(self.window, TimeWindow::Session { .. }) => {
+                Some(partitions.convert_columns(&columns(&mut self.group_by.columns.iter())?).map_err(kernel_error)?)
+            }
+            _ => None,
+        };
+
+        let mut groups = Vec::with_capacity(batch.num_rows());
+        for (i, row) in rows.iter().enumerate() {
+            let key = Some(row.owned());
+            let group = match self.group_index.get(&key) {
+                Some(group) => *group,
+                None => {
+                    let partition_key = partitions.as_ref().map(|rows| rows.row(i).owned());
+                    let next = self.partition_index.len();
+                    let partition = *self.partition_index.entry(partition_key).or_insert(next);
+                    self.partition_of.push(partition);
+                    self.groups.push(key.clone());
+                    self.group_index.insert(key, self.groups.len() - 1);
+                    self.groups.len() - 1
+                }
+            };
+            groups.push(group);
+        }
+        Ok(groups)
+    }
+
+    /// Aggregates all pushed batches into one row per group and window.
+    ///
+    /// Without group by columns and window the result has exactly one row,
+    /// even if no rows were pushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::internal` if the result cannot be assembled.
+    pub fn finish(mut self) -> Result<RecordBatch, Status> {
+        let output = self.output_schema()?;
+        let (mut arrays, states) = self.result_rows()?;
+        for aggregate in &self.aggregates {
+            let column = self.column_of(aggregate);
+            let values = states.iter().map(|state| match (aggregate.function, aggregate.column.as_str()) {
+                (AggregateFunction::Count, "*") => Some(state.rows as f64),
+                (function, _) => state.evaluate(function, column),
+            });
+            let array: ArrayRef = match aggregate.function {
+                AggregateFunction::Count | AggregateFunction::CountDistinct => {
+                    Arc::new(values.map(|v| v.map(|v| v as i64)).collect::<Int64Array>())
+                }
+                _ => Arc::new(values.collect::<Float64Array>()),
+            };
+            arrays.push(array);
+        }
+        let arrays = arrays.iter()
+            .zip(output.fields())
+            .map(|(array, field)| cast(array, field.data_type()).map_err(kernel_error))
+            .collect::<Result<Vec<_>, _>>()?;
+        RecordBatch::try_new(Arc::new(output), arrays)
+            .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+    }
+
+    /// Returns the state of all pushed batches as a partial state batch
+    /// with [`partial_schema`], to be merged with the state of other
+    /// operators by a [`PartialCombiner`](super::partial::PartialCombiner).
+    ///
+    /// Quantiles and distinct counts are kept as sketches in partial state.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` for session windows, whose
+    /// sessions cannot be stitched across operators.
+    pub fn finish_partial(mut self) -> Result<RecordBatch, Status> {
+        if matches!(self.window, TimeWindow::Session { .. }) {
+            return Err(Status::invalid_argument("Partial state of session windows cannot be combined"));
+        }
+        let schema = partial_schema(&self.aggregates, &self.group_by, self.windowed(), &self.schema)?;
+        let (keys, states) = self.result_rows()?;
+        let states = states.iter()
+            .map(|state| {
+                self.aggregates.iter()
+                    .map(|aggregate| {
+                        let column = self.column_of(aggregate);
+                        state.partial(aggregate, column, self.distinct[column].as_ref())
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let keys = keys.iter()
+            .zip(schema.fields())
+            .map(|(array, field)| cast(array, field.data_type()).map_err(kernel_error))
+            .collect::<Result<Vec<_>, _>>()?;
+        partial_batch(schema, keys, &states, self.aggregates.len())
+    }
+
+    fn column_of(&self, aggregate: &AggregateExpr) -> usize {
+        self.columns.iter().position(|c| *c == aggregate.column).unwrap_or_default()
+    }
+
+    /// Returns the key columns of the result, the group by columns and the
+    /// window bounds, and the state of every result row, ordered by group
+    /// and window.
+    fn result_rows(&mut self) -> Result<(Vec<ArrayRef>, Vec<WindowState>), Status> {
+        let mut states: Vec<(StateKey, WindowState)> = match self.window {
+            TimeWindow::Session { gap } => self.sessions(gap.as_secs().max(1) as i64)?,
+            _ => std::mem::take(&mut self.states).into_iter().collect(),
+        };
+        states.retain(|(_, state)| state.rows > 0);
+        if states.is_empty() && self.keys.is_none() && !self.windowed() {
+            states.push(((0, 0, 0), WindowState::new(self.columns.len())));
+            if self.groups.is_empty() {
+                self.groups.push(None);
+            }
+        }
+        states.sort_by(|((a, a_start, a_end), _), ((b, b_start, b_end), _)| {
+            (&self.groups[*a], a_start, a_end).cmp(&(&self.groups[*b], b_start, b_end))
+        });
+
+        let mut arrays: Vec<ArrayRef> = Vec::new();
+        if let Some(keys) = &self.keys {
+            let rows = states.iter().filter_map(|((group, _, _), _)| self.groups[*group].as_ref().map(|r| r.row()));
+            arrays.extend(keys.convert_rows(rows).map_err(kernel_error)?);
+        }
+        if self.windowed() {
+            arrays.push(Arc::new(Int64Array::from_iter_values(states.iter().map(|((_, start, _), _)| *start))));
+            arrays.push(Arc::new(Int64Array::from_iter_values(states.iter().map(|((_, _, end), _)| *end))));
+        }
+        Ok((arrays, states.into_iter().map(|(_, state)| state).collect()))
+    }
+
+    /// Merges the state per group and timestamp into sessions, which break
+    /// where consecutive timestamps of a partition are at least `gap` apart.
+    fn sessions(&mut self, gap: i64) -> Result<Vec<(StateKey, WindowState)>, Status> {
+        let mut timestamps: HashMap<usize, Vec<i64>> = HashMap::new();
+        for (group, timestamp, _) in self.states.keys() {
+            timestamps.entry(self.partition_of[*group]).or_default().push(*timestamp);
+        }
+        let bounds: HashMap<usize, Vec<(i64, i64)>> = timestamps.into_iter()
+            .map(|(partition, mut timestamps)| {
+                timestamps.sort_unstable();
+                timestamps.dedup();
+                let mut sessions: Vec<(i64, i64)> = Vec::new();
+                for timestamp in timestamps {
+                    match sessions.last_mut() {
+                        Some((_, end)) if timestamp < *end => *end = timestamp + gap,
+                        _ => sessions.push((timestamp, timestamp + gap)),
+                    }
+                }
+                (partition, sessions)
+            })
+            .collect();
+
+        let mut merged: HashMap<StateKey, WindowState> = HashMap::new();
+        let mut states: Vec<_> = std::mem::take(&mut self.states).into_iter().collect();
+        states.sort_by_key(|(key, _)| *key);
+        for ((group, timestamp, _), state) in states {
+            let sessions = &bounds[&self.partition_of[group]];
+            let (start, end) = sessions[sessions.partition_point(|(start, _)| *start <= timestamp) - 1];
+            match merged.get_mut(&(group, start, end)) {
+                Some(session) => session.merge(state)?,
+                None => {
+                    merged.insert((group, start, end), state);
+                }
+            }
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    /// Pushes every batch of a stream and returns the result.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the stream, see [`push`](Self::push) and
+    /// [`finish`](Self::finish).
+    pub async fn aggregate_stream<S>(mut self, mut batches: S) -> Result<RecordBatch, Status>
+    where
+        S: Stream<Item = Result<RecordBatch, Status>> + Unpin,
+    {
+        while let Some(batch) = batches.next().await {
+            self.push(&batch?)?;
+        }
+        self.finish()
+    }
+}
+
+/// The arrays of an aggregated column in a batch.
+struct ColumnArrays {
+    /// The column as is, `None` for `*`
+    array: Option<ArrayRef>,
+    /// The column cast to `Float64`, if numeric
+    values: Option<Float64Array>,
+}
+
+impl ColumnArrays {
+    fn new(batch: &RecordBatch, name: &str) -> Result<Self, Status> {
+        if name == "*" {
+            return Ok(Self { array: None, values: None });
+        }
+        let array = column(batch, name)?;
+        let values = match array.data_type().is_numeric() {
+            true => Some(float64_column(&array)?),
+            false => None,
+        };
+        Ok(Self { array: Some(array), values })
+    }
+}
+
+/// Aggregation state of a group in a window.
+#[derive(Debug, Clone)]
+struct WindowState {
+    /// Number of rows in the time range
+    rows: i64,
+    /// Earliest and latest timestamp of those rows
+    min_timestamp: Option<i64>,
+    max_timestamp: Option<i64>,
+    columns: Vec<ColumnState>,
+}
+
+/// Aggregation state of the non-null values of a column.
+#[derive(Debug, Clone, Default)]
+struct ColumnState {
+    count: i64,
+    sum: f64,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    first: Option<(i64, f64)>,
+    last: Option<(i64, f64)>,
+    /// All values, only kept for exact quantiles
+    values: Vec<f64>,
+    /// Row encoded distinct values, only kept for exact distinct counts
+    distinct: HashSet<OwnedRow>,
+    /// Sketch of the values, kept for quantiles instead of the values if
+    /// the operator keeps sketches
+    quantile_sketch: Option<QuantileSketch>,
+    /// Sketch of the distinct values, kept for distinct counts instead of
+    /// the values if the operator keeps sketches
+    distinct_sketch: Option<HyperLogLog>,
+}
+
+impl WindowState {
+    fn new(columns: usize) -> Self {
+        Self { rows: 0, min_timestamp: None, max_timestamp: None, columns: vec![ColumnState::default(); columns] }
+    }
+
+    /// Adds the rows at `indices` of a batch.
+    fn update(
+        &mut self,
+        indices: &UInt32Array,
+        timestamps: Option<&Int64Array>,
+        arrays: &[ColumnArrays],
+        distinct: &[Option<RowConverter>],
+        quantiles: &[bool],
+        sketches: bool,
+    ) -> Result<(), Status> {
+        if indices.is_empty() {
+            return Ok(());
+        }
+        self.rows += indices.len() as i64;
+        let timestamps = match timestamps {
+            Some(timestamps) => {
+                let taken = take(timestamps, indices, None).map_err(kernel_error)?;
+                let taken = taken.as_any().downcast_ref::<Int64Array>().cloned()
+                    .ok_or_else(|| Status::internal("Failed to read timestamps"))?;
+                self.min_timestamp = min_option(self.min_timestamp, min(&taken));
+                self.max_timestamp = self.max_timestamp.max(max(&taken));
+                Some(taken)
+            }
+            None => None,
+        };
+
+        for (i, arrays) in arrays.iter().enumerate() {
+            let state = &mut self.columns[i];
+            let Some(array) = &arrays.array else { continue };
+            if let Some(converter) = distinct[i].as_ref().filter(|_| sketches) {
+                let taken = take(array.as_ref(), indices, None).map_err(kernel_error)?;
+                insert_distinct(state.distinct_sketch.get_or_insert_with(HyperLogLog::new), &taken, converter)?;
+            } else if let Some(converter) = &distinct[i] {
+                let taken = take(array.as_ref(), indices, None).map_err(kernel_error)?;
+                let rows = converter.convert_columns(std::slice::from_ref(&taken)).map_err(kernel_error)?;
+                for (j, row) in rows.iter().enumerate() {
+                    if taken.is_valid(j) {
+                        state.distinct.insert(row.owned());
+                    }
+                }
+            }
+            let Some(values) = &arrays.values else {
+                let taken = take(array.as_ref(), indices, None).map_err(kernel_error)?;
+                state.count += (taken.len() - taken.null_count()) as i64;
+                continue;
+            };
+            let taken = take(values, indices, None).map_err(kernel_error)?;
+            let taken = taken.as_any().downcast_ref::<Float64Array>()
+                .ok_or_else(|| Status::internal("Failed to read values"))?;
+            let count = (taken.len() - taken.null_count()) as i64;
+            if count == 0 {
+                continue;
+            }
+            let total = sum(taken).unwrap_or_default();
+            let mean = total / count as f64;
+            let mut slice = ColumnState {
+                count,
+                sum: total,
+                mean,
+                m2: taken.iter().flatten().map(|v| (v - mean) * (v - mean)).sum(),
+                min: min(taken),
+                max: max(taken),
+                ..Default::default()
+            };
+            if let Some(timestamps) = &timestamps {
+                for (timestamp, value) in timestamps.iter().zip(taken.iter()) {
+                    let (Some(timestamp), Some(value)) = (timestamp, value) else { continue };
+                    if slice.first.is_none_or(|(first, _)| timestamp < first) {
+                        slice.first = Some((timestamp, value));
+                    }
+                    if slice.last.is_none_or(|(last, _)| timestamp > last) {
+                        slice.last = Some((timestamp, value));
+                    }
+                }
+            }
+            if quantiles[i] && sketches {
+                let sketch = state.quantile_sketch.get_or_insert_with(QuantileSketch::new);
+                taken.iter().flatten().for_each(|value| sketch.insert(value));
+            } else if quantiles[i] {
+                slice.values = taken.iter().flatten().collect();
+            }
+            state.merge(slice)?;
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: WindowState) -> Result<(), Status> {
+        self.rows += other.rows;
+        self.min_timestamp = min_option(self.min_timestamp, other.min_timestamp);
+        self.max_timestamp = self.max_timestamp.max(other.max_timestamp);
+        for (state, other) in self.columns.iter_mut().zip(other.columns) {
+            state.merge(other)?;
+        }
+        Ok(())
+    }
+
+    /// Converts the state of a column into the partial state of `aggregate`.
+    fn partial(
+        &self,
+        aggregate: &AggregateExpr,
+        column: usize,
+        distinct: Option<&RowConverter>,
+    ) -> Result<PartialState, Status> {
+        let state = &self.columns[column];
+        let count = if aggregate.column == "*" { self.rows } else { state.count };
+        let mut partial = PartialState::empty();
+        let aggregation = &mut partial.0;
+        aggregation.window_start = self.min_timestamp.unwrap_or(i64::MAX);
+        aggregation.window_end = self.max_timestamp.unwrap_or(i64::MIN);
+        aggregation.running_count = count;
+        aggregation.sample_count = count;
+        aggregation.running_sum = state.sum;
+        aggregation.m2 = state.m2;
+        aggregation.min_value = state.min.unwrap_or(f64::INFINITY);
+        aggregation.max_value = state.max.unwrap_or(f64::NEG_INFINITY);
+        if let Some((timestamp, value)) = state.first {
+            aggregation.first_timestamp = timestamp;
+            aggregation.first_value = value;
+        }
+        if let Some((timestamp, value)) = state.last {
+            aggregation.last_timestamp = timestamp;
+            aggregation.last_value = value;
+        }
+        match aggregate.function {
+            AggregateFunction::Quantile(_) => {
+                let sketch = state.quantile_sketch.clone().unwrap_or_else(|| {
+                    let mut sketch = QuantileSketch::new();
+                    state.values.iter().for_each(|value| sketch.insert(*value));
+                    sketch
+                });
+                aggregation.quantile_sketch = Some(sketch);
+            }
+            AggregateFunction::CountDistinct => {
+                let mut sketch = state.distinct_sketch.clone().unwrap_or_default();
+                if let Some(converter) = distinct.filter(|_| !state.distinct.is_empty()) {
+                    let rows = state.distinct.iter().map(|row| row.row());
+                    let values = converter.convert_rows(rows).map_err(kernel_error)?.remove(0);
+                    insert_distinct(&mut sketch, &values, converter)?;
+                }
+                aggregation.distinct_sketch = Some(sketch);
+            }
+            _ => {}
+        }
+        Ok(partial)
+    }
+
+    /// Evaluates `function` over the state of a column, `None` for SQL NULL.
+    fn evaluate(&self, function: AggregateFunction, column: usize) -> Option<f64> {
+        let state = &self.columns[column];
+        let delta = || match (state.first, state.last) {
+            (Some((_, first)), Some((_, last))) => Some(last - first),
+            _ => None,
+        };
+        let present = |value: f64| (state.count > 0).then_some(value);
+        match function {
+            AggregateFunction::Count => Some(state.count as f64),
+            AggregateFunction::CountDistinct => match &state.distinct_sketch {
+                Some(sketch) => Some(sketch.estimate().round()),
+                None => Some(state.distinct.len() as f64),
+            },
+            AggregateFunction::Sum => present(state.sum),
+            AggregateFunction::Avg => present(state.sum / state.count as f64),
+            AggregateFunction::Min => state.min,
+            AggregateFunction::Max => state.max,
+            AggregateFunction::Variance => (state.count > 1).then(|| state.m2 / (state.count - 1) as f64),
+            AggregateFunction::StdDev => (state.count > 1).then(|| (state.m2 / (state.count - 1) as f64).sqrt()),
+            AggregateFunction::First => state.first.map(|(_, value)| value),
+            AggregateFunction::Last => state.last.map(|(_, value)| value),
+            AggregateFunction::Delta => delta(),
+            AggregateFunction::Rate => {
+                let duration = self.max_timestamp.zip(self.min_timestamp).map(|(max, min)| max - min);
+                delta().zip(duration.filter(|d| *d != 0)).map(|(delta, duration)| delta / duration as f64)
+            }
+            AggregateFunction::Quantile(q) => match &state.quantile_sketch {
+                Some(sketch) => sketch.quantile(q),
+                None => quantile_cont(&state.values, q),
+            },
+        }
+    }
+}
+
+impl ColumnState {
+    fn merge(&mut self, other: ColumnState) -> Result<(), Status> {
+        if other.count > 0 {
+            let count = self.count + other.count;
+            let delta = other.mean - self.mean;
+            self.m2 += other.m2 + delta * delta * (self.count as f64 * other.count as f64) / count as f64;
+            self.mean += delta * other.count as f64 / count as f64;
+            self.count = count;
+            self.sum += other.sum;
+            self.min = min_option(self.min, other.min);
+            self.max = match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some((timestamp, _)) = other.first {
+                if self.first.is_none_or(|(first, _)| timestamp < first) {
+                    self.first = other.first;
+                }
+            }
+            if let Some((timestamp, _)) = other.last {
+                if self.last.is_none_or(|(last, _)| timestamp > last) {
+                    self.last = other.last;
+                }
+            }
+            self.values.extend(other.values);
+        }
+        self.distinct.extend(other.distinct);
+        match (&mut self.quantile_sketch, other.quantile_sketch) {
+            (Some(sketch), Some(other)) => sketch.merge(&other)?,
+            (sketch, other) => *sketch = sketch.take().or(other),
+        }
+        match (&mut self.distinct_sketch, other.distinct_sketch) {
+            (Some(sketch), Some(other)) => sketch.merge(&other)?,
+            (sketch, other) => *sketch = sketch.take().or(other),
+        }
+        Ok(())
+    }
+}
+
+/// Adds the non-null values of a column to a distinct count sketch.
+/// Numbers are hashed like the distinct sketches of stored aggregation
+/// state, other values by their bytes.
+fn insert_distinct(sketch: &mut HyperLogLog, values: &ArrayRef, converter: &RowConverter) -> Result<(), Status> {
+    if values.data_type().is_numeric() {
+        float64_column(values)?.iter().flatten().for_each(|value| sketch.insert(value));
+    } else if let Ok(strings) = cast(values, &DataType::Utf8) {
+        let strings = strings.as_any().downcast_ref::<StringArray>()
+            .ok_or_else(|| Status::internal("Failed to read distinct values"))?;
+        strings.iter().flatten().for_each(|value| sketch.insert_bytes(value.as_bytes()));
+    } else {
+        let rows = converter.convert_columns(std::slice::from_ref(values)).map_err(kernel_error)?;
+        for (i, row) in rows.iter().enumerate() {
+            if values.is_valid(i) {
+                sketch.insert_bytes(row.as_ref());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `q` quantile of `values`, interpolating linearly between the
+/// closest ranks like `QUANTILE_CONT`.
+fn quantile_cont(values: &[f64], q: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let position = q * (sorted.len() - 1) as f64;
+    let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+    Some(sorted[lower] + (position - lower as f64) * (sorted[upper] - sorted[lower]))
+}
+
+fn min_option<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+fn converter(types: Vec<DataType>) -> Result<RowConverter, Status> {
+    RowConverter::new(types.into_iter().map(SortField::new).collect())
+        .map_err(|e| Status::invalid_argument(format!("Cannot group by column: {}", e)))
+}
+
+fn column(batch: &RecordBatch, name: &str) -> Result<ArrayRef, Status> {
+    batch.column_by_name(name)
+        .cloned()
+        .ok_or_else(|| Status::invalid_argument(format!("Missing column {}", name)))
+}
+
+fn int64_column(batch: &RecordBatch, name: &str) -> Result<Int64Array, Status> {
+    let array = cast(&column(batch, name)?, &DataType::Int64)
+        .map_err(|e| Status::invalid_argument(format!("Failed to cast column {}: {}", name, e)))?;
+    array.as_any().downcast_ref::<Int64Array>().cloned()
+        .ok_or_else(|| Status::internal(format!("Failed to read column {}", name)))
+}
+
+fn float64_column(array: &ArrayRef) -> Result<Float64Array, Status> {
+    let values = cast(array, &DataType::Float64)
+        .map_err(|e| Status::invalid_argument(format!("Failed to cast values: {}", e)))?;
+    values.as_any().downcast_ref::<Float64Array>().cloned()
+        .ok_or_else(|| Status::internal("Failed to read values"))
+}
+
+fn kernel_error(e: arrow_schema::ArrowError) -> Status {
+    Status::internal(format!("Aggregation kernel failed: {}", e))
+}
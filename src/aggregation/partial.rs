@@ -0,0 +1,421 @@
+//! Serializable partial aggregation state.
+//!
+//! A [`PartialState`] is the mergeable state of one aggregate function over
+//! a group and window. Edge nodes pre-aggregate their rows into partial
+//! state with [`AggregateOperator::finish_partial`], ship it as a record
+//! batch, and an upstream node merges the batches of all edges with a
+//! [`PartialCombiner`] without seeing the raw rows.
+//!
+//! A partial batch has the group by columns and window bounds of
+//! [`aggregate_output_schema`], followed by one `Binary` column per
+//! aggregate, named after its output, holding [`PartialState::to_bytes`].
+//! The format version is stored in every state and in the
+//! [`PARTIAL_STATE_VERSION_KEY`] schema metadata. The serialized state is
+//! its own struct, so the format only changes with the version, not with
+//! [`BatchAggregation`].
+//!
+//! Quantiles and distinct counts are kept as sketches, so results combined
+//! from partial state estimate them, see [`QuantileSketch`] and
+//! [`HyperLogLog`]; all other functions are exact.
+//!
+//! [`AggregateOperator::finish_partial`]: super::operator::AggregateOperator::finish_partial
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use arrow::row::{OwnedRow, RowConverter, SortField};
+use arrow_array::{Array, ArrayRef, BinaryArray, Float64Array, Int64Array, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use super::hll::HyperLogLog;
+use super::sketch::QuantileSketch;
+use super::{aggregate_output_schema, AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+use crate::storage::BatchAggregation;
+
+/// Version of the serialized partial state format.
+pub const PARTIAL_STATE_VERSION: u8 = 2;
+
+/// Schema metadata key holding the partial state version of a batch.
+pub const PARTIAL_STATE_VERSION_KEY: &str = "hyprstream.partial_state_version";
+
+/// Serialized form of a [`PartialState`], in bincode behind the format
+/// version. Sketches are stored in their own versioned format, and the
+/// state has no metric id.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireState {
+    window_start: i64,
+    window_end: i64,
+    running_sum: f64,
+    running_count: i64,
+    min_value: f64,
+    max_value: f64,
+    sample_count: i64,
+    m2: f64,
+    first_timestamp: i64,
+    first_value: f64,
+    last_timestamp: i64,
+    last_value: f64,
+    quantile_sketch: Option<Vec<u8>>,
+    distinct_sketch: Option<Vec<u8>>,
+}
+
+impl WireState {
+    fn from_state(state: &BatchAggregation) -> Result<Self, Status> {
+        Ok(WireState {
+            window_start: state.window_start,
+            window_end: state.window_end,
+            running_sum: state.running_sum,
+            running_count: state.running_count,
+            min_value: state.min_value,
+            max_value: state.max_value,
+            sample_count: state.sample_count,
+            m2: state.m2,
+            first_timestamp: state.first_timestamp,
+            first_value: state.first_value,
+            last_timestamp: state.last_timestamp,
+            last_value: state.last_value,
+            quantile_sketch: state.quantile_sketch.as_ref().map(QuantileSketch::to_bytes).transpose()?,
+            distinct_sketch: state.distinct_sketch.as_ref().map(HyperLogLog::to_bytes).transpose()?,
+        })
+    }
+
+    fn into_state(self) -> Result<BatchAggregation, Status> {
+        let quantile_sketch = self.quantile_sketch.as_deref().map(QuantileSketch::from_bytes).transpose();
+        let distinct_sketch = self.distinct_sketch.as_deref().map(HyperLogLog::from_bytes).transpose();
+        let invalid = |e: Status| Status::invalid_argument(format!("Invalid partial state: {}", e.message()));
+        Ok(BatchAggregation {
+            metric_id: String::new(),
+            window_start: self.window_start,
+            window_end: self.window_end,
+            running_sum: self.running_sum,
+            running_count: self.running_count,
+            min_value: self.min_value,
+            max_value: self.max_value,
+            quantile_sketch: quantile_sketch.map_err(invalid)?,
+            sample_count: self.sample_count,
+            m2: self.m2,
+            first_timestamp: self.first_timestamp,
+            first_value: self.first_value,
+            last_timestamp: self.last_timestamp,
+            last_value: self.last_value,
+            distinct_sketch: distinct_sketch.map_err(invalid)?,
+        })
+    }
+}
+
+/// Mergeable state of an aggregate function: the [`BatchAggregation`] of
+/// the non-null values of the aggregated column.
+///
+/// The sample and running counts are the number of values, or of rows for
+/// `COUNT(*)`. The window bounds are the earliest and latest timestamp of
+/// the rows, which merging widens like the bounds of merged windows, so
+/// `Rate` divides by the time span of the rows. Only the sketch the
+/// function needs is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialState(pub BatchAggregation);
+
+impl PartialState {
+    /// Returns the state of no rows.
+    pub fn empty() -> Self {
+        PartialState(BatchAggregation {
+            quantile_sketch: None,
+            distinct_sketch: None,
+            ..BatchAggregation::new(String::new(), i64::MAX, i64::MIN)
+        })
+    }
+
+    /// Merges the state of another partition of the same group and window.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the sketches of the states
+    /// cannot be merged.
+    pub fn merge(&mut self, other: &PartialState) -> Result<(), Status> {
+        self.0.merge(&other.0).map_err(|e| Status::invalid_argument(e.message().to_string()))
+    }
+
+    /// Evaluates `function` over the state, `None` for SQL NULL.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` for an invalid quantile, and
+    /// `Status::failed_precondition` if the state lacks the sketch of
+    /// `function`.
+    pub fn evaluate(&self, function: AggregateFunction) -> Result<Option<f64>, Status> {
+        function.validate()?;
+        let state = &self.0;
+        let present = match function {
+            AggregateFunction::Count | AggregateFunction::CountDistinct => true,
+            AggregateFunction::Variance | AggregateFunction::StdDev => state.sample_count > 1,
+            AggregateFunction::First | AggregateFunction::Last | AggregateFunction::Delta => {
+                state.first_timestamp <= state.last_timestamp
+            }
+            AggregateFunction::Rate => {
+                state.first_timestamp <= state.last_timestamp && state.window_start < state.window_end
+            }
+            _ => state.sample_count > 0,
+        };
+        if !present {
+            return Ok(None);
+        }
+        Ok(Some(match function {
+            AggregateFunction::Rate => {
+                state.evaluate(AggregateFunction::Delta)? / (state.window_end - state.window_start) as f64
+            }
+            function => state.evaluate(function)?,
+        }))
+    }
+
+    /// Serializes the state, prefixed with [`PARTIAL_STATE_VERSION`]. The
+    /// metric id of the state is not serialized.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Status> {
+        let mut bytes = vec![PARTIAL_STATE_VERSION];
+        bincode::serialize_into(&mut bytes, &WireState::from_state(&self.0)?)
+            .map_err(|e| Status::internal(format!("Failed to serialize partial state: {}", e)))?;
+        Ok(bytes)
+    }
+
+    /// Deserializes a state produced by [`to_bytes`](Self::to_bytes).
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the bytes are not a partial
+    /// state of a supported version, or hold invalid sketches.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Status> {
+        match bytes.split_first() {
+            Some((&PARTIAL_STATE_VERSION, payload)) => {
+                let state: WireState = bincode::deserialize(payload)
+                    .map_err(|e| Status::invalid_argument(format!("Invalid partial state: {}", e)))?;
+                Ok(PartialState(state.into_state()?))
+            }
+            Some((version, _)) => Err(Status::invalid_argument(format!(
+                "Unsupported partial state format version {}",
+                version
+            ))),
+            None => Err(Status::invalid_argument("Empty partial state")),
+        }
+    }
+}
+
+/// Returns the schema of partial state batches: the group by columns and
+/// window bounds of [`aggregate_output_schema`], then one `Binary` state
+/// column per aggregate, with the format version in the metadata.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if a group by column is not in
+/// `schema`.
+pub fn partial_schema(
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    windowed: bool,
+    schema: &Schema,
+) -> Result<Schema, Status> {
+    let output = aggregate_output_schema(aggregates, group_by, windowed, schema)?;
+    let keys = output.fields().len() - aggregates.len();
+    let fields: Vec<Field> = output.fields().iter()
+        .enumerate()
+        .map(|(i, field)| match i < keys {
+            true => field.as_ref().clone(),
+            false => Field::new(field.name(), DataType::Binary, false),
+        })
+        .collect();
+    let metadata = HashMap::from([(PARTIAL_STATE_VERSION_KEY.to_string(), PARTIAL_STATE_VERSION.to_string())]);
+    Ok(Schema::new_with_metadata(fields, metadata))
+}
+
+/// Builds a partial state batch from key columns and the states of every
+/// row, one per aggregate.
+pub(crate) fn partial_batch(
+    schema: Schema,
+    mut keys: Vec<ArrayRef>,
+    states: &[Vec<PartialState>],
+    aggregates: usize,
+) -> Result<RecordBatch, Status> {
+    for i in 0..aggregates {
+        let bytes = states.iter().map(|row| row[i].to_bytes()).collect::<Result<Vec<_>, _>>()?;
+        keys.push(Arc::new(BinaryArray::from_iter_values(bytes.iter())));
+    }
+    RecordBatch::try_new(Arc::new(schema), keys)
+        .map_err(|e| Status::internal(format!("Failed to create partial state batch: {}", e)))
+}
+
+/// Key fields, key columns and states of combined rows.
+type CombinedRows = (Vec<Field>, Vec<ArrayRef>, Vec<Vec<PartialState>>);
+
+/// Merges partial state batches of the same aggregation spec, e.g. those of
+/// several edge nodes, see the [module documentation](self).
+pub struct PartialCombiner {
+    aggregates: Vec<AggregateExpr>,
+    group_by: GroupBy,
+    windowed: bool,
+    /// Schema of the partial batches, known from the first batch
+    schema: Option<SchemaRef>,
+    keys: Option<RowConverter>,
+    states: BTreeMap<Option<OwnedRow>, Vec<PartialState>>,
+}
+
+impl PartialCombiner {
+    /// Creates a combiner for the partial state of an aggregation spec.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` for session windows, whose
+    /// sessions cannot be stitched from separately formed partial state.
+    pub fn try_new(aggregates: &[AggregateExpr], group_by: &GroupBy, window: TimeWindow) -> Result<Self, Status> {
+        if matches!(window, TimeWindow::Session { .. }) {
+            return Err(Status::invalid_argument("Partial state of session windows cannot be combined"));
+        }
+        Ok(Self {
+            aggregates: aggregates.to_vec(),
+            group_by: group_by.clone(),
+            windowed: !matches!(window, TimeWindow::None),
+            schema: None,
+            keys: None,
+            states: BTreeMap::new(),
+        })
+    }
+
+    /// Number of key columns of a partial batch.
+    fn key_count(&self) -> usize {
+        self.group_by.columns.len() + self.group_by.time_column.iter().count() + if self.windowed { 2 } else { 0 }
+    }
+
+    /// Merges a partial state batch into the combined state.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the batch does not have the
+    /// layout of [`partial_schema`] for the spec, its version is not
+    /// supported, or its key columns differ from earlier batches.
+    pub fn push(&mut self, batch: &RecordBatch) -> Result<(), Status> {
+        let schema = batch.schema();
+        match schema.metadata().get(PARTIAL_STATE_VERSION_KEY) {
+            Some(version) if *version == PARTIAL_STATE_VERSION.to_string() => {}
+            Some(version) => {
+                return Err(Status::invalid_argument(format!("Unsupported partial state format version {}", version)));
+            }
+            None => return Err(Status::invalid_argument("Batch is not partial aggregation state")),
+        }
+        let key_count = self.key_count();
+        let mut expected: Vec<String> = self.group_by.columns.iter().chain(self.group_by.time_column.iter()).cloned().collect();
+        if self.windowed {
+            expected.extend(["window_start".to_string(), "window_end".to_string()]);
+        }
+        expected.extend(self.aggregates.iter().map(|a| a.output_name()));
+        let names: Vec<&String> = schema.fields().iter().map(|f| f.name()).collect();
+        if names.len() != expected.len() || names.iter().zip(&expected).any(|(a, b)| *a != b) {
+            return Err(Status::invalid_argument(format!(
+                "Partial state columns {:?} do not match the aggregation, expected {:?}",
+                names, expected
+            )));
+        }
+        match &self.schema {
+            Some(known) if known.fields()[..key_count] != schema.fields()[..key_count] => {
+                return Err(Status::invalid_argument("Partial state key columns differ between batches"));
+            }
+            Some(_) => {}
+            None => {
+                if key_count > 0 {
+                    let fields = schema.fields()[..key_count].iter().map(|f| SortField::new(f.data_type().clone()));
+                    self.keys = Some(
+                        RowConverter::new(fields.collect())
+                            .map_err(|e| Status::invalid_argument(format!("Invalid partial state keys: {}", e)))?,
+                    );
+                }
+                self.schema = Some(schema.clone());
+            }
+        }
+
+        let rows = match &self.keys {
+            Some(keys) => Some(
+                keys.convert_columns(&batch.columns()[..key_count])
+                    .map_err(|e| Status::invalid_argument(format!("Invalid partial state keys: {}", e)))?,
+            ),
+            None => None,
+        };
+        let states = batch.columns()[key_count..].iter()
+            .map(|column| {
+                column.as_any().downcast_ref::<BinaryArray>()
+                    .ok_or_else(|| Status::invalid_argument("Partial state columns must be binary"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for row in 0..batch.num_rows() {
+            let key = rows.as_ref().map(|rows| rows.row(row).owned());
+            let merged = self.states.entry(key).or_insert_with(|| {
+                vec![PartialState::empty(); self.aggregates.len()]
+            });
+            for (merged, states) in merged.iter_mut().zip(&states) {
+                if states.is_null(row) {
+                    return Err(Status::invalid_argument("Partial state must not be null"));
+                }
+                merged.merge(&PartialState::from_bytes(states.value(row))?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the key columns and states of the combined rows, in key
+    /// order.
+    fn rows(self) -> Result<CombinedRows, Status> {
+        let key_count = self.key_count();
+        let Some(schema) = self.schema else {
+            // Without batches only a global aggregation has a row
+            if key_count > 0 {
+                return Err(Status::failed_precondition("No partial state was combined"));
+            }
+            let empty = vec![PartialState::empty(); self.aggregates.len()];
+            return Ok((vec![], vec![], vec![empty]));
+        };
+        let fields: Vec<Field> = schema.fields()[..key_count].iter().map(|f| f.as_ref().clone()).collect();
+        let keys = match &self.keys {
+            Some(converter) => converter
+                .convert_rows(self.states.keys().filter_map(|key| key.as_ref().map(|row| row.row())))
+                .map_err(|e| Status::internal(format!("Failed to build partial state keys: {}", e)))?,
+            None => vec![],
+        };
+        Ok((fields, keys, self.states.into_values().collect()))
+    }
+
+    /// Returns the combined state as a partial state batch, to be combined
+    /// further upstream.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::failed_precondition` if no batch was pushed to a
+    /// grouped or windowed combiner, whose key types are then unknown.
+    pub fn finish_partial(self) -> Result<RecordBatch, Status> {
+        let aggregates = self.aggregates.clone();
+        let (mut fields, keys, states) = self.rows()?;
+        fields.extend(aggregates.iter().map(|a| Field::new(a.output_name(), DataType::Binary, false)));
+        let metadata = HashMap::from([(PARTIAL_STATE_VERSION_KEY.to_string(), PARTIAL_STATE_VERSION.to_string())]);
+        partial_batch(Schema::new_with_metadata(fields, metadata), keys, &states, aggregates.len())
+    }
+
+    /// Evaluates the combined state into one row per group and window, with
+    /// the columns of [`aggregate_output_schema`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::failed_precondition` like
+    /// [`finish_partial`](Self::finish_partial).
+    pub fn finish(self) -> Result<RecordBatch, Status> {
+        let aggregates = self.aggregates.clone();
+        let (mut fields, mut arrays, states) = self.rows()?;
+        for (i, aggregate) in aggregates.iter().enumerate() {
+            let values = states.iter()
+                .map(|row| row[i].evaluate(aggregate.function))
+                .collect::<Result<Vec<_>, _>>()?;
+            let array: ArrayRef = match aggregate.function {
+                AggregateFunction::Count | AggregateFunction::CountDistinct => {
+                    Arc::new(values.into_iter().map(|v| v.map(|v| v as i64)).collect::<Int64Array>())
+                }
+                _ => Arc::new(values.into_iter().collect::<Float64Array>()),
+            };
+            fields.push(Field::new(aggregate.output_name(), array.data_type().clone(), true));
+            arrays.push(array);
+        }
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)
+            .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+    }
+}
@@ -0,0 +1,279 @@
+//! Arithmetic and statistics across aligned series.
+//!
+//! A [`SeriesAlignment`] aggregates two operands in the same time windows,
+//! each grouped by its labels, and pairs their series by label matching,
+//! as PromQL does for binary operators:
+//!
+//! - `Ignoring` pairs series whose labels are equal except for the ignored
+//!   ones, e.g. ignoring `metric_id` pairs `errors` with `requests`
+//! - `On` pairs series whose listed labels are equal
+//!
+//! Matching is one to one: a series matching several series of the other
+//! operand is an error. Paired series are joined on their windows, and
+//! windows missing from either series are dropped.
+//!
+//! Arithmetic operators produce one value per paired window, null where the
+//! divisor is zero. `Covariance` and `Correlation` produce one value per
+//! pair of series over all its windows: the sample covariance and the
+//! Pearson correlation coefficient, null with fewer than two windows or,
+//! for the correlation, a constant series.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use arrow::compute::cast;
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use super::filter::Filter;
+use super::{AggregateExpr, GroupBy, TimeWindow};
+
+/// One side of an alignment: an aggregate per series of its labels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesOperand {
+    pub aggregate: AggregateExpr,
+    /// Columns identifying a series; a single series if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+impl SeriesOperand {
+    /// Grouping of the aggregation of the operand.
+    pub fn group_by(&self) -> GroupBy {
+        GroupBy { columns: self.labels.clone(), time_column: None }
+    }
+}
+
+/// Labels that must be equal for series of the two operands to pair.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelMatching {
+    On(Vec<String>),
+    Ignoring(Vec<String>),
+}
+
+impl Default for LabelMatching {
+    fn default() -> Self {
+        LabelMatching::Ignoring(Vec::new())
+    }
+}
+
+/// Operation on paired series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Covariance,
+    Correlation,
+}
+
+impl SeriesOp {
+    /// Whether the operation reduces each pair of series to one value.
+    pub fn is_statistic(&self) -> bool {
+        matches!(self, SeriesOp::Covariance | SeriesOp::Correlation)
+    }
+
+    fn apply(&self, left: f64, right: f64) -> Option<f64> {
+        match self {
+            SeriesOp::Add => Some(left + right),
+            SeriesOp::Sub => Some(left - right),
+            SeriesOp::Mul => Some(left * right),
+            SeriesOp::Div => (right != 0.0).then(|| left / right),
+            SeriesOp::Covariance | SeriesOp::Correlation => None,
+        }
+    }
+}
+
+/// Two operands aligned on the same windows and combined by `op`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesAlignment {
+    pub left: SeriesOperand,
+    pub right: SeriesOperand,
+    #[serde(default)]
+    pub matching: LabelMatching,
+    pub op: SeriesOp,
+}
+
+impl SeriesAlignment {
+    /// Returns the labels of the result, which identify a pair of series.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if an `On` label is not a label
+    /// of both operands, or the operands have different labels apart from
+    /// the ignored ones.
+    pub fn output_labels(&self) -> Result<Vec<String>, Status> {
+        match &self.matching {
+            LabelMatching::On(labels) => {
+                for label in labels {
+                    if !self.left.labels.contains(label) || !self.right.labels.contains(label) {
+                        return Err(Status::invalid_argument(format!(
+                            "Matching label {} is not a label of both operands",
+                            label
+                        )));
+                    }
+                }
+                Ok(labels.clone())
+            }
+            LabelMatching::Ignoring(ignored) => {
+                let remaining = |labels: &[String]| -> Vec<String> {
+                    labels.iter().filter(|label| !ignored.contains(label)).cloned().collect()
+                };
+                let left = remaining(&self.left.labels);
+                let mut sorted = left.clone();
+                let mut right = remaining(&self.right.labels);
+                sorted.sort();
+                right.sort();
+                if sorted != right {
+                    return Err(Status::invalid_argument(
+                        "Operands must have the same labels apart from the ignored ones",
+                    ));
+                }
+                Ok(left)
+            }
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the labels do not match, see
+    /// [`output_labels`](Self::output_labels), or the window is a session
+    /// window, whose windows differ between series.
+    pub fn validate(&self, window: TimeWindow) -> Result<(), Status> {
+        if matches!(window, TimeWindow::Session { .. }) {
+            return Err(Status::invalid_argument("Series cannot be aligned on session windows"));
+        }
+        self.output_labels().map(|_| ())
+    }
+
+    /// Pairs and combines the series of the aggregation results of the two
+    /// operands.
+    ///
+    /// The result has the output labels as strings, `window_start`,
+    /// `window_end` and `value`, ordered by labels and window. For
+    /// statistics the window is the span of the paired windows.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the labels do not match or a
+    /// series matches several series of the other operand.
+    pub fn combine(&self, left: &RecordBatch, right: &RecordBatch) -> Result<RecordBatch, Status> {
+        let labels = self.output_labels()?;
+        let left = read_series(left, &self.left, &labels, "left")?;
+        let right = read_series(right, &self.right, &labels, "right")?;
+
+        let mut keys: Vec<Vec<Option<String>>> = Vec::new();
+        let mut starts = Vec::new();
+        let mut ends = Vec::new();
+        let mut values = Vec::new();
+        for (key, left) in &left {
+            let Some(right) = right.get(key) else { continue };
+            let pairs: Vec<(i64, i64, f64, f64)> = left.iter()
+                .filter_map(|(start, (end, x))| right.get(start).map(|(_, y)| (*start, *end, *x, *y)))
+                .collect();
+            if self.op.is_statistic() {
+                let (Some(first), Some(last)) = (pairs.first(), pairs.last()) else { continue };
+                keys.push(key.clone());
+                starts.push(first.0);
+                ends.push(last.1);
+                let pairs: Vec<(f64, f64)> = pairs.iter().map(|p| (p.2, p.3)).collect();
+                values.push(statistic(self.op, &pairs));
+            } else {
+                for (start, end, x, y) in pairs {
+                    keys.push(key.clone());
+                    starts.push(start);
+                    ends.push(end);
+                    values.push(self.op.apply(x, y));
+                }
+            }
+        }
+
+        let mut fields: Vec<Field> = labels.iter().map(|label| Field::new(label, DataType::Utf8, true)).collect();
+        let mut columns: Vec<Arc<dyn Array>> = (0..labels.len())
+            .map(|i| Arc::new(keys.iter().map(|key| key[i].clone()).collect::<StringArray>()) as _)
+            .collect();
+        fields.push(Field::new("window_start", DataType::Int64, false));
+        fields.push(Field::new("window_end", DataType::Int64, false));
+        fields.push(Field::new("value", DataType::Float64, true));
+        columns.push(Arc::new(Int64Array::from(starts)));
+        columns.push(Arc::new(Int64Array::from(ends)));
+        columns.push(Arc::new(Float64Array::from(values)));
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .map_err(|e| Status::internal(format!("Failed to build aligned series: {}", e)))
+    }
+}
+
+/// Windows of a series by start, with their end and value.
+type Windows = BTreeMap<i64, (i64, f64)>;
+
+/// Reads the series of an operand by their matching labels.
+fn read_series(
+    batch: &RecordBatch,
+    operand: &SeriesOperand,
+    labels: &[String],
+    side: &str,
+) -> Result<BTreeMap<Vec<Option<String>>, Windows>, Status> {
+    let column = |name: &str, data_type: &DataType| {
+        let column = batch.column_by_name(name)
+            .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", name)))?;
+        cast(column, data_type).map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))
+    };
+    let label_columns = labels.iter()
+        .map(|label| column(label, &DataType::Utf8))
+        .collect::<Result<Vec<_>, Status>>()?;
+    let label_columns: Vec<&StringArray> = label_columns.iter()
+        .map(|column| column.as_any().downcast_ref::<StringArray>())
+        .collect::<Option<_>>()
+        .ok_or_else(|| Status::internal("Failed to read label column"))?;
+    let starts = column("window_start", &DataType::Int64)?;
+    let starts = starts.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_start"))?;
+    let ends = column("window_end", &DataType::Int64)?;
+    let ends = ends.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_end"))?;
+    let values = column(&operand.aggregate.output_name(), &DataType::Float64)?;
+    let values = values.as_any().downcast_ref::<Float64Array>()
+        .ok_or_else(|| Status::internal("Failed to read aggregate column"))?;
+
+    let mut series: BTreeMap<Vec<Option<String>>, Windows> = BTreeMap::new();
+    for row in 0..batch.num_rows() {
+        if values.is_null(row) {
+            continue;
+        }
+        let key: Vec<Option<String>> = label_columns.iter()
+            .map(|column| column.is_valid(row).then(|| column.value(row).to_string()))
+            .collect();
+        let windows = series.entry(key).or_default();
+        if windows.insert(starts.value(row), (ends.value(row), values.value(row))).is_some() {
+            return Err(Status::invalid_argument(format!(
+                "Several series of the {} operand match the same labels; match on more labels",
+                side
+            )));
+        }
+    }
+    Ok(series)
+}
+
+fn statistic(op: SeriesOp, pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        sxy += (x - mean_x) * (y - mean_y);
+        sxx += (x - mean_x).powi(2);
+        syy += (y - mean_y).powi(2);
+    }
+    match op {
+        SeriesOp::Covariance => Some(sxy / (n - 1.0)),
+        _ => (sxx > 0.0 && syy > 0.0).then(|| sxy / (sxx * syy).sqrt()),
+    }
+}
@@ -0,0 +1,240 @@
+//! Mergeable quantile sketch.
+//!
+//! `QuantileSketch` is a DDSketch: values are mapped to logarithmically sized
+//! buckets so that every quantile estimate is within a fixed relative error of
+//! the true value. Sketches built over different windows or groups can be
+//! merged losslessly, which lets per-window sketches stored in
+//! `metric_aggregations` be combined into quantiles over any range.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tonic::Status;
+
+/// Default relative accuracy of quantile estimates (1%).
+pub const DEFAULT_RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Maximum number of buckets kept per sign before the smallest are collapsed.
+const MAX_BUCKETS: usize = 2048;
+
+/// Magnitudes below this are counted as zero.
+const MIN_INDEXABLE: f64 = 1e-12;
+
+/// Version of the serialized sketch format.
+const FORMAT_VERSION: u8 = 1;
+
+/// A mergeable quantile sketch with relative error guarantees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantileSketch {
+    relative_accuracy: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        Self::with_relative_accuracy(DEFAULT_RELATIVE_ACCURACY)
+    }
+}
+
+impl QuantileSketch {
+    /// Creates an empty sketch with the default relative accuracy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty sketch whose estimates are within `relative_accuracy`
+    /// of the true value, e.g. `0.01` for 1%.
+    pub fn with_relative_accuracy(relative_accuracy: f64) -> Self {
+        Self {
+            relative_accuracy: relative_accuracy.clamp(1e-6, 0.5),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Returns the number of values added to the sketch.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns true if no values were added.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Adds a value. Non-finite values are ignored.
+    pub fn insert(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        if value.abs() < MIN_INDEXABLE {
+            self.zero_count += 1;
+        } else {
+            let key = self.key(value.abs());
+            let buckets = if value > 0.0 { &mut self.positive } else { &mut self.negative };
+            *buckets.entry(key).or_insert(0) += 1;
+            collapse(buckets);
+        }
+
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Merges another sketch into this one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the sketches were built with
+    /// different relative accuracies.
+    pub fn merge(&mut self, other: &QuantileSketch) -> Result<(), Status> {
+        if other.is_empty() {
+            return Ok(());
+        }
+        if self.relative_accuracy != other.relative_accuracy {
+            return Err(Status::invalid_argument(format!(
+                "Cannot merge quantile sketches with relative accuracy {} and {}",
+                self.relative_accuracy, other.relative_accuracy
+            )));
+        }
+
+        for (key, count) in &other.positive {
+            *self.positive.entry(*key).or_insert(0) += count;
+        }
+        for (key, count) in &other.negative {
+            *self.negative.entry(*key).or_insert(0) += count;
+        }
+        collapse(&mut self.positive);
+        collapse(&mut self.negative);
+
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        Ok(())
+    }
+
+    /// Estimates the `q`-quantile, `0 <= q <= 1`.
+    ///
+    /// Returns `None` for an empty sketch. The estimate approximates the value
+    /// at rank `q * (count - 1)` within the sketch's relative accuracy and is
+    /// never outside the range of the added values.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        if q == 0.0 {
+            return Some(self.min);
+        }
+        if q == 1.0 {
+            return Some(self.max);
+        }
+
+        let rank = q * (self.count - 1) as f64;
+        let mut seen = 0u64;
+
+        // Negative values, from the largest magnitude to the smallest
+        for (key, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen as f64 > rank {
+                return Some((-self.value(*key)).clamp(self.min, self.max));
+            }
+        }
+
+        seen += self.zero_count;
+        if seen as f64 > rank {
+            return Some(0.0f64.clamp(self.min, self.max));
+        }
+
+        for (key, count) in &self.positive {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(self.value(*key).clamp(self.min, self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    /// Serializes the sketch for storage.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Status> {
+        let mut bytes = vec![FORMAT_VERSION];
+        bincode::serialize_into(&mut bytes, self)
+            .map_err(|e| Status::internal(format!("Failed to serialize quantile sketch: {}", e)))?;
+        Ok(bytes)
+    }
+
+    /// Deserializes a sketch produced by [`to_bytes`](Self::to_bytes).
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::internal` if the bytes are not a valid sketch.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Status> {
+        match bytes.split_first() {
+            Some((&FORMAT_VERSION, payload)) => {
+                let sketch: Self = bincode::deserialize(payload)
+                    .map_err(|e| Status::internal(format!("Failed to deserialize quantile sketch: {}", e)))?;
+                sketch.validate()?;
+                Ok(sketch)
+            }
+            Some((version, _)) => Err(Status::internal(format!(
+                "Unsupported quantile sketch format version {}",
+                version
+            ))),
+            None => Err(Status::internal("Empty quantile sketch")),
+        }
+    }
+
+    /// Checks that a deserialized sketch has a relative accuracy
+    /// [`with_relative_accuracy`](Self::with_relative_accuracy) accepts.
+    pub(crate) fn validate(&self) -> Result<(), Status> {
+        if !(1e-6..=0.5).contains(&self.relative_accuracy) {
+            return Err(Status::internal(format!(
+                "Invalid quantile sketch relative accuracy {}",
+                self.relative_accuracy
+            )));
+        }
+        Ok(())
+    }
+
+    fn gamma(&self) -> f64 {
+        (1.0 + self.relative_accuracy) / (1.0 - self.relative_accuracy)
+    }
+
+    fn key(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma().ln()).ceil() as i32
+    }
+
+    /// Representative value of a bucket, equidistant in relative terms from
+    /// both bucket bounds.
+    fn value(&self, key: i32) -> f64 {
+        let gamma = self.gamma();
+        2.0 * gamma.powi(key) / (gamma + 1.0)
+    }
+}
+
+/// Merges the lowest buckets until at most `MAX_BUCKETS` remain.
+///
+/// Collapsing only ever affects the smallest magnitudes, so the relative
+/// accuracy of the upper quantiles is preserved.
+fn collapse(buckets: &mut BTreeMap<i32, u64>) {
+    while buckets.len() > MAX_BUCKETS {
+        let Some((lowest, count)) = buckets.pop_first() else { return };
+        match buckets.first_entry() {
+            Some(mut next) => *next.get_mut() += count,
+            None => {
+                buckets.insert(lowest, count);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Exponential smoothing of windowed series.
+//!
+//! [`HoltWinters`] is the additive Holt-Winters model: a level, a trend and
+//! one seasonal offset per position in the season, each updated with its
+//! own smoothing factor as values arrive. A season of one window has no
+//! seasonality and is Holt's linear trend model.
+//!
+//! The model is initialized from its first two seasons: the level is the
+//! mean of the first season, the trend the difference of the season means
+//! per window and the seasonal offsets the deviations of the first season
+//! from its mean. The second season is then applied as regular updates.
+
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+/// Smoothing factors and season length of a [`HoltWinters`] model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HoltWintersParams {
+    /// Smoothing of the level, in `[0, 1]`
+    pub alpha: f64,
+    /// Smoothing of the trend, in `[0, 1]`
+    pub beta: f64,
+    /// Smoothing of the seasonal offsets, in `[0, 1]`
+    pub gamma: f64,
+    /// Windows per season
+    pub season: usize,
+}
+
+impl HoltWintersParams {
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a smoothing factor is outside
+    /// `[0, 1]` or the season is empty.
+    pub fn validate(&self) -> Result<(), Status> {
+        for (name, factor) in [("alpha", self.alpha), ("beta", self.beta), ("gamma", self.gamma)] {
+            if !(0.0..=1.0).contains(&factor) {
+                return Err(Status::invalid_argument(format!("Holt-Winters {} must be in [0, 1]", name)));
+            }
+        }
+        if self.season == 0 {
+            return Err(Status::invalid_argument("Holt-Winters season must be at least one window"));
+        }
+        Ok(())
+    }
+}
+
+/// An additive Holt-Winters model updated one value at a time.
+#[derive(Debug, Clone)]
+pub struct HoltWinters {
+    params: HoltWintersParams,
+    /// Values of the first two seasons, until they initialize the model
+    warmup: Vec<f64>,
+    level: f64,
+    trend: f64,
+    seasonal: Vec<f64>,
+    observed: usize,
+    /// Count, mean and sum of squared deviations of the one-step residuals
+    residuals: (usize, f64, f64),
+}
+
+impl HoltWinters {
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the parameters are invalid, see
+    /// [`HoltWintersParams::validate`].
+    pub fn new(params: HoltWintersParams) -> Result<Self, Status> {
+        params.validate()?;
+        Ok(Self {
+            params,
+            warmup: Vec::with_capacity(2 * params.season),
+            level: 0.0,
+            trend: 0.0,
+            seasonal: vec![0.0; params.season],
+            observed: 0,
+            residuals: (0, 0.0, 0.0),
+        })
+    }
+
+    /// Fits a model to a series, in order.
+    pub fn fit(params: HoltWintersParams, values: &[f64]) -> Result<Self, Status> {
+        let mut model = Self::new(params)?;
+        for &value in values {
+            model.update(value);
+        }
+        Ok(model)
+    }
+
+    /// Whether the first two seasons were observed.
+    pub fn is_ready(&self) -> bool {
+        self.observed >= 2 * self.params.season
+    }
+
+    /// Number of values observed.
+    pub fn observed(&self) -> usize {
+        self.observed
+    }
+
+    /// Forecasts the value `steps` windows after the last observed one,
+    /// `None` before the model is ready or for zero steps.
+    pub fn forecast(&self, steps: usize) -> Option<f64> {
+        if !self.is_ready() || steps == 0 {
+            return None;
+        }
+        let position = (self.observed + steps - 1) % self.params.season;
+        Some(self.level + steps as f64 * self.trend + self.seasonal[position])
+    }
+
+    /// Sample standard deviation of the one-step forecast residuals seen
+    /// since the model was initialized, `None` with fewer than two.
+    pub fn residual_stddev(&self) -> Option<f64> {
+        let (count, _, m2) = self.residuals;
+        (count >= 2).then(|| (m2 / (count - 1) as f64).sqrt())
+    }
+
+    /// Observes the next value and returns its one-step forecast residual,
+    /// `None` while the model is not ready to forecast it.
+    pub fn update(&mut self, value: f64) -> Option<f64> {
+        if !self.is_ready() {
+            self.warmup.push(value);
+            self.observed += 1;
+            if self.is_ready() {
+                self.initialize();
+            }
+            return None;
+        }
+        Some(self.smooth(value))
+    }
+
+    fn initialize(&mut self) {
+        let season = self.params.season;
+        let warmup = std::mem::take(&mut self.warmup);
+        let (first, second) = warmup.split_at(season);
+        let mean = |values: &[f64]| values.iter().sum::<f64>() / season as f64;
+        self.level = mean(first);
+        self.trend = (mean(second) - self.level) / season as f64;
+        self.seasonal = first.iter().map(|value| value - self.level).collect();
+        self.observed = season;
+        for &value in second {
+            self.smooth(value);
+        }
+    }
+
+    /// Applies the smoothing equations to the next value and returns its
+    /// residual.
+    fn smooth(&mut self, value: f64) -> f64 {
+        let HoltWintersParams { alpha, beta, gamma, season } = self.params;
+        let position = self.observed % season;
+        let forecast = self.level + self.trend + self.seasonal[position];
+        let level = alpha * (value - self.seasonal[position]) + (1.0 - alpha) * (self.level + self.trend);
+        self.trend = beta * (level - self.level) + (1.0 - beta) * self.trend;
+        self.level = level;
+        self.seasonal[position] = gamma * (value - level) + (1.0 - gamma) * self.seasonal[position];
+        self.observed += 1;
+
+        let residual = value - forecast;
+        let (count, mean, m2) = &mut self.residuals;
+        *count += 1;
+        let delta = residual - *mean;
+        *mean += delta / *count as f64;
+        *m2 += delta * (residual - *mean);
+        residual
+    }
+}
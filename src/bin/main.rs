@@ -171,16 +171,20 @@
 use clap::Parser;
 use hyprstream_core::{
     config::{CliArgs, Settings},
+    promql,
     service::FlightSqlService,
     storage::{
         StorageBackendType,
         StorageBackend, 
         adbc::AdbcBackend, 
+        alerts,
         duckdb::DuckDbBackend,
+        rollup,
     },
     models::{storage::TimeSeriesModelStorage, ModelStorage},
 };
 use std::sync::Arc;
+use std::time::Duration;
 use tonic::transport::Server;
 use tracing_subscriber::{fmt, EnvFilter};
 
@@ -221,6 +225,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize the storage backend
     engine_backend.init().await?;
 
+    // Evaluate scheduled alert rules
+    alerts::spawn_scheduler(engine_backend.clone(), Duration::from_secs(1));
+
+    // Roll up tables once windows of their finest tier complete
+    rollup::spawn_scheduler(engine_backend.clone(), Duration::from_secs(1));
+
+    // Serve the Prometheus-compatible query API
+    if settings.prometheus.enabled {
+        let addr = format!("{}:{}", settings.prometheus.host, settings.prometheus.port);
+        let listener = tokio::net::TcpListener::bind(&addr).await?;
+        tracing::info!("Serving the Prometheus query API on {}", addr);
+        tokio::spawn(promql::api::serve(engine_backend.clone(), listener));
+    }
+
     // Create the model storage using the same backend
     let model_storage = Box::new(TimeSeriesModelStorage::new(engine_backend.clone()));
     model_storage.init().await?;
@@ -229,7 +247,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let service = FlightSqlService::new(
         engine_backend.clone(),
         model_storage,
-    );
+    )
+    .with_rate_limits(settings.rate_limit.clone())?
+    .with_idempotency(settings.idempotency.clone())
+    .with_quality_rules(&settings.quality)?;
 
     // Start the server
     let addr = format!("{}:{}", settings.server.host, settings.server.port).parse()?;
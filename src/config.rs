@@ -24,6 +24,7 @@ use serde::Deserialize;
 use std::env;
 use std::path::PathBuf;
 use std::collections::HashMap;
+use crate::storage::quality::QualityRules;
 
 const DEFAULT_CONFIG: &str = include_str!("../config/default.toml");
 const DEFAULT_CONFIG_PATH: &str = "/etc/hyprstream/config.toml";
@@ -114,6 +115,18 @@ pub struct Settings {
     pub engine: EngineConfig,
     /// Cache configuration
     pub cache: CacheConfig,
+    /// Ingestion rate limit configuration
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Data quality rules keyed by table name
+    #[serde(default)]
+    pub quality: HashMap<String, QualityRules>,
+    /// Idempotent ingestion configuration
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    /// Prometheus-compatible HTTP query API configuration
+    #[serde(default)]
+    pub prometheus: PrometheusConfig,
 }
 
 /// Server configuration options.
@@ -196,6 +209,127 @@ impl Default for CacheConfig {
     }
 }
 
+/// Per-client ingestion rate limits.
+///
+/// Limits are enforced with token buckets keyed by a digest of the client's
+/// bearer token, or by its peer address when the request is unauthenticated.
+/// A bucket holds `burst_secs` worth of its rate, so short bursts above the
+/// sustained rate are accepted. Rates must be positive; an unset rate is
+/// unlimited.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// Whether rate limiting is enabled
+    #[serde(default)]
+    pub enabled: bool,
+    /// Sustained rows per second per client (unlimited if unset)
+    #[serde(default)]
+    pub rows_per_sec: Option<u64>,
+    /// Sustained bytes per second per client (unlimited if unset)
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Bucket capacity expressed in seconds of the sustained rate
+    #[serde(default = "default_burst_secs")]
+    pub burst_secs: f64,
+    /// Per-client overrides keyed by `token:<sha256 of the token, hex>` or
+    /// `peer:<ip>`, see [`crate::rate_limit::token_key`]
+    #[serde(default)]
+    pub clients: HashMap<String, ClientRateLimit>,
+}
+
+/// Rate limit override for a single client.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientRateLimit {
+    /// Sustained rows per second (unlimited if unset)
+    #[serde(default)]
+    pub rows_per_sec: Option<u64>,
+    /// Sustained bytes per second (unlimited if unset)
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+}
+
+fn default_burst_secs() -> f64 {
+    1.0
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rows_per_sec: None,
+            bytes_per_sec: None,
+            burst_secs: default_burst_secs(),
+            clients: HashMap::new(),
+        }
+    }
+}
+
+/// Idempotent ingestion settings.
+///
+/// Batches tagged with a producer ID and sequence number are applied at most
+/// once; see [`crate::storage::idempotency`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdempotencyConfig {
+    /// Number of recent sequence numbers remembered per producer
+    #[serde(default = "default_idempotency_window")]
+    pub window: u64,
+    /// Seconds after which the acks of an idle producer are dropped from
+    /// memory; they are reloaded from the ack table when it returns
+    #[serde(default = "default_idle_secs")]
+    pub idle_secs: f64,
+}
+
+fn default_idempotency_window() -> u64 {
+    1024
+}
+
+fn default_idle_secs() -> f64 {
+    600.0
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            window: default_idempotency_window(),
+            idle_secs: default_idle_secs(),
+        }
+    }
+}
+
+/// Prometheus-compatible HTTP query API settings.
+///
+/// Serves PromQL queries on `/api/v1/query` and `/api/v1/query_range`; see
+/// [`crate::promql`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrometheusConfig {
+    /// Whether to serve the API
+    #[serde(default)]
+    pub enabled: bool,
+    /// Host address to bind to
+    #[serde(default = "default_prometheus_host")]
+    pub host: String,
+    /// Port to bind to
+    #[serde(default = "default_prometheus_port")]
+    pub port: u16,
+}
+
+fn default_prometheus_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_prometheus_port() -> u16 {
+    9090
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: default_prometheus_host(),
+            port: default_prometheus_port(),
+        }
+    }
+}
+
 fn default_cache_engine() -> String {
     "duckdb".to_string()
 }
@@ -83,9 +83,12 @@ pub mod service;
 pub mod config;
 pub mod aggregation;
 pub mod models;
+pub mod promql;
+pub mod rate_limit;
 
 pub use service::FlightSqlService;
 pub use storage::StorageBackend;
 pub use metrics::MetricRecord;
-pub use aggregation::{TimeWindow, AggregateFunction, GroupBy, AggregateResult};
+pub use aggregation::{TimeWindow, AggregateFunction, AggregateExpr, GroupBy, ResultOptions};
+pub use aggregation::filter::Filter;
 pub use models::{Model, ModelLayer, ModelMetadata, ModelVersion, ModelStorage};
@@ -12,16 +12,11 @@
 //! logic and optimizations.
 
 use crate::metrics::MetricRecord;
-use crate::aggregation::{AggregateFunction, GroupBy, build_aggregate_query};
+use crate::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, build_aggregate_query};
+use crate::aggregation::filter::Filter;
+use crate::storage::BatchAggregation;
 use tonic::Status;
 
-/// The standard metric value columns used in aggregation queries
-const METRIC_VALUE_COLUMNS: [&str; 3] = [
-    "value_running_window_sum",
-    "value_running_window_avg",
-    "value_running_window_count"
-];
-
 /// Applies the aggregation function to a set of metrics.
 ///
 /// This function implements metric-specific aggregation by operating directly
@@ -37,6 +32,7 @@ const METRIC_VALUE_COLUMNS: [&str; 3] = [
 ///
 /// The aggregated value as a float, or an error if the operation fails
 pub fn apply_function(function: AggregateFunction, metrics: &[MetricRecord]) -> Result<f64, Status> {
+    function.validate()?;
     if metrics.is_empty() {
         return Ok(0.0);
     }
@@ -56,42 +52,52 @@ pub fn apply_function(function: AggregateFunction, metrics: &[MetricRecord]) ->
             .map(|m| m.value_running_window_sum)
             .fold(f64::NEG_INFINITY, f64::max)),
         AggregateFunction::Count => Ok(metrics.len() as f64),
+        // Sketch- and order-based functions share the per-window state so
+        // they agree with the persisted batch aggregations
+        function => {
+            let mut state = BatchAggregation::new(String::new(), i64::MIN, i64::MAX);
+            for metric in metrics {
+                state.update(metric.timestamp, metric.value_running_window_sum, 1);
+            }
+            state.evaluate(function)
+        }
     }
 }
 
 /// Builds a SQL query for metrics aggregation.
 ///
 /// This function specializes the generic aggregate query builder for metrics
-/// by providing the metric-specific value columns and table name. It reuses
-/// the core query building logic while adding metric-specific context.
+/// by providing the metric table name. It reuses the core query building
+/// logic while adding metric-specific context.
 ///
 /// # Arguments
 ///
-/// * `function` - The aggregation function to apply
+/// * `aggregates` - The aggregate expressions over the metric value columns
 /// * `group_by` - The grouping specification
-/// * `from_timestamp` - The start of the time range
-/// * `to_timestamp` - The optional end of the time range
+/// * `from_timestamp` - The start of the time range, inclusive
+/// * `to_timestamp` - The optional end of the time range, exclusive
+/// * `filter` - Optional filter on the metric rows, e.g. a `metric_id` regex
+/// * `options` - HAVING, ORDER BY, LIMIT and top-k clauses on the result
 ///
 /// # Returns
 ///
-/// A SQL query string optimized for metric aggregation
+/// A SQL query string optimized for metric aggregation, or
+/// `Status::invalid_argument` if the filter cannot be rendered
 pub fn build_metrics_query(
-    function: AggregateFunction,
+    aggregates: &[AggregateExpr],
     group_by: &GroupBy,
     from_timestamp: i64,
     to_timestamp: Option<i64>,
-) -> String {
-    let columns = METRIC_VALUE_COLUMNS.iter()
-        .map(|&c| c.to_string())
-        .collect::<Vec<_>>();
-
-    let column_refs: Vec<&str> = columns.iter().map(|s| s.as_str()).collect();
+    filter: Option<&Filter>,
+    options: &ResultOptions,
+) -> Result<String, Status> {
     build_aggregate_query(
         "metrics",
-        function,
+        aggregates,
         group_by,
-        &column_refs,
         Some(from_timestamp),
         to_timestamp,
+        filter,
+        options,
     )
-} 
\ No newline at end of file
+}
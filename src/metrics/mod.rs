@@ -4,6 +4,7 @@ use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
 use arrow_schema::{DataType, Field, Schema};
 use std::sync::Arc;
 use tonic::Status;
+use crate::storage::schema_adapter::{ColumnDefault, SchemaAdapter};
 
 /// A single metric record with running window calculations.
 #[derive(Debug, Clone)]
@@ -21,7 +22,13 @@ pub struct MetricRecord {
 }
 
 impl MetricRecord {
+    /// Converts a record batch into metric records.
+    ///
+    /// The batch is first adapted to the metrics schema, so compatible column
+    /// types are cast and omitted optional columns are filled with defaults.
     pub fn try_from_record_batch(batch: &RecordBatch) -> Result<Vec<Self>, Status> {
+        let batch = get_metrics_schema_adapter().adapt(batch)?;
+
         let metric_ids = batch
             .column_by_name("metric_id")
             .and_then(|col| col.as_any().downcast_ref::<StringArray>())
@@ -73,6 +80,16 @@ pub fn get_metrics_schema() -> Schema {
     ])
 }
 
+/// Gets the ingest adapter for metric record batches.
+///
+/// Producers may omit the running average and count: a single point's
+/// average is its sum, and its count is one.
+pub fn get_metrics_schema_adapter() -> SchemaAdapter {
+    SchemaAdapter::new(Arc::new(get_metrics_schema()))
+        .with_default("value_running_window_avg", ColumnDefault::CopyOf("value_running_window_sum".to_string()))
+        .with_default("value_running_window_count", ColumnDefault::Int64(1))
+}
+
 /// Creates a RecordBatch from a vector of MetricRecords.
 pub fn create_record_batch(metrics: &[MetricRecord]) -> Result<RecordBatch, Status> {
     let schema = get_metrics_schema();
@@ -97,36 +114,5 @@ pub fn create_record_batch(metrics: &[MetricRecord]) -> Result<RecordBatch, Stat
 
 /// Encodes a RecordBatch into a vector of MetricRecords.
 pub fn encode_record_batch(batch: &RecordBatch) -> Result<Vec<MetricRecord>, Status> {
-    let metric_ids = batch.column_by_name("metric_id")
-        .and_then(|col| col.as_any().downcast_ref::<StringArray>())
-        .ok_or_else(|| Status::internal("Invalid metric_id column"))?;
-
-    let timestamps = batch.column_by_name("timestamp")
-        .and_then(|col| col.as_any().downcast_ref::<Int64Array>())
-        .ok_or_else(|| Status::internal("Invalid timestamp column"))?;
-
-    let sums = batch.column_by_name("value_running_window_sum")
-        .and_then(|col| col.as_any().downcast_ref::<Float64Array>())
-        .ok_or_else(|| Status::internal("Invalid value_running_window_sum column"))?;
-
-    let avgs = batch.column_by_name("value_running_window_avg")
-        .and_then(|col| col.as_any().downcast_ref::<Float64Array>())
-        .ok_or_else(|| Status::internal("Invalid value_running_window_avg column"))?;
-
-    let counts = batch.column_by_name("value_running_window_count")
-        .and_then(|col| col.as_any().downcast_ref::<Int64Array>())
-        .ok_or_else(|| Status::internal("Invalid value_running_window_count column"))?;
-
-    let mut metrics = Vec::with_capacity(batch.num_rows());
-    for i in 0..batch.num_rows() {
-        metrics.push(MetricRecord {
-            metric_id: metric_ids.value(i).to_string(),
-            timestamp: timestamps.value(i),
-            value_running_window_sum: sums.value(i),
-            value_running_window_avg: avgs.value(i),
-            value_running_window_count: counts.value(i),
-        });
-    }
-
-    Ok(metrics)
-} 
\ No newline at end of file
+    MetricRecord::try_from_record_batch(batch)
+}
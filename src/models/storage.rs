@@ -1,4 +1,5 @@
 use super::{Model, ModelLayer, ModelMetadata, ModelVersion, ModelStorage};
+use crate::aggregation::filter::{CompareOp, Filter, Literal};
 use crate::storage::{StorageBackend, StorageBackendType};
 use arrow_array::{
     Array, ArrayRef, RecordBatch, StringArray, Int64Array, BinaryArray,
@@ -53,6 +54,16 @@ impl TimeSeriesModelStorage {
         Ok(())
     }
 
+    /// Build the filter selecting the rows of a model, optionally restricted
+    /// to one version
+    fn model_filter(model_id: &str, version: Option<&str>) -> Filter {
+        let filter = Filter::compare("model_id", CompareOp::Eq, Literal::String(model_id.to_string()));
+        match version {
+            Some(version) => filter.and(Filter::compare("version", CompareOp::Eq, Literal::String(version.to_string()))),
+            None => filter,
+        }
+    }
+
     /// Extract an optional string value from a StringArray column
     /// Maintains zero-copy until the final conversion to owned String
     fn get_optional_string(batch: &RecordBatch, column: &str, row: usize) -> Result<Option<String>, Status> {
@@ -97,20 +108,13 @@ impl ModelStorage for TimeSeriesModelStorage {
     }
 
     async fn load_model(&self, model_id: &str, version: Option<&str>) -> Result<Model, Status> {
-        // Build query conditions
-        let version_condition = version.map_or(String::new(), |v| format!(" AND version = '{}'", v));
-        
+        let filter = Self::model_filter(model_id, version);
+
         // Query metadata
-        let metadata_batch = self.backend.query_table(
-            "model_metadata",
-            Some(vec![format!("* WHERE model_id = '{}'{}", model_id, version_condition)]),
-        ).await?;
+        let metadata_batch = self.backend.query_table("model_metadata", None, Some(&filter)).await?;
 
         // Query layers
-        let layers_batch = self.backend.query_table(
-            "model_layers",
-            Some(vec![format!("* WHERE model_id = '{}'{}", model_id, version_condition)]),
-        ).await?;
+        let layers_batch = self.backend.query_table("model_layers", None, Some(&filter)).await?;
 
         // Convert record batches back to Model
         Self::record_batches_to_model(metadata_batch, layers_batch)
@@ -123,22 +127,16 @@ impl ModelStorage for TimeSeriesModelStorage {
         version: Option<&str>,
     ) -> Result<Vec<ModelLayer>, Status> {
         // Build query conditions
-        let version_condition = version.map_or(String::new(), |v| format!(" AND version = '{}'", v));
-        let names_condition = if !layer_names.is_empty() {
-            format!(" AND name IN ({})", layer_names.iter()
-                .map(|n| format!("'{}'", n))
-                .collect::<Vec<_>>()
-                .join(","))
-        } else {
-            String::new()
-        };
+        let mut filter = Self::model_filter(model_id, version);
+        if !layer_names.is_empty() {
+            filter = filter.and(Filter::In {
+                column: "name".to_string(),
+                values: layer_names.iter().map(|n| Literal::String(n.clone())).collect(),
+            });
+        }
 
         // Query specific layers
-        let layers_batch = self.backend.query_table(
-            "model_layers",
-            Some(vec![format!("* WHERE model_id = '{}'{}{}", 
-                model_id, version_condition, names_condition)]),
-        ).await?;
+        let layers_batch = self.backend.query_table("model_layers", None, Some(&filter)).await?;
 
         // Convert record batch to ModelLayer instances
         Self::record_batch_to_layers(layers_batch)
@@ -149,6 +147,7 @@ impl ModelStorage for TimeSeriesModelStorage {
         let metadata_batch = self.backend.query_table(
             "model_metadata",
             Some(vec!["DISTINCT model_id, name, architecture, version, created_at, description, parent_version, parameters".to_string()]),
+            None,
         ).await?;
 
         // Convert record batch to ModelMetadata instances
@@ -159,7 +158,8 @@ impl ModelStorage for TimeSeriesModelStorage {
         // Query versions for specific model
         let versions_batch = self.backend.query_table(
             "model_metadata",
-            Some(vec![format!("version, created_at, description, parent_version WHERE model_id = '{}'", model_id)]),
+            Some(vec!["version, created_at, description, parent_version".to_string()]),
+            Some(&Self::model_filter(model_id, None)),
         ).await?;
 
         // Convert record batch to ModelVersion instances
@@ -167,17 +167,13 @@ impl ModelStorage for TimeSeriesModelStorage {
     }
 
     async fn delete_version(&self, model_id: &str, version: &str) -> Result<(), Status> {
+        let filter = Self::model_filter(model_id, Some(version));
+
         // Delete metadata
-        self.backend.query_table(
-            "model_metadata",
-            Some(vec![format!("DELETE WHERE model_id = '{}' AND version = '{}'", model_id, version)]),
-        ).await?;
+        self.backend.delete_from_table("model_metadata", &filter).await?;
 
         // Delete layers
-        self.backend.query_table(
-            "model_layers",
-            Some(vec![format!("DELETE WHERE model_id = '{}' AND version = '{}'", model_id, version)]),
-        ).await?;
+        self.backend.delete_from_table("model_layers", &filter).await?;
 
         Ok(())
     }
@@ -0,0 +1,11 @@
+//! PromQL queries over stored metrics.
+//!
+//! A subset of PromQL, see [`parser`], is evaluated by compiling every
+//! selector to an aggregation over aligned sub-windows and combining the
+//! sub-window aggregates per step, see [`eval`]. The [`api`] module serves
+//! the Prometheus HTTP query API, so that Prometheus clients such as
+//! Grafana's Prometheus data source can query Hyprstream unchanged.
+
+pub mod api;
+pub mod eval;
+pub mod parser;
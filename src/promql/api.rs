@@ -0,0 +1,263 @@
+//! Prometheus-compatible HTTP query API.
+//!
+//! Serves the endpoints the Grafana Prometheus data source uses:
+//! - `/api/v1/query` and `/api/v1/query_range`, evaluating PromQL
+//! - `/api/v1/labels` and `/api/v1/label/__name__/values`, listing the
+//!   metric names of the `metrics` table
+//! - `/api/v1/metadata`, always empty
+//!
+//! Parameters are read from the query string and, for POST requests, from
+//! a form-encoded body of at most [`MAX_BODY_BYTES`]. Responses use the
+//! Prometheus JSON envelope; invalid queries are answered with 400, larger
+//! bodies with 413 and failed evaluations with 422.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use arrow::compute::cast;
+use arrow_array::{Array, StringArray};
+use arrow_schema::DataType;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tonic::{Code, Status};
+use crate::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow};
+use crate::storage::StorageBackend;
+use super::eval::{evaluate, EvalRange, QueryValue, NAME_LABEL};
+use super::parser::{parse, parse_duration};
+
+/// Maximum size of a POST body.
+pub const MAX_BODY_BYTES: usize = 1 << 20;
+
+/// Pause after failing to accept a connection, e.g. while out of file
+/// descriptors, before accepting again.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Serves the API on connections accepted from `listener` until the task is
+/// dropped. Failures to accept a connection are logged and do not stop the
+/// server.
+pub async fn serve<B: StorageBackend + ?Sized>(backend: Arc<B>, listener: TcpListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Failed to accept a Prometheus API connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let backend = backend.clone();
+                async move { Ok::<_, Infallible>(handle(backend.as_ref(), request).await) }
+            });
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                tracing::debug!("Prometheus API connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers one request.
+pub async fn handle<B: StorageBackend + ?Sized>(backend: &B, request: Request<Incoming>) -> Response<Full<Bytes>> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let mut params = request.uri().query().map(parse_form).unwrap_or_default();
+    if method == Method::POST {
+        match Limited::new(request.into_body(), MAX_BODY_BYTES).collect().await {
+            Ok(body) => params.extend(parse_form(&String::from_utf8_lossy(&body.to_bytes()))),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return response(StatusCode::PAYLOAD_TOO_LARGE, error_body("bad_data", "Request body too large"));
+            }
+            Err(e) => return error_response(Status::invalid_argument(format!("Failed to read request body: {}", e))),
+        }
+    } else if method != Method::GET {
+        return response(StatusCode::METHOD_NOT_ALLOWED, error_body("bad_data", "Method not allowed"));
+    }
+
+    let result = match path.as_str() {
+        "/api/v1/query" => instant_query(backend, &params).await,
+        "/api/v1/query_range" => range_query(backend, &params).await,
+        "/api/v1/labels" => Ok(json!([NAME_LABEL])),
+        "/api/v1/label/__name__/values" => metric_names(backend).await,
+        "/api/v1/metadata" => Ok(json!({})),
+        path if path.starts_with("/api/v1/label/") && path.ends_with("/values") => Ok(json!([])),
+        _ => return response(StatusCode::NOT_FOUND, error_body("not_found", "Unknown endpoint")),
+    };
+    match result {
+        Ok(data) => response(StatusCode::OK, json!({ "status": "success", "data": data })),
+        Err(status) => error_response(status),
+    }
+}
+
+fn response(code: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = code;
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn error_body(error_type: &str, message: &str) -> Value {
+    json!({ "status": "error", "errorType": error_type, "error": message })
+}
+
+fn error_response(status: Status) -> Response<Full<Bytes>> {
+    match status.code() {
+        Code::InvalidArgument => response(StatusCode::BAD_REQUEST, error_body("bad_data", status.message())),
+        _ => response(StatusCode::UNPROCESSABLE_ENTITY, error_body("execution", status.message())),
+    }
+}
+
+async fn instant_query<B: StorageBackend + ?Sized>(backend: &B, params: &[(String, String)]) -> Result<Value, Status> {
+    let expr = parse(required(params, "query")?)?;
+    let time = match param(params, "time") {
+        Some(time) => parse_time(time)?,
+        None => chrono::Utc::now().timestamp(),
+    };
+    Ok(match evaluate(backend, &expr, EvalRange::instant(time)).await? {
+        QueryValue::Scalar(values) => json!({ "resultType": "scalar", "result": [time, format_value(values[0])] }),
+        QueryValue::Vector(series) => {
+            let result: Vec<Value> = series.iter()
+                .filter_map(|series| series.values[0].map(|value| {
+                    json!({ "metric": series.labels, "value": [time, format_value(value)] })
+                }))
+                .collect();
+            json!({ "resultType": "vector", "result": result })
+        }
+    })
+}
+
+async fn range_query<B: StorageBackend + ?Sized>(backend: &B, params: &[(String, String)]) -> Result<Value, Status> {
+    let expr = parse(required(params, "query")?)?;
+    let start = parse_time(required(params, "start")?)?;
+    let end = parse_time(required(params, "end")?)?;
+    let step = parse_step(required(params, "step")?)?;
+    let eval = EvalRange::new(start, end, step)?;
+    let result: Vec<Value> = match evaluate(backend, &expr, eval).await? {
+        QueryValue::Scalar(values) => {
+            let values: Vec<Value> = eval.timestamps().zip(values).map(|(t, v)| json!([t, format_value(v)])).collect();
+            vec![json!({ "metric": {}, "values": values })]
+        }
+        QueryValue::Vector(series) => series.iter()
+            .map(|series| {
+                let values: Vec<Value> = eval.timestamps()
+                    .zip(&series.values)
+                    .filter_map(|(t, value)| value.map(|value| json!([t, format_value(value)])))
+                    .collect();
+                json!({ "metric": series.labels, "values": values })
+            })
+            .collect(),
+    };
+    Ok(json!({ "resultType": "matrix", "result": result }))
+}
+
+/// Lists the distinct `metric_id` values of the `metrics` table.
+async fn metric_names<B: StorageBackend + ?Sized>(backend: &B) -> Result<Value, Status> {
+    let batch = backend.aggregate_metrics(
+        &[AggregateExpr::new(AggregateFunction::Count, "*")],
+        &GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+        TimeWindow::None,
+        0,
+        None,
+        None,
+        &ResultOptions::default(),
+    ).await?;
+    let column = batch.column_by_name("metric_id")
+        .ok_or_else(|| Status::internal("Missing aggregation column metric_id"))?;
+    let column = cast(column, &DataType::Utf8).map_err(|e| Status::internal(format!("Failed to read metric_id: {}", e)))?;
+    let column = column.as_any().downcast_ref::<StringArray>()
+        .ok_or_else(|| Status::internal("Failed to read metric_id"))?;
+    let mut names: Vec<&str> = column.iter().flatten().collect();
+    names.sort_unstable();
+    names.dedup();
+    Ok(json!(names))
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+fn required<'a>(params: &'a [(String, String)], name: &str) -> Result<&'a str, Status> {
+    param(params, name).ok_or_else(|| Status::invalid_argument(format!("Missing parameter {}", name)))
+}
+
+/// Parses a timestamp given as Unix seconds or RFC 3339, truncated to
+/// seconds. Unix seconds outside the range of dates chrono represents are
+/// rejected, so later arithmetic on timestamps cannot overflow.
+fn parse_time(value: &str) -> Result<i64, Status> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        let time = Some(seconds.floor())
+            .filter(|seconds| seconds.is_finite())
+            .and_then(|seconds| chrono::DateTime::from_timestamp(seconds as i64, 0));
+        return time
+            .map(|time| time.timestamp())
+            .ok_or_else(|| Status::invalid_argument(format!("Timestamp {} is out of range", value)));
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|time| time.timestamp())
+        .map_err(|_| Status::invalid_argument(format!("Invalid timestamp {}", value)))
+}
+
+/// Parses a step given as seconds, rounded up to whole seconds, or as a
+/// duration such as `1m`.
+fn parse_step(value: &str) -> Result<i64, Status> {
+    let step = match value.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Some(seconds.ceil() as i64),
+        Ok(_) => None,
+        Err(_) => parse_duration(value),
+    };
+    step.ok_or_else(|| Status::invalid_argument(format!("Invalid step {}", value)))
+}
+
+/// Formats a sample value as Prometheus does.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parses `application/x-www-form-urlencoded` pairs.
+fn parse_form(form: &str) -> Vec<(String, String)> {
+    form.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,827 @@
+//! Evaluation of PromQL expressions over stored series.
+//!
+//! Every selector is compiled to an aggregation spec, see
+//! [`compile_selector`]: the samples of each series are aggregated into
+//! aligned sub-windows whose size divides both the step and the range of
+//! the selector, so the samples of any step are the union of whole
+//! sub-windows. Functions and operators are then evaluated per step from
+//! the sub-window aggregates:
+//!
+//! - an instant selector takes the last sample within the lookback delta
+//! - `rate` and `increase` sum the increases within and between
+//!   sub-windows, adjusted for counter resets, and extrapolate them to the
+//!   range as Prometheus does
+//! - the `*_over_time` functions combine the sums, counts and extremes
+//!
+//! Metric names select the `metrics` table by `metric_id`, labelled by
+//! `__name__` only. A name `table:column` of a registered table selects
+//! that numeric column instead, labelled by the string columns of the
+//! table.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use arrow::compute::cast;
+use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, StringArray};
+use arrow_schema::DataType;
+use tonic::Status;
+use crate::aggregation::filter::{CompareOp, Filter, Literal};
+use crate::aggregation::operator::AggregateOperator;
+use crate::aggregation::series::LabelMatching;
+use crate::aggregation::{AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+use crate::storage::StorageBackend;
+use super::parser::{AggregateOp, BinaryOp, Expr, Grouping, RangeFunction, Selector};
+
+/// Maximum number of steps of a range query, as in Prometheus.
+pub const MAX_STEPS: usize = 11_000;
+
+/// Maximum number of sub-windows a selector is aggregated into.
+pub const MAX_SUB_WINDOWS: i64 = 100_000;
+
+/// Seconds an instant selector looks back for the last sample.
+pub const LOOKBACK_DELTA: i64 = 300;
+
+/// Label holding the metric name.
+pub const NAME_LABEL: &str = "__name__";
+
+const METRICS_TABLE: &str = "metrics";
+const METRICS_VALUE: &str = "value_running_window_sum";
+
+/// Labels of a series, ordered by name.
+pub type Labels = BTreeMap<String, String>;
+
+/// A series with one value per step, absent where it has no sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    pub values: Vec<Option<f64>>,
+}
+
+/// Result of an expression, with one value per step.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryValue {
+    Scalar(Vec<f64>),
+    Vector(Vec<Series>),
+}
+
+/// Evaluation timestamps, in Unix seconds: `start`, `start + step`, ... up
+/// to `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalRange {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+}
+
+impl EvalRange {
+    /// A single evaluation at `time`.
+    pub fn instant(time: i64) -> Self {
+        Self { start: time, end: time, step: 1 }
+    }
+
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the step is not positive, the
+    /// end is before the start, or there are more than [`MAX_STEPS`] steps,
+    /// including ranges too long to count.
+    pub fn new(start: i64, end: i64, step: i64) -> Result<Self, Status> {
+        if step <= 0 {
+            return Err(Status::invalid_argument("Step must be positive"));
+        }
+        if end < start {
+            return Err(Status::invalid_argument("End timestamp must not be before start timestamp"));
+        }
+        let range = Self { start, end, step };
+        if end.checked_sub(start).is_none() || range.steps() > MAX_STEPS {
+            return Err(Status::invalid_argument(format!(
+                "Exceeded maximum resolution of {} points per series; increase the step",
+                MAX_STEPS
+            )));
+        }
+        Ok(range)
+    }
+
+    /// Number of evaluation timestamps.
+    pub fn steps(&self) -> usize {
+        ((self.end - self.start) / self.step + 1) as usize
+    }
+
+    /// Returns the evaluation timestamps.
+    pub fn timestamps(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..self.steps() as i64).map(move |i| self.start + i * self.step)
+    }
+
+    fn last(&self) -> i64 {
+        self.start + (self.steps() as i64 - 1) * self.step
+    }
+
+    /// Seconds of samples an instant selector reads at each step: the
+    /// lookback delta, rounded up to a multiple of the step so that
+    /// sub-windows are one step long.
+    fn lookback(&self) -> i64 {
+        if self.steps() == 1 {
+            LOOKBACK_DELTA
+        } else {
+            (LOOKBACK_DELTA + self.step - 1) / self.step * self.step
+        }
+    }
+}
+
+/// The aggregation spec a selector is compiled to.
+///
+/// The aggregates are, per series and sub-window, the first, last,
+/// minimum, maximum, sum and count of the samples, and the timestamps of
+/// the first and last samples.
+#[derive(Debug, Clone)]
+pub struct SelectorPlan {
+    /// Table holding the samples
+    pub table: String,
+    /// Metric name of the series, for a table column
+    pub name: Option<String>,
+    /// Columns whose values label a series
+    pub labels: Vec<String>,
+    pub aggregates: Vec<AggregateExpr>,
+    pub group_by: GroupBy,
+    /// Aligned sub-windows ending one second after the first step
+    pub window: TimeWindow,
+    pub from_timestamp: i64,
+    /// End of the samples read, after the last step
+    pub to_timestamp: i64,
+    pub filter: Option<Filter>,
+    /// Seconds of samples read at each step, a multiple of the sub-window
+    pub range: i64,
+    /// Sub-window size in seconds
+    pub sub_window: i64,
+}
+
+impl SelectorPlan {
+    fn label_name<'a>(&self, column: &'a str) -> &'a str {
+        if self.table == METRICS_TABLE && column == "metric_id" {
+            NAME_LABEL
+        } else {
+            column
+        }
+    }
+}
+
+/// Compiles a selector reading `range` seconds of samples at each step of
+/// `eval` to an aggregation spec.
+///
+/// Returns `None` if a matcher on a label the series do not have can never
+/// match, so the selector selects nothing.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if a regular expression is invalid
+/// or the selector needs more than [`MAX_SUB_WINDOWS`] sub-windows.
+pub async fn compile_selector<B: StorageBackend + ?Sized>(
+    backend: &B,
+    selector: &Selector,
+    range: i64,
+    eval: &EvalRange,
+) -> Result<Option<SelectorPlan>, Status> {
+    let sub_window = if eval.steps() == 1 { range } else { gcd(eval.step, range) };
+    let origin = eval.start + 1 - range;
+    if (eval.last() + 1 - origin) / sub_window > MAX_SUB_WINDOWS {
+        return Err(Status::invalid_argument(format!(
+            "Query needs more than {} sub-windows of {}s; increase the step or use a range that is a multiple of it",
+            MAX_SUB_WINDOWS, sub_window
+        )));
+    }
+
+    let column = match &selector.name {
+        Some(name) => table_column(backend, name).await,
+        None => None,
+    };
+    let mut filters = Vec::new();
+    let (table, value, labels, name) = match column {
+        Some((table, value, labels)) => {
+            for matcher in &selector.matchers {
+                if matcher.label == NAME_LABEL {
+                    let name = selector.name.as_deref().unwrap_or_default();
+                    if !matcher.op.is_match(&matcher.value, name)? {
+                        return Ok(None);
+                    }
+                } else if labels.contains(&matcher.label) {
+                    filters.push(Filter::matches(&matcher.label, matcher.op, &matcher.value));
+                } else if !matcher.op.is_match(&matcher.value, "")? {
+                    return Ok(None);
+                }
+            }
+            (table, value, labels, selector.name.clone())
+        }
+        None => {
+            if let Some(name) = &selector.name {
+                filters.push(Filter::compare("metric_id", CompareOp::Eq, Literal::String(name.clone())));
+            }
+            for matcher in &selector.matchers {
+                if matcher.label == NAME_LABEL {
+                    filters.push(Filter::matches("metric_id", matcher.op, &matcher.value));
+                } else if !matcher.op.is_match(&matcher.value, "")? {
+                    return Ok(None);
+                }
+            }
+            (METRICS_TABLE.to_string(), METRICS_VALUE.to_string(), vec!["metric_id".to_string()], None)
+        }
+    };
+
+    let sample = |function, column: &str, alias: &str| AggregateExpr::new(function, column).with_alias(alias);
+    let aggregates = vec![
+        sample(AggregateFunction::First, &value, "sample_first"),
+        sample(AggregateFunction::Last, &value, "sample_last"),
+        sample(AggregateFunction::Min, &value, "sample_min"),
+        sample(AggregateFunction::Max, &value, "sample_max"),
+        sample(AggregateFunction::Sum, &value, "sample_sum"),
+        sample(AggregateFunction::Count, &value, "sample_count"),
+        sample(AggregateFunction::Min, "timestamp", "sample_start"),
+        sample(AggregateFunction::Max, "timestamp", "sample_end"),
+    ];
+    let filter = filters.into_iter().reduce(Filter::and);
+    Ok(Some(SelectorPlan {
+        table,
+        name,
+        group_by: GroupBy { columns: labels.clone(), time_column: None },
+        labels,
+        aggregates,
+        window: TimeWindow::Aligned { size: Duration::from_secs(sub_window as u64), origin },
+        from_timestamp: origin,
+        to_timestamp: eval.last() + 1,
+        filter,
+        range,
+        sub_window,
+    }))
+}
+
+/// Resolves a metric name `table:column` to a registered table other than
+/// `metrics`, its numeric column and its string columns.
+async fn table_column<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Option<(String, String, Vec<String>)> {
+    let (table, column) = name.split_once(':')?;
+    if table == METRICS_TABLE {
+        return None;
+    }
+    let schema = backend.table_manager().get_table_schema(table).await.ok()?;
+    let field = schema.field_with_name(column).ok()?;
+    if !field.data_type().is_numeric() || schema.field_with_name("timestamp").is_err() {
+        return None;
+    }
+    let labels = schema.fields().iter()
+        .filter(|f| f.data_type() == &DataType::Utf8)
+        .map(|f| f.name().clone())
+        .collect();
+    Some((table.to_string(), column.to_string(), labels))
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Aggregates of the samples of a series in a sub-window.
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    first: f64,
+    last: f64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: f64,
+    start: f64,
+    end: f64,
+}
+
+/// Sub-windows of a series, in time order.
+struct SampledSeries {
+    labels: Labels,
+    cells: Vec<Option<Cell>>,
+}
+
+/// The sampled series of a selector.
+struct Sampled {
+    series: Vec<SampledSeries>,
+    range: i64,
+    sub_window: i64,
+}
+
+/// Runs the aggregation of a plan and reads its sub-windows.
+async fn sample<B: StorageBackend + ?Sized>(backend: &B, plan: &SelectorPlan, eval: &EvalRange) -> Result<Vec<SampledSeries>, Status> {
+    let batch = if plan.table == METRICS_TABLE {
+        backend.aggregate_metrics_between(
+            &plan.aggregates,
+            &plan.group_by,
+            plan.window,
+            plan.from_timestamp,
+            plan.to_timestamp,
+            plan.filter.as_ref(),
+        ).await?
+    } else {
+        let range = Filter::compare("timestamp", CompareOp::Ge, Literal::Int(plan.from_timestamp))
+            .and(Filter::compare("timestamp", CompareOp::Lt, Literal::Int(plan.to_timestamp)));
+        let filter = match &plan.filter {
+            Some(filter) => filter.clone().and(range),
+            None => range,
+        };
+        let rows = backend.query_table(&plan.table, None, Some(&filter)).await?;
+        let mut operator = AggregateOperator::try_new(rows.schema(), &plan.aggregates, &plan.group_by, plan.window)?;
+        operator.push(&rows)?;
+        operator.finish()?
+    };
+
+    let column = |name: &str, data_type: &DataType| -> Result<ArrayRef, Status> {
+        let column = batch.column_by_name(name)
+            .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", name)))?;
+        cast(column, data_type).map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))
+    };
+    let label_columns = plan.labels.iter()
+        .map(|label| column(label, &DataType::Utf8))
+        .collect::<Result<Vec<_>, Status>>()?;
+    let label_columns: Vec<&StringArray> = label_columns.iter()
+        .map(|column| column.as_any().downcast_ref::<StringArray>())
+        .collect::<Option<_>>()
+        .ok_or_else(|| Status::internal("Failed to read label column"))?;
+    let starts = column("window_start", &DataType::Int64)?;
+    let starts = starts.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_start"))?;
+    let values = plan.aggregates.iter()
+        .map(|aggregate| column(&aggregate.output_name(), &DataType::Float64))
+        .collect::<Result<Vec<_>, Status>>()?;
+    let values: Vec<&Float64Array> = values.iter()
+        .map(|column| column.as_any().downcast_ref::<Float64Array>())
+        .collect::<Option<_>>()
+        .ok_or_else(|| Status::internal("Failed to read aggregate column"))?;
+
+    let cells = ((eval.last() - eval.start) / plan.sub_window + plan.range / plan.sub_window) as usize;
+    let mut series: BTreeMap<Labels, Vec<Option<Cell>>> = BTreeMap::new();
+    for row in 0..batch.num_rows() {
+        if values.iter().any(|column| column.is_null(row)) {
+            continue;
+        }
+        let k = (starts.value(row) - plan.from_timestamp) / plan.sub_window;
+        if k < 0 || k as usize >= cells {
+            continue;
+        }
+        let mut labels = Labels::new();
+        if let Some(name) = &plan.name {
+            labels.insert(NAME_LABEL.to_string(), name.clone());
+        }
+        for (label, column) in plan.labels.iter().zip(&label_columns) {
+            if column.is_valid(row) && !column.value(row).is_empty() {
+                labels.insert(plan.label_name(label).to_string(), column.value(row).to_string());
+            }
+        }
+        let value = |i: usize| values[i].value(row);
+        series.entry(labels).or_insert_with(|| vec![None; cells])[k as usize] = Some(Cell {
+            first: value(0),
+            last: value(1),
+            min: value(2),
+            max: value(3),
+            sum: value(4),
+            count: value(5),
+            start: value(6),
+            end: value(7),
+        });
+    }
+    Ok(series.into_iter().map(|(labels, cells)| SampledSeries { labels, cells }).collect())
+}
+
+/// Evaluates an expression at every step of `eval`.
+///
+/// Series without any value are dropped, and the series of a vector are
+/// ordered by labels.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the expression cannot be
+/// evaluated, such as a scalar comparison without `bool` or a vector
+/// matching several series of the other operand, and the errors of the
+/// storage backend.
+pub async fn evaluate<B: StorageBackend + ?Sized>(backend: &B, expr: &Expr, eval: EvalRange) -> Result<QueryValue, Status> {
+    let mut selectors = Vec::new();
+    collect_selectors(expr, &eval, &mut selectors);
+    let mut sampled = Vec::with_capacity(selectors.len());
+    for (selector, range) in selectors {
+        let plan = compile_selector(backend, selector, range, &eval).await?;
+        let (series, sub_window) = match plan {
+            Some(plan) => (sample(backend, &plan, &eval).await?, plan.sub_window),
+            None => (Vec::new(), range),
+        };
+        sampled.push(Sampled { series, range, sub_window });
+    }
+
+    let mut evaluator = Evaluator { eval, sampled: sampled.into_iter() };
+    let mut value = evaluator.eval(expr)?;
+    if let QueryValue::Vector(series) = &mut value {
+        series.retain(|series| series.values.iter().any(Option::is_some));
+        series.sort_by(|a, b| a.labels.cmp(&b.labels));
+    }
+    Ok(value)
+}
+
+/// Collects the selectors of an expression with the seconds of samples
+/// they read, in evaluation order.
+fn collect_selectors<'a>(expr: &'a Expr, eval: &EvalRange, selectors: &mut Vec<(&'a Selector, i64)>) {
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Selector(selector) => selectors.push((selector, eval.lookback())),
+        Expr::RangeFunction { selector, range, .. } => selectors.push((selector, *range)),
+        Expr::Aggregate { expr, .. } | Expr::Negate(expr) => collect_selectors(expr, eval, selectors),
+        Expr::HistogramQuantile { quantile, expr } => {
+            collect_selectors(quantile, eval, selectors);
+            collect_selectors(expr, eval, selectors);
+        }
+        Expr::Binary { lhs, rhs, .. } => {
+            collect_selectors(lhs, eval, selectors);
+            collect_selectors(rhs, eval, selectors);
+        }
+    }
+}
+
+struct Evaluator {
+    eval: EvalRange,
+    /// Sampled selectors, in the order of [`collect_selectors`]
+    sampled: std::vec::IntoIter<Sampled>,
+}
+
+impl Evaluator {
+    fn eval(&mut self, expr: &Expr) -> Result<QueryValue, Status> {
+        let steps = self.eval.steps();
+        Ok(match expr {
+            Expr::Number(value) => QueryValue::Scalar(vec![*value; steps]),
+            Expr::Selector(_) => {
+                let sampled = self.next_selector()?;
+                let series = sampled.series.iter().map(|series| Series {
+                    labels: series.labels.clone(),
+                    values: (0..steps)
+                        .map(|i| sampled.cells(series, i, &self.eval).iter().rev().flatten().next().map(|cell| cell.last))
+                        .collect(),
+                });
+                QueryValue::Vector(series.collect())
+            }
+            Expr::RangeFunction { function, .. } => {
+                let sampled = self.next_selector()?;
+                let series = sampled.series.iter().map(|series| Series {
+                    labels: without_name(&series.labels),
+                    values: self.eval.timestamps()
+                        .enumerate()
+                        .map(|(i, t)| range_function(*function, sampled.cells(series, i, &self.eval), t, sampled.range))
+                        .collect(),
+                });
+                QueryValue::Vector(series.collect())
+            }
+            Expr::Aggregate { op, grouping, expr } => {
+                let series = self.vector(expr)?;
+                QueryValue::Vector(aggregate(*op, grouping.as_ref(), series, steps))
+            }
+            Expr::HistogramQuantile { quantile, expr } => {
+                let QueryValue::Scalar(quantiles) = self.eval(quantile)? else {
+                    return Err(Status::invalid_argument("histogram_quantile expects a scalar quantile"));
+                };
+                let series = self.vector(expr)?;
+                QueryValue::Vector(histogram_quantile(&quantiles, series))
+            }
+            Expr::Binary { op, lhs, rhs, matching, return_bool } => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                binary(*op, lhs, rhs, matching.as_ref(), *return_bool)?
+            }
+            Expr::Negate(expr) => match self.eval(expr)? {
+                QueryValue::Scalar(values) => QueryValue::Scalar(values.iter().map(|v| -v).collect()),
+                QueryValue::Vector(series) => QueryValue::Vector(series.into_iter().map(|series| Series {
+                    labels: without_name(&series.labels),
+                    values: series.values.iter().map(|v| v.map(|v| -v)).collect(),
+                }).collect()),
+            },
+        })
+    }
+
+    fn vector(&mut self, expr: &Expr) -> Result<Vec<Series>, Status> {
+        match self.eval(expr)? {
+            QueryValue::Vector(series) => Ok(series),
+            QueryValue::Scalar(_) => Err(Status::invalid_argument("Expected an instant vector, got a scalar")),
+        }
+    }
+
+    fn next_selector(&mut self) -> Result<Sampled, Status> {
+        self.sampled.next().ok_or_else(|| Status::internal("Selector was not sampled"))
+    }
+}
+
+impl Sampled {
+    /// Sub-windows of a series holding the samples of step `i`.
+    fn cells<'a>(&self, series: &'a SampledSeries, i: usize, eval: &EvalRange) -> &'a [Option<Cell>] {
+        let first = (i as i64 * eval.step / self.sub_window) as usize;
+        let count = (self.range / self.sub_window) as usize;
+        &series.cells[first..(first + count).min(series.cells.len())]
+    }
+}
+
+fn without_name(labels: &Labels) -> Labels {
+    let mut labels = labels.clone();
+    labels.remove(NAME_LABEL);
+    labels
+}
+
+/// Applies a range function to the samples in `(end - range, end]`.
+fn range_function(function: RangeFunction, cells: &[Option<Cell>], end: i64, range: i64) -> Option<f64> {
+    let cells: Vec<&Cell> = cells.iter().flatten().collect();
+    if cells.is_empty() {
+        return None;
+    }
+    let count: f64 = cells.iter().map(|cell| cell.count).sum();
+    let sum: f64 = cells.iter().map(|cell| cell.sum).sum();
+    match function {
+        RangeFunction::Rate => extrapolated_increase(&cells, end - range, end).map(|increase| increase / range as f64),
+        RangeFunction::Increase => extrapolated_increase(&cells, end - range, end),
+        RangeFunction::AvgOverTime => Some(sum / count),
+        RangeFunction::SumOverTime => Some(sum),
+        RangeFunction::MinOverTime => Some(cells.iter().map(|cell| cell.min).fold(f64::NAN, f64::min)),
+        RangeFunction::MaxOverTime => Some(cells.iter().map(|cell| cell.max).fold(f64::NAN, f64::max)),
+        RangeFunction::CountOverTime => Some(count),
+    }
+}
+
+/// Increase of a counter over `(range_start, range_end]`, extrapolated
+/// from the sampled interval as Prometheus does: to the range boundaries if
+/// the samples come within 1.1 average intervals of them, else by half an
+/// interval, and never below zero before the first sample.
+///
+/// A counter reset within a sub-window is assumed to happen once, after
+/// its maximum.
+fn extrapolated_increase(cells: &[&Cell], range_start: i64, range_end: i64) -> Option<f64> {
+    let count: f64 = cells.iter().map(|cell| cell.count).sum();
+    let (first, last) = (cells.first()?, cells.last()?);
+    let sampled = last.end - first.start;
+    if count < 2.0 || sampled <= 0.0 {
+        return None;
+    }
+    let mut increase = 0.0;
+    let mut previous: Option<f64> = None;
+    for cell in cells {
+        if let Some(previous) = previous {
+            increase += if cell.first < previous { cell.first } else { cell.first - previous };
+        }
+        increase += if cell.last >= cell.first {
+            cell.last - cell.first
+        } else {
+            cell.max - cell.first + cell.last
+        };
+        previous = Some(cell.last);
+    }
+
+    let average = sampled / (count - 1.0);
+    let threshold = average * 1.1;
+    let mut to_start = first.start - range_start as f64;
+    let to_end = range_end as f64 - last.end;
+    if increase > 0.0 && first.first >= 0.0 {
+        to_start = to_start.min(sampled * first.first / increase);
+    }
+    let mut interval = sampled;
+    interval += if to_start < threshold { to_start } else { average / 2.0 };
+    interval += if to_end < threshold { to_end } else { average / 2.0 };
+    Some(increase * interval / sampled)
+}
+
+/// Sum, count and extremes of the values of a group at one step.
+#[derive(Debug, Clone, Copy)]
+struct Accumulator {
+    sum: f64,
+    count: usize,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self { sum: 0.0, count: 0, min: f64::NAN, max: f64::NAN }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn value(&self, op: AggregateOp) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(match op {
+            AggregateOp::Sum => self.sum,
+            AggregateOp::Avg => self.sum / self.count as f64,
+            AggregateOp::Min => self.min,
+            AggregateOp::Max => self.max,
+            AggregateOp::Count => self.count as f64,
+        })
+    }
+}
+
+fn group_labels(labels: &Labels, grouping: Option<&Grouping>) -> Labels {
+    match grouping {
+        None => Labels::new(),
+        Some(Grouping::By(kept)) => labels.iter()
+            .filter(|(label, _)| kept.contains(label))
+            .map(|(label, value)| (label.clone(), value.clone()))
+            .collect(),
+        Some(Grouping::Without(dropped)) => labels.iter()
+            .filter(|(label, _)| label.as_str() != NAME_LABEL && !dropped.contains(label))
+            .map(|(label, value)| (label.clone(), value.clone()))
+            .collect(),
+    }
+}
+
+fn aggregate(op: AggregateOp, grouping: Option<&Grouping>, series: Vec<Series>, steps: usize) -> Vec<Series> {
+    let mut groups: BTreeMap<Labels, Vec<Accumulator>> = BTreeMap::new();
+    for series in series {
+        let accumulators = groups.entry(group_labels(&series.labels, grouping))
+            .or_insert_with(|| vec![Accumulator::default(); steps]);
+        for (accumulator, value) in accumulators.iter_mut().zip(&series.values) {
+            if let Some(value) = value {
+                accumulator.add(*value);
+            }
+        }
+    }
+    groups.into_iter()
+        .map(|(labels, accumulators)| Series {
+            labels,
+            values: accumulators.iter().map(|accumulator| accumulator.value(op)).collect(),
+        })
+        .collect()
+}
+
+/// Buckets of a histogram: upper bounds with their counts per step.
+type Buckets = Vec<(f64, Vec<Option<f64>>)>;
+
+/// Estimates quantiles of histograms given as cumulative `le` buckets,
+/// grouped by their labels other than `le`.
+fn histogram_quantile(quantiles: &[f64], series: Vec<Series>) -> Vec<Series> {
+    let mut groups: BTreeMap<Labels, Buckets> = BTreeMap::new();
+    for series in series {
+        let Some(upper) = series.labels.get("le").and_then(|le| parse_bound(le)) else { continue };
+        let mut labels = without_name(&series.labels);
+        labels.remove("le");
+        groups.entry(labels).or_default().push((upper, series.values));
+    }
+    groups.into_iter()
+        .map(|(labels, buckets)| {
+            let values = quantiles.iter().enumerate().map(|(i, quantile)| {
+                let buckets: Vec<(f64, f64)> = buckets.iter()
+                    .filter_map(|(upper, values)| values[i].map(|count| (*upper, count)))
+                    .collect();
+                (!buckets.is_empty()).then(|| bucket_quantile(*quantile, buckets))
+            });
+            Series { labels, values: values.collect() }
+        })
+        .collect()
+}
+
+fn parse_bound(le: &str) -> Option<f64> {
+    match le {
+        "+Inf" | "Inf" | "inf" => Some(f64::INFINITY),
+        le => le.parse().ok(),
+    }
+}
+
+/// Interpolates a quantile linearly within its bucket, as Prometheus does.
+fn bucket_quantile(quantile: f64, mut buckets: Vec<(f64, f64)>) -> f64 {
+    if quantile.is_nan() {
+        return f64::NAN;
+    }
+    if quantile < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if quantile > 1.0 {
+        return f64::INFINITY;
+    }
+    buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if buckets.len() < 2 || buckets[buckets.len() - 1].0 != f64::INFINITY {
+        return f64::NAN;
+    }
+    // Counts of cumulative buckets cannot decrease
+    for i in 1..buckets.len() {
+        buckets[i].1 = buckets[i].1.max(buckets[i - 1].1);
+    }
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+    let mut rank = quantile * observations;
+    let b = buckets.iter().position(|bucket| bucket.1 >= rank).unwrap_or(buckets.len() - 1);
+    if b == buckets.len() - 1 {
+        return buckets[b - 1].0;
+    }
+    if b == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+    let (start, below) = if b == 0 { (0.0, 0.0) } else { buckets[b - 1] };
+    let end = buckets[b].0;
+    rank -= below;
+    start + (end - start) * (rank / (buckets[b].1 - below))
+}
+
+fn arithmetic(op: BinaryOp, lhs: f64, rhs: f64) -> f64 {
+    match op {
+        BinaryOp::Add => lhs + rhs,
+        BinaryOp::Sub => lhs - rhs,
+        BinaryOp::Mul => lhs * rhs,
+        BinaryOp::Div => lhs / rhs,
+        BinaryOp::Mod => lhs % rhs,
+        BinaryOp::Pow => lhs.powf(rhs),
+        BinaryOp::Eq => (lhs == rhs) as u8 as f64,
+        BinaryOp::Ne => (lhs != rhs) as u8 as f64,
+        BinaryOp::Gt => (lhs > rhs) as u8 as f64,
+        BinaryOp::Lt => (lhs < rhs) as u8 as f64,
+        BinaryOp::Ge => (lhs >= rhs) as u8 as f64,
+        BinaryOp::Le => (lhs <= rhs) as u8 as f64,
+    }
+}
+
+/// Result of an operator for a vector element: comparisons without `bool`
+/// keep the vector value `kept` if true and drop it otherwise.
+fn apply(op: BinaryOp, lhs: f64, rhs: f64, return_bool: bool, kept: f64) -> Option<f64> {
+    let result = arithmetic(op, lhs, rhs);
+    if op.is_comparison() && !return_bool {
+        (result == 1.0).then_some(kept)
+    } else {
+        Some(result)
+    }
+}
+
+fn binary(
+    op: BinaryOp,
+    lhs: QueryValue,
+    rhs: QueryValue,
+    matching: Option<&LabelMatching>,
+    return_bool: bool,
+) -> Result<QueryValue, Status> {
+    let drop_name = !op.is_comparison() || return_bool;
+    let result_labels = |labels: &Labels| if drop_name { without_name(labels) } else { labels.clone() };
+    Ok(match (lhs, rhs) {
+        (QueryValue::Scalar(lhs), QueryValue::Scalar(rhs)) => {
+            if op.is_comparison() && !return_bool {
+                return Err(Status::invalid_argument("Comparisons between scalars must use the bool modifier"));
+            }
+            QueryValue::Scalar(lhs.iter().zip(&rhs).map(|(l, r)| arithmetic(op, *l, *r)).collect())
+        }
+        (QueryValue::Vector(series), QueryValue::Scalar(scalar)) => QueryValue::Vector(series.iter().map(|series| Series {
+            labels: result_labels(&series.labels),
+            values: series.values.iter().zip(&scalar)
+                .map(|(value, r)| value.and_then(|l| apply(op, l, *r, return_bool, l)))
+                .collect(),
+        }).collect()),
+        (QueryValue::Scalar(scalar), QueryValue::Vector(series)) => QueryValue::Vector(series.iter().map(|series| Series {
+            labels: result_labels(&series.labels),
+            values: series.values.iter().zip(&scalar)
+                .map(|(value, l)| value.and_then(|r| apply(op, *l, r, return_bool, r)))
+                .collect(),
+        }).collect()),
+        (QueryValue::Vector(lhs), QueryValue::Vector(rhs)) => {
+            let key = |labels: &Labels| -> Labels {
+                match matching {
+                    Some(LabelMatching::On(on)) => labels.iter()
+                        .filter(|(label, _)| on.contains(label))
+                        .map(|(label, value)| (label.clone(), value.clone()))
+                        .collect(),
+                    Some(LabelMatching::Ignoring(ignored)) => labels.iter()
+                        .filter(|(label, _)| label.as_str() != NAME_LABEL && !ignored.contains(label))
+                        .map(|(label, value)| (label.clone(), value.clone()))
+                        .collect(),
+                    None => without_name(labels),
+                }
+            };
+            let mut right: BTreeMap<Labels, &Series> = BTreeMap::new();
+            for series in &rhs {
+                if right.insert(key(&series.labels), series).is_some() {
+                    return Err(Status::invalid_argument(
+                        "Many-to-many matching not allowed: found duplicate series on the right side of the operation",
+                    ));
+                }
+            }
+            let mut matched = BTreeSet::new();
+            let mut result = Vec::new();
+            for series in &lhs {
+                let key = key(&series.labels);
+                let Some(other) = right.get(&key) else { continue };
+                if !matched.insert(key.clone()) {
+                    return Err(Status::invalid_argument(
+                        "Found duplicate series for the match group on the left side of the operation",
+                    ));
+                }
+                let labels = match matching {
+                    Some(LabelMatching::On(_)) => key,
+                    Some(LabelMatching::Ignoring(ignored)) => {
+                        let mut labels = result_labels(&series.labels);
+                        labels.retain(|label, _| !ignored.contains(label));
+                        labels
+                    }
+                    None => result_labels(&series.labels),
+                };
+                let values = series.values.iter().zip(&other.values).map(|(l, r)| match (l, r) {
+                    (Some(l), Some(r)) => apply(op, *l, *r, return_bool, *l),
+                    _ => None,
+                });
+                result.push(Series { labels, values: values.collect() });
+            }
+            QueryValue::Vector(result)
+        }
+    })
+}
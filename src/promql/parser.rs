@@ -0,0 +1,548 @@
+//! Parser of the supported PromQL subset.
+//!
+//! The grammar is that of PromQL restricted to:
+//! - number literals, including `Inf` and `NaN`
+//! - vector selectors with label matchers, e.g. `http_requests{job=~"api.*"}`
+//! - `rate`, `increase` and the `*_over_time` functions of a range selector,
+//!   e.g. `rate(http_requests[5m])`
+//! - `sum`, `avg`, `min`, `max` and `count`, with `by` or `without` before
+//!   or after the argument
+//! - `histogram_quantile`
+//! - unary minus, and arithmetic and comparison operators with `bool`,
+//!   `on` and `ignoring`, at PromQL precedence
+//!
+//! Offsets, `@` modifiers, subqueries, string literals, set operators and
+//! `group_left`/`group_right` are rejected.
+
+use std::fmt::{Display, Formatter};
+use crate::aggregation::filter::MatchOp;
+use crate::aggregation::series::LabelMatching;
+use tonic::Status;
+
+/// A label matcher of a selector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub label: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+/// A vector selector: a metric name and label matchers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub name: Option<String>,
+    pub matchers: Vec<LabelMatcher>,
+}
+
+/// Functions of a range selector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeFunction {
+    Rate,
+    Increase,
+    AvgOverTime,
+    SumOverTime,
+    MinOverTime,
+    MaxOverTime,
+    CountOverTime,
+}
+
+impl RangeFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rate" => RangeFunction::Rate,
+            "increase" => RangeFunction::Increase,
+            "avg_over_time" => RangeFunction::AvgOverTime,
+            "sum_over_time" => RangeFunction::SumOverTime,
+            "min_over_time" => RangeFunction::MinOverTime,
+            "max_over_time" => RangeFunction::MaxOverTime,
+            "count_over_time" => RangeFunction::CountOverTime,
+            _ => return None,
+        })
+    }
+}
+
+/// Aggregation operators across series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggregateOp {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sum" => AggregateOp::Sum,
+            "avg" => AggregateOp::Avg,
+            "min" => AggregateOp::Min,
+            "max" => AggregateOp::Max,
+            "count" => AggregateOp::Count,
+            _ => return None,
+        })
+    }
+}
+
+/// Labels kept by an aggregation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+/// Binary operators, arithmetic and comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+}
+
+impl BinaryOp {
+    /// Whether the operator compares, filtering series unless `bool` is
+    /// given.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Gt | BinaryOp::Lt | BinaryOp::Ge | BinaryOp::Le)
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Gt | BinaryOp::Lt | BinaryOp::Ge | BinaryOp::Le => 1,
+            BinaryOp::Add | BinaryOp::Sub => 2,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 3,
+            BinaryOp::Pow => 4,
+        }
+    }
+}
+
+/// A parsed PromQL expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    /// A range function of a selector over a range in seconds
+    RangeFunction { function: RangeFunction, selector: Selector, range: i64 },
+    Aggregate { op: AggregateOp, grouping: Option<Grouping>, expr: Box<Expr> },
+    HistogramQuantile { quantile: Box<Expr>, expr: Box<Expr> },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        /// Label matching of two vectors, on all labels but the metric name
+        /// if absent
+        matching: Option<LabelMatching>,
+        /// Whether comparisons return 0 or 1 instead of filtering
+        return_bool: bool,
+    },
+    Negate(Box<Expr>),
+}
+
+/// Parses a PromQL expression.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` with the position of the error if
+/// the expression is invalid or outside the supported subset.
+pub fn parse(query: &str) -> Result<Expr, Status> {
+    let tokens = tokenize(query)?;
+    let mut parser = Parser { tokens, position: 0 };
+    let expr = parser.expr(0)?;
+    match parser.peek() {
+        Token::End => Ok(expr),
+        token => Err(parser.error(format!("unexpected {}", token))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    /// A duration in seconds
+    Duration(i64),
+    String(String),
+    Punct(&'static str),
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "identifier {}", name),
+            Token::Number(value) => write!(f, "number {}", value),
+            Token::Duration(secs) => write!(f, "duration {}s", secs),
+            Token::String(value) => write!(f, "string {:?}", value),
+            Token::Punct(punct) => write!(f, "\"{}\"", punct),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Punctuation, two-character operators first.
+const PUNCTUATION: [&str; 22] = [
+    "==", "!=", "=~", "!~", ">=", "<=", "(", ")", "{", "}", "[", "]", ",", "=", "+", "-", "*", "/", "%", "^", ">", "<",
+];
+
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, Status> {
+    let error = |position: usize, message: &str| {
+        Status::invalid_argument(format!("PromQL parse error at position {}: {}", position, message))
+    };
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == ':') {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            tokens.push((start, match ident.to_ascii_lowercase().as_str() {
+                "inf" => Token::Number(f64::INFINITY),
+                "nan" => Token::Number(f64::NAN),
+                _ => Token::Ident(ident),
+            }));
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())) {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                // Exponent signs belong to the number
+                if matches!(chars[i], 'e' | 'E') && matches!(chars.get(i + 1), Some('+' | '-')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            let token = match literal.parse::<f64>() {
+                Ok(value) => Token::Number(value),
+                Err(_) => Token::Duration(parse_duration(&literal).ok_or_else(|| error(start, "invalid number or duration"))?),
+            };
+            tokens.push((start, token));
+        } else if matches!(c, '"' | '\'' | '`') {
+            i += 1;
+            let mut value = String::new();
+            loop {
+                match chars.get(i) {
+                    None => return Err(error(start, "unterminated string")),
+                    Some(&quote) if quote == c => break,
+                    Some('\\') if c != '`' => {
+                        value.push(match chars.get(i + 1) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(&escaped) => escaped,
+                            None => return Err(error(start, "unterminated string")),
+                        });
+                        i += 2;
+                    }
+                    Some(&other) => {
+                        value.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push((start, Token::String(value)));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let punct = PUNCTUATION.iter()
+                .find(|punct| rest.starts_with(**punct))
+                .ok_or_else(|| error(start, &format!("unexpected character {:?}", c)))?;
+            i += punct.len();
+            tokens.push((start, Token::Punct(punct)));
+        }
+    }
+    tokens.push((chars.len(), Token::End));
+    Ok(tokens)
+}
+
+/// Parses a PromQL duration such as `5m` or `1h30m` into seconds.
+pub fn parse_duration(literal: &str) -> Option<i64> {
+    let mut total: i64 = 0;
+    let mut rest = literal;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let value: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let seconds = match &rest[..unit] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86_400,
+            "w" => 604_800,
+            "y" => 31_536_000,
+            _ => return None,
+        };
+        total = total.checked_add(value.checked_mul(seconds)?)?;
+        rest = &rest[unit..];
+    }
+    (total > 0).then_some(total)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].1.clone();
+        if self.position + 1 < self.tokens.len() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error(&self, message: impl Display) -> Status {
+        Status::invalid_argument(format!("PromQL parse error at position {}: {}", self.tokens[self.position].0, message))
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Token::Punct(p) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(ident) if ident == keyword)
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), Status> {
+        if !self.is_punct(punct) {
+            return Err(self.error(format!("expected \"{}\", found {}", punct, self.peek())));
+        }
+        self.next();
+        Ok(())
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        Some(match self.peek() {
+            Token::Punct("+") => BinaryOp::Add,
+            Token::Punct("-") => BinaryOp::Sub,
+            Token::Punct("*") => BinaryOp::Mul,
+            Token::Punct("/") => BinaryOp::Div,
+            Token::Punct("%") => BinaryOp::Mod,
+            Token::Punct("^") => BinaryOp::Pow,
+            Token::Punct("==") => BinaryOp::Eq,
+            Token::Punct("!=") => BinaryOp::Ne,
+            Token::Punct(">") => BinaryOp::Gt,
+            Token::Punct("<") => BinaryOp::Lt,
+            Token::Punct(">=") => BinaryOp::Ge,
+            Token::Punct("<=") => BinaryOp::Le,
+            _ => return None,
+        })
+    }
+
+    /// Parses binary operators of at least `min_precedence`; `^` is right
+    /// associative, the others left associative.
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, Status> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.binary_op().filter(|op| op.precedence() >= min_precedence) {
+            self.next();
+            let return_bool = self.is_keyword("bool");
+            if return_bool {
+                if !op.is_comparison() {
+                    return Err(self.error("bool is only allowed on comparison operators"));
+                }
+                self.next();
+            }
+            let matching = if self.is_keyword("on") {
+                self.next();
+                Some(LabelMatching::On(self.labels()?))
+            } else if self.is_keyword("ignoring") {
+                self.next();
+                Some(LabelMatching::Ignoring(self.labels()?))
+            } else {
+                None
+            };
+            if self.is_keyword("group_left") || self.is_keyword("group_right") {
+                return Err(self.error("group_left and group_right are not supported"));
+            }
+            let next_precedence = if op == BinaryOp::Pow { op.precedence() } else { op.precedence() + 1 };
+            let rhs = self.expr(next_precedence)?;
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs), matching, return_bool };
+        }
+        if let Token::Ident(ident) = self.peek() {
+            if matches!(ident.as_str(), "and" | "or" | "unless") {
+                return Err(self.error(format!("set operator {} is not supported", ident)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Status> {
+        if self.is_punct("-") || self.is_punct("+") {
+            let negate = self.is_punct("-");
+            self.next();
+            // `^` binds tighter than unary minus
+            let expr = self.expr(BinaryOp::Pow.precedence())?;
+            return Ok(match (negate, expr) {
+                (false, expr) => expr,
+                (true, Expr::Number(value)) => Expr::Number(-value),
+                (true, expr) => Expr::Negate(Box::new(expr)),
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, Status> {
+        let expr = match self.next() {
+            Token::Number(value) => Expr::Number(value),
+            Token::Punct("(") => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                expr
+            }
+            Token::Punct("{") => Expr::Selector(self.selector(None)?),
+            Token::Ident(ident) => {
+                if let Some(op) = AggregateOp::from_name(&ident) {
+                    if self.is_punct("(") || self.is_keyword("by") || self.is_keyword("without") {
+                        return self.aggregate(op);
+                    }
+                }
+                if self.is_punct("(") {
+                    self.next();
+                    return self.call(&ident);
+                }
+                let selector = if self.is_punct("{") {
+                    self.next();
+                    self.selector(Some(ident))?
+                } else {
+                    Selector { name: Some(ident), matchers: Vec::new() }
+                };
+                Expr::Selector(selector)
+            }
+            Token::Duration(_) => return Err(self.error("durations are only allowed in ranges")),
+            Token::String(_) => return Err(self.error("string literals are not supported")),
+            token => return Err(self.error(format!("unexpected {}", token))),
+        };
+        if self.is_punct("[") {
+            return Err(self.error("range selectors are only supported as arguments of range functions"));
+        }
+        if self.is_keyword("offset") {
+            return Err(self.error("offset modifiers are not supported"));
+        }
+        Ok(expr)
+    }
+
+    /// Parses the matchers of a selector after its opening brace.
+    fn selector(&mut self, name: Option<String>) -> Result<Selector, Status> {
+        let mut matchers = Vec::new();
+        while !self.is_punct("}") {
+            let Token::Ident(label) = self.next() else {
+                return Err(self.error("expected label name"));
+            };
+            let op = match self.next() {
+                Token::Punct("=") => MatchOp::Eq,
+                Token::Punct("!=") => MatchOp::Ne,
+                Token::Punct("=~") => MatchOp::Regex,
+                Token::Punct("!~") => MatchOp::NotRegex,
+                token => return Err(self.error(format!("expected label matcher, found {}", token))),
+            };
+            let Token::String(value) = self.next() else {
+                return Err(self.error("expected label value string"));
+            };
+            matchers.push(LabelMatcher { label, op, value });
+            if !self.is_punct("}") {
+                self.expect(",")?;
+            }
+        }
+        self.next();
+        if name.is_none() && !matchers.iter().any(|m| m.label == "__name__") {
+            return Err(self.error("selectors need a metric name or a __name__ matcher"));
+        }
+        Ok(Selector { name, matchers })
+    }
+
+    fn labels(&mut self) -> Result<Vec<String>, Status> {
+        self.expect("(")?;
+        let mut labels = Vec::new();
+        while !self.is_punct(")") {
+            let Token::Ident(label) = self.next() else {
+                return Err(self.error("expected label name"));
+            };
+            labels.push(label);
+            if !self.is_punct(")") {
+                self.expect(",")?;
+            }
+        }
+        self.next();
+        Ok(labels)
+    }
+
+    fn grouping(&mut self) -> Result<Option<Grouping>, Status> {
+        if self.is_keyword("by") {
+            self.next();
+            return Ok(Some(Grouping::By(self.labels()?)));
+        }
+        if self.is_keyword("without") {
+            self.next();
+            return Ok(Some(Grouping::Without(self.labels()?)));
+        }
+        Ok(None)
+    }
+
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr, Status> {
+        let before = self.grouping()?;
+        self.expect("(")?;
+        let expr = self.expr(0)?;
+        self.expect(")")?;
+        let grouping = match (before, self.grouping()?) {
+            (Some(_), Some(_)) => return Err(self.error("duplicate grouping")),
+            (before, after) => before.or(after),
+        };
+        Ok(Expr::Aggregate { op, grouping, expr: Box::new(expr) })
+    }
+
+    /// Parses the arguments of a function call after its opening parenthesis.
+    fn call(&mut self, name: &str) -> Result<Expr, Status> {
+        if let Some(function) = RangeFunction::from_name(name) {
+            let selector = match self.next() {
+                Token::Punct("{") => self.selector(None)?,
+                Token::Ident(ident) if self.is_punct("{") => {
+                    self.next();
+                    self.selector(Some(ident))?
+                }
+                Token::Ident(ident) => Selector { name: Some(ident), matchers: Vec::new() },
+                _ => return Err(self.error(format!("{} expects a range selector", name))),
+            };
+            self.expect("[")?;
+            let Token::Duration(range) = self.next() else {
+                return Err(self.error("expected range duration"));
+            };
+            if matches!(self.peek(), Token::Ident(ident) if ident.starts_with(':')) {
+                return Err(self.error("subqueries are not supported"));
+            }
+            self.expect("]")?;
+            if self.is_keyword("offset") {
+                return Err(self.error("offset modifiers are not supported"));
+            }
+            self.expect(")")?;
+            return Ok(Expr::RangeFunction { function, selector, range });
+        }
+        if name == "histogram_quantile" {
+            let quantile = self.expr(0)?;
+            self.expect(",")?;
+            let expr = self.expr(0)?;
+            self.expect(")")?;
+            return Ok(Expr::HistogramQuantile { quantile: Box::new(quantile), expr: Box::new(expr) });
+        }
+        Err(self.error(format!("function {} is not supported", name)))
+    }
+}
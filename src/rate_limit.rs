@@ -0,0 +1,305 @@
+//! Per-client ingestion rate limiting.
+//!
+//! Every client gets a pair of token buckets, one counting rows and one
+//! counting bytes. Buckets refill continuously at the configured sustained
+//! rate and hold `burst_secs` worth of tokens, so short bursts are absorbed
+//! while sustained overload is rejected.
+//!
+//! Clients are identified by the SHA-256 digest of their bearer token when
+//! one is presented and by their peer address otherwise, so tokens never
+//! appear in configuration or in memory beyond the request. Rejected requests fail with
+//! `RESOURCE_EXHAUSTED`; the status carries `retry-after` (seconds) and
+//! `retry-after-ms` metadata telling the client when the request would fit.
+//!
+//! Buckets that have been idle long enough to refill completely are evicted,
+//! since a full bucket is indistinguishable from a fresh one. Memory is thus
+//! bounded by the clients active within the last refill period.
+
+use crate::config::RateLimitConfig;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Status};
+
+/// A continuously refilling token bucket.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst_secs: f64) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate * burst_secs).max(1.0);
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Returns how long the caller must wait before `amount` can be taken.
+    ///
+    /// A request larger than the bucket is admitted once the bucket is full
+    /// and drives it into debt, so oversized batches are delayed rather than
+    /// rejected forever.
+    fn wait_time(&self, amount: f64) -> Duration {
+        let needed = amount.min(self.capacity);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    /// Returns true if the bucket has refilled completely by `now`.
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+/// Row and byte buckets of a single client.
+#[derive(Debug)]
+struct ClientBuckets {
+    rows: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl ClientBuckets {
+    fn is_full(&self, now: Instant) -> bool {
+        self.rows.iter().chain(&self.bytes).all(|bucket| bucket.is_full(now))
+    }
+}
+
+/// Interval between sweeps for idle buckets.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Buckets of all clients seen since they were last evicted.
+#[derive(Debug)]
+struct Clients {
+    buckets: HashMap<String, ClientBuckets>,
+    last_eviction: Instant,
+}
+
+impl Clients {
+    fn evict_idle(&mut self, now: Instant) {
+        self.buckets.retain(|_, buckets| !buckets.is_full(now));
+        self.last_eviction = now;
+    }
+}
+
+/// Enforces per-client ingestion quotas.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter from configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a rate is zero or `burst_secs`
+    /// is not a positive number. A zero rate would never refill its bucket;
+    /// leave the rate unset for no limit.
+    pub fn new(config: RateLimitConfig) -> Result<Self, Status> {
+        let overrides = config.clients.iter().map(|(client, limits)| {
+            (client.as_str(), limits.rows_per_sec, limits.bytes_per_sec)
+        });
+        let rates = std::iter::once(("default", config.rows_per_sec, config.bytes_per_sec)).chain(overrides);
+        for (client, rows, bytes) in rates {
+            if rows == Some(0) || bytes == Some(0) {
+                return Err(Status::invalid_argument(format!(
+                    "Rate limits of {} must be positive", client
+                )));
+            }
+        }
+        if !(config.burst_secs.is_finite() && config.burst_secs > 0.0) {
+            return Err(Status::invalid_argument(format!(
+                "Burst must be a positive number of seconds, got {}", config.burst_secs
+            )));
+        }
+        Ok(Self::unchecked(config))
+    }
+
+    /// Creates a rate limiter that admits everything.
+    pub fn disabled() -> Self {
+        Self::unchecked(RateLimitConfig::default())
+    }
+
+    fn unchecked(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(Clients {
+                buckets: HashMap::new(),
+                last_eviction: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns true if limits are enforced.
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Charges `rows` and `bytes` against the quota of `client`.
+    ///
+    /// Both buckets are checked before either is charged, so a rejected
+    /// request consumes nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::resource_exhausted` with retry metadata if the client
+    /// is over its row or byte quota.
+    pub fn check(&self, client: &str, rows: u64, bytes: u64) -> Result<(), Status> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|_| Status::internal("Rate limiter lock poisoned"))?;
+        let now = Instant::now();
+        if now.saturating_duration_since(clients.last_eviction) >= EVICTION_INTERVAL {
+            clients.evict_idle(now);
+        }
+        let entry = clients
+            .buckets
+            .entry(client.to_string())
+            .or_insert_with(|| self.buckets_for(client));
+
+        let mut wait = Duration::ZERO;
+        let mut exceeded = Vec::new();
+        if let Some(bucket) = entry.rows.as_mut() {
+            bucket.refill(now);
+            let delay = bucket.wait_time(rows as f64);
+            if !delay.is_zero() {
+                exceeded.push(format!("{} rows/s", bucket.rate));
+                wait = wait.max(delay);
+            }
+        }
+        if let Some(bucket) = entry.bytes.as_mut() {
+            bucket.refill(now);
+            let delay = bucket.wait_time(bytes as f64);
+            if !delay.is_zero() {
+                exceeded.push(format!("{} bytes/s", bucket.rate));
+                wait = wait.max(delay);
+            }
+        }
+
+        if !exceeded.is_empty() {
+            return Err(rate_limited(&exceeded.join(", "), wait));
+        }
+
+        if let Some(bucket) = entry.rows.as_mut() {
+            bucket.take(rows as f64);
+        }
+        if let Some(bucket) = entry.bytes.as_mut() {
+            bucket.take(bytes as f64);
+        }
+        Ok(())
+    }
+
+    /// Drops the buckets of clients that have been idle long enough for them
+    /// to refill completely.
+    ///
+    /// Called periodically by [`check`](Self::check).
+    pub fn evict_idle(&self) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.evict_idle(Instant::now());
+        }
+    }
+
+    /// Returns the number of clients with tracked buckets.
+    pub fn tracked_clients(&self) -> usize {
+        self.clients.lock().map(|clients| clients.buckets.len()).unwrap_or_default()
+    }
+
+    /// Builds fresh buckets for a client, honoring per-client overrides.
+    fn buckets_for(&self, client: &str) -> ClientBuckets {
+        let (rows, bytes) = match self.config.clients.get(client) {
+            Some(limits) => (limits.rows_per_sec, limits.bytes_per_sec),
+            None => (self.config.rows_per_sec, self.config.bytes_per_sec),
+        };
+        ClientBuckets {
+            rows: rows.map(|rate| TokenBucket::new(rate, self.config.burst_secs)),
+            bytes: bytes.map(|rate| TokenBucket::new(rate, self.config.burst_secs)),
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+/// Returns the key identifying the client that sent `request`.
+///
+/// The key is `token:<digest>` for requests carrying a bearer token, see
+/// [`token_key`], `peer:<ip>` for other requests with a known peer address,
+/// and `anonymous` otherwise.
+pub fn client_key<T>(request: &Request<T>) -> String {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (token, request.remote_addr()) {
+        (Some(token), _) => token_key(token),
+        (None, Some(addr)) => format!("peer:{}", addr.ip()),
+        (None, None) => "anonymous".to_string(),
+    }
+}
+
+/// Returns the client key of a bearer token, `token:` followed by the hex
+/// encoded SHA-256 digest of the token.
+///
+/// Per-client overrides use the same key, which is the output of
+/// `printf '%s' "$TOKEN" | sha256sum` prefixed with `token:`.
+pub fn token_key(token: &str) -> String {
+    format!("token:{}", hex::encode(Sha256::digest(token.as_bytes())))
+}
+
+/// Builds the `RESOURCE_EXHAUSTED` status returned to throttled clients.
+fn rate_limited(limits: &str, wait: Duration) -> Status {
+    let retry_ms = wait.as_millis().max(1);
+    let retry_secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+    let mut metadata = MetadataMap::new();
+    if let Ok(value) = retry_secs.max(1).to_string().parse() {
+        metadata.insert("retry-after", value);
+    }
+    if let Ok(value) = retry_ms.to_string().parse() {
+        metadata.insert("retry-after-ms", value);
+    }
+
+    Status::with_metadata(
+        Code::ResourceExhausted,
+        format!(
+            "Ingestion rate limit exceeded ({}); retry after {} ms",
+            limits, retry_ms
+        ),
+        metadata,
+    )
+}
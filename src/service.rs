@@ -11,7 +11,20 @@
 
 use crate::storage::{StorageBackendType, StorageBackend};
 use crate::models::{Model, ModelStorage};
+use crate::config::{IdempotencyConfig, RateLimitConfig};
+use crate::rate_limit::{client_key, RateLimiter};
+use crate::metrics::get_metrics_schema_adapter;
+use crate::storage::idempotency::{
+    ack_batch, ack_schema, expired_acks, producer_acks, IdempotencyCache, IdempotencyKey, KeyState, ACK_TABLE,
+};
+use crate::storage::quality::{
+    dead_letter_batch, dead_letter_schema, QualityGate, QualityRules, DEAD_LETTER_TABLE,
+};
+use std::collections::HashMap;
 use arrow_flight::{
+    decode::FlightRecordBatchStream,
+    encode::FlightDataEncoderBuilder,
+    error::FlightError,
     flight_service_server::FlightService,
     Action, ActionType, Criteria, FlightData, FlightDescriptor, FlightInfo,
     HandshakeRequest, HandshakeResponse, PutResult, SchemaResult, Ticket,
@@ -22,16 +35,27 @@ use futures::{Stream, StreamExt};
 use std::pin::Pin;
 use std::sync::Arc;
 use tonic::{Request, Response, Status, Streaming};
-use serde::Deserialize;
+use tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
 use arrow_ipc::writer::IpcWriteOptions;
 use arrow_ipc::writer::IpcDataGenerator;
-use arrow_schema::Schema;
+use arrow_schema::{Schema, SchemaRef};
 use crate::storage::table_manager::AggregationView;
+use crate::storage::rollup::RollupPolicy;
+use crate::storage::alerts::{self, Alert, AlertRule};
+use crate::storage::anomaly::AnomalyMonitor;
+use crate::aggregation::{AggregateExpr, GroupBy, ResultOptions, TimeWindow};
+use crate::aggregation::filter::Filter;
+use crate::aggregation::forecast::ForecastSpec;
+use crate::aggregation::matrix::RangeQuery;
+use crate::aggregation::series::SeriesAlignment;
+use crate::aggregation::operator::AggregateOperator;
+use crate::aggregation::partial::PartialCombiner;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use arrow_array::{
-    ArrayRef, Float32Array,
+    ArrayRef, Float32Array, RecordBatch,
     builder::Float32Builder,
 };
 use serde_json;
@@ -92,6 +116,19 @@ enum TableCommand {
     CreateAggregationView(AggregationView),
     DropTable(String),
     DropAggregationView(String),
+    RefreshAggregationView(String),
+    SetRollupPolicy {
+        table: String,
+        policy: RollupPolicy,
+    },
+    RunRollups(String),
+    CreateAlertRule(AlertRule),
+    DropAlertRule(String),
+    EvaluateAlertRule(String),
+    ListAlerts,
+    CreateAnomalyMonitor(AnomalyMonitor),
+    DropAnomalyMonitor(String),
+    RunAnomalyMonitor(String),
 }
 
 impl TableCommand {
@@ -130,6 +167,54 @@ impl TableCommand {
                     .ok_or_else(|| Status::invalid_argument("Missing view name"))?;
                 Ok(TableCommand::DropAggregationView(name.to_string()))
             }
+            Some("refresh_aggregation_view") => {
+                let name = value["data"]["name"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing view name"))?;
+                Ok(TableCommand::RefreshAggregationView(name.to_string()))
+            }
+            Some("set_rollup_policy") => {
+                let table = value["data"]["table"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing table name"))?;
+                let policy: RollupPolicy = serde_json::from_value(value["data"]["policy"].clone())
+                    .map_err(|e| Status::invalid_argument(format!("Invalid rollup policy: {}", e)))?;
+                Ok(TableCommand::SetRollupPolicy { table: table.to_string(), policy })
+            }
+            Some("run_rollups") => {
+                let table = value["data"]["table"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing table name"))?;
+                Ok(TableCommand::RunRollups(table.to_string()))
+            }
+            Some("create_alert_rule") => {
+                let rule: AlertRule = serde_json::from_value(value["data"].clone())
+                    .map_err(|e| Status::invalid_argument(format!("Invalid alert rule: {}", e)))?;
+                Ok(TableCommand::CreateAlertRule(rule))
+            }
+            Some("drop_alert_rule") => {
+                let name = value["data"]["name"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing alert rule name"))?;
+                Ok(TableCommand::DropAlertRule(name.to_string()))
+            }
+            Some("evaluate_alert_rule") => {
+                let name = value["data"]["name"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing alert rule name"))?;
+                Ok(TableCommand::EvaluateAlertRule(name.to_string()))
+            }
+            Some("list_alerts") => Ok(TableCommand::ListAlerts),
+            Some("create_anomaly_monitor") => {
+                let monitor: AnomalyMonitor = serde_json::from_value(value["data"].clone())
+                    .map_err(|e| Status::invalid_argument(format!("Invalid anomaly monitor: {}", e)))?;
+                Ok(TableCommand::CreateAnomalyMonitor(monitor))
+            }
+            Some("drop_anomaly_monitor") => {
+                let name = value["data"]["name"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing anomaly monitor name"))?;
+                Ok(TableCommand::DropAnomalyMonitor(name.to_string()))
+            }
+            Some("run_anomaly_monitor") => {
+                let name = value["data"]["name"].as_str()
+                    .ok_or_else(|| Status::invalid_argument("Missing anomaly monitor name"))?;
+                Ok(TableCommand::RunAnomalyMonitor(name.to_string()))
+            }
             _ => Err(Status::invalid_argument("Invalid command type")),
         }
     }
@@ -162,6 +247,180 @@ impl ModelCommand {
     }
 }
 
+/// Queries answered through DoGet, sent as JSON tickets.
+///
+/// The result is streamed as a single record batch, except for alert
+/// subscriptions, which stream a batch per alert until the client
+/// disconnects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum QueryTicket {
+    /// See [`StorageBackend::aggregate_metrics`]
+    AggregateMetrics(AggregateMetricsQuery),
+    /// See [`StorageBackend::forecast_metrics`]
+    ForecastMetrics(ForecastMetricsQuery),
+    /// See [`StorageBackend::align_metrics`]
+    AlignMetrics(AlignMetricsQuery),
+    /// See [`StorageBackend::range_metrics`]
+    RangeMetrics(RangeQuery),
+    /// Streams the firing and resolved alerts of the rules notifying
+    /// subscriptions, see [`alerts`]
+    SubscribeAlerts(AlertSubscription),
+}
+
+/// Alerts streamed to a DoGet subscription, with [`Alert::schema`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AlertSubscription {
+    /// Names of the rules to stream alerts of; all if empty
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+/// Parameters of [`StorageBackend::aggregate_metrics`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateMetricsQuery {
+    pub aggregates: Vec<AggregateExpr>,
+    pub group_by: GroupBy,
+    pub window: TimeWindow,
+    pub from_timestamp: i64,
+    /// Exclusive end of the time range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub options: ResultOptions,
+}
+
+/// Parameters of [`StorageBackend::forecast_metrics`]: the aggregation of
+/// the history, with the forecast in `forecast`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForecastMetricsQuery {
+    #[serde(flatten)]
+    pub query: AggregateMetricsQuery,
+    pub forecast: ForecastSpec,
+}
+
+/// Parameters of [`StorageBackend::align_metrics`]: the operands and their
+/// combination, with the windows and range both operands are aggregated in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlignMetricsQuery {
+    #[serde(flatten)]
+    pub alignment: SeriesAlignment,
+    pub window: TimeWindow,
+    pub from_timestamp: i64,
+    /// Exclusive end of the time range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_timestamp: Option<i64>,
+}
+
+/// Aggregation of the record batches sent through DoExchange, sent as the
+/// JSON command of the flight descriptor of the first message.
+///
+/// The batches are aggregated in process, see [`AggregateOperator`], and the
+/// result is streamed back as a single record batch. Quantiles and distinct
+/// counts are estimated from sketches, so the state of a stream is bounded
+/// by its groups and windows, see [`AggregateOperator::with_sketches`]. With `partial` the
+/// result is partial state instead, and with `combine` the batches are
+/// partial state of other nodes to be merged, see
+/// [`partial`](crate::aggregation::partial).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeAggregation {
+    pub aggregates: Vec<AggregateExpr>,
+    pub group_by: GroupBy,
+    pub window: TimeWindow,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_timestamp: Option<i64>,
+    /// Exclusive end of the time range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    /// Return partial state instead of results
+    #[serde(default)]
+    pub partial: bool,
+    /// Merge batches of partial state instead of aggregating rows
+    #[serde(default)]
+    pub combine: bool,
+}
+
+impl ExchangeAggregation {
+    /// Creates the operator aggregating batches with `schema`.
+    fn operator(&self, schema: SchemaRef) -> Result<AggregateOperator, Status> {
+        let operator = AggregateOperator::try_new(schema, &self.aggregates, &self.group_by, self.window)?
+            .with_range(self.from_timestamp, self.to_timestamp)
+            .with_sketches();
+        match &self.filter {
+            Some(filter) => operator.with_filter(filter.clone()),
+            None => Ok(operator),
+        }
+    }
+}
+
+/// Acknowledgement returned for every record batch ingested through DoPut.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestAck {
+    /// Table the batch was written to
+    pub table: String,
+    /// Number of rows written
+    pub rows: usize,
+    /// Number of rows rejected by quality gates and sent to the dead-letter table
+    #[serde(default)]
+    pub rejected: usize,
+    /// Producer that sent the batch, for idempotent ingestion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_id: Option<String>,
+    /// Sequence number of the batch, for idempotent ingestion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+/// Per-request ingestion context derived from DoPut metadata.
+#[derive(Clone)]
+struct IngestContext {
+    /// Rate limiting key of the client
+    client: String,
+    /// Peer address recorded with dead-lettered rows
+    source: String,
+    /// Producer ID and sequence number of the first batch, if the client
+    /// requested idempotent ingestion
+    producer: Option<(String, u64)>,
+}
+
+impl IngestContext {
+    /// Reads the client identity and the optional `x-producer-id` and
+    /// `x-sequence` headers of a request.
+    fn from_request<T>(request: &Request<T>) -> Result<Self, Status> {
+        let header = |name: &str| -> Result<Option<String>, Status> {
+            request.metadata().get(name)
+                .map(|value| value.to_str()
+                    .map(str::to_string)
+                    .map_err(|_| Status::invalid_argument(format!("Invalid {} header", name))))
+                .transpose()
+        };
+
+        let producer = match (header("x-producer-id")?, header("x-sequence")?) {
+            (Some(producer_id), Some(sequence)) => {
+                let sequence = sequence.parse::<u64>()
+                    .map_err(|_| Status::invalid_argument(format!("Invalid x-sequence header '{}'", sequence)))?;
+                Some((producer_id, sequence))
+            }
+            (None, None) => None,
+            _ => return Err(Status::invalid_argument(
+                "x-producer-id and x-sequence must be provided together",
+            )),
+        };
+
+        Ok(Self {
+            client: client_key(request),
+            source: request.remote_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            producer,
+        })
+    }
+}
+
 /// Tracks progress of model data transfers
 #[derive(Debug)]
 pub struct TransferProgress {
@@ -240,6 +499,9 @@ pub struct FlightSqlService {
     model_storage: Arc<Box<dyn ModelStorage>>,
     statement_counter: Arc<AtomicU64>,
     prepared_statements: Arc<Mutex<Vec<String>>>,
+    rate_limiter: Arc<RateLimiter>,
+    quality_gates: Arc<HashMap<String, QualityGate>>,
+    idempotency: Arc<IdempotencyCache>,
 }
 
 impl FlightSqlService {
@@ -249,9 +511,44 @@ impl FlightSqlService {
             model_storage: Arc::new(model_storage),
             statement_counter: Arc::new(AtomicU64::new(0)),
             prepared_statements: Arc::new(Mutex::new(Vec::new())),
+            rate_limiter: Arc::new(RateLimiter::disabled()),
+            quality_gates: Arc::new(HashMap::new()),
+            idempotency: Arc::new(IdempotencyCache::default()),
         }
     }
 
+    /// Enforces per-client ingestion rate limits on DoPut.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a rate is zero or the burst is
+    /// not positive.
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Result<Self, Status> {
+        self.rate_limiter = Arc::new(RateLimiter::new(config)?);
+        Ok(self)
+    }
+
+    /// Validates ingested rows against per-table data quality rules.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if any of the rules is invalid.
+    pub fn with_quality_rules(mut self, rules: &HashMap<String, QualityRules>) -> Result<Self, Status> {
+        let gates = rules
+            .iter()
+            .map(|(table, rules)| Ok((table.clone(), QualityGate::new(rules.clone())?)))
+            .collect::<Result<HashMap<_, _>, Status>>()?;
+        self.quality_gates = Arc::new(gates);
+        Ok(self)
+    }
+
+    /// Configures how many batches per producer are remembered for
+    /// idempotent ingestion.
+    pub fn with_idempotency(mut self, config: IdempotencyConfig) -> Self {
+        self.idempotency = Arc::new(IdempotencyCache::new(config));
+        self
+    }
+
     async fn authenticate<T>(&self, request: &Request<T>) -> Result<AuthToken, Status> {
         let token = request.metadata().get("authorization")
             .ok_or_else(|| Status::unauthenticated("Missing authorization token"))?
@@ -382,6 +679,49 @@ impl FlightSqlService {
                 self.backend.drop_aggregation_view(&name).await?;
                 Ok(vec![])
             }
+            TableCommand::RefreshAggregationView(name) => {
+                self.backend.refresh_aggregation_view(&name).await?;
+                Ok(vec![])
+            }
+            TableCommand::SetRollupPolicy { table, policy } => {
+                self.backend.set_rollup_policy(&table, policy).await?;
+                Ok(vec![])
+            }
+            TableCommand::RunRollups(table) => {
+                self.backend.run_rollups(&table).await?;
+                Ok(vec![])
+            }
+            TableCommand::CreateAlertRule(rule) => {
+                self.backend.create_alert_rule(rule).await?;
+                Ok(vec![])
+            }
+            TableCommand::DropAlertRule(name) => {
+                self.backend.drop_alert_rule(&name).await?;
+                Ok(vec![])
+            }
+            TableCommand::EvaluateAlertRule(name) => {
+                let alerts = self.backend.evaluate_alert_rule(&name).await?;
+                serde_json::to_vec(&alerts)
+                    .map_err(|e| Status::internal(format!("Failed to serialize alerts: {}", e)))
+            }
+            TableCommand::ListAlerts => {
+                let alerts = alerts::active_alerts(self.backend.as_ref()).await;
+                serde_json::to_vec(&alerts)
+                    .map_err(|e| Status::internal(format!("Failed to serialize alerts: {}", e)))
+            }
+            TableCommand::CreateAnomalyMonitor(monitor) => {
+                self.backend.create_anomaly_monitor(monitor).await?;
+                Ok(vec![])
+            }
+            TableCommand::DropAnomalyMonitor(name) => {
+                self.backend.drop_anomaly_monitor(&name).await?;
+                Ok(vec![])
+            }
+            TableCommand::RunAnomalyMonitor(name) => {
+                let scores = self.backend.run_anomaly_monitor(&name).await?;
+                serde_json::to_vec(&scores)
+                    .map_err(|e| Status::internal(format!("Failed to serialize anomaly scores: {}", e)))
+            }
         }
     }
 
@@ -424,6 +764,71 @@ impl FlightSqlService {
         }
     }
 
+    /// Runs a DoGet query and streams its result.
+    async fn stream_query(
+        &self,
+        query: QueryTicket,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let batch = match query {
+            QueryTicket::AggregateMetrics(query) => {
+                self.backend.aggregate_metrics(
+                    &query.aggregates,
+                    &query.group_by,
+                    query.window,
+                    query.from_timestamp,
+                    query.to_timestamp,
+                    query.filter.as_ref(),
+                    &query.options,
+                ).await?
+            }
+            QueryTicket::ForecastMetrics(ForecastMetricsQuery { query, forecast }) => {
+                self.backend.forecast_metrics(
+                    &query.aggregates,
+                    &query.group_by,
+                    query.window,
+                    query.from_timestamp,
+                    query.to_timestamp,
+                    query.filter.as_ref(),
+                    &query.options,
+                    &forecast,
+                ).await?
+            }
+            QueryTicket::AlignMetrics(query) => {
+                self.backend.align_metrics(&query.alignment, query.window, query.from_timestamp, query.to_timestamp).await?
+            }
+            QueryTicket::RangeMetrics(query) => self.backend.range_metrics(&query).await?,
+            QueryTicket::SubscribeAlerts(subscription) => return Ok(self.subscribe_alerts(subscription)),
+        };
+        let flight_data = arrow_flight::utils::batches_to_flight_data(&batch.schema(), vec![batch])
+            .map_err(|e| e.to_status())?;
+        Ok(Response::new(Box::pin(futures::stream::iter(flight_data.into_iter().map(Ok)))))
+    }
+
+    /// Streams alerts as they are notified, starting with the schema so
+    /// clients can read it before the first alert.
+    fn subscribe_alerts(&self, subscription: AlertSubscription) -> Response<<Self as FlightService>::DoGetStream> {
+        let mut receiver = self.backend.table_manager().subscribe_alerts();
+        let batches = async_stream::stream! {
+            loop {
+                match receiver.recv().await {
+                    Ok(alert) if subscription.rules.is_empty() || subscription.rules.contains(&alert.rule) => {
+                        yield Alert::to_batch(&[alert]).map_err(FlightError::from);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Alert subscriber fell behind and missed {} alerts", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        };
+        let flight_data = FlightDataEncoderBuilder::new()
+            .with_schema(Arc::new(Alert::schema()))
+            .build(batches)
+            .map(|data| data.map_err(Status::from));
+        Response::new(Box::pin(flight_data))
+    }
+
     // Optimize large model transfers
     async fn stream_model_weights(
         &self,
@@ -490,6 +895,7 @@ impl FlightSqlService {
     async fn upload_model_weights(
         &self,
         mut stream: Streaming<FlightData>,
+        client: String,
         model_id: String,
         version: Option<String>,
         progress: Arc<TransferProgress>
@@ -502,6 +908,7 @@ impl FlightSqlService {
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
             let data = chunk.data_header;
+            self.rate_limiter.check(&client, 0, data.len() as u64)?;
             
             // Zero-copy slice to f32 array
             let float_data = unsafe {
@@ -544,6 +951,201 @@ impl FlightSqlService {
 
         Ok(Response::new(Box::pin(stream)))
     }
+
+    /// Ingests record batches sent through DoPut.
+    ///
+    /// The target table is the first path element of the flight descriptor.
+    /// Batches for `metrics` are decoded into metric records, all other
+    /// tables are written through `insert_into_table`. Either way each batch
+    /// is adapted to the target schema before it is written.
+    ///
+    /// Each batch is charged against the client's row and byte quotas before
+    /// it is written; a throttled batch fails the stream with
+    /// `RESOURCE_EXHAUSTED` and nothing after it is ingested.
+    ///
+    /// If the request carries `x-producer-id` and `x-sequence` headers, the
+    /// n-th batch of the stream is identified by sequence number
+    /// `x-sequence + n` and applied at most once.
+    async fn ingest_batches(
+        &self,
+        mut stream: Streaming<FlightData>,
+        context: IngestContext,
+    ) -> Result<Response<<Self as FlightService>::DoPutStream>, Status> {
+        let first = stream.message().await?
+            .ok_or_else(|| Status::invalid_argument("Empty DoPut stream"))?;
+
+        let table = first.flight_descriptor.as_ref()
+            .and_then(|descriptor| descriptor.path.first().cloned())
+            .ok_or_else(|| Status::invalid_argument("Missing target table in flight descriptor"))?;
+
+        // Quotas are charged for the bytes received on the wire
+        let received = Arc::new(AtomicU64::new(0));
+        let counter = received.clone();
+        let flight_data = futures::stream::once(async move { Ok(first) })
+            .chain(stream)
+            .map(move |data| {
+                if let Ok(data) = &data {
+                    let size = data.data_header.len() + data.data_body.len() + data.app_metadata.len();
+                    counter.fetch_add(size as u64, Ordering::Relaxed);
+                }
+                data.map_err(FlightError::from)
+            });
+        let batches = FlightRecordBatchStream::new_from_flight_data(flight_data);
+
+        // Acks are sent as batches are applied, so a client whose stream
+        // fails still learns which of its batches were stored
+        let service = self.clone();
+        let acks = futures::stream::try_unfold((batches, 0u64), move |(mut batches, index)| {
+            let (service, table, context, received) = (service.clone(), table.clone(), context.clone(), received.clone());
+            async move {
+                let batch = match batches.next().await {
+                    Some(batch) => batch.map_err(Status::from)?,
+                    None => return Ok(None),
+                };
+                let rows = batch.num_rows();
+                let bytes = received.swap(0, Ordering::Relaxed);
+                service.rate_limiter.check(&context.client, rows as u64, bytes)?;
+                let ack = match &context.producer {
+                    Some((producer_id, first_sequence)) => {
+                        let key = IdempotencyKey {
+                            producer_id: producer_id.clone(),
+                            sequence: first_sequence.checked_add(index)
+                                .ok_or_else(|| Status::invalid_argument("Sequence number overflow"))?,
+                        };
+                        service.ingest_batch_once(&table, batch, &context.source, &key).await?
+                    }
+                    None => service.ingest_batch(&table, batch, &context.source).await?,
+                };
+
+                let app_metadata = serde_json::to_vec(&ack)
+                    .map_err(|e| Status::internal(format!("Failed to serialize ack: {}", e)))?;
+                Ok(Some((PutResult { app_metadata: Bytes::from(app_metadata) }, (batches, index + 1))))
+            }
+        });
+
+        Ok(Response::new(Box::pin(acks)))
+    }
+
+    /// Writes a single ingested batch to its target table.
+    ///
+    /// If the table has data quality rules, rows failing them are written to
+    /// the dead-letter table, tagged with `source`, and the remaining rows
+    /// are ingested.
+    pub async fn ingest_batch(
+        &self,
+        table: &str,
+        batch: RecordBatch,
+        source: &str,
+    ) -> Result<IngestAck, Status> {
+        let (writes, ack) = self.prepare_ingest(table, batch, source).await?;
+        self.backend.insert_into_tables(writes).await?;
+        Ok(ack)
+    }
+
+    /// Returns the writes of an ingested batch, the accepted rows for its
+    /// target table and rejected rows for the dead-letter table, together
+    /// with its ack.
+    async fn prepare_ingest(
+        &self,
+        table: &str,
+        batch: RecordBatch,
+        source: &str,
+    ) -> Result<(Vec<(String, RecordBatch)>, IngestAck), Status> {
+        let mut writes = Vec::new();
+        let (batch, rejected) = match self.quality_gates.get(table) {
+            Some(gate) => {
+                let batch = if table == "metrics" {
+                    get_metrics_schema_adapter().adapt(&batch)?
+                } else {
+                    self.backend.table_manager().adapt_batch(table, &batch).await?
+                };
+                let report = gate.evaluate(&batch)?;
+                if !report.rejected.is_empty() {
+                    self.ensure_table(DEAD_LETTER_TABLE, &dead_letter_schema()).await?;
+                    let dead_letters = dead_letter_batch(table, source, &batch, &report.rejected)?;
+                    writes.push((DEAD_LETTER_TABLE.to_string(), dead_letters));
+                }
+                (report.accepted, report.rejected.len())
+            }
+            None => (batch, 0),
+        };
+
+        let rows = batch.num_rows();
+        if rows > 0 {
+            writes.push((table.to_string(), batch));
+        }
+
+        let ack = IngestAck {
+            table: table.to_string(),
+            rows,
+            rejected,
+            producer_id: None,
+            sequence: None,
+        };
+        Ok((writes, ack))
+    }
+
+    /// Writes a batch at most once per idempotency key.
+    ///
+    /// The first time a key is seen the batch is ingested and its ack is
+    /// persisted in the ack table, in the same transaction. Retries of the
+    /// same key return the original ack without touching the data.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::already_exists` if the sequence number is older than
+    /// the remembered window of the producer.
+    pub async fn ingest_batch_once(
+        &self,
+        table: &str,
+        batch: RecordBatch,
+        source: &str,
+        key: &IdempotencyKey,
+    ) -> Result<IngestAck, Status> {
+        let slot = self.idempotency.producer(&key.producer_id)?;
+        let mut slot = slot.lock().await;
+        if slot.is_none() {
+            self.ensure_table(ACK_TABLE, &ack_schema()).await?;
+            let filter = producer_acks(&key.producer_id);
+            let persisted = self.backend.query_table(ACK_TABLE, None, Some(&filter)).await?;
+            *slot = Some(self.idempotency.load_producer(&key.producer_id, &persisted)?);
+        }
+        let state = slot.as_mut()
+            .ok_or_else(|| Status::internal("Producer state not loaded"))?;
+
+        if let KeyState::Applied(ack) = state.check(&key.producer_id, key.sequence)? {
+            return serde_json::from_str(&ack)
+                .map_err(|e| Status::internal(format!("Failed to decode stored ack: {}", e)));
+        }
+
+        let (mut writes, mut ack) = self.prepare_ingest(table, batch, source).await?;
+        ack.producer_id = Some(key.producer_id.clone());
+        ack.sequence = Some(key.sequence);
+        let payload = serde_json::to_string(&ack)
+            .map_err(|e| Status::internal(format!("Failed to serialize ack: {}", e)))?;
+        writes.push((ACK_TABLE.to_string(), ack_batch(key, &payload)?));
+
+        self.backend.insert_into_tables(writes).await?;
+        state.record(key.sequence, payload);
+        if let Some(oldest) = state.take_expired() {
+            // The ack is stored, so a failed cleanup only delays it
+            if let Err(e) = self.backend.delete_from_table(ACK_TABLE, &expired_acks(&key.producer_id, oldest)).await {
+                tracing::warn!("Failed to delete expired acks of producer {}: {}", key.producer_id, e);
+            }
+        }
+        Ok(ack)
+    }
+
+    /// Creates a service-managed table if it is not registered yet.
+    async fn ensure_table(&self, name: &str, schema: &Schema) -> Result<(), Status> {
+        if self.backend.table_manager().get_table_schema(name).await.is_ok() {
+            return Ok(());
+        }
+        match self.backend.create_table(name, schema).await {
+            Err(status) if status.code() != tonic::Code::AlreadyExists => Err(status),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -592,8 +1194,15 @@ impl FlightService for FlightSqlService {
         &self,
         request: Request<Ticket>,
     ) -> Result<Response<Self::DoGetStream>, Status> {
-        let cmd = serde_json::from_slice::<ModelCommand>(&request.get_ref().ticket)
-            .map_err(|e| Status::invalid_argument(format!("Invalid ticket: {}", e)))?;
+        let ticket = &request.get_ref().ticket;
+        let cmd = match serde_json::from_slice::<ModelCommand>(ticket) {
+            Ok(cmd) => cmd,
+            Err(_) => {
+                let query = serde_json::from_slice::<QueryTicket>(ticket)
+                    .map_err(|e| Status::invalid_argument(format!("Invalid ticket: {}", e)))?;
+                return self.stream_query(query).await;
+            }
+        };
 
         match cmd {
             ModelCommand::LoadModel { model_id, version } => {
@@ -687,11 +1296,16 @@ impl FlightService for FlightSqlService {
         &self,
         request: Request<Streaming<FlightData>>,
     ) -> Result<Response<<Self as FlightService>::DoPutStream>, Status> {
-        // Fix metadata access and error handling
-        let cmd_bytes = request.metadata()
-            .get("x-command")
-            .ok_or_else(|| Status::invalid_argument("Missing x-command metadata"))?
-            .as_bytes();
+        // Model uploads are identified by the x-command metadata, everything
+        // else is record batch ingestion into the table named by the descriptor.
+        let client = client_key(&request);
+        let cmd_bytes = match request.metadata().get("x-command") {
+            Some(cmd) => cmd.as_bytes(),
+            None => {
+                let context = IngestContext::from_request(&request)?;
+                return self.ingest_batches(request.into_inner(), context).await;
+            }
+        };
 
         let cmd = serde_json::from_slice::<ModelCommand>(cmd_bytes)
             .map_err(|e| Status::invalid_argument(format!("Invalid command: {}", e)))?;
@@ -701,6 +1315,7 @@ impl FlightService for FlightSqlService {
                 let progress = Arc::new(TransferProgress::new(model.estimated_size()));
                 self.upload_model_weights(
                     request.into_inner(),
+                    client,
                     model.id.clone(),
                     Some(model.version.clone()),
                     progress
@@ -731,6 +1346,46 @@ impl FlightService for FlightSqlService {
                 r#type: "DropAggregationView".to_string(),
                 description: "Drop an existing aggregation view".to_string(),
             },
+            ActionType {
+                r#type: "RefreshAggregationView".to_string(),
+                description: "Rebuild a materialized aggregation view from its source table".to_string(),
+            },
+            ActionType {
+                r#type: "SetRollupPolicy".to_string(),
+                description: "Configure the rollup tiers and retention of a table".to_string(),
+            },
+            ActionType {
+                r#type: "RunRollups".to_string(),
+                description: "Roll up complete windows of a table and expire old data".to_string(),
+            },
+            ActionType {
+                r#type: "CreateAlertRule".to_string(),
+                description: "Register an alert rule on windowed aggregates".to_string(),
+            },
+            ActionType {
+                r#type: "DropAlertRule".to_string(),
+                description: "Drop an alert rule and its alerts".to_string(),
+            },
+            ActionType {
+                r#type: "EvaluateAlertRule".to_string(),
+                description: "Evaluate an alert rule now and return the alerts that changed state".to_string(),
+            },
+            ActionType {
+                r#type: "ListAlerts".to_string(),
+                description: "Return the pending and firing alerts".to_string(),
+            },
+            ActionType {
+                r#type: "CreateAnomalyMonitor".to_string(),
+                description: "Score windows of metric series with an anomaly detector".to_string(),
+            },
+            ActionType {
+                r#type: "DropAnomalyMonitor".to_string(),
+                description: "Drop an anomaly monitor, keeping its derived series".to_string(),
+            },
+            ActionType {
+                r#type: "RunAnomalyMonitor".to_string(),
+                description: "Score the closed windows of an anomaly monitor and return the scores".to_string(),
+            },
         ];
         
         let stream = futures::stream::iter(actions.into_iter().map(Ok));
@@ -739,9 +1394,51 @@ impl FlightService for FlightSqlService {
 
     async fn do_exchange(
         &self,
-        _request: Request<Streaming<FlightData>>,
+        request: Request<Streaming<FlightData>>,
     ) -> Result<Response<Self::DoExchangeStream>, Status> {
-        Err(Status::unimplemented("do_exchange not implemented"))
+        let mut stream = request.into_inner();
+        let first = stream.message().await?
+            .ok_or_else(|| Status::invalid_argument("Empty DoExchange stream"))?;
+        let cmd = first.flight_descriptor.as_ref()
+            .map(|descriptor| descriptor.cmd.clone())
+            .ok_or_else(|| Status::invalid_argument("Missing aggregation in flight descriptor"))?;
+        let aggregation: ExchangeAggregation = serde_json::from_slice(&cmd)
+            .map_err(|e| Status::invalid_argument(format!("Invalid aggregation: {}", e)))?;
+
+        let flight_data = futures::stream::once(async move { Ok(first) })
+            .chain(stream)
+            .map(|data| data.map_err(FlightError::from));
+        let mut batches = FlightRecordBatchStream::new_from_flight_data(flight_data);
+        let batch = if aggregation.combine {
+            let mut combiner =
+                PartialCombiner::try_new(&aggregation.aggregates, &aggregation.group_by, aggregation.window)?;
+            while let Some(batch) = batches.next().await {
+                combiner.push(&batch.map_err(Status::from)?)?;
+            }
+            match aggregation.partial {
+                true => combiner.finish_partial()?,
+                false => combiner.finish()?,
+            }
+        } else {
+            let mut operator = None;
+            while let Some(batch) = batches.next().await {
+                let batch = batch.map_err(Status::from)?;
+                let operator = match &mut operator {
+                    Some(operator) => operator,
+                    None => operator.insert(aggregation.operator(batch.schema())?),
+                };
+                operator.push(&batch)?;
+            }
+            let operator = operator
+                .ok_or_else(|| Status::invalid_argument("DoExchange stream has no record batches"))?;
+            match aggregation.partial {
+                true => operator.finish_partial()?,
+                false => operator.finish()?,
+            }
+        };
+        let flight_data = arrow_flight::utils::batches_to_flight_data(&batch.schema(), vec![batch])
+            .map_err(|e| e.to_status())?;
+        Ok(Response::new(Box::pin(futures::stream::iter(flight_data.into_iter().map(Ok)))))
     }
 
     async fn do_action(
@@ -4,6 +4,7 @@
 //! - Connection to any ADBC-compliant database
 //! - High-performance data transport using Arrow's columnar format
 //! - Connection pooling and prepared statements
+//! - Native bulk ingestion of Arrow record batches (no SQL value rendering)
 //! - Support for various database systems (PostgreSQL, MySQL, etc.)
 //!
 //! # Configuration
@@ -41,17 +42,18 @@
 //!
 //! The implementation is optimized for efficient data transfer and
 //! query execution using Arrow's native formats.
+//!
+//! Aggregation queries and non-materialized aggregation views are rendered
+//! in the Postgres dialect by the query builder, see
+//! [`build_windowed_aggregate_query`].
 
 use adbc_core::{
     driver_manager::{ManagedConnection, ManagedDriver},
-    options::{AdbcVersion, OptionDatabase, OptionValue},
+    options::{AdbcVersion, IngestMode, OptionDatabase, OptionStatement, OptionValue},
     Connection, Database, Driver, Statement, Optionable,
 };
-use arrow_array::{
-    Array, Int8Array, Int16Array, Int32Array, Int64Array,
-    Float32Array, Float64Array, BooleanArray, StringArray,
-    BinaryArray, TimestampNanosecondArray,
-};
+use arrow::compute::concat_batches;
+use arrow_array::{Array, Int64Array, Float64Array, StringArray, StructArray};
 use arrow_schema::{Schema, DataType, Field};
 use async_trait::async_trait;
 use std::collections::HashMap;
@@ -59,18 +61,26 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::Mutex;
 use tonic::Status;
-use crate::aggregation::{AggregateFunction, GroupBy, AggregateResult, build_aggregate_query};
+use crate::aggregation::{AggregateExpr, GroupBy, ResultOptions, aggregate_output_schema, build_windowed_aggregate_query};
+use crate::aggregation::filter::{Filter, SqlDialect};
 use crate::storage::table_manager::{TableManager, AggregationView};
 use crate::config::Credentials;
-use crate::metrics::MetricRecord;
-use crate::storage::StorageBackend;
+use crate::metrics::{get_metrics_schema, get_metrics_schema_adapter, MetricRecord};
+use crate::storage::{aggregate_windows, aggregation_result, counted_aggregates, upsert_state, INGEST_WINDOW, materialized, on_ingest, StorageBackend};
 use crate::storage::cache::{CacheManager, CacheEviction};
 use arrow_array::ArrayRef;
 use arrow_array::RecordBatch;
 use crate::aggregation::TimeWindow;
 use crate::storage::BatchAggregation;
-use std::time::Duration;
-use hex;
+
+/// Record batch type used by the ADBC driver manager.
+///
+/// `adbc_core` is built against a different arrow release than this crate, so
+/// batches are moved across the boundary through the Arrow C Data Interface.
+type AdbcRecordBatch = duckdb::arrow::array::RecordBatch;
+
+/// Name of the temporary table aggregation updates are staged in before merging.
+const AGGREGATION_STAGING_TABLE: &str = "metric_aggregations_staging";
 
 #[derive(Clone)]
 pub struct AdbcBackend {
@@ -105,7 +115,7 @@ impl AdbcBackend {
         let mut driver = ManagedDriver::load_dynamic_from_filename(
             driver_path,
             None,
-            AdbcVersion::V100,
+            AdbcVersion::V110,
         ).map_err(|e| Status::internal(format!("Failed to load ADBC driver: {}", e)))?;
 
         let mut database = driver.new_database()
@@ -163,86 +173,59 @@ impl AdbcBackend {
             .map_err(|e| Status::internal(format!("Failed to set query: {}", e)))?;
 
         if let Some(batch) = params {
-            // Create a new statement for binding parameters
-            let mut bind_stmt = conn.new_statement()
-                .map_err(|e| Status::internal(format!("Failed to create bind statement: {}", e)))?;
-
-            // Set the parameters using SQL directly
-            let mut param_values = Vec::new();
-            for i in 0..batch.num_rows() {
-                for j in 0..batch.num_columns() {
-                    let col = batch.column(j);
-                    match col.data_type() {
-                        DataType::Int64 => {
-                            let array = col.as_any().downcast_ref::<Int64Array>().unwrap();
-                            param_values.push(array.value(i).to_string());
-                        }
-                        DataType::Float64 => {
-                            let array = col.as_any().downcast_ref::<Float64Array>().unwrap();
-                            param_values.push(array.value(i).to_string());
-                        }
-                        DataType::Utf8 => {
-                            let array = col.as_any().downcast_ref::<StringArray>().unwrap();
-                            param_values.push(format!("'{}'", array.value(i)));
-                        }
-                        _ => return Err(Status::internal("Unsupported parameter type")),
-                    }
-                }
-            }
-
-            let params_sql = format!("VALUES ({})", param_values.join(", "));
-            bind_stmt.set_sql_query(&params_sql)
-                .map_err(|e| Status::internal(format!("Failed to set parameters: {}", e)))?;
-
-            let mut bind_result = bind_stmt.execute()
-                .map_err(|e| Status::internal(format!("Failed to execute parameter binding: {}", e)))?;
-
-            while let Some(batch_result) = bind_result.next() {
-                let _ = batch_result.map_err(|e| Status::internal(format!("Failed to bind parameters: {}", e)))?;
-            }
+            stmt.bind(to_adbc_batch(&batch)?)
+                .map_err(|e| Status::internal(format!("Failed to bind parameters: {}", e)))?;
         }
 
-        let mut reader = stmt.execute()
+        let reader = stmt.execute()
             .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
 
         let mut metrics = Vec::new();
-        while let Some(batch_result) = reader.next() {
+        for batch_result in reader {
             let batch = batch_result.map_err(|e| Status::internal(format!("Failed to get next batch: {}", e)))?;
-            
-            let metric_ids = batch.column_by_name("metric_id")
-                .and_then(|col| col.as_any().downcast_ref::<StringArray>())
-                .ok_or_else(|| Status::internal("Invalid metric_id column"))?;
-
-            let timestamps = batch.column_by_name("timestamp")
-                .and_then(|col| col.as_any().downcast_ref::<Int64Array>())
-                .ok_or_else(|| Status::internal("Invalid timestamp column"))?;
-
-            let sums = batch.column_by_name("value_running_window_sum")
-                .and_then(|col| col.as_any().downcast_ref::<Float64Array>())
-                .ok_or_else(|| Status::internal("Invalid value_running_window_sum column"))?;
-
-            let avgs = batch.column_by_name("value_running_window_avg")
-                .and_then(|col| col.as_any().downcast_ref::<Float64Array>())
-                .ok_or_else(|| Status::internal("Invalid value_running_window_avg column"))?;
-
-            let counts = batch.column_by_name("value_running_window_count")
-                .and_then(|col| col.as_any().downcast_ref::<Int64Array>())
-                .ok_or_else(|| Status::internal("Invalid value_running_window_count column"))?;
-
-            for i in 0..batch.num_rows() {
-                metrics.push(MetricRecord {
-                    metric_id: metric_ids.value(i).to_string(),
-                    timestamp: timestamps.value(i),
-                    value_running_window_sum: sums.value(i),
-                    value_running_window_avg: avgs.value(i),
-                    value_running_window_count: counts.value(i),
-                });
-            }
+            metrics.extend(MetricRecord::try_from_record_batch(&from_adbc_batch(batch)?)?);
         }
 
         Ok(metrics)
     }
 
+    /// Bulk-ingests a record batch into `table_name`.
+    ///
+    /// The batch is bound to the statement as a whole and written using the
+    /// driver's native ingestion path, so values keep their Arrow types and
+    /// are never rendered into SQL text. Modes other than append and
+    /// temporary tables need an ADBC 1.1 driver.
+    async fn bulk_ingest(
+        &self,
+        conn: &mut ManagedConnection,
+        table_name: &str,
+        batch: RecordBatch,
+        mode: IngestMode,
+        temporary: bool,
+    ) -> Result<(), Status> {
+        let mut stmt = conn.new_statement()
+            .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
+
+        stmt.set_option(OptionStatement::TargetTable, OptionValue::String(table_name.to_string()))
+            .map_err(|e| Status::internal(format!("Failed to set target table: {}", e)))?;
+
+        stmt.set_option(OptionStatement::IngestMode, mode.into())
+            .map_err(|e| Status::internal(format!("Failed to set ingest mode: {}", e)))?;
+
+        if temporary {
+            stmt.set_option(OptionStatement::Temporary, OptionValue::String("true".to_string()))
+                .map_err(|e| Status::internal(format!("Failed to set temporary option: {}", e)))?;
+        }
+
+        stmt.bind(to_adbc_batch(&batch)?)
+            .map_err(|e| Status::internal(format!("Failed to bind batch: {}", e)))?;
+
+        stmt.execute_update()
+            .map_err(|e| Status::internal(format!("Failed to ingest into {}: {}", table_name, e)))?;
+
+        Ok(())
+    }
+
     fn prepare_timestamp_param(timestamp: i64) -> Result<RecordBatch, Status> {
         let schema = Arc::new(Schema::new(vec![
             Field::new("timestamp", DataType::Int64, false),
@@ -281,119 +264,127 @@ impl AdbcBackend {
             .map_err(|e| Status::internal(format!("Failed to create parameter batch: {}", e)))
     }
 
-    /// Inserts a batch of metrics with optimized aggregation updates.
-    async fn insert_batch_optimized(&self, metrics: &[MetricRecord], _window: TimeWindow) -> Result<(), Status> {
-        // Begin transaction
-        self.begin_transaction().await?;
-        let mut conn = self.conn.lock().await;
-        
-        // Insert metrics
-        let batch = Self::prepare_params(metrics)?;
-        let sql = self.build_insert_sql("metrics", &batch);
+    /// Executes a query and returns its results as record batches.
+    async fn query_batches(
+        &self,
+        conn: &mut ManagedConnection,
+        query: &str,
+        params: Option<RecordBatch>,
+    ) -> Result<Vec<RecordBatch>, Status> {
         let mut stmt = conn.new_statement()
             .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
 
-        stmt.set_sql_query(&sql)
+        stmt.set_sql_query(query)
             .map_err(|e| Status::internal(format!("Failed to set query: {}", e)))?;
 
-        // Bind parameters
-        let mut bind_stmt = conn.new_statement()
-            .map_err(|e| Status::internal(format!("Failed to create bind statement: {}", e)))?;
-
-        let mut param_values = Vec::new();
-        for i in 0..batch.num_rows() {
-            for j in 0..batch.num_columns() {
-                let col = batch.column(j);
-                match col.data_type() {
-                    DataType::Int64 => {
-                        let array = col.as_any().downcast_ref::<Int64Array>().unwrap();
-                        param_values.push(array.value(i).to_string());
-                    }
-                    DataType::Float64 => {
-                        let array = col.as_any().downcast_ref::<Float64Array>().unwrap();
-                        param_values.push(array.value(i).to_string());
-                    }
-                    DataType::Utf8 => {
-                        let array = col.as_any().downcast_ref::<StringArray>().unwrap();
-                        param_values.push(format!("'{}'", array.value(i)));
-                    }
-                    _ => return Err(Status::internal("Unsupported parameter type")),
-                }
-            }
-        }
-
-        let params_sql = format!("VALUES ({})", param_values.join(", "));
-        bind_stmt.set_sql_query(&params_sql)
-            .map_err(|e| Status::internal(format!("Failed to set parameters: {}", e)))?;
-
-        let mut bind_result = bind_stmt.execute()
-            .map_err(|e| Status::internal(format!("Failed to execute parameter binding: {}", e)))?;
-
-        while let Some(batch_result) = bind_result.next() {
-            let _ = batch_result.map_err(|e| Status::internal(format!("Failed to bind parameters: {}", e)))?;
+        if let Some(batch) = params {
+            stmt.bind(to_adbc_batch(&batch)?)
+                .map_err(|e| Status::internal(format!("Failed to bind parameters: {}", e)))?;
         }
 
-        stmt.execute_update()
-            .map_err(|e| Status::internal(format!("Failed to insert metrics: {}", e)))?;
-
-        // Commit transaction
-        self.commit_transaction().await?;
-
-        Ok(())
-    }
-
-    /// Prepares parameters for aggregation insertion
-    fn prepare_aggregation_params(agg: &BatchAggregation) -> Result<RecordBatch, Status> {
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("metric_id", DataType::Utf8, false),
-            Field::new("window_start", DataType::Int64, false),
-            Field::new("window_end", DataType::Int64, false),
-            Field::new("running_sum", DataType::Float64, false),
-            Field::new("running_count", DataType::Int64, false),
-            Field::new("min_value", DataType::Float64, false),
-            Field::new("max_value", DataType::Float64, false),
-        ]));
-
-        let arrays: Vec<ArrayRef> = vec![
-            Arc::new(StringArray::from(vec![agg.metric_id.as_str()])),
-            Arc::new(Int64Array::from(vec![agg.window_start])),
-            Arc::new(Int64Array::from(vec![agg.window_end])),
-            Arc::new(Float64Array::from(vec![agg.running_sum])),
-            Arc::new(Int64Array::from(vec![agg.running_count])),
-            Arc::new(Float64Array::from(vec![agg.min_value])),
-            Arc::new(Float64Array::from(vec![agg.max_value])),
-        ];
+        let reader = stmt.execute()
+            .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
 
-        RecordBatch::try_new(schema, arrays)
-            .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+        reader
+            .map(|batch| {
+                let batch = batch.map_err(|e| Status::internal(format!("Failed to get next batch: {}", e)))?;
+                from_adbc_batch(batch)
+            })
+            .collect()
     }
 
-    async fn begin_transaction(&self) -> Result<(), Status> {
-        let mut conn = self.conn.lock().await;
+    /// Executes a query and returns its results as a single record batch,
+    /// which keeps the schema of the result if it has no rows.
+    async fn query_batch(&self, conn: &mut ManagedConnection, query: &str) -> Result<RecordBatch, Status> {
         let mut stmt = conn.new_statement()
             .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
 
-        stmt.set_sql_query("BEGIN")
-            .map_err(|e| Status::internal(format!("Failed to set SQL query: {}", e)))?;
+        stmt.set_sql_query(query)
+            .map_err(|e| Status::internal(format!("Failed to set query: {}", e)))?;
 
-        stmt.execute_update()
+        let reader = stmt.execute()
             .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
 
+        let schema = duckdb::arrow::record_batch::RecordBatchReader::schema(&reader);
+        let mut batches = vec![from_adbc_batch(AdbcRecordBatch::new_empty(schema))?];
+        for batch in reader {
+            let batch = batch.map_err(|e| Status::internal(format!("Failed to read record batch: {}", e)))?;
+            batches.push(from_adbc_batch(batch)?);
+        }
+        concat_batches(&batches[0].schema(), &batches)
+            .map_err(|e| Status::internal(format!("Failed to concatenate record batches: {}", e)))
+    }
+
+    /// Upserts partial aggregations into `metric_aggregations`, within the
+    /// caller's transaction.
+    async fn upsert_aggregations(&self, conn: &mut ManagedConnection, aggregations: &[BatchAggregation]) -> Result<(), Status> {
+        // Stage the partial aggregations with a bulk ingest, then merge them
+        // into the aggregation table in a single statement. Windows repeated
+        // in the input are merged first, as a window can only be upserted
+        // once per statement.
+        let aggregations = upsert_state(aggregations, &[])?;
+        let batch = BatchAggregation::to_record_batch(&aggregations)?;
+        self.bulk_ingest(conn, AGGREGATION_STAGING_TABLE, batch, IngestMode::Replace, true).await?;
+
+        // Sketches and variance state cannot be merged in SQL: merge the
+        // stored state of the staged windows with the new state and stage the
+        // result again.
+        let stored_sql = format!(r#"
+            SELECT {}
+            FROM metric_aggregations a
+            JOIN {} s ON a.metric_id = s.metric_id
+                AND a.window_start = s.window_start
+                AND a.window_end = s.window_end
+        "#, qualified_aggregation_columns("a"), AGGREGATION_STAGING_TABLE);
+        let stored = self.query_batches(conn, &stored_sql, None).await?;
+        let mut stored_state = Vec::new();
+        for batch in &stored {
+            stored_state.extend(BatchAggregation::from_record_batch(batch)?);
+        }
+        if !stored_state.is_empty() {
+            let batch = BatchAggregation::to_record_batch(&upsert_state(&aggregations, &stored_state)?)?;
+            self.bulk_ingest(conn, AGGREGATION_STAGING_TABLE, batch, IngestMode::Replace, true).await?;
+        }
+
+        let merge_sql = format!(r#"
+            INSERT INTO metric_aggregations ({columns})
+            SELECT {columns}
+            FROM {staging}
+            ON CONFLICT (metric_id, window_start, window_end) DO UPDATE
+            SET running_sum = EXCLUDED.running_sum,
+                running_count = EXCLUDED.running_count,
+                min_value = EXCLUDED.min_value,
+                max_value = EXCLUDED.max_value,
+                quantile_sketch = EXCLUDED.quantile_sketch,
+                sample_count = EXCLUDED.sample_count,
+                m2 = EXCLUDED.m2,
+                first_timestamp = EXCLUDED.first_timestamp,
+                first_value = EXCLUDED.first_value,
+                last_timestamp = EXCLUDED.last_timestamp,
+                last_value = EXCLUDED.last_value,
+                distinct_sketch = EXCLUDED.distinct_sketch
+        "#, columns = qualified_aggregation_columns(""), staging = AGGREGATION_STAGING_TABLE);
+        self.execute_statement(conn, &merge_sql).await
+    }
+
+    /// Appends an adapted batch to its table, merging metrics into the
+    /// windowed aggregation state, within the caller's transaction.
+    async fn write_batch(&self, conn: &mut ManagedConnection, table_name: &str, batch: &RecordBatch) -> Result<(), Status> {
+        self.bulk_ingest(conn, table_name, batch.clone(), IngestMode::Append, false).await?;
+        if table_name == "metrics" && batch.num_rows() > 0 {
+            let metrics = MetricRecord::try_from_record_batch(batch)?;
+            let aggregations = aggregate_windows(&metrics, INGEST_WINDOW, |m| m.value_running_window_count);
+            self.upsert_aggregations(conn, &aggregations).await?;
+        }
         Ok(())
     }
 
-    async fn commit_transaction(&self) -> Result<(), Status> {
-        let mut conn = self.conn.lock().await;
-        let mut stmt = conn.new_statement()
-            .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
-
-        stmt.set_sql_query("COMMIT")
-            .map_err(|e| Status::internal(format!("Failed to set SQL query: {}", e)))?;
-
-        stmt.execute_update()
-            .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
+    async fn begin_transaction(&self, conn: &mut ManagedConnection) -> Result<(), Status> {
+        self.execute_statement(conn, "BEGIN").await
+    }
 
-        Ok(())
+    async fn commit_transaction(&self, conn: &mut ManagedConnection) -> Result<(), Status> {
+        self.execute_statement(conn, "COMMIT").await
     }
 
     async fn rollback_transaction(&self, conn: &mut ManagedConnection) -> Result<(), Status> {
@@ -441,33 +432,6 @@ impl AdbcBackend {
         sql
     }
 
-    fn build_insert_sql(&self, table_name: &str, batch: &RecordBatch) -> String {
-        let mut sql = format!("INSERT INTO {} (", table_name);
-        let mut first = true;
-
-        for field in batch.schema().fields() {
-            if !first {
-                sql.push_str(", ");
-            }
-            first = false;
-            sql.push_str(field.name());
-        }
-
-        sql.push_str(") VALUES (");
-        first = true;
-
-        for _i in 0..batch.num_columns() {
-            if !first {
-                sql.push_str(", ");
-            }
-            first = false;
-            sql.push('?');
-        }
-
-        sql.push(')');
-        sql
-    }
-
     fn arrow_type_to_sql_type(&self, data_type: &DataType) -> &'static str {
         match data_type {
             DataType::Boolean => "BOOLEAN",
@@ -522,16 +486,34 @@ impl StorageBackend for AdbcBackend {
                 running_count BIGINT NOT NULL,
                 min_value DOUBLE PRECISION NOT NULL,
                 max_value DOUBLE PRECISION NOT NULL,
+                quantile_sketch BYTEA,
+                sample_count BIGINT,
+                m2 DOUBLE PRECISION,
+                first_timestamp BIGINT,
+                first_value DOUBLE PRECISION,
+                last_timestamp BIGINT,
+                last_value DOUBLE PRECISION,
+                distinct_sketch BYTEA,
                 PRIMARY KEY (metric_id, window_start, window_end)
             );
 
             CREATE INDEX IF NOT EXISTS idx_aggregations_window 
             ON metric_aggregations(window_start, window_end);
+
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS quantile_sketch BYTEA;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS sample_count BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS m2 DOUBLE PRECISION;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS first_timestamp BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS first_value DOUBLE PRECISION;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS last_timestamp BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS last_value DOUBLE PRECISION;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS distinct_sketch BYTEA;
         "#).map_err(|e| Status::internal(format!("Failed to set query: {}", e)))?;
 
         stmt.execute_update()
             .map_err(|e| Status::internal(format!("Failed to create tables: {}", e)))?;
 
+        self.table_manager.register_table("metrics", get_metrics_schema()).await;
         Ok(())
     }
 
@@ -539,21 +521,7 @@ impl StorageBackend for AdbcBackend {
         if metrics.is_empty() {
             return Ok(());
         }
-
-        // Check if eviction is needed
-        if let Some(cutoff) = self.cache_manager.should_evict().await? {
-            let query = self.cache_manager.eviction_query(cutoff);
-            self.execute_eviction(&query).await?;
-        }
-
-        // Use sliding window for batch-level aggregations
-        let window = TimeWindow::Sliding {
-            window: Duration::from_secs(3600), // 1 hour window
-            slide: Duration::from_secs(60),    // 1 minute slide
-        };
-
-        // Use optimized batch insertion
-        self.insert_batch_optimized(&metrics, window).await
+        self.insert_into_tables(vec![("metrics".to_string(), Self::prepare_params(&metrics)?)]).await
     }
 
     async fn query_metrics(&self, from_timestamp: i64) -> Result<Vec<MetricRecord>, Status> {
@@ -607,47 +575,47 @@ impl StorageBackend for AdbcBackend {
 
     async fn aggregate_metrics(
         &self,
-        function: AggregateFunction,
+        aggregates: &[AggregateExpr],
         group_by: &GroupBy,
+        window: TimeWindow,
         from_timestamp: i64,
         to_timestamp: Option<i64>,
-    ) -> Result<Vec<AggregateResult>, Status> {
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+    ) -> Result<RecordBatch, Status> {
         // Check if eviction is needed
         if let Some(cutoff) = self.cache_manager.should_evict().await? {
             let query = self.cache_manager.eviction_query(cutoff);
             self.execute_eviction(&query).await?;
         }
 
-        const DEFAULT_COLUMNS: [&str; 5] = [
-            "metric_id",
-            "timestamp",
-            "value_running_window_sum",
-            "value_running_window_avg",
-            "value_running_window_count"
-        ];
-
-        let query = build_aggregate_query(
+        let aggregates = counted_aggregates(aggregates);
+        let windowed = !matches!(window, TimeWindow::None);
+        self.table_manager.validate_aggregates("metrics", &aggregates, group_by).await?;
+        window.validate()?;
+        if let Some(filter) = filter {
+            self.table_manager.validate_filter("metrics", filter).await?;
+        }
+        self.table_manager.validate_result_options("metrics", &aggregates, group_by, windowed, options).await?;
+        let query = build_windowed_aggregate_query(
             "metrics",
-            function,
+            &aggregates,
             group_by,
-            &DEFAULT_COLUMNS,
+            window,
             Some(from_timestamp),
             to_timestamp,
-        );
-        let mut conn = self.conn.lock().await;
-        let metrics = self.execute_query(&mut conn, &query, None).await?;
-
-        let mut results = Vec::new();
-        for metric in metrics {
-            let result = AggregateResult {
-                value: metric.value_running_window_sum,
-                timestamp: metric.timestamp,
-                // Add any other fields required by AggregateResult
-            };
-            results.push(result);
-        }
+            filter,
+            options,
+            SqlDialect::Postgres,
+        )?;
+        let batches = {
+            let mut conn = self.conn.lock().await;
+            self.query_batches(&mut conn, &query, None).await?
+        };
 
-        Ok(results)
+        let schema = self.table_manager.get_table_schema("metrics").await?;
+        let output = aggregate_output_schema(&aggregates, group_by, true, &schema)?;
+        aggregation_result(&batches, output, window, from_timestamp, to_timestamp)
     }
 
     fn new_with_options(
@@ -661,7 +629,7 @@ impl StorageBackend for AdbcBackend {
         let mut driver = ManagedDriver::load_dynamic_from_filename(
             driver_path,
             None,
-            AdbcVersion::V100,
+            AdbcVersion::V110,
         ).map_err(|e| Status::internal(format!("Failed to load ADBC driver: {}", e)))?;
 
         let mut database = driver.new_database()
@@ -695,155 +663,144 @@ impl StorageBackend for AdbcBackend {
     async fn create_table(&self, table_name: &str, schema: &Schema) -> Result<(), Status> {
         let mut conn = self.conn.lock().await;
         let sql = self.build_create_table_sql(table_name, schema);
-        self.execute_statement(&mut conn, &sql).await
+        self.execute_statement(&mut conn, &sql).await?;
+
+        // Register table in manager
+        self.table_manager.create_table(table_name.to_string(), schema.clone()).await
     }
 
     async fn insert_into_table(&self, table_name: &str, batch: RecordBatch) -> Result<(), Status> {
-        let mut conn = self.conn.lock().await;
-        let sql = self.build_insert_sql(table_name, &batch);
-        self.execute_statement(&mut conn, &sql).await
+        self.insert_into_tables(vec![(table_name.to_string(), batch)]).await
     }
 
-    async fn query_table(&self, table_name: &str, projection: Option<Vec<String>>) -> Result<RecordBatch, Status> {
-        let mut conn = self.conn.lock().await;
-        let mut stmt = conn.new_statement()
-            .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
+    async fn insert_into_tables(&self, batches: Vec<(String, RecordBatch)>) -> Result<(), Status> {
+        let mut adapted = Vec::with_capacity(batches.len());
+        for (table_name, batch) in batches {
+            let batch = if table_name == "metrics" {
+                get_metrics_schema_adapter().adapt(&batch)?
+            } else {
+                self.table_manager.adapt_batch(&table_name, &batch).await?
+            };
+            adapted.push((table_name, batch));
+        }
 
-        let columns = projection.map(|cols| cols.join(", ")).unwrap_or_else(|| "*".to_string());
-        let sql = format!("SELECT {} FROM {}", columns, table_name);
+        // Check if eviction is needed
+        if adapted.iter().any(|(table_name, _)| table_name == "metrics") {
+            if let Some(cutoff) = self.cache_manager.should_evict().await? {
+                let query = self.cache_manager.eviction_query(cutoff);
+                self.execute_eviction(&query).await?;
+            }
+        }
 
-        stmt.set_sql_query(&sql)
-            .map_err(|e| Status::internal(format!("Failed to set query: {}", e)))?;
+        let sources = materialized::lock_sources(self, &adapted).await;
+        {
+            let mut conn = self.conn.lock().await;
+            self.begin_transaction(&mut conn).await?;
+            for (table_name, batch) in &adapted {
+                if let Err(e) = self.write_batch(&mut conn, table_name, batch).await {
+                    self.rollback_transaction(&mut conn).await?;
+                    return Err(e);
+                }
+            }
+            self.commit_transaction(&mut conn).await?;
+        }
 
-        let mut reader = stmt.execute()
-            .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
+        on_ingest(self, &adapted, sources).await;
+        Ok(())
+    }
 
-        let batch = reader.next()
-            .ok_or_else(|| Status::internal("No data returned"))?
-            .map_err(|e| Status::internal(format!("Failed to read record batch: {}", e)))?;
-
-        // Convert ADBC RecordBatch to Arrow RecordBatch
-        let schema = batch.schema();
-        let mut arrays = Vec::with_capacity(batch.num_columns());
-
-        for _i in 0..batch.num_columns() {
-            let col = batch.column(_i);
-            let array: ArrayRef = match col.data_type() {
-                &duckdb::arrow::datatypes::DataType::Int64 => {
-                    Arc::new(col.as_any().downcast_ref::<Int64Array>().unwrap().clone())
-                },
-                &duckdb::arrow::datatypes::DataType::Float64 => {
-                    Arc::new(col.as_any().downcast_ref::<Float64Array>().unwrap().clone())
-                },
-                &duckdb::arrow::datatypes::DataType::Utf8 => {
-                    Arc::new(col.as_any().downcast_ref::<StringArray>().unwrap().clone())
-                },
-                _ => return Err(Status::internal("Unsupported column type")),
-            };
-            arrays.push(array);
+    async fn query_table(
+        &self,
+        table_name: &str,
+        projection: Option<Vec<String>>,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
+        let columns = projection.map(|cols| cols.join(", ")).unwrap_or_else(|| "*".to_string());
+        let mut sql = format!("SELECT {} FROM {}", columns, table_name);
+        if let Some(filter) = filter {
+            self.table_manager.validate_filter(table_name, filter).await?;
+            sql.push_str(&format!(" WHERE {}", filter.to_sql(SqlDialect::Postgres)?));
         }
 
-        // Convert DuckDB schema to Arrow schema
-        let fields: Vec<Field> = schema.fields().iter().map(|f| {
-            Field::new(
-                f.name(),
-                match f.data_type() {
-                    &duckdb::arrow::datatypes::DataType::Int64 => DataType::Int64,
-                    &duckdb::arrow::datatypes::DataType::Float64 => DataType::Float64,
-                    &duckdb::arrow::datatypes::DataType::Utf8 => DataType::Utf8,
-                    _ => DataType::Utf8, // Default to string for unsupported types
-                },
-                f.is_nullable()
-            )
-        }).collect();
+        let mut conn = self.conn.lock().await;
+        self.query_batch(&mut conn, &sql).await
+    }
 
-        let arrow_schema = Schema::new(fields);
-        RecordBatch::try_new(Arc::new(arrow_schema), arrays)
-            .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))
+    async fn delete_from_table(&self, table_name: &str, filter: &Filter) -> Result<(), Status> {
+        self.table_manager.validate_filter(table_name, filter).await?;
+        let sql = format!("DELETE FROM {} WHERE {}", table_name, filter.to_sql(SqlDialect::Postgres)?);
+        {
+            let mut conn = self.conn.lock().await;
+            self.execute_statement(&mut conn, &sql).await?;
+        }
+        materialized::mark_stale(self, table_name).await;
+        Ok(())
+    }
+
+    async fn replace_in_tables(&self, replacements: Vec<(String, Filter, RecordBatch)>) -> Result<(), Status> {
+        let mut statements = Vec::with_capacity(replacements.len());
+        for (table_name, filter, batch) in replacements {
+            self.table_manager.validate_filter(&table_name, &filter).await?;
+            let delete = format!("DELETE FROM {} WHERE {}", table_name, filter.to_sql(SqlDialect::Postgres)?);
+            let batch = self.table_manager.adapt_batch(&table_name, &batch).await?;
+            statements.push((delete, table_name, batch));
+        }
+
+        let mut conn = self.conn.lock().await;
+        self.begin_transaction(&mut conn).await?;
+        for (delete, table_name, batch) in statements {
+            let result = match self.execute_statement(&mut conn, &delete).await {
+                Ok(_) => self.bulk_ingest(&mut conn, &table_name, batch, IngestMode::Append, false).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.rollback_transaction(&mut conn).await?;
+                return Err(e);
+            }
+        }
+        self.commit_transaction(&mut conn).await
     }
 
     async fn create_aggregation_view(&self, view: &AggregationView) -> Result<(), Status> {
-        let columns: Vec<&str> = view.aggregate_columns.iter()
-            .map(|s| s.as_str())
-            .collect();
-            
-        let sql = build_aggregate_query(
+        self.table_manager.validate_aggregates(&view.source_table, &view.aggregates, &view.group_by).await?;
+        view.window.validate()?;
+        if let Some(filter) = &view.filter {
+            self.table_manager.validate_filter(&view.source_table, filter).await?;
+        }
+        let view_name = format!("agg_view_{}", view.source_table);
+        if view.materialized {
+            return materialized::create(self, &view_name, view).await;
+        }
+
+        let sql = build_windowed_aggregate_query(
             &view.source_table,
-            view.function,
+            &view.aggregates,
             &view.group_by,
-            &columns,
+            view.window,
             None,
-            None
-        );
-        
-        let mut conn = self.conn.lock().await;
-        let mut stmt = conn.new_statement()
-            .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
-
-        stmt.set_sql_query(&format!("CREATE VIEW {} AS {}", view.source_table, sql))
-            .map_err(|e| Status::internal(format!("Failed to set SQL query: {}", e)))?;
-
-        stmt.execute_update()
-            .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
+            None,
+            view.filter.as_ref(),
+            &ResultOptions::default(),
+            SqlDialect::Postgres,
+        )?;
+        {
+            let mut conn = self.conn.lock().await;
+            self.execute_statement(&mut conn, &format!("CREATE VIEW {} AS {}", view_name, sql)).await?;
+        }
 
-        Ok(())
+        // Register view in manager
+        self.table_manager.create_aggregation_view(view_name, view.clone()).await
     }
 
     async fn query_aggregation_view(&self, view_name: &str) -> Result<RecordBatch, Status> {
+        if let Ok(view) = self.table_manager.get_aggregation_view(view_name).await {
+            if view.materialized {
+                return materialized::query(self, view_name).await;
+            }
+        }
         let sql = format!("SELECT * FROM {}", view_name);
-
         let mut conn = self.conn.lock().await;
-        let mut stmt = conn.new_statement()
-            .map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
-
-        stmt.set_sql_query(&sql)
-            .map_err(|e| Status::internal(format!("Failed to set SQL query: {}", e)))?;
-
-        let mut reader = stmt.execute()
-            .map_err(|e| Status::internal(format!("Failed to execute query: {}", e)))?;
-
-        let batch = reader.next()
-            .ok_or_else(|| Status::internal("No data returned"))?
-            .map_err(|e| Status::internal(format!("Failed to read record batch: {}", e)))?;
-
-        // Convert DuckDB RecordBatch to Arrow RecordBatch
-        let schema = batch.schema();
-        let mut arrays = Vec::with_capacity(batch.num_columns());
-
-        for i in 0..batch.num_columns() {
-            let col = batch.column(i);
-            let array: ArrayRef = match col.data_type() {
-                &duckdb::arrow::datatypes::DataType::Int64 => {
-                    Arc::new(col.as_any().downcast_ref::<Int64Array>().unwrap().clone())
-                },
-                &duckdb::arrow::datatypes::DataType::Float64 => {
-                    Arc::new(col.as_any().downcast_ref::<Float64Array>().unwrap().clone())
-                },
-                &duckdb::arrow::datatypes::DataType::Utf8 => {
-                    Arc::new(col.as_any().downcast_ref::<StringArray>().unwrap().clone())
-                },
-                _ => return Err(Status::internal("Unsupported column type")),
-            };
-            arrays.push(array);
-        }
-
-        // Convert DuckDB schema to Arrow schema
-        let fields: Vec<Field> = schema.fields().iter().map(|f| {
-            Field::new(
-                f.name(),
-                match f.data_type() {
-                    &duckdb::arrow::datatypes::DataType::Int64 => DataType::Int64,
-                    &duckdb::arrow::datatypes::DataType::Float64 => DataType::Float64,
-                    &duckdb::arrow::datatypes::DataType::Utf8 => DataType::Utf8,
-                    _ => DataType::Utf8, // Default to string for unsupported types
-                },
-                f.is_nullable()
-            )
-        }).collect();
-
-        let arrow_schema = Schema::new(fields);
-        RecordBatch::try_new(Arc::new(arrow_schema), arrays)
-            .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))
+        self.query_batch(&mut conn, &sql).await
     }
 
     async fn drop_table(&self, table_name: &str) -> Result<(), Status> {
@@ -852,10 +809,20 @@ impl StorageBackend for AdbcBackend {
         stmt.set_sql_query(&format!("DROP TABLE IF EXISTS {}", table_name))
             .map_err(|e| Status::internal(format!("Failed to set SQL query: {}", e)))?;
         stmt.execute_update().map_err(|e| Status::internal(format!("Failed to drop table: {}", e)))?;
-        Ok(())
+        drop(stmt);
+        drop(conn);
+
+        self.table_manager.drop_table(table_name).await
     }
 
     async fn drop_aggregation_view(&self, view_name: &str) -> Result<(), Status> {
+        if let Ok(view) = self.table_manager.get_aggregation_view(view_name).await {
+            if view.materialized {
+                self.table_manager.drop_aggregation_view(view_name).await?;
+                self.drop_table(view_name).await?;
+                return self.drop_table(&materialized::state_table(view_name)).await;
+            }
+        }
         let mut conn = self.conn.lock().await;
         let mut stmt = conn.new_statement().map_err(|e| Status::internal(format!("Failed to create statement: {}", e)))?;
         stmt.set_sql_query(&format!("DROP VIEW IF EXISTS {}", view_name))
@@ -867,27 +834,97 @@ impl StorageBackend for AdbcBackend {
     fn table_manager(&self) -> &TableManager {
         &self.table_manager
     }
-}
 
-fn format_value(array: &dyn Array, index: usize) -> String {
-    match array.data_type() {
-        DataType::Int8 => format!("{}", array.as_any().downcast_ref::<Int8Array>().unwrap().value(index)),
-        DataType::Int16 => format!("{}", array.as_any().downcast_ref::<Int16Array>().unwrap().value(index)),
-        DataType::Int32 => format!("{}", array.as_any().downcast_ref::<Int32Array>().unwrap().value(index)),
-        DataType::Int64 => format!("{}", array.as_any().downcast_ref::<Int64Array>().unwrap().value(index)),
-        DataType::Float32 => format!("{}", array.as_any().downcast_ref::<Float32Array>().unwrap().value(index)),
-        DataType::Float64 => format!("{}", array.as_any().downcast_ref::<Float64Array>().unwrap().value(index)),
-        DataType::Boolean => format!("{}", array.as_any().downcast_ref::<BooleanArray>().unwrap().value(index)),
-        DataType::Utf8 => format!("'{}'", array.as_any().downcast_ref::<StringArray>().unwrap().value(index)),
-        DataType::Binary => format!("X'{}'", hex::encode(array.as_any().downcast_ref::<BinaryArray>().unwrap().value(index))),
-        DataType::Timestamp(_, _) => {
-            let ts = array.as_any().downcast_ref::<TimestampNanosecondArray>().unwrap().value(index);
-            let seconds = ts / 1_000_000_000;
-            let nanos = (ts % 1_000_000_000) as u32;
-            format!("'{}'", chrono::DateTime::from_timestamp(seconds, nanos)
-                .unwrap_or_default()
-                .naive_utc())
-        },
-        _ => "NULL".to_string(),
+    async fn insert_batch_aggregations(
+        &self,
+        aggregations: Vec<BatchAggregation>,
+    ) -> Result<(), Status> {
+        if aggregations.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.conn.lock().await;
+        self.begin_transaction(&mut conn).await?;
+        if let Err(e) = self.upsert_aggregations(&mut conn, &aggregations).await {
+            self.rollback_transaction(&mut conn).await?;
+            return Err(e);
+        }
+        self.commit_transaction(&mut conn).await
+    }
+
+    async fn query_batch_aggregations(
+        &self,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<Vec<BatchAggregation>, Status> {
+        let sql = format!(r#"
+            SELECT {}
+            FROM metric_aggregations
+            WHERE window_start >= ? AND window_end <= ?
+            ORDER BY metric_id, window_start, window_end
+        "#, qualified_aggregation_columns(""));
+        let params = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("from_timestamp", DataType::Int64, false),
+                Field::new("to_timestamp", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![from_timestamp])),
+                Arc::new(Int64Array::from(vec![to_timestamp.unwrap_or(i64::MAX)])),
+            ],
+        ).map_err(|e| Status::internal(format!("Failed to create parameter batch: {}", e)))?;
+
+        let mut conn = self.conn.lock().await;
+        let batches = self.query_batches(&mut conn, &sql, Some(params)).await?;
+        let mut aggregations = Vec::new();
+        for batch in &batches {
+            aggregations.extend(BatchAggregation::from_record_batch(batch)?);
+        }
+        Ok(aggregations)
     }
 }
+
+/// Lists the `metric_aggregations` columns in the order of
+/// [`BatchAggregation::schema`], qualified with `alias` if it is not empty.
+fn qualified_aggregation_columns(alias: &str) -> String {
+    BatchAggregation::schema()
+        .fields()
+        .iter()
+        .map(|f| if alias.is_empty() { f.name().clone() } else { format!("{}.{}", alias, f.name()) })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Moves a record batch into the arrow release used by the ADBC driver manager.
+fn to_adbc_batch(batch: &RecordBatch) -> Result<AdbcRecordBatch, Status> {
+    let data = StructArray::from(batch.clone()).into_data();
+    let (mut array, mut schema) = arrow::ffi::to_ffi(&data)
+        .map_err(|e| Status::internal(format!("Failed to export record batch: {}", e)))?;
+
+    // SAFETY: both arrow releases implement the same C Data Interface, so the
+    // exported structs have an identical layout. `from_raw` takes ownership and
+    // leaves released structs behind, so nothing is freed twice.
+    let data = unsafe {
+        let array = duckdb::arrow::ffi::FFI_ArrowArray::from_raw(&mut array as *mut _ as *mut _);
+        let schema = duckdb::arrow::ffi::FFI_ArrowSchema::from_raw(&mut schema as *mut _ as *mut _);
+        duckdb::arrow::ffi::from_ffi(array, &schema)
+    }.map_err(|e| Status::internal(format!("Failed to import record batch: {}", e)))?;
+
+    Ok(AdbcRecordBatch::from(duckdb::arrow::array::StructArray::from(data)))
+}
+
+/// Moves a record batch returned by the ADBC driver manager into this crate's arrow release.
+fn from_adbc_batch(batch: AdbcRecordBatch) -> Result<RecordBatch, Status> {
+    let data = duckdb::arrow::array::Array::into_data(duckdb::arrow::array::StructArray::from(batch));
+    let (mut array, mut schema) = duckdb::arrow::ffi::to_ffi(&data)
+        .map_err(|e| Status::internal(format!("Failed to export record batch: {}", e)))?;
+
+    // SAFETY: see `to_adbc_batch`.
+    let data = unsafe {
+        let array = arrow::ffi::FFI_ArrowArray::from_raw(&mut array as *mut _ as *mut _);
+        let schema = arrow::ffi::FFI_ArrowSchema::from_raw(&mut schema as *mut _ as *mut _);
+        arrow::ffi::from_ffi(array, &schema)
+    }.map_err(|e| Status::internal(format!("Failed to import record batch: {}", e)))?;
+
+    Ok(RecordBatch::from(StructArray::from(data)))
+}
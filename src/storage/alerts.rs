@@ -0,0 +1,695 @@
+//! Alert rules evaluated on windowed aggregates.
+//!
+//! An [`AlertRule`] aggregates the `metrics` table, see
+//! [`StorageBackend::aggregate_metrics`], or reads an aggregation view, and
+//! checks its condition against the aggregated rows. Each group of the
+//! result is a series with its own alert: a series matching the condition
+//! becomes pending, and fires once it matched for the `for` duration of the
+//! rule. A firing series that stops matching, or is missing from the result,
+//! is resolved; a pending one is dropped silently.
+//!
+//! Windowed sources are evaluated on the latest window of each series that
+//! closed by the evaluation time. Rules are evaluated on a schedule, see
+//! [`run_due`] and [`spawn_scheduler`], or whenever ingested rows close a
+//! window of the source, see [`on_ingest`].
+//!
+//! Firing and resolved alerts are sent to the notifiers of the rule, see
+//! [`NotifierConfig`], and to those registered with
+//! [`TableManager::add_alert_notifier`](crate::storage::table_manager::TableManager::add_alert_notifier).
+//! Alerts are sent in the background, in the order of the evaluations, so
+//! slow notifiers do not hold up evaluations or the ingests that trigger
+//! them. Notifier failures are logged and do not fail the evaluation. Rules
+//! and alert state are kept in memory.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use arrow::compute::{cast, max};
+use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tonic::Status;
+use crate::aggregation::{aggregate_output_schema, AggregateExpr, GroupBy, ResultOptions, TimeWindow};
+use crate::aggregation::filter::Filter;
+use crate::storage::materialized::unix_now;
+use crate::storage::{counted_aggregates, StorageBackend};
+
+/// Alerts buffered per Flight subscriber before the oldest are dropped.
+pub(crate) const ALERT_SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// Evaluations whose alerts wait to be sent before further ones are
+/// dropped.
+const ALERT_DELIVERY_CAPACITY: usize = 1024;
+
+/// How long a webhook may take to accept an alert.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rows an alert rule is evaluated on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSource {
+    /// Aggregates the rows of the `metrics` table in the `range` before the
+    /// evaluation time
+    Metrics {
+        aggregates: Vec<AggregateExpr>,
+        group_by: GroupBy,
+        window: TimeWindow,
+        range: Duration,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<Filter>,
+    },
+    /// Reads an aggregation view
+    View(String),
+}
+
+/// When an alert rule is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSchedule {
+    /// Every interval, see [`run_due`]
+    Interval(Duration),
+    /// Whenever ingested rows close a window of the source, see
+    /// [`on_ingest`]; requires a fixed, aligned or calendar window
+    WindowClose,
+}
+
+/// A notifier of an alert rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifierConfig {
+    /// Logs alerts, see [`LogNotifier`]
+    Log,
+    /// Streams alerts to Flight subscribers, see [`SubscriptionNotifier`]
+    Subscription,
+    /// Posts alerts to an HTTP endpoint, see [`WebhookNotifier`]
+    Webhook { url: String },
+}
+
+impl NotifierConfig {
+    fn build<B: StorageBackend + ?Sized>(&self, backend: &B) -> Result<Arc<dyn Notifier>, Status> {
+        Ok(match self {
+            NotifierConfig::Log => Arc::new(LogNotifier),
+            NotifierConfig::Subscription => Arc::new(SubscriptionNotifier::new(backend.table_manager().alert_sender())),
+            NotifierConfig::Webhook { url } => Arc::new(WebhookNotifier::new(url)?),
+        })
+    }
+}
+
+fn default_notifiers() -> Vec<NotifierConfig> {
+    vec![NotifierConfig::Log]
+}
+
+/// A declarative alert rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub source: AlertSource,
+    /// Condition on the aggregated rows, e.g. `avg_value > 0.9`
+    pub condition: Filter,
+    /// How long a series must match the condition before its alert fires
+    #[serde(rename = "for", default)]
+    pub for_duration: Duration,
+    /// Added to the labels of every alert, replacing group labels of the
+    /// same name
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub schedule: AlertSchedule,
+    #[serde(default = "default_notifiers")]
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+/// State of the alert of a series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    /// Matching, but not for the `for` duration of the rule yet
+    Pending,
+    Firing,
+    /// Firing until this evaluation
+    Resolved,
+}
+
+impl AlertState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertState::Pending => "pending",
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+/// The alert of one series of a rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub rule: String,
+    pub state: AlertState,
+    /// Group by values of the series and the labels of the rule
+    pub labels: BTreeMap<String, String>,
+    /// Non-null aggregate values of the series at the last evaluation
+    pub values: BTreeMap<String, f64>,
+    /// Evaluation time the series started matching
+    pub active_since: i64,
+    pub evaluated_at: i64,
+    /// Window the values were aggregated in, if the source is windowed
+    pub window_start: Option<i64>,
+    pub window_end: Option<i64>,
+}
+
+impl Alert {
+    /// Schema of the record batches streamed to Flight subscribers, with
+    /// labels and values as JSON objects.
+    pub fn schema() -> Schema {
+        Schema::new(vec![
+            Field::new("rule", DataType::Utf8, false),
+            Field::new("state", DataType::Utf8, false),
+            Field::new("labels", DataType::Utf8, false),
+            Field::new("values", DataType::Utf8, false),
+            Field::new("active_since", DataType::Int64, false),
+            Field::new("evaluated_at", DataType::Int64, false),
+            Field::new("window_start", DataType::Int64, true),
+            Field::new("window_end", DataType::Int64, true),
+        ])
+    }
+
+    /// Converts alerts to a record batch with [`schema`](Self::schema).
+    pub fn to_batch(alerts: &[Alert]) -> Result<RecordBatch, Status> {
+        let labels = alerts.iter().map(|a| to_json(&a.labels)).collect::<Result<Vec<_>, _>>()?;
+        let values = alerts.iter().map(|a| to_json(&a.values)).collect::<Result<Vec<_>, _>>()?;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(alerts.iter().map(|a| a.rule.as_str()))),
+            Arc::new(StringArray::from_iter_values(alerts.iter().map(|a| a.state.as_str()))),
+            Arc::new(StringArray::from(labels)),
+            Arc::new(StringArray::from(values)),
+            Arc::new(Int64Array::from_iter_values(alerts.iter().map(|a| a.active_since))),
+            Arc::new(Int64Array::from_iter_values(alerts.iter().map(|a| a.evaluated_at))),
+            Arc::new(Int64Array::from_iter(alerts.iter().map(|a| a.window_start))),
+            Arc::new(Int64Array::from_iter(alerts.iter().map(|a| a.window_end))),
+        ];
+        RecordBatch::try_new(Arc::new(Self::schema()), columns)
+            .map_err(|e| Status::internal(format!("Failed to create alert batch: {}", e)))
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, Status> {
+    serde_json::to_string(value).map_err(|e| Status::internal(format!("Failed to serialize alert: {}", e)))
+}
+
+/// Receives the firing and resolved alerts of each evaluation of a rule.
+#[async_trait]
+pub trait Notifier: Send + Sync + std::fmt::Debug {
+    async fn notify(&self, alerts: &[Alert]) -> Result<(), Status>;
+}
+
+/// Logs firing alerts as warnings and resolved alerts as info.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn notify(&self, alerts: &[Alert]) -> Result<(), Status> {
+        for alert in alerts {
+            match alert.state {
+                AlertState::Resolved => {
+                    tracing::info!("Alert {} resolved: labels {:?}, values {:?}", alert.rule, alert.labels, alert.values)
+                }
+                _ => tracing::warn!("Alert {} firing: labels {:?}, values {:?}", alert.rule, alert.labels, alert.values),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends alerts to the subscribers of the alert stream of the table
+/// manager, which the Flight service streams to DoGet subscriptions.
+#[derive(Debug, Clone)]
+pub struct SubscriptionNotifier {
+    sender: broadcast::Sender<Alert>,
+}
+
+impl SubscriptionNotifier {
+    pub fn new(sender: broadcast::Sender<Alert>) -> Self {
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl Notifier for SubscriptionNotifier {
+    async fn notify(&self, alerts: &[Alert]) -> Result<(), Status> {
+        for alert in alerts {
+            // Fails only if nobody is subscribed
+            let _ = self.sender.send(alert.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Posts alerts as a JSON object `{"alerts": [...]}` to an `http://` URL.
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    uri: Uri,
+}
+
+impl WebhookNotifier {
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the URL is not an absolute
+    /// `http://` URL.
+    pub fn new(url: &str) -> Result<Self, Status> {
+        let uri: Uri = url.parse()
+            .map_err(|e| Status::invalid_argument(format!("Invalid webhook URL {}: {}", url, e)))?;
+        if uri.scheme_str() != Some("http") || uri.host().is_none() {
+            return Err(Status::invalid_argument(format!("Webhook URL {} must be an http:// URL", url)));
+        }
+        Ok(Self { uri })
+    }
+
+    async fn post(&self, body: Vec<u8>) -> Result<(), Status> {
+        let unavailable = |e: &dyn std::fmt::Display| {
+            Status::unavailable(format!("Failed to post alerts to {}: {}", self.uri, e))
+        };
+        let host = self.uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let stream = TcpStream::connect((host, self.uri.port_u16().unwrap_or(80))).await
+            .map_err(|e| unavailable(&e))?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+            .map_err(|e| unavailable(&e))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("Webhook connection failed: {}", e);
+            }
+        });
+
+        let authority = self.uri.authority().map(|a| a.as_str()).unwrap_or_default();
+        let path = self.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let request = hyper::Request::post(path)
+            .header(hyper::header::HOST, authority)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| Status::internal(format!("Failed to build webhook request: {}", e)))?;
+        let response = sender.send_request(request).await.map_err(|e| unavailable(&e))?;
+        if !response.status().is_success() {
+            return Err(unavailable(&response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, alerts: &[Alert]) -> Result<(), Status> {
+        let body = serde_json::to_vec(&serde_json::json!({ "alerts": alerts }))
+            .map_err(|e| Status::internal(format!("Failed to serialize alerts: {}", e)))?;
+        tokio::time::timeout(WEBHOOK_TIMEOUT, self.post(body)).await
+            .map_err(|_| Status::deadline_exceeded(format!("Webhook {} timed out", self.uri)))?
+    }
+}
+
+/// Evaluation state of a rule.
+#[derive(Debug, Default)]
+pub(crate) struct RuleState {
+    /// Time of the last evaluation
+    last_evaluation: Option<i64>,
+    /// Pending and firing alerts by series
+    active: BTreeMap<String, Alert>,
+}
+
+/// Evaluation state by rule name.
+pub(crate) type RuleStates = HashMap<String, RuleState>;
+
+/// The latest closed window of a series of a rule.
+struct Series {
+    labels: BTreeMap<String, String>,
+    values: BTreeMap<String, f64>,
+    window: Option<(i64, i64)>,
+    matched: bool,
+}
+
+/// Validates and registers an alert rule.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the name is empty, the aggregates,
+/// window or filter of the source are invalid for the `metrics` table, the
+/// range or interval is shorter than a second, the condition does not match
+/// the output columns of the source, a window close schedule has no fixed,
+/// aligned or calendar window, or a webhook URL is invalid.
+/// `Status::not_found` if the source view does not exist and
+/// `Status::already_exists` if a rule of the same name exists.
+pub async fn create_rule<B: StorageBackend + ?Sized>(backend: &B, rule: AlertRule) -> Result<(), Status> {
+    if rule.name.is_empty() {
+        return Err(Status::invalid_argument("Alert rule name must not be empty"));
+    }
+    let manager = backend.table_manager();
+    let (window, output) = match &rule.source {
+        AlertSource::Metrics { aggregates, group_by, window, range, filter } => {
+            let aggregates = counted_aggregates(aggregates);
+            manager.validate_aggregates("metrics", &aggregates, group_by).await?;
+            window.validate()?;
+            if let Some(filter) = filter {
+                manager.validate_filter("metrics", filter).await?;
+            }
+            if range.as_secs() == 0 {
+                return Err(Status::invalid_argument("Alert rule range must be at least one second"));
+            }
+            let schema = manager.get_table_schema("metrics").await?;
+            (*window, aggregate_output_schema(&aggregates, group_by, true, &schema)?)
+        }
+        AlertSource::View(name) => {
+            let view = manager.get_aggregation_view(name).await?;
+            let schema = manager.get_table_schema(&view.source_table).await?;
+            let windowed = !matches!(view.window, TimeWindow::None);
+            let output = aggregate_output_schema(&view.aggregates, &view.group_by, windowed, &schema)?;
+            (view.window, output)
+        }
+    };
+    rule.condition.validate(&output)?;
+    match rule.schedule {
+        AlertSchedule::Interval(interval) if interval.as_secs() == 0 => {
+            return Err(Status::invalid_argument("Alert rule interval must be at least one second"));
+        }
+        AlertSchedule::WindowClose
+            if !matches!(window, TimeWindow::Fixed(_) | TimeWindow::Aligned { .. } | TimeWindow::Calendar { .. }) =>
+        {
+            return Err(Status::invalid_argument(
+                "Evaluating on window close requires a fixed, aligned or calendar window",
+            ));
+        }
+        _ => {}
+    }
+    for notifier in &rule.notifiers {
+        notifier.build(backend)?;
+    }
+    manager.create_alert_rule(rule).await
+}
+
+/// Drops an alert rule and its alerts, without resolving them.
+pub async fn drop_rule<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Result<(), Status> {
+    let manager = backend.table_manager();
+    let mut states = manager.alert_states().lock().await;
+    manager.drop_alert_rule(name).await?;
+    states.remove(name);
+    Ok(())
+}
+
+/// Returns the pending and firing alerts of all rules.
+pub async fn active_alerts<B: StorageBackend + ?Sized>(backend: &B) -> Vec<Alert> {
+    let states = backend.table_manager().alert_states().lock().await;
+    let mut names: Vec<&String> = states.keys().collect();
+    names.sort();
+    names.into_iter().flat_map(|name| states[name].active.values().cloned()).collect()
+}
+
+/// Evaluates a rule at `now`, notifies its firing and resolved alerts and
+/// returns the alerts that changed state, including new pending ones.
+pub async fn evaluate<B: StorageBackend + ?Sized>(backend: &B, name: &str, now: i64) -> Result<Vec<Alert>, Status> {
+    let rule = backend.table_manager().get_alert_rule(name).await?;
+    let alerts = {
+        let mut states = backend.table_manager().alert_states().lock().await;
+        let series = read_series(backend, &rule, now).await?;
+        let state = states.entry(rule.name.clone()).or_default();
+        state.last_evaluation = Some(now);
+        transition(&rule, state, series, now)
+    };
+    notify(backend, &rule, &alerts).await;
+    Ok(alerts)
+}
+
+/// Evaluates the rules whose interval elapsed since their last evaluation.
+///
+/// Failures are logged, so one rule cannot keep others from running.
+pub async fn run_due<B: StorageBackend + ?Sized>(backend: &B, now: i64) {
+    for rule in backend.table_manager().alert_rules().await {
+        let AlertSchedule::Interval(interval) = rule.schedule else {
+            continue;
+        };
+        let last = last_evaluation(backend, &rule.name).await;
+        if last.is_some_and(|last| now - last < interval.as_secs() as i64) {
+            continue;
+        }
+        if let Err(e) = evaluate(backend, &rule.name, now).await {
+            tracing::error!("Failed to evaluate alert rule {}: {}", rule.name, e);
+        }
+    }
+}
+
+/// Spawns a task calling [`run_due`] every `tick`.
+pub fn spawn_scheduler<B: StorageBackend>(backend: Arc<B>, tick: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tick);
+        loop {
+            interval.tick().await;
+            run_due(backend.as_ref(), unix_now()).await;
+        }
+    })
+}
+
+/// Evaluates the window close rules of a table if the timestamps of an
+/// ingested batch closed a window since their last evaluation.
+///
+/// A window is closed once a row at or after its end was ingested. The
+/// rule is evaluated at the end of the latest closed window, so rows
+/// arriving later for that window are not seen by the rule. Called by
+/// [`storage::on_ingest`](super::on_ingest).
+pub async fn on_ingest<B: StorageBackend + ?Sized>(backend: &B, table: &str, batch: &RecordBatch) {
+    let rules = backend.table_manager().alert_rules().await;
+    if !rules.iter().any(|rule| rule.schedule == AlertSchedule::WindowClose) {
+        return;
+    }
+    let Some(watermark) = batch.column_by_name("timestamp")
+        .and_then(|column| cast(column, &DataType::Int64).ok())
+        .and_then(|column| column.as_any().downcast_ref::<Int64Array>().and_then(max))
+    else {
+        return;
+    };
+    for rule in rules.into_iter().filter(|rule| rule.schedule == AlertSchedule::WindowClose) {
+        let (source_table, window) = match &rule.source {
+            AlertSource::Metrics { window, .. } => ("metrics".to_string(), *window),
+            AlertSource::View(name) => match backend.table_manager().get_aggregation_view(name).await {
+                Ok(view) => (view.source_table, view.window),
+                Err(_) => continue,
+            },
+        };
+        if source_table != table {
+            continue;
+        }
+        let closed_until = window.window_bounds(watermark).0;
+        if last_evaluation(backend, &rule.name).await.is_some_and(|last| closed_until <= last) {
+            continue;
+        }
+        if let Err(e) = evaluate(backend, &rule.name, closed_until).await {
+            tracing::error!("Failed to evaluate alert rule {}: {}", rule.name, e);
+        }
+    }
+}
+
+async fn last_evaluation<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Option<i64> {
+    let states = backend.table_manager().alert_states().lock().await;
+    states.get(name).and_then(|state| state.last_evaluation)
+}
+
+/// Reads the source of a rule and returns the latest window closed by
+/// `now` of each series.
+async fn read_series<B: StorageBackend + ?Sized>(
+    backend: &B,
+    rule: &AlertRule,
+    now: i64,
+) -> Result<Vec<Series>, Status> {
+    let (batch, aggregates, group_by) = match &rule.source {
+        AlertSource::Metrics { aggregates, group_by, window, range, filter } => {
+            let from = now - range.as_secs() as i64;
+            let batch = backend
+                .aggregate_metrics(aggregates, group_by, *window, from, Some(now), filter.as_ref(), &ResultOptions::default())
+                .await?;
+            (batch, aggregates.clone(), group_by.clone())
+        }
+        AlertSource::View(name) => {
+            let view = backend.table_manager().get_aggregation_view(name).await?;
+            (backend.query_aggregation_view(name).await?, view.aggregates, view.group_by)
+        }
+    };
+
+    let matched = rule.condition.evaluate(&batch)?;
+    let window_start = int_column(&batch, "window_start")?;
+    let window_end = int_column(&batch, "window_end")?;
+    let label_columns: Vec<(String, StringArray)> = group_by.columns.iter()
+        .chain(group_by.time_column.as_ref())
+        .map(|name| {
+            let column = batch.column_by_name(name)
+                .ok_or_else(|| Status::internal(format!("Missing alert source column {}", name)))?;
+            let strings = cast(column, &DataType::Utf8)
+                .map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))?;
+            let strings = strings.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Status::internal(format!("Failed to read column {}", name)))?;
+            Ok((name.clone(), strings.clone()))
+        })
+        .collect::<Result<_, Status>>()?;
+    let value_columns: Vec<(String, Float64Array)> = aggregates.iter()
+        .filter_map(|aggregate| {
+            let name = aggregate.output_name();
+            let column = cast(batch.column_by_name(&name)?, &DataType::Float64).ok()?;
+            Some((name, column.as_any().downcast_ref::<Float64Array>()?.clone()))
+        })
+        .collect();
+
+    let mut latest: BTreeMap<String, Series> = BTreeMap::new();
+    for row in 0..batch.num_rows() {
+        let window = match (&window_start, &window_end) {
+            (Some(start), Some(end)) if start.is_valid(row) && end.is_valid(row) => {
+                Some((start.value(row), end.value(row)))
+            }
+            _ => None,
+        };
+        if window.is_some_and(|(_, end)| end > now) {
+            continue;
+        }
+        let labels: BTreeMap<String, String> = label_columns.iter()
+            .filter(|(_, column)| column.is_valid(row))
+            .map(|(name, column)| (name.clone(), column.value(row).to_string()))
+            .collect();
+        let key = series_key(&labels);
+        if latest.get(&key).is_some_and(|series| series.window >= window) {
+            continue;
+        }
+        let values = value_columns.iter()
+            .filter(|(_, column)| column.is_valid(row))
+            .map(|(name, column)| (name.clone(), column.value(row)))
+            .collect();
+        let matched = matched.is_valid(row) && matched.value(row);
+        latest.insert(key, Series { labels, values, window, matched });
+    }
+    Ok(latest.into_values().collect())
+}
+
+fn int_column(batch: &RecordBatch, name: &str) -> Result<Option<Int64Array>, Status> {
+    let Some(column) = batch.column_by_name(name) else {
+        return Ok(None);
+    };
+    let column = cast(column, &DataType::Int64)
+        .map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))?;
+    Ok(column.as_any().downcast_ref::<Int64Array>().cloned())
+}
+
+fn series_key(labels: &BTreeMap<String, String>) -> String {
+    serde_json::to_string(labels).unwrap_or_default()
+}
+
+/// Applies the series of an evaluation to the alerts of a rule and returns
+/// the alerts that changed state.
+fn transition(rule: &AlertRule, state: &mut RuleState, series: Vec<Series>, now: i64) -> Vec<Alert> {
+    let for_duration = rule.for_duration.as_secs() as i64;
+    let mut changed = Vec::new();
+    let mut seen = HashSet::new();
+    for series in series {
+        let key = series_key(&series.labels);
+        seen.insert(key.clone());
+        let mut labels = series.labels;
+        labels.extend(rule.labels.clone());
+        let (window_start, window_end) = series.window.unzip();
+        let update = |alert: &mut Alert| {
+            alert.values = series.values.clone();
+            alert.evaluated_at = now;
+            alert.window_start = window_start;
+            alert.window_end = window_end;
+        };
+
+        match state.active.get_mut(&key) {
+            None if series.matched => {
+                let mut alert = Alert {
+                    rule: rule.name.clone(),
+                    state: AlertState::Pending,
+                    labels,
+                    values: BTreeMap::new(),
+                    active_since: now,
+                    evaluated_at: now,
+                    window_start,
+                    window_end,
+                };
+                update(&mut alert);
+                if for_duration == 0 {
+                    alert.state = AlertState::Firing;
+                }
+                changed.push(alert.clone());
+                state.active.insert(key, alert);
+            }
+            Some(alert) if series.matched => {
+                update(alert);
+                if alert.state == AlertState::Pending && now - alert.active_since >= for_duration {
+                    alert.state = AlertState::Firing;
+                    changed.push(alert.clone());
+                }
+            }
+            Some(_) => {
+                if let Some(mut alert) = state.active.remove(&key).filter(|a| a.state == AlertState::Firing) {
+                    update(&mut alert);
+                    alert.state = AlertState::Resolved;
+                    changed.push(alert);
+                }
+            }
+            None => {}
+        }
+    }
+
+    // Series missing from the source resolve with their last values
+    let missing: Vec<String> = state.active.keys().filter(|key| !seen.contains(*key)).cloned().collect();
+    for key in missing {
+        if let Some(mut alert) = state.active.remove(&key).filter(|a| a.state == AlertState::Firing) {
+            alert.state = AlertState::Resolved;
+            alert.evaluated_at = now;
+            changed.push(alert);
+        }
+    }
+    changed
+}
+
+/// Alerts of an evaluation waiting to be sent to the notifiers of its rule.
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    rule: String,
+    notifiers: Vec<Arc<dyn Notifier>>,
+    alerts: Vec<Alert>,
+}
+
+/// Spawns the task sending queued alerts to their notifiers, one delivery
+/// after the other. The task ends once the queue is dropped.
+pub(crate) fn spawn_delivery() -> mpsc::Sender<Delivery> {
+    let (sender, mut receiver) = mpsc::channel::<Delivery>(ALERT_DELIVERY_CAPACITY);
+    tokio::spawn(async move {
+        while let Some(delivery) = receiver.recv().await {
+            for notifier in &delivery.notifiers {
+                if let Err(e) = notifier.notify(&delivery.alerts).await {
+                    tracing::error!("Failed to notify alerts of rule {}: {}", delivery.rule, e);
+                }
+            }
+        }
+    });
+    sender
+}
+
+/// Queues the firing and resolved alerts of an evaluation for the notifiers
+/// of the rule and the registered notifiers, logging failures.
+async fn notify<B: StorageBackend + ?Sized>(backend: &B, rule: &AlertRule, alerts: &[Alert]) {
+    let alerts: Vec<Alert> = alerts.iter().filter(|a| a.state != AlertState::Pending).cloned().collect();
+    if alerts.is_empty() {
+        return;
+    }
+    let mut notifiers = Vec::new();
+    for config in &rule.notifiers {
+        match config.build(backend) {
+            Ok(notifier) => notifiers.push(notifier),
+            Err(e) => tracing::error!("Invalid notifier of alert rule {}: {}", rule.name, e),
+        }
+    }
+    notifiers.extend(backend.table_manager().alert_notifiers().await);
+    let delivery = Delivery { rule: rule.name.clone(), notifiers, alerts };
+    if let Err(e) = backend.table_manager().alert_deliveries().try_send(delivery) {
+        tracing::error!("Dropped alerts of rule {}: {}", rule.name, e);
+    }
+}
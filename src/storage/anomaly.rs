@@ -0,0 +1,251 @@
+//! Anomaly monitors scoring metric series window by window.
+//!
+//! An [`AnomalyMonitor`] aggregates the `metrics` table per `metric_id` in
+//! fixed, aligned or calendar windows and feeds each closed window to an
+//! [`AnomalyDetector`] per series. Windows are scored once, in order, when
+//! ingested rows close them, see [`on_ingest`], or on demand with [`run`];
+//! windows without rows are skipped.
+//!
+//! Scores are written back to `metrics` as derived series at the start of
+//! each window: the score under [`score_metric_id`] and a flag of 1 or 0
+//! under [`flag_metric_id`], so alert rules can watch them like any other
+//! metric. Derived series are never scored themselves. Detector state is
+//! kept in memory, so scoring restarts with an empty history after a
+//! restart.
+
+use std::collections::{BTreeMap, HashMap};
+use arrow::compute::{cast, max};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::DataType;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use crate::aggregation::anomaly::{AnomalyDetector, DetectorState};
+use crate::aggregation::filter::{Filter, MatchOp};
+use crate::aggregation::{is_identifier, AggregateExpr, GroupBy, ResultOptions, TimeWindow};
+use crate::metrics::MetricRecord;
+use crate::storage::StorageBackend;
+
+/// Scores a metric aggregate per window with an anomaly detector.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyMonitor {
+    /// Identifier of the monitor, part of the derived metric ids
+    pub name: String,
+    /// Aggregate scored per metric and window, e.g. the average of
+    /// `value_running_window_sum`
+    pub aggregate: AggregateExpr,
+    /// Fixed, aligned or calendar window
+    pub window: TimeWindow,
+    /// Metrics to score, e.g. a `metric_id` matcher; all if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    pub detector: AnomalyDetector,
+}
+
+/// The score of one window of a series.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnomalyScore {
+    pub metric_id: String,
+    pub window_start: i64,
+    pub window_end: i64,
+    pub value: f64,
+    pub expected: f64,
+    pub score: f64,
+    pub anomaly: bool,
+}
+
+/// Scoring progress of a monitor.
+#[derive(Debug, Default)]
+pub(crate) struct MonitorState {
+    /// End of the last scored window
+    scored_until: Option<i64>,
+    series: HashMap<String, DetectorState>,
+}
+
+/// Scoring progress by monitor name.
+pub(crate) type MonitorStates = HashMap<String, MonitorState>;
+
+/// Returns the metric id of the anomaly scores of a metric.
+pub fn score_metric_id(metric_id: &str, monitor: &str) -> String {
+    format!("{}:{}:score", metric_id, monitor)
+}
+
+/// Returns the metric id of the anomaly flags of a metric.
+pub fn flag_metric_id(metric_id: &str, monitor: &str) -> String {
+    format!("{}:{}:anomaly", metric_id, monitor)
+}
+
+/// Validates and registers an anomaly monitor.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the name is not an identifier, the
+/// window is not fixed, aligned or calendar, the detector is invalid, or the
+/// aggregate or filter is invalid for the `metrics` table, and
+/// `Status::already_exists` if a monitor of the same name exists.
+pub async fn create_monitor<B: StorageBackend + ?Sized>(backend: &B, monitor: AnomalyMonitor) -> Result<(), Status> {
+    if !is_identifier(&monitor.name) {
+        return Err(Status::invalid_argument(format!("Invalid anomaly monitor name {}", monitor.name)));
+    }
+    if !matches!(monitor.window, TimeWindow::Fixed(_) | TimeWindow::Aligned { .. } | TimeWindow::Calendar { .. }) {
+        return Err(Status::invalid_argument("Anomaly monitors require a fixed, aligned or calendar window"));
+    }
+    monitor.detector.validate()?;
+    let manager = backend.table_manager();
+    manager.validate_aggregates("metrics", std::slice::from_ref(&monitor.aggregate), &by_metric()).await?;
+    if let Some(filter) = &monitor.filter {
+        manager.validate_filter("metrics", filter).await?;
+    }
+    manager.create_anomaly_monitor(monitor).await
+}
+
+/// Drops an anomaly monitor and its detector state. Its derived series
+/// are kept.
+pub async fn drop_monitor<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Result<(), Status> {
+    let manager = backend.table_manager();
+    let mut states = manager.anomaly_states().lock().await;
+    manager.drop_anomaly_monitor(name).await?;
+    states.remove(name);
+    Ok(())
+}
+
+/// Scores the windows of a monitor closed by `now` that were not scored
+/// yet, writes their derived series and returns the scores, ordered by
+/// metric and window.
+///
+/// The first run scores all windows of the table, so detectors start with
+/// the full history.
+pub async fn run<B: StorageBackend + ?Sized>(backend: &B, name: &str, now: i64) -> Result<Vec<AnomalyScore>, Status> {
+    let manager = backend.table_manager();
+    let monitor = manager.get_anomaly_monitor(name).await?;
+    let closed_until = monitor.window.window_bounds(now).0;
+    let scores = {
+        let mut states = manager.anomaly_states().lock().await;
+        let state = states.entry(monitor.name.clone()).or_default();
+        let from = state.scored_until.unwrap_or(i64::MIN);
+        if closed_until <= from {
+            return Ok(Vec::new());
+        }
+        let filter = match &monitor.filter {
+            Some(filter) => filter.clone().and(not_derived(&manager.anomaly_monitor_names().await)),
+            None => not_derived(&manager.anomaly_monitor_names().await),
+        };
+        let batch = backend.aggregate_metrics(
+            std::slice::from_ref(&monitor.aggregate),
+            &by_metric(),
+            monitor.window,
+            from,
+            Some(closed_until),
+            Some(&filter),
+            &ResultOptions::default(),
+        ).await?;
+        let scores = score_windows(&monitor, state, &batch)?;
+        state.scored_until = Some(closed_until);
+        scores
+    };
+
+    // Written once the state is released, as the insert runs the ingest
+    // hooks again
+    let records = scores.iter()
+        .flat_map(|score| {
+            let record = |metric_id: String, value: f64| MetricRecord {
+                metric_id,
+                timestamp: score.window_start,
+                value_running_window_sum: value,
+                value_running_window_avg: value,
+                value_running_window_count: 1,
+            };
+            [
+                record(score_metric_id(&score.metric_id, name), score.score),
+                record(flag_metric_id(&score.metric_id, name), if score.anomaly { 1.0 } else { 0.0 }),
+            ]
+        })
+        .collect::<Vec<_>>();
+    backend.insert_metrics(records).await?;
+    Ok(scores)
+}
+
+/// Scores the windows of the monitors closed by the timestamps of a batch
+/// ingested into `metrics`; called by
+/// [`storage::on_ingest`](super::on_ingest).
+pub async fn on_ingest<B: StorageBackend + ?Sized>(backend: &B, table: &str, batch: &RecordBatch) {
+    if table != "metrics" {
+        return;
+    }
+    let names = backend.table_manager().anomaly_monitor_names().await;
+    if names.is_empty() {
+        return;
+    }
+    let Some(watermark) = batch.column_by_name("timestamp")
+        .and_then(|column| cast(column, &DataType::Int64).ok())
+        .and_then(|column| column.as_any().downcast_ref::<Int64Array>().and_then(max))
+    else {
+        return;
+    };
+    for name in names {
+        if let Err(e) = run(backend, &name, watermark).await {
+            tracing::error!("Failed to score anomaly monitor {}: {}", name, e);
+        }
+    }
+}
+
+fn by_metric() -> GroupBy {
+    GroupBy { columns: vec!["metric_id".to_string()], time_column: None }
+}
+
+/// Excludes the derived series of all monitors.
+fn not_derived(monitors: &[String]) -> Filter {
+    let pattern = format!(".*:({}):(score|anomaly)", monitors.join("|"));
+    Filter::Not(Box::new(Filter::matches("metric_id", MatchOp::Regex, pattern)))
+}
+
+/// Feeds the windows of an aggregation result to the detectors of their
+/// series, in window order.
+fn score_windows(
+    monitor: &AnomalyMonitor,
+    state: &mut MonitorState,
+    batch: &RecordBatch,
+) -> Result<Vec<AnomalyScore>, Status> {
+    let column = |name: &str, data_type: &DataType| {
+        let column = batch.column_by_name(name)
+            .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", name)))?;
+        cast(column, data_type).map_err(|e| Status::internal(format!("Failed to read column {}: {}", name, e)))
+    };
+    let metric_ids = column("metric_id", &DataType::Utf8)?;
+    let metric_ids = metric_ids.as_any().downcast_ref::<StringArray>()
+        .ok_or_else(|| Status::internal("Failed to read column metric_id"))?;
+    let starts = column("window_start", &DataType::Int64)?;
+    let starts = starts.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_start"))?;
+    let ends = column("window_end", &DataType::Int64)?;
+    let ends = ends.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read column window_end"))?;
+    let values = column(&monitor.aggregate.output_name(), &DataType::Float64)?;
+    let values = values.as_any().downcast_ref::<Float64Array>()
+        .ok_or_else(|| Status::internal("Failed to read aggregate column"))?;
+
+    let mut windows: BTreeMap<(&str, i64), (i64, f64)> = BTreeMap::new();
+    for row in 0..batch.num_rows() {
+        if metric_ids.is_valid(row) && values.is_valid(row) {
+            windows.insert((metric_ids.value(row), starts.value(row)), (ends.value(row), values.value(row)));
+        }
+    }
+    let mut scores = Vec::new();
+    for ((metric_id, window_start), (window_end, value)) in windows {
+        let detector = match state.series.get_mut(metric_id) {
+            Some(detector) => detector,
+            None => state.series.entry(metric_id.to_string()).or_insert(monitor.detector.start()?),
+        };
+        if let Some(observation) = detector.observe(value) {
+            scores.push(AnomalyScore {
+                metric_id: metric_id.to_string(),
+                window_start,
+                window_end,
+                value,
+                expected: observation.expected,
+                score: observation.score,
+                anomaly: observation.anomaly,
+            });
+        }
+    }
+    Ok(scores)
+}
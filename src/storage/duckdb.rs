@@ -39,21 +39,28 @@ use std::sync::Arc;
 use duckdb::{Connection, Config, params, ToSql};
 use tokio::sync::Mutex;
 use tonic::Status;
-use crate::metrics::MetricRecord;
+use crate::metrics::{get_metrics_schema, get_metrics_schema_adapter, MetricRecord};
 use crate::config::Credentials;
-use crate::storage::{StorageBackend, BatchAggregation};
+use crate::storage::{aggregate_windows, aggregation_result, INGEST_WINDOW, counted_aggregates, materialized, on_ingest, StorageBackend, BatchAggregation};
 use crate::storage::cache::{CacheManager, CacheEviction};
 use crate::storage::table_manager::{TableManager, AggregationView};
-use crate::aggregation::{TimeWindow, AggregateFunction, GroupBy, AggregateResult, build_aggregate_query};
+use crate::aggregation::{TimeWindow, AggregateExpr, GroupBy, ResultOptions, aggregate_output_schema, build_windowed_aggregate_query};
+use crate::aggregation::filter::{Filter, SqlDialect};
+use crate::aggregation::hll::HyperLogLog;
+use crate::aggregation::sketch::QuantileSketch;
 use async_trait::async_trait;
 use arrow::datatypes::{DataType, Field, Schema};
 use arrow::array::{
-    Array, ArrayRef, RecordBatch, Int64Array, Float64Array, StringArray,
+    Array, ArrayRef, RecordBatch, Int64Array, Float64Array, StringArray, BinaryArray, BooleanArray,
 };
 use arrow::array::builder::{
-    ArrayBuilder, Int64Builder, Float64Builder, StringBuilder,
+    ArrayBuilder, Int64Builder, Float64Builder, StringBuilder, BinaryBuilder, BooleanBuilder,
 };
-use std::time::Duration;
+
+/// Columns of `metric_aggregations`, in the order of [`BatchAggregation::schema`].
+const AGGREGATION_COLUMNS: &str = "metric_id, window_start, window_end, \
+    running_sum, running_count, min_value, max_value, quantile_sketch, \
+    sample_count, m2, first_timestamp, first_value, last_timestamp, last_value, distinct_sketch";
 
 /// DuckDB-based storage backend for metrics.
 #[derive(Clone)]
@@ -96,16 +103,11 @@ impl DuckDbBackend {
         Self::new(":memory:".to_string(), HashMap::new(), Some(0))
     }
 
-    /// Inserts a batch of metrics with optimized aggregation updates.
-    async fn insert_batch_optimized(&self, metrics: &[MetricRecord], window: TimeWindow) -> Result<(), Status> {
-        let conn = self.conn.lock().await;
-        
-        // Begin transaction
-        conn.execute("BEGIN TRANSACTION", params![])
-            .map_err(|e| Status::internal(format!("Failed to begin transaction: {}", e)))?;
-
-        // Convert metrics to RecordBatch for efficient insertion
-        let batch = Self::prepare_params(metrics)?;
+    /// Inserts metric rows and merges them into the windowed aggregation
+    /// state; callers must hold the connection for the duration of a
+    /// transaction.
+    fn write_metrics(conn: &Connection, batch: &RecordBatch) -> Result<(), Status> {
+        let metrics = MetricRecord::try_from_record_batch(batch)?;
 
         // Insert metrics using prepared statement
         let mut stmt = conn.prepare(r#"
@@ -118,92 +120,128 @@ impl DuckDbBackend {
             ) VALUES (?, ?, ?, ?, ?)
         "#).map_err(|e| Status::internal(format!("Failed to prepare statement: {}", e)))?;
 
-        // Bind and execute in batches
-        for i in 0..batch.num_rows() {
-            let metric_id = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap().value(i);
-            let timestamp = batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap().value(i);
-            let sum = batch.column(2).as_any().downcast_ref::<Float64Array>().unwrap().value(i);
-            let avg = batch.column(3).as_any().downcast_ref::<Float64Array>().unwrap().value(i);
-            let count = batch.column(4).as_any().downcast_ref::<Int64Array>().unwrap().value(i);
-
+        for metric in &metrics {
             stmt.execute(params![
-                metric_id,
-                timestamp,
-                sum,
-                avg,
-                count,
+                metric.metric_id,
+                metric.timestamp,
+                metric.value_running_window_sum,
+                metric.value_running_window_avg,
+                metric.value_running_window_count,
             ]).map_err(|e| Status::internal(format!("Failed to insert metrics: {}", e)))?;
         }
 
-        // Update aggregations based on window
-        let window_start = match window {
-            TimeWindow::Sliding { window, slide: _ } => {
-                let now = metrics.iter().map(|m| m.timestamp).max().unwrap_or(0);
-                now - window.as_nanos() as i64
-            }
-            TimeWindow::Fixed(start) => start.as_nanos() as i64,
-            TimeWindow::None => metrics.iter().map(|m| m.timestamp).min().unwrap_or(0),
-        };
+        // Group metrics by ID and window and calculate aggregations
+        let aggregations = aggregate_windows(&metrics, INGEST_WINDOW, |m| m.value_running_window_count);
+        Self::upsert_aggregations(conn, &aggregations)
+    }
 
-        let window_end = match window {
-            TimeWindow::Sliding { window: _, slide: _ } => {
-                metrics.iter().map(|m| m.timestamp).max().unwrap_or(0)
+    /// Writes adapted batches to their tables; callers must hold the
+    /// connection for the duration of a transaction.
+    fn write_batches(conn: &Connection, batches: &[(String, RecordBatch)]) -> Result<(), Status> {
+        for (table_name, batch) in batches {
+            if table_name == "metrics" {
+                Self::write_metrics(conn, batch)?;
+            } else {
+                Self::insert_rows(conn, table_name, batch)?;
             }
-            TimeWindow::Fixed(end) => end.as_nanos() as i64,
-            TimeWindow::None => metrics.iter().map(|m| m.timestamp).max().unwrap_or(0),
-        };
-
-        // Group metrics by ID and calculate aggregations
-        let mut aggregations = HashMap::new();
-        for metric in metrics {
-            let entry = aggregations.entry(metric.metric_id.clone()).or_insert_with(|| BatchAggregation {
-                metric_id: metric.metric_id.clone(),
-                window_start,
-                window_end,
-                running_sum: 0.0,
-                running_count: 0,
-                min_value: f64::INFINITY,
-                max_value: f64::NEG_INFINITY,
-            });
-
-            entry.running_sum += metric.value_running_window_sum;
-            entry.running_count += metric.value_running_window_count as i64;
-            entry.min_value = entry.min_value.min(metric.value_running_window_sum);
-            entry.max_value = entry.max_value.max(metric.value_running_window_sum);
         }
+        Ok(())
+    }
 
-        // Update aggregations table using prepared statement with proper type handling
-        let mut agg_stmt = conn.prepare(r#"
-            INSERT INTO metric_aggregations (
-                metric_id, window_start, window_end,
-                running_sum, running_count, min_value, max_value
-            ) VALUES (?, ?, ?, ?, ?, ?, ?)
+    /// Merges partial aggregations into the `metric_aggregations` table.
+    ///
+    /// Sketches and variance state cannot be merged in SQL, so the stored
+    /// state of each window is read and merged with
+    /// [`BatchAggregation::merge`] first; callers must hold the connection
+    /// for the duration of a transaction.
+    fn upsert_aggregations(conn: &Connection, aggregations: &[BatchAggregation]) -> Result<(), Status> {
+        let mut select_stmt = conn.prepare(&format!(
+            "SELECT {} FROM metric_aggregations WHERE metric_id = ? AND window_start = ? AND window_end = ?",
+            AGGREGATION_COLUMNS
+        )).map_err(|e| Status::internal(format!("Failed to prepare aggregation query: {}", e)))?;
+
+        let mut agg_stmt = conn.prepare(&format!(r#"
+            INSERT INTO metric_aggregations ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT (metric_id, window_start, window_end) DO UPDATE
-            SET running_sum = metric_aggregations.running_sum + EXCLUDED.running_sum,
-                running_count = metric_aggregations.running_count + EXCLUDED.running_count,
-                min_value = LEAST(metric_aggregations.min_value, EXCLUDED.min_value),
-                max_value = GREATEST(metric_aggregations.max_value, EXCLUDED.max_value)
-        "#).map_err(|e| Status::internal(format!("Failed to prepare aggregation statement: {}", e)))?;
+            SET running_sum = EXCLUDED.running_sum,
+                running_count = EXCLUDED.running_count,
+                min_value = EXCLUDED.min_value,
+                max_value = EXCLUDED.max_value,
+                quantile_sketch = EXCLUDED.quantile_sketch,
+                sample_count = EXCLUDED.sample_count,
+                m2 = EXCLUDED.m2,
+                first_timestamp = EXCLUDED.first_timestamp,
+                first_value = EXCLUDED.first_value,
+                last_timestamp = EXCLUDED.last_timestamp,
+                last_value = EXCLUDED.last_value,
+                distinct_sketch = EXCLUDED.distinct_sketch
+        "#, AGGREGATION_COLUMNS)).map_err(|e| Status::internal(format!("Failed to prepare aggregation statement: {}", e)))?;
+
+        for agg in aggregations {
+            let mut rows = select_stmt
+                .query(params![agg.metric_id, agg.window_start, agg.window_end])
+                .map_err(|e| Status::internal(format!("Failed to read aggregation: {}", e)))?;
+            let merged = match rows.next().map_err(|e| Status::internal(e.to_string()))? {
+                Some(row) => {
+                    let mut stored = Self::read_aggregation(row)?;
+                    stored.merge(agg)?;
+                    stored
+                }
+                None => agg.clone(),
+            };
 
-        for agg in aggregations.values() {
             agg_stmt.execute(params![
-                agg.metric_id,
-                agg.window_start,
-                agg.window_end,
-                agg.running_sum,
-                agg.running_count,
-                agg.min_value,
-                agg.max_value,
+                merged.metric_id,
+                merged.window_start,
+                merged.window_end,
+                merged.running_sum,
+                merged.running_count,
+                merged.min_value,
+                merged.max_value,
+                merged.quantile_sketch.as_ref().map(|sketch| sketch.to_bytes()).transpose()?,
+                merged.sample_count,
+                merged.m2,
+                merged.first_timestamp,
+                merged.first_value,
+                merged.last_timestamp,
+                merged.last_value,
+                merged.distinct_sketch.as_ref().map(|sketch| sketch.to_bytes()).transpose()?,
             ]).map_err(|e| Status::internal(format!("Failed to update aggregations: {}", e)))?;
         }
 
-        // Commit transaction
-        conn.execute("COMMIT", params![])
-            .map_err(|e| Status::internal(format!("Failed to commit transaction: {}", e)))?;
-
         Ok(())
     }
 
+    /// Reads a `metric_aggregations` row selected with [`AGGREGATION_COLUMNS`].
+    ///
+    /// Columns added after a row was written are NULL and read as empty
+    /// state or missing sketches, see [`BatchAggregation::from_record_batch`].
+    fn read_aggregation(row: &duckdb::Row<'_>) -> Result<BatchAggregation, Status> {
+        let get_err = |e: duckdb::Error| Status::internal(format!("Failed to read aggregation: {}", e));
+        let mut agg = BatchAggregation::new(
+            row.get(0).map_err(get_err)?,
+            row.get(1).map_err(get_err)?,
+            row.get(2).map_err(get_err)?,
+        );
+        agg.running_sum = row.get(3).map_err(get_err)?;
+        agg.running_count = row.get(4).map_err(get_err)?;
+        agg.min_value = row.get(5).map_err(get_err)?;
+        agg.max_value = row.get(6).map_err(get_err)?;
+        agg.quantile_sketch = row.get::<_, Option<Vec<u8>>>(7).map_err(get_err)?
+            .map(|bytes| QuantileSketch::from_bytes(&bytes))
+            .transpose()?;
+        agg.sample_count = row.get::<_, Option<i64>>(8).map_err(get_err)?.unwrap_or(agg.running_count);
+        agg.m2 = row.get::<_, Option<f64>>(9).map_err(get_err)?.unwrap_or(0.0);
+        agg.first_timestamp = row.get::<_, Option<i64>>(10).map_err(get_err)?.unwrap_or(i64::MAX);
+        agg.first_value = row.get::<_, Option<f64>>(11).map_err(get_err)?.unwrap_or(f64::NAN);
+        agg.last_timestamp = row.get::<_, Option<i64>>(12).map_err(get_err)?.unwrap_or(i64::MIN);
+        agg.last_value = row.get::<_, Option<f64>>(13).map_err(get_err)?.unwrap_or(f64::NAN);
+        agg.distinct_sketch = row.get::<_, Option<Vec<u8>>>(14).map_err(get_err)?
+            .map(|bytes| HyperLogLog::from_bytes(&bytes))
+            .transpose()?;
+        Ok(agg)
+    }
+
     /// Prepares parameters for batch insertion
     fn prepare_params(metrics: &[MetricRecord]) -> Result<RecordBatch, Status> {
         let schema = Arc::new(Schema::new(vec![
@@ -264,6 +302,14 @@ impl DuckDbBackend {
                 running_count BIGINT NOT NULL,
                 min_value DOUBLE NOT NULL,
                 max_value DOUBLE NOT NULL,
+                quantile_sketch BLOB,
+                sample_count BIGINT,
+                m2 DOUBLE,
+                first_timestamp BIGINT,
+                first_value DOUBLE,
+                last_timestamp BIGINT,
+                last_value DOUBLE,
+                distinct_sketch BLOB,
                 PRIMARY KEY (metric_id, window_start, window_end)
             )
         "#, params![]).map_err(|e| Status::internal(e.to_string()))?;
@@ -319,13 +365,31 @@ impl StorageBackend for DuckDbBackend {
                 running_count BIGINT NOT NULL,
                 min_value DOUBLE NOT NULL,
                 max_value DOUBLE NOT NULL,
+                quantile_sketch BLOB,
+                sample_count BIGINT,
+                m2 DOUBLE,
+                first_timestamp BIGINT,
+                first_value DOUBLE,
+                last_timestamp BIGINT,
+                last_value DOUBLE,
+                distinct_sketch BLOB,
                 PRIMARY KEY (metric_id, window_start, window_end)
             );
 
             CREATE INDEX IF NOT EXISTS idx_aggregations_window 
             ON metric_aggregations(window_start, window_end);
+
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS quantile_sketch BLOB;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS sample_count BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS m2 DOUBLE;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS first_timestamp BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS first_value DOUBLE;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS last_timestamp BIGINT;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS last_value DOUBLE;
+            ALTER TABLE metric_aggregations ADD COLUMN IF NOT EXISTS distinct_sketch BLOB;
         "#).map_err(|e| Status::internal(format!("Failed to create tables: {}", e)))?;
 
+        self.table_manager.register_table("metrics", get_metrics_schema()).await;
         Ok(())
     }
 
@@ -333,21 +397,7 @@ impl StorageBackend for DuckDbBackend {
         if metrics.is_empty() {
             return Ok(());
         }
-
-        // Check if eviction is needed
-        if let Some(cutoff) = self.cache_manager.should_evict().await? {
-            let query = self.cache_manager.eviction_query(cutoff);
-            self.execute_eviction(&query).await?;
-        }
-
-        // Use sliding window for batch-level aggregations
-        let window = TimeWindow::Sliding {
-            window: Duration::from_secs(3600), // 1 hour window
-            slide: Duration::from_secs(60),    // 1 minute slide
-        };
-
-        // Use optimized batch insertion
-        self.insert_batch_optimized(&metrics, window).await
+        self.insert_into_tables(vec![("metrics".to_string(), Self::prepare_params(&metrics)?)]).await
     }
 
     async fn query_metrics(&self, from_timestamp: i64) -> Result<Vec<MetricRecord>, Status> {
@@ -396,45 +446,48 @@ impl StorageBackend for DuckDbBackend {
 
     async fn aggregate_metrics(
         &self,
-        function: AggregateFunction,
+        aggregates: &[AggregateExpr],
         group_by: &GroupBy,
+        window: TimeWindow,
         from_timestamp: i64,
         to_timestamp: Option<i64>,
-    ) -> Result<Vec<AggregateResult>, Status> {
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+    ) -> Result<RecordBatch, Status> {
         // Check if eviction is needed
         if let Some(cutoff) = self.cache_manager.should_evict().await? {
             let query = self.cache_manager.eviction_query(cutoff);
             self.execute_eviction(&query).await?;
         }
 
-        let query = build_aggregate_query(
+        let aggregates = counted_aggregates(aggregates);
+        let windowed = !matches!(window, TimeWindow::None);
+        self.table_manager.validate_aggregates("metrics", &aggregates, group_by).await?;
+        window.validate()?;
+        if let Some(filter) = filter {
+            self.table_manager.validate_filter("metrics", filter).await?;
+        }
+        self.table_manager.validate_result_options("metrics", &aggregates, group_by, windowed, options).await?;
+        let query = build_windowed_aggregate_query(
             "metrics",
-            function,
+            &aggregates,
             group_by,
-            &["value_running_window_sum"],
+            window,
             Some(from_timestamp),
             to_timestamp,
-        );
-
-        let conn = self.conn.lock().await;
-        let mut stmt = conn.prepare(&query)
-            .map_err(|e| Status::internal(e.to_string()))?;
-
-        let mut rows = stmt.query(params![])
-            .map_err(|e| Status::internal(e.to_string()))?;
-
-        let mut results = Vec::new();
-        while let Some(row) = rows.next().map_err(|e| Status::internal(e.to_string()))? {
-            let value: f64 = row.get(0).map_err(|e| Status::internal(e.to_string()))?;
-            let timestamp: i64 = row.get(1).map_err(|e| Status::internal(e.to_string()))?;
-            
-            results.push(AggregateResult {
-                value,
-                timestamp,
-            });
-        }
-
-        Ok(results)
+            filter,
+            options,
+            SqlDialect::DuckDb,
+        )?;
+
+        let schema = self.table_manager.get_table_schema("metrics").await?;
+        let output = aggregate_output_schema(&aggregates, group_by, true, &schema)?;
+        let rows = aggregate_output_schema(&aggregates, group_by, windowed, &schema)?;
+        let batch = {
+            let conn = self.conn.lock().await;
+            Self::read_batch(&conn, &query, rows)?
+        };
+        aggregation_result(&[batch], output, window, from_timestamp, to_timestamp)
     }
 
     fn new_with_options(
@@ -467,135 +520,143 @@ impl StorageBackend for DuckDbBackend {
     }
 
     async fn insert_into_table(&self, table_name: &str, batch: RecordBatch) -> Result<(), Status> {
-        let conn = self.conn.lock().await;
-        let mut stmt = conn.prepare(&format!("INSERT INTO {} VALUES ({})",
-            table_name,
-            (0..batch.num_columns()).map(|_| "?").collect::<Vec<_>>().join(", ")
-        )).map_err(|e| Status::internal(e.to_string()))?;
+        self.insert_into_tables(vec![(table_name.to_string(), batch)]).await
+    }
 
-        for row_idx in 0..batch.num_rows() {
-            let mut param_values: Vec<Box<dyn ToSql>> = Vec::new();
-            for col_idx in 0..batch.num_columns() {
-                let col = batch.column(col_idx);
-                match col.data_type() {
-                    DataType::Int64 => {
-                        let array = col.as_any().downcast_ref::<Int64Array>().unwrap();
-                        param_values.push(Box::new(array.value(row_idx)));
-                    }
-                    DataType::Float64 => {
-                        let array = col.as_any().downcast_ref::<Float64Array>().unwrap();
-                        param_values.push(Box::new(array.value(row_idx)));
-                    }
-                    DataType::Utf8 => {
-                        let array = col.as_any().downcast_ref::<StringArray>().unwrap();
-                        param_values.push(Box::new(array.value(row_idx).to_string()));
-                    }
-                    _ => return Err(Status::internal("Unsupported column type")),
-                }
+    async fn insert_into_tables(&self, batches: Vec<(String, RecordBatch)>) -> Result<(), Status> {
+        let mut adapted = Vec::with_capacity(batches.len());
+        for (table_name, batch) in batches {
+            let batch = if table_name == "metrics" {
+                get_metrics_schema_adapter().adapt(&batch)?
+            } else {
+                self.table_manager.adapt_batch(&table_name, &batch).await?
+            };
+            adapted.push((table_name, batch));
+        }
+
+        // Check if eviction is needed
+        if adapted.iter().any(|(table_name, _)| table_name == "metrics") {
+            if let Some(cutoff) = self.cache_manager.should_evict().await? {
+                let query = self.cache_manager.eviction_query(cutoff);
+                self.execute_eviction(&query).await?;
             }
+        }
 
-            let param_refs: Vec<&dyn ToSql> = param_values.iter().map(|p| p.as_ref()).collect();
-            stmt.execute(param_refs.as_slice()).map_err(|e| Status::internal(e.to_string()))?;
+        let sources = materialized::lock_sources(self, &adapted).await;
+        {
+            let conn = self.conn.lock().await;
+            conn.execute("BEGIN TRANSACTION", params![])
+                .map_err(|e| Status::internal(format!("Failed to begin transaction: {}", e)))?;
+            if let Err(e) = Self::write_batches(&conn, &adapted) {
+                let _ = conn.execute("ROLLBACK", params![]);
+                return Err(e);
+            }
+            conn.execute("COMMIT", params![])
+                .map_err(|e| Status::internal(format!("Failed to commit transaction: {}", e)))?;
         }
 
+        on_ingest(self, &adapted, sources).await;
         Ok(())
     }
 
-    async fn query_table(&self, table_name: &str, projection: Option<Vec<String>>) -> Result<RecordBatch, Status> {
+    async fn query_table(
+        &self,
+        table_name: &str,
+        projection: Option<Vec<String>>,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
         let schema = self.table_manager.get_table_schema(table_name).await?;
         
-        let mut builders: Vec<Box<dyn ArrayBuilder>> = schema.fields().iter()
-            .map(|field| Self::create_array_builder(field))
-            .collect();
-        
         let projection = projection.unwrap_or_else(|| {
             schema.fields().iter().map(|f| f.name().clone()).collect()
         });
 
-        let sql = format!(
+        let mut sql = format!(
             "SELECT {} FROM {}",
             projection.join(", "),
             table_name
         );
+        if let Some(filter) = filter {
+            filter.validate(&schema)?;
+            sql.push_str(&format!(" WHERE {}", filter.to_sql(SqlDialect::DuckDb)?));
+        }
 
         let conn = self.conn.lock().await;
-        let mut stmt = conn.prepare(&sql)
-            .map_err(|e| Status::internal(e.to_string()))?;
+        Self::read_batch(&conn, &sql, schema)
+    }
 
-        let mut rows = stmt.query(params![])
-            .map_err(|e| Status::internal(e.to_string()))?;
+    async fn delete_from_table(&self, table_name: &str, filter: &Filter) -> Result<(), Status> {
+        self.table_manager.validate_filter(table_name, filter).await?;
+        let sql = format!("DELETE FROM {} WHERE {}", table_name, filter.to_sql(SqlDialect::DuckDb)?);
+        self.execute(&sql).await?;
+        materialized::mark_stale(self, table_name).await;
+        Ok(())
+    }
 
-        while let Some(row) = rows.next().map_err(|e| Status::internal(e.to_string()))? {
-            for (i, field) in schema.fields().iter().enumerate() {
-                match field.data_type() {
-                    DataType::Int64 => {
-                        let builder = builders[i].as_any_mut().downcast_mut::<Int64Builder>().unwrap();
-                        match row.get::<usize, i64>(i) {
-                            Ok(value) => builder.append_value(value),
-                            Err(_) => builder.append_null(),
-                        }
-                    }
-                    DataType::Float64 => {
-                        let builder = builders[i].as_any_mut().downcast_mut::<Float64Builder>().unwrap();
-                        match row.get::<usize, f64>(i) {
-                            Ok(value) => builder.append_value(value),
-                            Err(_) => builder.append_null(),
-                        }
-                    }
-                    DataType::Utf8 => {
-                        let builder = builders[i].as_any_mut().downcast_mut::<StringBuilder>().unwrap();
-                        match row.get::<usize, String>(i) {
-                            Ok(value) => builder.append_value(value),
-                            Err(_) => builder.append_null(),
-                        }
-                    }
-                    _ => return Err(Status::internal("Unsupported column type")),
-                }
-            }
+    async fn replace_in_tables(&self, replacements: Vec<(String, Filter, RecordBatch)>) -> Result<(), Status> {
+        let mut statements = Vec::with_capacity(replacements.len());
+        for (table_name, filter, batch) in replacements {
+            self.table_manager.validate_filter(&table_name, &filter).await?;
+            let delete = format!("DELETE FROM {} WHERE {}", table_name, filter.to_sql(SqlDialect::DuckDb)?);
+            let batch = self.table_manager.adapt_batch(&table_name, &batch).await?;
+            statements.push((delete, table_name, batch));
         }
 
-        let arrays: Vec<ArrayRef> = builders.into_iter()
-            .map(|mut builder| Arc::new(builder.finish()) as ArrayRef)
-            .collect();
-
-        Ok(RecordBatch::try_new(Arc::new(schema), arrays)
-            .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))?)
+        let conn = self.conn.lock().await;
+        conn.execute("BEGIN TRANSACTION", params![])
+            .map_err(|e| Status::internal(format!("Failed to begin transaction: {}", e)))?;
+        let result = statements.iter().try_for_each(|(delete, table_name, batch)| {
+            conn.execute(delete, params![])
+                .map_err(|e| Status::internal(format!("Failed to delete rows: {}", e)))?;
+            Self::insert_rows(&conn, table_name, batch)
+        });
+        if let Err(e) = result {
+            let _ = conn.execute("ROLLBACK", params![]);
+            return Err(e);
+        }
+        conn.execute("COMMIT", params![])
+            .map_err(|e| Status::internal(format!("Failed to commit transaction: {}", e)))?;
+        Ok(())
     }
 
     async fn create_aggregation_view(&self, view: &AggregationView) -> Result<(), Status> {
-        let columns: Vec<&str> = view.aggregate_columns.iter()
-            .map(|s| s.as_str())
-            .collect();
-            
-        let sql = build_aggregate_query(
+        self.table_manager.validate_aggregates(&view.source_table, &view.aggregates, &view.group_by).await?;
+        view.window.validate()?;
+        if let Some(filter) = &view.filter {
+            self.table_manager.validate_filter(&view.source_table, filter).await?;
+        }
+        let view_name = format!("agg_view_{}", view.source_table);
+        if view.materialized {
+            return materialized::create(self, &view_name, view).await;
+        }
+
+        let sql = build_windowed_aggregate_query(
             &view.source_table,
-            view.function,
+            &view.aggregates,
             &view.group_by,
-            &columns,
+            view.window,
             None,
-            None
-        );
-        
-        let view_name = format!("agg_view_{}", view.source_table);
+            None,
+            view.filter.as_ref(),
+            &ResultOptions::default(),
+            SqlDialect::DuckDb,
+        )?;
+
         let conn = self.conn.lock().await;
         conn.execute(&format!("CREATE VIEW {} AS {}", view_name, sql), params![])
             .map_err(|e| Status::internal(format!("Failed to create view: {}", e)))?;
 
         // Register view in manager
-        self.table_manager.create_aggregation_view(
-            view_name,
-            view.source_table.clone(),
-            view.function.clone(),
-            view.group_by.clone(),
-            view.window.clone(),
-            view.aggregate_columns.clone(),
-        ).await?;
+        self.table_manager.create_aggregation_view(view_name, view.clone()).await?;
 
         Ok(())
     }
 
     async fn query_aggregation_view(&self, view_name: &str) -> Result<RecordBatch, Status> {
-        self.query_table(view_name, None).await
+        match self.table_manager.get_aggregation_view(view_name).await {
+            Ok(view) if view.materialized => materialized::query(self, view_name).await,
+            _ => self.query_table(view_name, None, None).await,
+        }
     }
 
     async fn drop_table(&self, table_name: &str) -> Result<(), Status> {
@@ -608,6 +669,14 @@ impl StorageBackend for DuckDbBackend {
     }
 
     async fn drop_aggregation_view(&self, view_name: &str) -> Result<(), Status> {
+        if let Ok(view) = self.table_manager.get_aggregation_view(view_name).await {
+            if view.materialized {
+                self.table_manager.drop_aggregation_view(view_name).await?;
+                self.drop_table(view_name).await?;
+                return self.drop_table(&materialized::state_table(view_name)).await;
+            }
+        }
+
         let conn = self.conn.lock().await;
         conn.execute(&format!("DROP VIEW IF EXISTS {}", view_name), params![])
             .map_err(|e| Status::internal(format!("Failed to drop view: {}", e)))?;
@@ -619,9 +688,163 @@ impl StorageBackend for DuckDbBackend {
     fn table_manager(&self) -> &TableManager {
         &self.table_manager
     }
+
+    async fn query_batch_aggregations(
+        &self,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<Vec<BatchAggregation>, Status> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(&format!(r#"
+            SELECT {} FROM metric_aggregations
+            WHERE window_start >= ? AND window_end <= ?
+            ORDER BY metric_id, window_start, window_end
+        "#, AGGREGATION_COLUMNS)).map_err(|e| Status::internal(format!("Failed to prepare aggregation query: {}", e)))?;
+
+        let mut rows = stmt.query(params![from_timestamp, to_timestamp.unwrap_or(i64::MAX)])
+            .map_err(|e| Status::internal(format!("Failed to query aggregations: {}", e)))?;
+
+        let mut aggregations = Vec::new();
+        while let Some(row) = rows.next().map_err(|e| Status::internal(e.to_string()))? {
+            aggregations.push(Self::read_aggregation(row)?);
+        }
+
+        Ok(aggregations)
+    }
+
+    async fn insert_batch_aggregations(
+        &self,
+        aggregations: Vec<BatchAggregation>,
+    ) -> Result<(), Status> {
+        if aggregations.is_empty() {
+            return Ok(());
+        }
+
+        let conn = self.conn.lock().await;
+        conn.execute("BEGIN TRANSACTION", params![])
+            .map_err(|e| Status::internal(format!("Failed to begin transaction: {}", e)))?;
+
+        if let Err(e) = Self::upsert_aggregations(&conn, &aggregations) {
+            let _ = conn.execute("ROLLBACK", params![]);
+            return Err(e);
+        }
+
+        conn.execute("COMMIT", params![])
+            .map_err(|e| Status::internal(format!("Failed to commit transaction: {}", e)))?;
+        Ok(())
+    }
 }
 
 impl DuckDbBackend {
+    /// Runs a query and reads its rows into a batch of `schema`, whose
+    /// columns must match those of the query.
+    fn read_batch(conn: &Connection, sql: &str, schema: Schema) -> Result<RecordBatch, Status> {
+        let mut builders: Vec<Box<dyn ArrayBuilder>> = schema.fields().iter()
+            .map(|field| Self::create_array_builder(field))
+            .collect();
+        let mut stmt = conn.prepare(sql)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut rows = stmt.query(params![])
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        while let Some(row) = rows.next().map_err(|e| Status::internal(e.to_string()))? {
+            for (i, field) in schema.fields().iter().enumerate() {
+                match field.data_type() {
+                    DataType::Int64 => {
+                        let builder = builders[i].as_any_mut().downcast_mut::<Int64Builder>().unwrap();
+                        match row.get::<usize, i64>(i) {
+                            Ok(value) => builder.append_value(value),
+                            Err(_) => builder.append_null(),
+                        }
+                    }
+                    DataType::Float64 => {
+                        let builder = builders[i].as_any_mut().downcast_mut::<Float64Builder>().unwrap();
+                        match row.get::<usize, f64>(i) {
+                            Ok(value) => builder.append_value(value),
+                            Err(_) => builder.append_null(),
+                        }
+                    }
+                    DataType::Utf8 => {
+                        let builder = builders[i].as_any_mut().downcast_mut::<StringBuilder>().unwrap();
+                        match row.get::<usize, String>(i) {
+                            Ok(value) => builder.append_value(value),
+                            Err(_) => builder.append_null(),
+                        }
+                    }
+                    DataType::Boolean => {
+                        let builder = builders[i].as_any_mut().downcast_mut::<BooleanBuilder>().unwrap();
+                        match row.get::<usize, bool>(i) {
+                            Ok(value) => builder.append_value(value),
+                            Err(_) => builder.append_null(),
+                        }
+                    }
+                    DataType::Binary => {
+                        let builder = builders[i].as_any_mut().downcast_mut::<BinaryBuilder>().unwrap();
+                        match row.get::<usize, Vec<u8>>(i) {
+                            Ok(value) => builder.append_value(value),
+                            Err(_) => builder.append_null(),
+                        }
+                    }
+                    _ => return Err(Status::internal("Unsupported column type")),
+                }
+            }
+        }
+
+        let arrays: Vec<ArrayRef> = builders.into_iter()
+            .map(|mut builder| Arc::new(builder.finish()) as ArrayRef)
+            .collect();
+
+        RecordBatch::try_new(Arc::new(schema), arrays)
+            .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))
+    }
+
+    /// Inserts the rows of a batch matching the columns of a table.
+    fn insert_rows(conn: &Connection, table_name: &str, batch: &RecordBatch) -> Result<(), Status> {
+        let mut stmt = conn.prepare(&format!("INSERT INTO {} VALUES ({})",
+            table_name,
+            (0..batch.num_columns()).map(|_| "?").collect::<Vec<_>>().join(", ")
+        )).map_err(|e| Status::internal(e.to_string()))?;
+
+        for row_idx in 0..batch.num_rows() {
+            let mut param_values: Vec<Box<dyn ToSql>> = Vec::new();
+            for col_idx in 0..batch.num_columns() {
+                let col = batch.column(col_idx);
+                if col.is_null(row_idx) {
+                    param_values.push(Box::new(None::<i64>));
+                    continue;
+                }
+                match col.data_type() {
+                    DataType::Boolean => {
+                        let array = col.as_any().downcast_ref::<BooleanArray>().unwrap();
+                        param_values.push(Box::new(array.value(row_idx)));
+                    }
+                    DataType::Binary => {
+                        let array = col.as_any().downcast_ref::<BinaryArray>().unwrap();
+                        param_values.push(Box::new(array.value(row_idx).to_vec()));
+                    }
+                    DataType::Int64 => {
+                        let array = col.as_any().downcast_ref::<Int64Array>().unwrap();
+                        param_values.push(Box::new(array.value(row_idx)));
+                    }
+                    DataType::Float64 => {
+                        let array = col.as_any().downcast_ref::<Float64Array>().unwrap();
+                        param_values.push(Box::new(array.value(row_idx)));
+                    }
+                    DataType::Utf8 => {
+                        let array = col.as_any().downcast_ref::<StringArray>().unwrap();
+                        param_values.push(Box::new(array.value(row_idx).to_string()));
+                    }
+                    _ => return Err(Status::internal("Unsupported column type")),
+                }
+            }
+
+            let param_refs: Vec<&dyn ToSql> = param_values.iter().map(|p| p.as_ref()).collect();
+            stmt.execute(param_refs.as_slice()).map_err(|e| Status::internal(e.to_string()))?;
+        }
+        Ok(())
+    }
+
     /// Executes a SQL query.
     async fn execute(&self, query: &str) -> Result<(), Status> {
         let conn = self.conn.lock().await;
@@ -678,6 +901,8 @@ impl DuckDbBackend {
             DataType::Int64 => Box::new(Int64Builder::new()),
             DataType::Float64 => Box::new(Float64Builder::new()),
             DataType::Utf8 => Box::new(StringBuilder::new()),
+            DataType::Boolean => Box::new(BooleanBuilder::new()),
+            DataType::Binary => Box::new(BinaryBuilder::new()),
             _ => panic!("Unsupported column type"),
         }
     }
@@ -0,0 +1,276 @@
+//! Idempotency keys for exactly-once ingestion.
+//!
+//! Producers tag ingested batches with a producer ID and a monotonically
+//! increasing sequence number. The acknowledgement of every applied batch is
+//! persisted in the `ingest_acks` table in the same transaction as the
+//! ingested data, and the most recent acks of each producer are kept in
+//! memory. A retried batch is answered with its original ack instead of
+//! being applied a second time.
+//!
+//! Only the last `window` sequence numbers of a producer are remembered, and
+//! older acks are deleted from the ack table as new ones are written. A
+//! batch whose sequence number is older than that, but not newer than the
+//! last applied one, cannot be told apart from a duplicate and is rejected.
+//!
+//! Producers idle for longer than `idle_secs` are evicted from memory, so
+//! memory is bounded by the producers active within that period. Their acks
+//! are reloaded from the ack table when they send again.
+
+use crate::aggregation::filter::{CompareOp, Filter, Literal};
+use crate::config::IdempotencyConfig;
+use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+/// Name of the table holding the acks of applied batches.
+pub const ACK_TABLE: &str = "ingest_acks";
+
+/// Identifies a single ingested batch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey {
+    /// Producer that sent the batch
+    pub producer_id: String,
+    /// Sequence number of the batch within the producer
+    pub sequence: u64,
+}
+
+/// Outcome of looking up a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyState {
+    /// The batch has not been applied yet
+    New,
+    /// The batch was applied; contains the original ack
+    Applied(String),
+}
+
+/// Remembered acks of a single producer.
+#[derive(Debug, Default)]
+pub struct ProducerState {
+    acks: BTreeMap<u64, String>,
+    last_sequence: Option<u64>,
+    window: u64,
+    /// Acks recorded since persisted acks outside the window were deleted
+    unpruned: u64,
+}
+
+impl ProducerState {
+    fn new(window: u64) -> Self {
+        Self {
+            window,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether `sequence` was already applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::already_exists` if the sequence number is too old to
+    /// be checked.
+    pub fn check(&self, producer_id: &str, sequence: u64) -> Result<KeyState, Status> {
+        if let Some(ack) = self.acks.get(&sequence) {
+            return Ok(KeyState::Applied(ack.clone()));
+        }
+        match self.last_sequence {
+            Some(last) if sequence <= last && last - sequence >= self.window => {
+                Err(Status::already_exists(format!(
+                    "Sequence {} of producer '{}' is older than the last {} remembered batches (last applied {})",
+                    sequence, producer_id, self.window, last
+                )))
+            }
+            _ => Ok(KeyState::New),
+        }
+    }
+
+    /// Records the ack of an applied batch and forgets acks outside the window.
+    pub fn record(&mut self, sequence: u64, ack: String) {
+        self.acks.insert(sequence, ack);
+        let last = self.last_sequence.map_or(sequence, |last| last.max(sequence));
+        self.last_sequence = Some(last);
+        let oldest = last.saturating_sub(self.window.saturating_sub(1));
+        self.acks = self.acks.split_off(&oldest);
+        self.unpruned += 1;
+    }
+
+    /// Returns the oldest remembered sequence number once a window's worth
+    /// of acks has been recorded, so that older persisted acks can be
+    /// deleted; see [`expired_acks`]. This keeps at most two windows of acks
+    /// per producer in the ack table.
+    pub fn take_expired(&mut self) -> Option<u64> {
+        if self.unpruned < self.window.max(1) {
+            return None;
+        }
+        self.unpruned = 0;
+        self.acks.keys().next().copied()
+    }
+}
+
+/// State slot of a producer; see [`IdempotencyCache::producer`].
+pub type ProducerSlot = Arc<tokio::sync::Mutex<Option<ProducerState>>>;
+
+/// State slots of all producers seen since they were last evicted.
+#[derive(Debug)]
+struct Producers {
+    slots: HashMap<String, (ProducerSlot, Instant)>,
+    last_eviction: Instant,
+}
+
+impl Producers {
+    /// Drops the slots not used since `idle` before `now`. Slots still held
+    /// by a batch are kept, so batches of a producer stay serialized.
+    fn evict_idle(&mut self, now: Instant, idle: Duration) {
+        self.slots.retain(|_, (slot, last_seen)| {
+            Arc::strong_count(slot) > 1 || now.saturating_duration_since(*last_seen) < idle
+        });
+        self.last_eviction = now;
+    }
+}
+
+/// In-memory view of recently applied keys, per producer.
+///
+/// Producers are loaded lazily from the persisted acks the first time they
+/// are seen; see [`IdempotencyCache::producer`].
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    config: IdempotencyConfig,
+    producers: Mutex<Producers>,
+}
+
+impl IdempotencyCache {
+    /// Creates an empty cache.
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            config,
+            producers: Mutex::new(Producers {
+                slots: HashMap::new(),
+                last_eviction: Instant::now(),
+            }),
+        }
+    }
+
+    fn idle(&self) -> Duration {
+        Duration::try_from_secs_f64(self.config.idle_secs).unwrap_or(Duration::MAX)
+    }
+
+    /// Returns the state slot of a producer.
+    ///
+    /// The slot is `None` until the producer's persisted acks have been
+    /// loaded with [`load_producer`](Self::load_producer). Holding the slot's lock serializes
+    /// batches of the same producer, so a batch and its retry can never be
+    /// applied concurrently. Idle producers are evicted once per idle period.
+    pub fn producer(&self, producer_id: &str) -> Result<ProducerSlot, Status> {
+        let mut producers = self
+            .producers
+            .lock()
+            .map_err(|_| Status::internal("Idempotency cache lock poisoned"))?;
+        let now = Instant::now();
+        let idle = self.idle();
+        if now.saturating_duration_since(producers.last_eviction) >= idle {
+            producers.evict_idle(now, idle);
+        }
+        let (slot, last_seen) = producers
+            .slots
+            .entry(producer_id.to_string())
+            .or_insert_with(|| (ProducerSlot::default(), now));
+        *last_seen = now;
+        Ok(slot.clone())
+    }
+
+    /// Drops the state of producers that have been idle for longer than
+    /// `idle_secs`.
+    ///
+    /// Called periodically by [`producer`](Self::producer).
+    pub fn evict_idle(&self) {
+        if let Ok(mut producers) = self.producers.lock() {
+            producers.evict_idle(Instant::now(), self.idle());
+        }
+    }
+
+    /// Returns the number of producers with state in memory.
+    pub fn tracked_producers(&self) -> usize {
+        self.producers.lock().map(|producers| producers.slots.len()).unwrap_or_default()
+    }
+
+    /// Builds the state of a producer from persisted ack rows.
+    ///
+    /// `acks` must have the [`ack_schema`]; rows of other producers are
+    /// ignored.
+    pub fn load_producer(&self, producer_id: &str, acks: &RecordBatch) -> Result<ProducerState, Status> {
+        let mut state = ProducerState::new(self.config.window);
+        if acks.num_rows() == 0 {
+            return Ok(state);
+        }
+
+        let producers = string_column(acks, "producer_id")?;
+        let sequences = acks
+            .column_by_name("sequence")
+            .and_then(|c| c.as_any().downcast_ref::<Int64Array>())
+            .ok_or_else(|| Status::internal("Invalid sequence column in ingest acks"))?;
+        let payloads = string_column(acks, "ack")?;
+
+        let mut rows: Vec<(u64, String)> = (0..acks.num_rows())
+            .filter(|&i| producers.value(i) == producer_id)
+            .map(|i| (sequences.value(i) as u64, payloads.value(i).to_string()))
+            .collect();
+        rows.sort_by_key(|(sequence, _)| *sequence);
+        for (sequence, ack) in rows {
+            state.record(sequence, ack);
+        }
+        Ok(state)
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(IdempotencyConfig::default())
+    }
+}
+
+/// Selects the persisted acks of a producer.
+pub fn producer_acks(producer_id: &str) -> Filter {
+    Filter::compare("producer_id", CompareOp::Eq, Literal::String(producer_id.to_string()))
+}
+
+/// Selects the persisted acks of a producer older than `oldest`.
+pub fn expired_acks(producer_id: &str, oldest: u64) -> Filter {
+    let oldest = i64::try_from(oldest).unwrap_or(i64::MAX);
+    producer_acks(producer_id).and(Filter::compare("sequence", CompareOp::Lt, Literal::Int(oldest)))
+}
+
+/// Returns the schema of the ack table.
+pub fn ack_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("producer_id", DataType::Utf8, false),
+        Field::new("sequence", DataType::Int64, false),
+        Field::new("ack", DataType::Utf8, false),
+        Field::new("applied_at", DataType::Int64, false),
+    ])
+}
+
+/// Builds the ack table row for an applied batch.
+pub fn ack_batch(key: &IdempotencyKey, ack: &str) -> Result<RecordBatch, Status> {
+    let applied_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let sequence = i64::try_from(key.sequence)
+        .map_err(|_| Status::invalid_argument(format!("Sequence {} is out of range", key.sequence)))?;
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(vec![key.producer_id.as_str()])),
+        Arc::new(Int64Array::from(vec![sequence])),
+        Arc::new(StringArray::from(vec![ack])),
+        Arc::new(Int64Array::from(vec![applied_at])),
+    ];
+    RecordBatch::try_new(Arc::new(ack_schema()), columns)
+        .map_err(|e| Status::internal(format!("Failed to build ack record: {}", e)))
+}
+
+fn string_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a StringArray, Status> {
+    batch
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<StringArray>())
+        .ok_or_else(|| Status::internal(format!("Invalid {} column in ingest acks", name)))
+}
@@ -0,0 +1,547 @@
+//! Incrementally maintained aggregation views.
+//!
+//! A materialized view stores its result in a table named after the view,
+//! with the columns of [`aggregate_output_schema`], and the mergeable state
+//! of every group, aggregated column and window in `<view>_state`, with the
+//! columns of [`BatchAggregation::schema`]. The `metric_id` of a state row
+//! is the JSON array of the group by values and the aggregated column.
+//!
+//! Ingested batches are aggregated into partial state that is merged with
+//! the stored state of the windows they touch, so only the result rows of
+//! those windows are recomputed. Deleted source rows cannot be subtracted
+//! from the state; they mark the view stale until [`rebuild`] recomputes it
+//! from the source table. The freshness of a view is attached to the schema
+//! metadata of [`query`] results.
+//!
+//! Rows with a null timestamp or group by value are not aggregated, and
+//! quantiles and distinct counts are estimated from the state sketches.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use arrow::compute::cast;
+use arrow_array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Schema};
+use tokio::sync::OwnedRwLockReadGuard;
+use tonic::Status;
+use crate::aggregation::{aggregate_output_schema, validate_aggregates, AggregateExpr, AggregateFunction, TimeWindow};
+use crate::aggregation::filter::{CompareOp, Filter, Literal};
+use crate::storage::table_manager::{AggregationView, MaterializationStatus};
+use crate::storage::{merge_aggregations, BatchAggregation, StorageBackend};
+
+/// Schema metadata key of the Unix time of the last rebuild.
+pub const REFRESHED_AT_KEY: &str = "hyprstream.refreshed_at";
+/// Schema metadata key of the Unix time of the last update.
+pub const UPDATED_AT_KEY: &str = "hyprstream.updated_at";
+/// Schema metadata key of the latest source timestamp in the view.
+pub const WATERMARK_KEY: &str = "hyprstream.watermark";
+/// Schema metadata key set to `true` if the view missed source changes.
+pub const STALE_KEY: &str = "hyprstream.stale";
+
+/// Returns the name of the table holding the state of a view.
+pub fn state_table(view_name: &str) -> String {
+    format!("{}_state", view_name)
+}
+
+/// Checks that a view over a table with `schema` can be maintained
+/// incrementally.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` for session windows and time
+/// columns, whose groups change as events arrive, if the table has no
+/// integer `timestamp` column, or a group by or aggregated column has a
+/// type the state cannot hold.
+pub fn validate(view: &AggregationView, schema: &Schema) -> Result<(), Status> {
+    if let TimeWindow::Session { .. } = view.window {
+        return Err(Status::invalid_argument("Session windows cannot be materialized"));
+    }
+    if view.group_by.time_column.is_some() {
+        return Err(Status::invalid_argument("Materialized views cannot group by a time column"));
+    }
+    validate_state_columns(&view.group_by.columns, &view.aggregates, schema)
+}
+
+/// Checks that the rows of a table with `schema` can be aggregated into
+/// state keyed by the `group_by` columns, see [`partial_states`].
+pub(crate) fn validate_state_columns(
+    group_by: &[String],
+    aggregates: &[AggregateExpr],
+    schema: &Schema,
+) -> Result<(), Status> {
+    match schema.field_with_name("timestamp") {
+        Ok(field) if field.data_type().is_integer() => {}
+        _ => {
+            return Err(Status::invalid_argument(
+                "Aggregation state requires an integer timestamp column",
+            ))
+        }
+    }
+    for column in group_by {
+        let field = schema
+            .field_with_name(column)
+            .map_err(|_| Status::invalid_argument(format!("Unknown group by column {}", column)))?;
+        if key_type(field.data_type()).is_none() {
+            return Err(Status::invalid_argument(format!(
+                "Cannot aggregate state by column {} of type {}",
+                column,
+                field.data_type()
+            )));
+        }
+    }
+    for aggregate in aggregates {
+        if aggregate.column == "*" || matches!(aggregate.function, AggregateFunction::Count) {
+            continue;
+        }
+        let field = schema
+            .field_with_name(&aggregate.column)
+            .map_err(|_| Status::invalid_argument(format!("Unknown column {}", aggregate.column)))?;
+        if !field.data_type().is_numeric() {
+            return Err(Status::invalid_argument(format!(
+                "Cannot keep state for {:?} of column {} of type {}",
+                aggregate.function,
+                aggregate.column,
+                field.data_type()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Creates the tables of a materialized view, registers it and builds it
+/// from the current rows of its source table.
+pub async fn create<B: StorageBackend + ?Sized>(
+    backend: &B,
+    name: &str,
+    view: &AggregationView,
+) -> Result<(), Status> {
+    let schema = backend.table_manager().get_table_schema(&view.source_table).await?;
+    validate_aggregates(&view.aggregates, &view.group_by, &schema)?;
+    validate(view, &schema)?;
+    if backend.table_manager().get_aggregation_view(name).await.is_ok() {
+        return Err(Status::already_exists(format!("View {} already exists", name)));
+    }
+
+    let output = aggregate_output_schema(&view.aggregates, &view.group_by, true, &schema)?;
+    backend.create_table(name, &output).await?;
+    backend.create_table(&state_table(name), &BatchAggregation::schema()).await?;
+    backend.table_manager().create_aggregation_view(name.to_string(), view.clone()).await?;
+    rebuild(backend, name).await
+}
+
+/// Recomputes a materialized view from all rows of its source table and
+/// clears its stale flag.
+///
+/// Ingests into the source table wait for the rebuild, so each batch is
+/// either read from the source table or applied to the rebuilt view, see
+/// [`lock_sources`]. The state and result rows are replaced in one
+/// transaction.
+pub async fn rebuild<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Result<(), Status> {
+    let view = backend.table_manager().get_aggregation_view(name).await?;
+    if !view.materialized {
+        return Err(Status::failed_precondition(format!("View {} is not materialized", name)));
+    }
+
+    let source_lock = backend.table_manager().source_lock(&view.source_table).await;
+    let _rebuilding = source_lock.write().await;
+    let lock = backend.table_manager().view_lock(name).await;
+    let _updating = lock.lock().await;
+    let source = backend.query_table(&view.source_table, None, None).await?;
+    let source = match &view.filter {
+        Some(filter) => filter.apply(&source)?,
+        None => source,
+    };
+    let PartialStates { states, watermark, .. } =
+        partial_states(&source, &view.group_by.columns, &view.aggregates, view.window)?;
+    let mut states: Vec<BatchAggregation> = states.into_values().collect();
+    states.sort_by(|a, b| {
+        (&a.metric_id, a.window_start, a.window_end).cmp(&(&b.metric_id, b.window_start, b.window_end))
+    });
+    let output = backend.table_manager().get_table_schema(name).await?;
+    let result = evaluate_states(&states, &view.group_by.columns, &view.aggregates, output)?;
+    let everything = Filter::And(vec![]);
+    backend.replace_in_tables(vec![
+        (state_table(name), everything.clone(), BatchAggregation::to_record_batch(&states)?),
+        (name.to_string(), everything, result),
+    ]).await?;
+
+    let now = unix_now();
+    backend.table_manager().update_materialization_status(name, |status| {
+        *status = MaterializationStatus { refreshed_at: now, updated_at: now, watermark, stale: false };
+    }).await;
+    Ok(())
+}
+
+/// Locks the tables batches are ingested into, for the ingest to hold from
+/// before the batches are committed until they are applied to the
+/// materialized views, so a [`rebuild`] never reads a batch that is applied
+/// to the view afterwards.
+pub(crate) async fn lock_sources<B: StorageBackend + ?Sized>(
+    backend: &B,
+    batches: &[(String, RecordBatch)],
+) -> Vec<OwnedRwLockReadGuard<()>> {
+    let mut tables: Vec<&str> = batches.iter().map(|(table, _)| table.as_str()).collect();
+    tables.sort_unstable();
+    tables.dedup();
+    let mut guards = Vec::with_capacity(tables.len());
+    for table in tables {
+        guards.push(backend.table_manager().source_lock(table).await.read_owned().await);
+    }
+    guards
+}
+
+/// Applies a batch ingested into `source_table` to its materialized views;
+/// called by [`storage::on_ingest`](super::on_ingest). A view that fails to
+/// update is marked stale.
+pub async fn update<B: StorageBackend + ?Sized>(backend: &B, source_table: &str, batch: &RecordBatch) {
+    for (name, view) in backend.table_manager().materialized_views(source_table).await {
+        let lock = backend.table_manager().view_lock(&name).await;
+        let result = {
+            let _updating = lock.lock().await;
+            apply(backend, &name, &view, batch).await
+        };
+        if let Err(e) = &result {
+            tracing::error!("Failed to update materialized view {}: {}", name, e);
+        }
+        backend.table_manager().update_materialization_status(&name, |status| match result {
+            Ok(watermark) => {
+                status.updated_at = unix_now();
+                status.watermark = status.watermark.max(watermark);
+            }
+            Err(_) => status.stale = true,
+        }).await;
+    }
+}
+
+/// Marks the materialized views of a table stale, e.g. after rows were
+/// deleted from it.
+pub async fn mark_stale<B: StorageBackend + ?Sized>(backend: &B, source_table: &str) {
+    for (name, _) in backend.table_manager().materialized_views(source_table).await {
+        backend.table_manager().update_materialization_status(&name, |status| status.stale = true).await;
+    }
+}
+
+/// Reads a materialized view, with its freshness in the schema metadata
+/// under [`REFRESHED_AT_KEY`], [`UPDATED_AT_KEY`], [`WATERMARK_KEY`] and
+/// [`STALE_KEY`].
+pub async fn query<B: StorageBackend + ?Sized>(backend: &B, name: &str) -> Result<RecordBatch, Status> {
+    let batch = backend.query_table(name, None, None).await?;
+    let status = backend.table_manager().materialization_status(name).await.unwrap_or_default();
+
+    let mut metadata = batch.schema().metadata().clone();
+    metadata.insert(REFRESHED_AT_KEY.to_string(), status.refreshed_at.to_string());
+    metadata.insert(UPDATED_AT_KEY.to_string(), status.updated_at.to_string());
+    if let Some(watermark) = status.watermark {
+        metadata.insert(WATERMARK_KEY.to_string(), watermark.to_string());
+    }
+    metadata.insert(STALE_KEY.to_string(), status.stale.to_string());
+
+    let schema = batch.schema().as_ref().clone().with_metadata(metadata);
+    RecordBatch::try_new(Arc::new(schema), batch.columns().to_vec())
+        .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))
+}
+
+/// Merges the rows of `batch` into the state of a view and rewrites the
+/// result rows of the windows they touch. Returns the latest timestamp of
+/// the aggregated rows.
+///
+/// Callers hold the lock of the view, see
+/// [`TableManager::view_lock`](crate::storage::table_manager::TableManager::view_lock), so
+/// concurrent updates do not overwrite each other's state. The state and
+/// result rows are rewritten in one transaction.
+async fn apply<B: StorageBackend + ?Sized>(
+    backend: &B,
+    name: &str,
+    view: &AggregationView,
+    batch: &RecordBatch,
+) -> Result<Option<i64>, Status> {
+    let batch = match &view.filter {
+        Some(filter) => filter.apply(batch)?,
+        None => batch.clone(),
+    };
+    let PartialStates { states: partial, groups, starts, watermark } =
+        partial_states(&batch, &view.group_by.columns, &view.aggregates, view.window)?;
+    if partial.is_empty() {
+        return Ok(watermark);
+    }
+
+    // Merge with the stored state of the touched groups and windows
+    let state_name = state_table(name);
+    let mut state_keys: Vec<String> = partial.keys().map(|(key, _, _)| key.clone()).collect();
+    state_keys.sort();
+    state_keys.dedup();
+    let touched_starts = Filter::In {
+        column: "window_start".to_string(),
+        values: starts.iter().map(|start| Literal::Int(*start)).collect(),
+    };
+    let touched_state = Filter::And(vec![
+        Filter::In {
+            column: "metric_id".to_string(),
+            values: state_keys.into_iter().map(Literal::String).collect(),
+        },
+        touched_starts.clone(),
+    ]);
+    let mut aggregations = BatchAggregation::from_record_batch(
+        &backend.query_table(&state_name, None, Some(&touched_state)).await?,
+    )?;
+    aggregations.extend(partial.into_values());
+    let mut merged: Vec<BatchAggregation> =
+        merge_aggregations(&aggregations, |a| (a.metric_id.clone(), a.window_start, a.window_end))?
+            .into_values()
+            .collect();
+    merged.sort_by(|a, b| {
+        (&a.metric_id, a.window_start, a.window_end).cmp(&(&b.metric_id, b.window_start, b.window_end))
+    });
+
+    // Recompute the result rows from the merged state
+    let output = backend.table_manager().get_table_schema(name).await?;
+    let result = evaluate_states(&merged, &view.group_by.columns, &view.aggregates, output)?;
+
+    // Result rows of the touched groups and windows are replaced
+    let touched_groups = Filter::Or(
+        groups.values()
+            .map(|group| {
+                Filter::And(
+                    view.group_by.columns.iter()
+                        .zip(group)
+                        .map(|(column, value)| Filter::compare(column.clone(), CompareOp::Eq, value.clone()))
+                        .collect(),
+                )
+            })
+            .collect(),
+    );
+    backend.replace_in_tables(vec![
+        (state_name, touched_state, BatchAggregation::to_record_batch(&merged)?),
+        (name.to_string(), Filter::And(vec![touched_groups, touched_starts]), result),
+    ]).await?;
+    Ok(watermark)
+}
+
+/// Aggregation state of the rows of a batch, see [`partial_states`].
+pub(crate) struct PartialStates {
+    /// State per state key, window start and window end
+    pub states: HashMap<(String, i64, i64), BatchAggregation>,
+    /// Group by values per JSON encoded group
+    pub groups: BTreeMap<String, Vec<Literal>>,
+    /// Starts of the windows with state
+    pub starts: BTreeSet<i64>,
+    /// Latest timestamp of the aggregated rows
+    pub watermark: Option<i64>,
+}
+
+/// Aggregates the rows of a batch into state per group, aggregated column
+/// and window, keyed by [`state_key`].
+///
+/// Every aggregated column gets a state for each window with rows of the
+/// group, which is empty if all its values are null. Rows with a null
+/// timestamp or group by value are skipped.
+pub(crate) fn partial_states(
+    batch: &RecordBatch,
+    group_by: &[String],
+    aggregates: &[AggregateExpr],
+    window: TimeWindow,
+) -> Result<PartialStates, Status> {
+    let timestamps = cast_column(batch, "timestamp", &DataType::Int64)?;
+    let timestamps = timestamps.as_any().downcast_ref::<Int64Array>()
+        .ok_or_else(|| Status::internal("Failed to read timestamp column"))?;
+    let keys = group_by.iter()
+        .map(|column| {
+            let data_type = batch.schema().field_with_name(column).ok().and_then(|f| key_type(f.data_type()));
+            let data_type = data_type
+                .ok_or_else(|| Status::invalid_argument(format!("Cannot aggregate state by column {}", column)))?;
+            cast_column(batch, column, &data_type)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Each aggregated column once, with its values if they are numeric
+    let mut columns: Vec<(&str, Option<ArrayRef>, Option<Float64Array>)> = Vec::new();
+    for aggregate in aggregates {
+        let column = aggregate.column.as_str();
+        if columns.iter().any(|(name, _, _)| *name == column) {
+            continue;
+        }
+        if column == "*" {
+            columns.push((column, None, None));
+            continue;
+        }
+        let array = batch.column_by_name(column)
+            .ok_or_else(|| Status::invalid_argument(format!("Unknown column {}", column)))?
+            .clone();
+        let values = if array.data_type().is_numeric() {
+            let values = cast_column(batch, column, &DataType::Float64)?;
+            values.as_any().downcast_ref::<Float64Array>().cloned()
+        } else {
+            None
+        };
+        columns.push((column, Some(array), values));
+    }
+
+    let mut partial: HashMap<(String, i64, i64), BatchAggregation> = HashMap::new();
+    let mut groups: BTreeMap<String, Vec<Literal>> = BTreeMap::new();
+    let mut starts = BTreeSet::new();
+    let mut watermark = None;
+    for row in 0..batch.num_rows() {
+        if timestamps.is_null(row) {
+            continue;
+        }
+        let Some(group) = keys.iter().map(|array| literal_at(array, row)).collect::<Option<Vec<_>>>() else {
+            continue;
+        };
+        let timestamp = timestamps.value(row);
+        watermark = watermark.max(Some(timestamp));
+
+        for (column, array, values) in &columns {
+            let state_key = state_key(&group, column)?;
+            // Null values leave the state empty, but still produce the window
+            let value = match (array, values) {
+                (None, _) => Some(0.0),
+                (Some(array), _) if array.is_null(row) => None,
+                (Some(_), Some(values)) => Some(values.value(row)),
+                (Some(_), None) => Some(0.0),
+            };
+            for (window_start, window_end) in window.windows(timestamp) {
+                starts.insert(window_start);
+                let state = partial
+                    .entry((state_key.clone(), window_start, window_end))
+                    .or_insert_with(|| BatchAggregation::new(state_key.clone(), window_start, window_end));
+                if let Some(value) = value {
+                    state.update(timestamp, value, 1);
+                }
+            }
+        }
+        groups.entry(group_key(&group)?).or_insert(group);
+    }
+    Ok(PartialStates { states: partial, groups, starts, watermark })
+}
+
+/// Evaluates state keyed by [`state_key`] into one row per group and
+/// window, with the columns of `output`: the group by columns, the window
+/// bounds and the aggregates.
+///
+/// Aggregates over empty state are null, except counts, which are zero.
+pub(crate) fn evaluate_states(
+    states: &[BatchAggregation],
+    group_by: &[String],
+    aggregates: &[AggregateExpr],
+    output: Schema,
+) -> Result<RecordBatch, Status> {
+    let mut groups: HashMap<String, Vec<Literal>> = HashMap::new();
+    let mut rows: BTreeMap<(String, i64, i64), HashMap<String, &BatchAggregation>> = BTreeMap::new();
+    for state in states {
+        let (group, column) = parse_state_key(&state.metric_id)?;
+        let group_key = group_key(&group)?;
+        groups.entry(group_key.clone()).or_insert(group);
+        rows.entry((group_key, state.window_start, state.window_end)).or_default().insert(column, state);
+    }
+
+    let mut arrays: Vec<ArrayRef> = Vec::with_capacity(output.fields().len());
+    for (i, column) in group_by.iter().enumerate() {
+        let values: Vec<&Literal> = rows.keys().map(|(group_key, _, _)| &groups[group_key][i]).collect();
+        let field = output.field_with_name(column)
+            .map_err(|_| Status::internal(format!("Missing output column {}", column)))?;
+        arrays.push(literal_array(&values, field.data_type())?);
+    }
+    arrays.push(Arc::new(Int64Array::from_iter_values(rows.keys().map(|(_, start, _)| *start))));
+    arrays.push(Arc::new(Int64Array::from_iter_values(rows.keys().map(|(_, _, end)| *end))));
+    for aggregate in aggregates {
+        let counted = matches!(aggregate.function, AggregateFunction::Count | AggregateFunction::CountDistinct);
+        let values = rows.values()
+            .map(|states| match states.get(&aggregate.column) {
+                Some(state) if state.sample_count > 0 => {
+                    state.evaluate(aggregate.function).map(|value| Some(value).filter(|v| !v.is_nan()))
+                }
+                _ => Ok(counted.then_some(0.0)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let array: ArrayRef = if counted {
+            Arc::new(values.into_iter().map(|v| v.map(|v| v as i64)).collect::<Int64Array>())
+        } else {
+            Arc::new(values.into_iter().collect::<Float64Array>())
+        };
+        arrays.push(array);
+    }
+    RecordBatch::try_new(Arc::new(output), arrays)
+        .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+}
+
+/// Returns the key of the state of an aggregated column within a group.
+pub fn state_key(group: &[Literal], column: &str) -> Result<String, Status> {
+    serde_json::to_string(&(group, column))
+        .map_err(|e| Status::internal(format!("Failed to encode state key: {}", e)))
+}
+
+/// Returns the group by values and the aggregated column of a state key.
+pub(crate) fn parse_state_key(key: &str) -> Result<(Vec<Literal>, String), Status> {
+    serde_json::from_str(key).map_err(|e| Status::internal(format!("Invalid state key {}: {}", key, e)))
+}
+
+fn group_key(group: &[Literal]) -> Result<String, Status> {
+    serde_json::to_string(group).map_err(|e| Status::internal(format!("Failed to encode group key: {}", e)))
+}
+
+/// Returns the type group by values of `data_type` are kept as, if any.
+fn key_type(data_type: &DataType) -> Option<DataType> {
+    match data_type {
+        DataType::Boolean => Some(DataType::Boolean),
+        DataType::Utf8 | DataType::LargeUtf8 => Some(DataType::Utf8),
+        t if t.is_integer() => Some(DataType::Int64),
+        t if t.is_floating() => Some(DataType::Float64),
+        _ => None,
+    }
+}
+
+fn cast_column(batch: &RecordBatch, name: &str, data_type: &DataType) -> Result<ArrayRef, Status> {
+    let column = batch.column_by_name(name)
+        .ok_or_else(|| Status::invalid_argument(format!("Missing column {}", name)))?;
+    cast(column, data_type)
+        .map_err(|e| Status::invalid_argument(format!("Failed to cast column {}: {}", name, e)))
+}
+
+/// Returns the value of a column cast to a [`key_type`], `None` if null.
+fn literal_at(array: &ArrayRef, row: usize) -> Option<Literal> {
+    if array.is_null(row) {
+        return None;
+    }
+    let any = array.as_any();
+    if let Some(values) = any.downcast_ref::<BooleanArray>() {
+        Some(Literal::Bool(values.value(row)))
+    } else if let Some(values) = any.downcast_ref::<Int64Array>() {
+        Some(Literal::Int(values.value(row)))
+    } else if let Some(values) = any.downcast_ref::<Float64Array>() {
+        Some(Literal::Float(values.value(row)))
+    } else {
+        any.downcast_ref::<StringArray>().map(|values| Literal::String(values.value(row).to_string()))
+    }
+}
+
+/// Builds a column of `data_type` from group by values.
+fn literal_array(values: &[&Literal], data_type: &DataType) -> Result<ArrayRef, Status> {
+    let key_type = key_type(data_type)
+        .ok_or_else(|| Status::internal(format!("Unsupported group by type {}", data_type)))?;
+    let array: ArrayRef = match key_type {
+        DataType::Boolean => Arc::new(values.iter()
+            .map(|v| match v { Literal::Bool(b) => Some(*b), _ => None })
+            .collect::<BooleanArray>()),
+        DataType::Int64 => Arc::new(values.iter()
+            .map(|v| match v { Literal::Int(i) => Some(*i), _ => None })
+            .collect::<Int64Array>()),
+        DataType::Float64 => Arc::new(values.iter()
+            .map(|v| match v {
+                Literal::Float(f) => Some(*f),
+                Literal::Int(i) => Some(*i as f64),
+                _ => None,
+            })
+            .collect::<Float64Array>()),
+        _ => Arc::new(values.iter()
+            .map(|v| match v { Literal::String(s) => Some(s.as_str()), _ => None })
+            .collect::<StringArray>()),
+    };
+    cast(&array, data_type).map_err(|e| Status::internal(format!("Failed to build group by column: {}", e)))
+}
+
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
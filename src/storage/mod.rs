@@ -5,6 +5,12 @@
 //! - `adbc`: Arrow Database Connectivity for external database integration
 //! - `cached`: Two-tier storage with configurable caching layer
 //!
+//! Aggregation views are either plain SQL views or, see [`materialized`],
+//! tables maintained incrementally as batches are ingested. Tables may also
+//! keep coarser resolutions of their data in [`rollup`] tiers, while
+//! [`anomaly`] monitors score metric series and [`alerts`] rules watch
+//! windowed aggregates.
+//!
 //! Each backend implements the `StorageBackend` trait, providing a consistent
 //! interface for metric storage and retrieval operations.
 
@@ -12,19 +18,40 @@ pub mod adbc;
 pub mod duckdb;
 pub mod cache;
 pub mod table_manager;
+pub mod schema_adapter;
+pub mod quality;
+pub mod idempotency;
+pub mod materialized;
+pub mod rollup;
+pub mod alerts;
+pub mod anomaly;
 
-use arrow_array::RecordBatch;
-use arrow_schema::Schema;
+use arrow::compute::{cast, concat};
+use arrow_array::{new_empty_array, Array, ArrayRef, BinaryArray, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::config::Credentials;
 use crate::metrics::MetricRecord;
 use crate::storage::table_manager::{TableManager, AggregationView};
-use crate::aggregation::{AggregateFunction, GroupBy, AggregateResult, TimeWindow};
+use crate::storage::rollup::RollupPolicy;
+use crate::storage::alerts::{Alert, AlertRule};
+use crate::storage::anomaly::{AnomalyMonitor, AnomalyScore};
+use crate::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow, ROW_COUNT_COLUMN};
+use crate::aggregation::fill::{fill_series, FillStrategy, FilledWindow};
+use crate::aggregation::filter::Filter;
+use crate::aggregation::forecast::{self, forecast_batch, ForecastSpec};
+use crate::aggregation::hll::HyperLogLog;
+use crate::aggregation::matrix::RangeQuery;
+use crate::aggregation::series::SeriesAlignment;
+use crate::aggregation::sketch::QuantileSketch;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedRwLockReadGuard;
 use tonic::Status;
 
 /// Batch-level aggregation state for efficient updates
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BatchAggregation {
     /// The metric ID this aggregation belongs to
     pub metric_id: String,
@@ -40,6 +67,525 @@ pub struct BatchAggregation {
     pub min_value: f64,
     /// Maximum value in the window
     pub max_value: f64,
+    /// Quantile sketch of the values in the window, `None` if the state
+    /// does not keep sketches
+    pub quantile_sketch: Option<QuantileSketch>,
+    /// Number of values added to the window
+    pub sample_count: i64,
+    /// Sum of squared deviations from the mean of the values
+    pub m2: f64,
+    /// Timestamp of the earliest value, `i64::MAX` if empty
+    pub first_timestamp: i64,
+    /// Earliest value in the window
+    pub first_value: f64,
+    /// Timestamp of the latest value, `i64::MIN` if empty
+    pub last_timestamp: i64,
+    /// Latest value in the window
+    pub last_value: f64,
+    /// Distinct count sketch of the values in the window, `None` if the
+    /// state does not keep sketches
+    pub distinct_sketch: Option<HyperLogLog>,
+}
+
+impl BatchAggregation {
+    /// Creates empty aggregation state for a window.
+    pub fn new(metric_id: String, window_start: i64, window_end: i64) -> Self {
+        Self {
+            metric_id,
+            window_start,
+            window_end,
+            running_sum: 0.0,
+            running_count: 0,
+            min_value: f64::INFINITY,
+            max_value: f64::NEG_INFINITY,
+            quantile_sketch: Some(QuantileSketch::new()),
+            sample_count: 0,
+            m2: 0.0,
+            first_timestamp: i64::MAX,
+            first_value: f64::NAN,
+            last_timestamp: i64::MIN,
+            last_value: f64::NAN,
+            distinct_sketch: Some(HyperLogLog::new()),
+        }
+    }
+
+    /// Adds a value observed at `timestamp` representing `count` samples to
+    /// the window.
+    pub fn update(&mut self, timestamp: i64, value: f64, count: i64) {
+        // Welford's update of the squared deviations, using the mean of the
+        // values before this one
+        let delta = if self.sample_count > 0 {
+            value - self.running_sum / self.sample_count as f64
+        } else {
+            0.0
+        };
+        self.sample_count += 1;
+        self.m2 += delta * delta * (self.sample_count - 1) as f64 / self.sample_count as f64;
+
+        self.running_sum += value;
+        self.running_count += count;
+        self.min_value = self.min_value.min(value);
+        self.max_value = self.max_value.max(value);
+        if let Some(sketch) = &mut self.quantile_sketch {
+            sketch.insert(value);
+        }
+        if let Some(sketch) = &mut self.distinct_sketch {
+            sketch.insert(value);
+        }
+
+        if timestamp < self.first_timestamp {
+            self.first_timestamp = timestamp;
+            self.first_value = value;
+        }
+        if timestamp >= self.last_timestamp {
+            self.last_timestamp = timestamp;
+            self.last_value = value;
+        }
+    }
+
+    /// Merges the state of another window or group into this one.
+    ///
+    /// The window bounds are widened to cover both inputs. Sketches are only
+    /// kept if both inputs with values have them.
+    pub fn merge(&mut self, other: &BatchAggregation) -> Result<(), Status> {
+        match (&mut self.quantile_sketch, &other.quantile_sketch) {
+            (Some(sketch), Some(other)) => sketch.merge(other)?,
+            _ if other.sample_count == 0 => {}
+            _ if self.sample_count == 0 => self.quantile_sketch = other.quantile_sketch.clone(),
+            _ => self.quantile_sketch = None,
+        }
+        match (&mut self.distinct_sketch, &other.distinct_sketch) {
+            (Some(sketch), Some(other)) => sketch.merge(other)?,
+            _ if other.sample_count == 0 => {}
+            _ if self.sample_count == 0 => self.distinct_sketch = other.distinct_sketch.clone(),
+            _ => self.distinct_sketch = None,
+        }
+
+        self.window_start = self.window_start.min(other.window_start);
+        self.window_end = self.window_end.max(other.window_end);
+
+        // Chan et al. parallel combination of the squared deviations
+        let (n_a, n_b) = (self.sample_count as f64, other.sample_count as f64);
+        if n_a > 0.0 && n_b > 0.0 {
+            let delta = other.running_sum / n_b - self.running_sum / n_a;
+            self.m2 += other.m2 + delta * delta * n_a * n_b / (n_a + n_b);
+        } else {
+            self.m2 += other.m2;
+        }
+        self.sample_count += other.sample_count;
+
+        if other.first_timestamp < self.first_timestamp {
+            self.first_timestamp = other.first_timestamp;
+            self.first_value = other.first_value;
+        }
+        if other.last_timestamp >= self.last_timestamp && other.sample_count > 0 {
+            self.last_timestamp = other.last_timestamp;
+            self.last_value = other.last_value;
+        }
+
+        self.running_sum += other.running_sum;
+        self.running_count += other.running_count;
+        self.min_value = self.min_value.min(other.min_value);
+        self.max_value = self.max_value.max(other.max_value);
+        Ok(())
+    }
+
+    /// Computes an aggregate function from the window state.
+    pub fn evaluate(&self, function: AggregateFunction) -> Result<f64, Status> {
+        function.validate()?;
+        Ok(match function {
+            AggregateFunction::Count => self.running_count as f64,
+            AggregateFunction::Sum => self.running_sum,
+            AggregateFunction::Avg => self.running_sum / self.running_count as f64,
+            AggregateFunction::Min => self.min_value,
+            AggregateFunction::Max => self.max_value,
+            AggregateFunction::Quantile(q) => match &self.quantile_sketch {
+                Some(sketch) => sketch.quantile(q).unwrap_or(f64::NAN),
+                None if self.sample_count == 0 => f64::NAN,
+                None => return Err(Status::failed_precondition("Window state has no quantile sketch")),
+            },
+            AggregateFunction::Variance => self.variance(),
+            AggregateFunction::StdDev => self.variance().sqrt(),
+            AggregateFunction::First => self.first_value,
+            AggregateFunction::Last => self.last_value,
+            AggregateFunction::CountDistinct => match &self.distinct_sketch {
+                Some(sketch) => sketch.estimate().round(),
+                None if self.sample_count == 0 => 0.0,
+                None => return Err(Status::failed_precondition("Window state has no distinct count sketch")),
+            },
+            AggregateFunction::Delta => self.last_value - self.first_value,
+            AggregateFunction::Rate => {
+                let seconds = self.last_timestamp.saturating_sub(self.first_timestamp);
+                if self.sample_count > 0 && seconds > 0 {
+                    (self.last_value - self.first_value) / seconds as f64
+                } else {
+                    f64::NAN
+                }
+            }
+        })
+    }
+
+    /// Sample variance of the values; NaN for fewer than two values.
+    fn variance(&self) -> f64 {
+        if self.sample_count < 2 {
+            f64::NAN
+        } else {
+            self.m2 / (self.sample_count - 1) as f64
+        }
+    }
+
+    /// Returns the Arrow schema of the `metric_aggregations` table.
+    pub fn schema() -> Schema {
+        Schema::new(vec![
+            Field::new("metric_id", DataType::Utf8, false),
+            Field::new("window_start", DataType::Int64, false),
+            Field::new("window_end", DataType::Int64, false),
+            Field::new("running_sum", DataType::Float64, false),
+            Field::new("running_count", DataType::Int64, false),
+            Field::new("min_value", DataType::Float64, false),
+            Field::new("max_value", DataType::Float64, false),
+            Field::new("quantile_sketch", DataType::Binary, true),
+            Field::new("sample_count", DataType::Int64, true),
+            Field::new("m2", DataType::Float64, true),
+            Field::new("first_timestamp", DataType::Int64, true),
+            Field::new("first_value", DataType::Float64, true),
+            Field::new("last_timestamp", DataType::Int64, true),
+            Field::new("last_value", DataType::Float64, true),
+            Field::new("distinct_sketch", DataType::Binary, true),
+        ])
+    }
+
+    /// Converts aggregation state into a record batch with [`schema`](Self::schema).
+    pub fn to_record_batch(aggregations: &[BatchAggregation]) -> Result<RecordBatch, Status> {
+        let sketches = aggregations
+            .iter()
+            .map(|a| a.quantile_sketch.as_ref().map(|sketch| sketch.to_bytes()).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        let distinct_sketches = aggregations
+            .iter()
+            .map(|a| a.distinct_sketch.as_ref().map(|sketch| sketch.to_bytes()).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(aggregations.iter().map(|a| a.metric_id.as_str()))),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.window_start))),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.window_end))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.running_sum))),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.running_count))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.min_value))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.max_value))),
+            Arc::new(BinaryArray::from_iter(sketches)),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.sample_count))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.m2))),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.first_timestamp))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.first_value))),
+            Arc::new(Int64Array::from_iter_values(aggregations.iter().map(|a| a.last_timestamp))),
+            Arc::new(Float64Array::from_iter_values(aggregations.iter().map(|a| a.last_value))),
+            Arc::new(BinaryArray::from_iter(distinct_sketches)),
+        ];
+
+        RecordBatch::try_new(Arc::new(Self::schema()), arrays)
+            .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+    }
+
+    /// Reads aggregation state from a record batch with [`schema`](Self::schema).
+    ///
+    /// Rows without sketches, such as those written before the sketch
+    /// columns existed, have none. Rows written before other state columns
+    /// existed get no first or last value, and a sample count equal to the
+    /// running count.
+    pub fn from_record_batch(batch: &RecordBatch) -> Result<Vec<BatchAggregation>, Status> {
+        fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T, Status> {
+            batch.column_by_name(name)
+                .and_then(|c| c.as_any().downcast_ref::<T>())
+                .ok_or_else(|| Status::internal(format!("Invalid {} column in aggregation batch", name)))
+        }
+
+        let metric_ids = column::<StringArray>(batch, "metric_id")?;
+        let window_starts = column::<Int64Array>(batch, "window_start")?;
+        let window_ends = column::<Int64Array>(batch, "window_end")?;
+        let sums = column::<Float64Array>(batch, "running_sum")?;
+        let counts = column::<Int64Array>(batch, "running_count")?;
+        let mins = column::<Float64Array>(batch, "min_value")?;
+        let maxs = column::<Float64Array>(batch, "max_value")?;
+        let sketches = column::<BinaryArray>(batch, "quantile_sketch")?;
+        let sample_counts = column::<Int64Array>(batch, "sample_count")?;
+        let m2s = column::<Float64Array>(batch, "m2")?;
+        let first_timestamps = column::<Int64Array>(batch, "first_timestamp")?;
+        let first_values = column::<Float64Array>(batch, "first_value")?;
+        let last_timestamps = column::<Int64Array>(batch, "last_timestamp")?;
+        let last_values = column::<Float64Array>(batch, "last_value")?;
+        let distinct_sketches = column::<BinaryArray>(batch, "distinct_sketch")?;
+
+        (0..batch.num_rows())
+            .map(|i| {
+                let quantile_sketch = if sketches.is_null(i) {
+                    None
+                } else {
+                    Some(QuantileSketch::from_bytes(sketches.value(i))?)
+                };
+                let distinct_sketch = if distinct_sketches.is_null(i) {
+                    None
+                } else {
+                    Some(HyperLogLog::from_bytes(distinct_sketches.value(i))?)
+                };
+                let i64_or = |array: &Int64Array, default: i64| {
+                    if array.is_null(i) { default } else { array.value(i) }
+                };
+                let f64_or = |array: &Float64Array, default: f64| {
+                    if array.is_null(i) { default } else { array.value(i) }
+                };
+                Ok(BatchAggregation {
+                    metric_id: metric_ids.value(i).to_string(),
+                    window_start: window_starts.value(i),
+                    window_end: window_ends.value(i),
+                    running_sum: sums.value(i),
+                    running_count: counts.value(i),
+                    min_value: mins.value(i),
+                    max_value: maxs.value(i),
+                    quantile_sketch,
+                    sample_count: i64_or(sample_counts, counts.value(i)),
+                    m2: f64_or(m2s, 0.0),
+                    first_timestamp: i64_or(first_timestamps, i64::MAX),
+                    first_value: f64_or(first_values, f64::NAN),
+                    last_timestamp: i64_or(last_timestamps, i64::MIN),
+                    last_value: f64_or(last_values, f64::NAN),
+                    distinct_sketch,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Windows of the aggregation state in `metric_aggregations`, which every
+/// backend maintains as metrics are ingested.
+///
+/// The windows are disjoint, so each metric updates a single window and
+/// longer ranges are merged from them without counting a sample twice.
+pub(crate) const INGEST_WINDOW: TimeWindow = TimeWindow::Fixed(std::time::Duration::from_secs(60));
+
+/// Aggregates metrics into per-metric window state.
+///
+/// Each metric is added to every window containing it, see
+/// [`TimeWindow::windows`]; `count` gives the number of samples a metric
+/// represents. Session windows are formed from the metrics of the batch
+/// alone; sessions continuing across batches are stitched with
+/// [`merge_sessions`].
+pub fn aggregate_windows<F>(metrics: &[MetricRecord], window: TimeWindow, count: F) -> Vec<BatchAggregation>
+where
+    F: Fn(&MetricRecord) -> i64,
+{
+    if let TimeWindow::Session { gap } = window {
+        let mut sorted: Vec<&MetricRecord> = metrics.iter().collect();
+        sorted.sort_by(|a, b| a.metric_id.cmp(&b.metric_id).then(a.timestamp.cmp(&b.timestamp)));
+
+        let gap = gap.as_secs().max(1) as i64;
+        let mut sessions: Vec<BatchAggregation> = Vec::new();
+        for metric in sorted {
+            match sessions.last_mut() {
+                Some(session) if session.metric_id == metric.metric_id && metric.timestamp < session.window_end => {
+                    session.window_end = metric.timestamp + gap;
+                    session.update(metric.timestamp, metric.value_running_window_sum, count(metric));
+                }
+                _ => {
+                    let mut session = BatchAggregation::new(metric.metric_id.clone(), metric.timestamp, metric.timestamp + gap);
+                    session.update(metric.timestamp, metric.value_running_window_sum, count(metric));
+                    sessions.push(session);
+                }
+            }
+        }
+        return sessions;
+    }
+
+    let mut aggregations = HashMap::new();
+    for metric in metrics {
+        for (window_start, window_end) in window.windows(metric.timestamp) {
+            let key = (metric.metric_id.clone(), window_start, window_end);
+            aggregations
+                .entry(key)
+                .or_insert_with(|| BatchAggregation::new(metric.metric_id.clone(), window_start, window_end))
+                .update(metric.timestamp, metric.value_running_window_sum, count(metric));
+        }
+    }
+    aggregations.into_values().collect()
+}
+
+/// Merges session windows of the same metric that overlap.
+///
+/// Sessions computed from different batches are stitched into one session
+/// when an event of the later one falls within the gap of the earlier one.
+/// The result is sorted by metric and window start.
+pub fn merge_sessions(aggregations: &[BatchAggregation]) -> Result<Vec<BatchAggregation>, Status> {
+    let mut sorted = aggregations.to_vec();
+    sorted.sort_by(|a, b| a.metric_id.cmp(&b.metric_id).then(a.window_start.cmp(&b.window_start)));
+
+    let mut merged: Vec<BatchAggregation> = Vec::new();
+    for session in sorted {
+        match merged.last_mut() {
+            Some(last) if last.metric_id == session.metric_id && session.window_start < last.window_end => {
+                last.merge(&session)?;
+            }
+            _ => merged.push(session),
+        }
+    }
+    Ok(merged)
+}
+
+/// Merges aggregation state by a grouping key.
+///
+/// Windows that map to the same key are combined with
+/// [`BatchAggregation::merge`], e.g. to roll fine windows up into coarser ones
+/// or to combine metrics into groups.
+pub fn merge_aggregations<K, F>(
+    aggregations: &[BatchAggregation],
+    key: F,
+) -> Result<HashMap<K, BatchAggregation>, Status>
+where
+    K: std::hash::Hash + Eq,
+    F: Fn(&BatchAggregation) -> K,
+{
+    let mut merged: HashMap<K, BatchAggregation> = HashMap::new();
+    for aggregation in aggregations {
+        match merged.entry(key(aggregation)) {
+            std::collections::hash_map::Entry::Occupied(mut entry) => entry.get_mut().merge(aggregation)?,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(aggregation.clone());
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Returns the state to upsert for new and stored window state: one
+/// aggregation per `(metric_id, window_start, window_end)`, merging windows
+/// repeated in `aggregations` with each other and with their stored state.
+///
+/// Windows only in `stored` are not returned. The result is sorted by key.
+pub fn upsert_state(
+    aggregations: &[BatchAggregation],
+    stored: &[BatchAggregation],
+) -> Result<Vec<BatchAggregation>, Status> {
+    let key = |a: &BatchAggregation| (a.metric_id.clone(), a.window_start, a.window_end);
+    let mut merged = merge_aggregations(aggregations, key)?;
+    for state in stored {
+        if let Some(new) = merged.get_mut(&key(state)) {
+            let mut state = state.clone();
+            state.merge(new)?;
+            *new = state;
+        }
+    }
+    let mut merged: Vec<_> = merged.into_iter().collect();
+    merged.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(merged.into_iter().map(|(_, aggregation)| aggregation).collect())
+}
+
+/// Evaluates per-metric window state as gap-filled series.
+///
+/// Every window of `window` in `[from, to)` is emitted for each metric in
+/// `aggregations`, see [`fill_series`]. State of the same metric and window
+/// is merged first. The series are sorted by metric id.
+pub fn fill_aggregations(
+    aggregations: &[BatchAggregation],
+    function: AggregateFunction,
+    window: TimeWindow,
+    from: i64,
+    to: i64,
+    strategy: FillStrategy,
+) -> Result<Vec<(String, Vec<FilledWindow>)>, Status> {
+    let windows = window.windows_between(from, to)?;
+    let merged = merge_aggregations(aggregations, |a| (a.metric_id.clone(), a.window_start))?;
+
+    let mut values: BTreeMap<String, HashMap<i64, f64>> = BTreeMap::new();
+    for ((metric_id, window_start), aggregation) in merged {
+        values.entry(metric_id).or_default().insert(window_start, aggregation.evaluate(function)?);
+    }
+    Ok(values
+        .into_iter()
+        .map(|(metric_id, values)| (metric_id, fill_series(&values, &windows, strategy)))
+        .collect())
+}
+
+/// Returns the aggregates computed by an `aggregate_metrics` query: the
+/// requested ones followed by the count of the aggregated rows.
+pub(crate) fn counted_aggregates(aggregates: &[AggregateExpr]) -> Vec<AggregateExpr> {
+    let mut counted = aggregates.to_vec();
+    counted.push(AggregateExpr::new(AggregateFunction::Count, "*").with_alias(ROW_COUNT_COLUMN));
+    counted
+}
+
+/// Assembles the result of an `aggregate_metrics` query from the batches
+/// it returned, with the columns of `output`.
+///
+/// Columns are cast to their output types. Queries without a time window
+/// have no window columns; their only window is the queried range, ending
+/// at `i64::MAX` if it is open.
+pub(crate) fn aggregation_result(
+    batches: &[RecordBatch],
+    output: Schema,
+    window: TimeWindow,
+    from_timestamp: i64,
+    to_timestamp: Option<i64>,
+) -> Result<RecordBatch, Status> {
+    let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+    let windowed = !matches!(window, TimeWindow::None);
+    let mut columns: Vec<ArrayRef> = Vec::with_capacity(output.fields().len());
+    let mut index = 0;
+    for field in output.fields() {
+        let bound = match field.name().as_str() {
+            "window_start" if !windowed => Some(from_timestamp),
+            "window_end" if !windowed => Some(to_timestamp.unwrap_or(i64::MAX)),
+            _ => None,
+        };
+        if let Some(bound) = bound {
+            columns.push(Arc::new(Int64Array::from(vec![bound; rows])));
+            continue;
+        }
+        let parts = batches.iter()
+            .map(|batch| {
+                let column = batch.columns().get(index)
+                    .ok_or_else(|| Status::internal(format!("Missing aggregation column {}", field.name())))?;
+                cast(column, field.data_type())
+                    .map_err(|e| Status::internal(format!("Invalid aggregation column {}: {}", field.name(), e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let parts: Vec<&dyn Array> = parts.iter().map(|part| part.as_ref()).collect();
+        columns.push(if parts.is_empty() {
+            new_empty_array(field.data_type())
+        } else {
+            concat(&parts).map_err(|e| Status::internal(format!("Failed to combine aggregation batches: {}", e)))?
+        });
+        index += 1;
+    }
+    RecordBatch::try_new(Arc::new(output), columns)
+        .map_err(|e| Status::internal(format!("Failed to create aggregation batch: {}", e)))
+}
+
+/// Runs the work following the ingest of batches into tables: updates
+/// their materialized views, scores the anomaly monitors and evaluates the
+/// alert rules whose windows they closed, in that order, so rules see the
+/// updated views and scores.
+///
+/// Backends call this once the batches are committed, with the locks of
+/// [`materialized::lock_sources`] taken before the commit, which are
+/// released once the views are updated. The rows are stored at that point,
+/// so failures are logged, or mark views stale, instead of failing the
+/// ingest. Rollups and alert notifications run in the background instead,
+/// see [`rollup::spawn_scheduler`] and [`alerts`].
+pub(crate) async fn on_ingest<B: StorageBackend + ?Sized>(
+    backend: &B,
+    batches: &[(String, RecordBatch)],
+    sources: Vec<OwnedRwLockReadGuard<()>>,
+) {
+    for (table, batch) in batches {
+        materialized::update(backend, table, batch).await;
+    }
+    // Released first, as monitors ingest derived series, which locks the
+    // sources again
+    drop(sources);
+    for (table, batch) in batches {
+        anomaly::on_ingest(backend, table, batch).await;
+        alerts::on_ingest(backend, table, batch).await;
+    }
 }
 
 /// Storage backend trait for metric data persistence.
@@ -71,14 +617,31 @@ pub trait StorageBackend: Send + Sync + 'static {
     /// The handle must have been obtained from prepare_sql.
     async fn query_sql(&self, statement_handle: &[u8]) -> Result<Vec<MetricRecord>, Status>;
 
-    /// Aggregate metrics using the specified function and grouping.
+    /// Aggregate metrics using the specified expressions, grouping and
+    /// time window.
+    /// All expressions are computed in one pass over the rows of the
+    /// `metrics` table matching the filter, and their columns are validated
+    /// against its registered schema. The result clauses in `options` are
+    /// applied to the aggregated rows, see [`ResultOptions`].
+    ///
+    /// The result has the group by columns, `window_start`, `window_end`,
+    /// one column per aggregate and the number of aggregated rows in
+    /// [`ROW_COUNT_COLUMN`], see [`aggregate_output_schema`](crate::aggregation::aggregate_output_schema).
+    /// Rows with a timestamp in `[from_timestamp, to_timestamp)` are
+    /// aggregated, so consecutive ranges never share a row and a closed
+    /// window is read up to its end. Without a time window the queried range
+    /// is the only window.
+    #[allow(clippy::too_many_arguments)]
     async fn aggregate_metrics(
         &self,
-        function: AggregateFunction,
+        aggregates: &[AggregateExpr],
         group_by: &GroupBy,
+        window: TimeWindow,
         from_timestamp: i64,
         to_timestamp: Option<i64>,
-    ) -> Result<Vec<AggregateResult>, Status>;
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+    ) -> Result<RecordBatch, Status>;
 
     /// Create a new instance with the given options.
     /// The connection string and options are backend-specific.
@@ -96,8 +659,31 @@ pub trait StorageBackend: Send + Sync + 'static {
     /// Insert data into a table
     async fn insert_into_table(&self, table_name: &str, batch: RecordBatch) -> Result<(), Status>;
 
-    /// Query data from a table
-    async fn query_table(&self, table_name: &str, projection: Option<Vec<String>>) -> Result<RecordBatch, Status>;
+    /// Insert batches into several tables in one transaction, so that either
+    /// all of them are stored or none is.
+    ///
+    /// Batches for `metrics` are stored as by `insert_metrics`, all others as
+    /// by `insert_into_table`.
+    async fn insert_into_tables(&self, batches: Vec<(String, RecordBatch)>) -> Result<(), Status>;
+
+    /// Query data from a table, optionally restricted to the rows matching
+    /// a filter
+    async fn query_table(
+        &self,
+        table_name: &str,
+        projection: Option<Vec<String>>,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status>;
+
+    /// Delete the rows of a table matching a filter
+    async fn delete_from_table(&self, table_name: &str, filter: &Filter) -> Result<(), Status>;
+
+    /// Replace the rows of several tables matching a filter with a batch each,
+    /// in one transaction.
+    ///
+    /// Unlike `insert_into_tables` and `delete_from_table`, no materialized
+    /// views, rollups or alerts are updated; it rewrites derived tables.
+    async fn replace_in_tables(&self, replacements: Vec<(String, Filter, RecordBatch)>) -> Result<(), Status>;
 
     /// Create an aggregation view
     async fn create_aggregation_view(&self, view: &AggregationView) -> Result<(), Status>;
@@ -105,6 +691,164 @@ pub trait StorageBackend: Send + Sync + 'static {
     /// Query data from an aggregation view
     async fn query_aggregation_view(&self, view_name: &str) -> Result<RecordBatch, Status>;
 
+    /// Rebuild a materialized aggregation view from its source table
+    async fn refresh_aggregation_view(&self, view_name: &str) -> Result<(), Status> {
+        materialized::rebuild(self, view_name).await
+    }
+
+    /// Set the rollup tiers of a table
+    async fn set_rollup_policy(&self, table_name: &str, policy: RollupPolicy) -> Result<(), Status> {
+        rollup::configure(self, table_name, policy).await
+    }
+
+    /// Roll up the complete windows of a table and expire old data
+    async fn run_rollups(&self, table_name: &str) -> Result<(), Status> {
+        rollup::run(self, table_name, materialized::unix_now()).await
+    }
+
+    /// Register an alert rule, see [`alerts::create_rule`]
+    async fn create_alert_rule(&self, rule: AlertRule) -> Result<(), Status> {
+        alerts::create_rule(self, rule).await
+    }
+
+    /// Drop an alert rule and its alerts
+    async fn drop_alert_rule(&self, name: &str) -> Result<(), Status> {
+        alerts::drop_rule(self, name).await
+    }
+
+    /// Evaluate an alert rule now, returning the alerts that changed state
+    async fn evaluate_alert_rule(&self, name: &str) -> Result<Vec<Alert>, Status> {
+        alerts::evaluate(self, name, materialized::unix_now()).await
+    }
+
+    /// Register an anomaly monitor, see [`anomaly::create_monitor`]
+    async fn create_anomaly_monitor(&self, monitor: AnomalyMonitor) -> Result<(), Status> {
+        anomaly::create_monitor(self, monitor).await
+    }
+
+    /// Drop an anomaly monitor, keeping its derived series
+    async fn drop_anomaly_monitor(&self, name: &str) -> Result<(), Status> {
+        anomaly::drop_monitor(self, name).await
+    }
+
+    /// Score the windows of an anomaly monitor closed by now
+    async fn run_anomaly_monitor(&self, name: &str) -> Result<Vec<AnomalyScore>, Status> {
+        anomaly::run(self, name, materialized::unix_now()).await
+    }
+
+    /// Forecast the windowed aggregates of the `metrics` table past the
+    /// queried range, see [`forecast_batch`]. The aggregation is that of
+    /// [`aggregate_metrics`](Self::aggregate_metrics), and its result is the
+    /// history the series are fitted to.
+    #[allow(clippy::too_many_arguments)]
+    async fn forecast_metrics(
+        &self,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+        spec: &ForecastSpec,
+    ) -> Result<RecordBatch, Status> {
+        spec.validate()?;
+        forecast::validate_window(window)?;
+        let history = self.aggregate_metrics(aggregates, group_by, window, from_timestamp, to_timestamp, filter, options).await?;
+        forecast_batch(&history, aggregates, window, spec)
+    }
+
+    /// Aggregate two operands over the `metrics` table in the same time
+    /// windows of `[from_timestamp, to_timestamp)` and combine their series,
+    /// see [`SeriesAlignment`].
+    async fn align_metrics(
+        &self,
+        alignment: &SeriesAlignment,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<RecordBatch, Status> {
+        alignment.validate(window)?;
+        let mut batches = Vec::with_capacity(2);
+        for operand in [&alignment.left, &alignment.right] {
+            batches.push(self.aggregate_metrics(
+                std::slice::from_ref(&operand.aggregate),
+                &operand.group_by(),
+                window,
+                from_timestamp,
+                to_timestamp,
+                operand.filter.as_ref(),
+                &ResultOptions::default(),
+            ).await?);
+        }
+        alignment.combine(&batches[0], &batches[1])
+    }
+
+    /// Aggregate the `metrics` table in step-aligned fixed windows, one row
+    /// per step and series, widening the step to at most `max_points`
+    /// steps, see [`RangeQuery`].
+    async fn range_metrics(&self, query: &RangeQuery) -> Result<RecordBatch, Status> {
+        query.validate()?;
+        let grid = query.grid();
+        let batch = self.aggregate_metrics_between(
+            &query.aggregates,
+            &query.group_by(),
+            query.window(&grid),
+            grid.start,
+            grid.end(),
+            query.filter.as_ref(),
+        ).await?;
+        query.arrange(&batch, &grid)
+    }
+
+    /// Aggregate the `metrics` table like `aggregate_metrics` over
+    /// `[from_timestamp, to_timestamp)`, reading its rollup tiers if it has
+    /// a rollup policy, see [`aggregate_range`](Self::aggregate_range).
+    ///
+    /// Tiers are only read if they keep the `*` column, which the row count
+    /// is computed from.
+    #[allow(clippy::too_many_arguments)]
+    async fn aggregate_metrics_between(
+        &self,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: i64,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
+        if self.table_manager().get_rollup_policy("metrics").await.is_none() {
+            return self.aggregate_metrics(
+                aggregates,
+                group_by,
+                window,
+                from_timestamp,
+                Some(to_timestamp),
+                filter,
+                &ResultOptions::default(),
+            ).await;
+        }
+        let aggregates = counted_aggregates(aggregates);
+        self.aggregate_range("metrics", &aggregates, group_by, window, from_timestamp, to_timestamp, filter).await
+    }
+
+    /// Aggregate the rows of a table in time windows over a time range,
+    /// reading its rollup tiers where they satisfy the query, see
+    /// [`rollup::aggregate`]
+    #[allow(clippy::too_many_arguments)]
+    async fn aggregate_range(
+        &self,
+        table_name: &str,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: i64,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
+        rollup::aggregate(self, table_name, aggregates, group_by, window, from_timestamp, to_timestamp, filter).await
+    }
+
     /// Drop a table
     async fn drop_table(&self, table_name: &str) -> Result<(), Status>;
 
@@ -122,32 +866,18 @@ pub trait StorageBackend: Send + Sync + 'static {
         window: TimeWindow,
     ) -> Result<Vec<BatchAggregation>, Status> {
         // Default implementation that processes the batch and updates aggregations
-        let mut aggregations = HashMap::new();
-
-        for metric in batch {
-            let (window_start, window_end) = window.window_bounds(metric.timestamp);
-            let key = (metric.metric_id.clone(), window_start, window_end);
-
-            let agg = aggregations.entry(key).or_insert_with(|| BatchAggregation {
-                metric_id: metric.metric_id.clone(),
-                window_start,
-                window_end,
-                running_sum: 0.0,
-                running_count: 0,
-                min_value: f64::INFINITY,
-                max_value: f64::NEG_INFINITY,
-            });
-
-            // Update running aggregations
-            agg.running_sum += metric.value_running_window_sum;
-            agg.running_count += 1;
-            agg.min_value = agg.min_value.min(metric.value_running_window_sum);
-            agg.max_value = agg.max_value.max(metric.value_running_window_sum);
-        }
-
-        Ok(aggregations.into_values().collect())
+        Ok(aggregate_windows(batch, window, |_| 1))
     }
 
+    /// Query persisted batch-level aggregations.
+    /// Returns every window starting at or after `from_timestamp` and, if
+    /// given, ending at or before `to_timestamp`.
+    async fn query_batch_aggregations(
+        &self,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<Vec<BatchAggregation>, Status>;
+
     /// Insert batch-level aggregations.
     /// This is called after update_batch_aggregations to persist the aggregations.
     async fn insert_batch_aggregations(
@@ -223,17 +953,20 @@ impl StorageBackend for StorageBackendType {
 
     async fn aggregate_metrics(
         &self,
-        function: AggregateFunction,
+        aggregates: &[AggregateExpr],
         group_by: &GroupBy,
+        window: TimeWindow,
         from_timestamp: i64,
         to_timestamp: Option<i64>,
-    ) -> Result<Vec<AggregateResult>, Status> {
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+    ) -> Result<RecordBatch, Status> {
         match self {
             StorageBackendType::Adbc(backend) => {
-                backend.aggregate_metrics(function, group_by, from_timestamp, to_timestamp).await
+                backend.aggregate_metrics(aggregates, group_by, window, from_timestamp, to_timestamp, filter, options).await
             },
             StorageBackendType::DuckDb(backend) => {
-                backend.aggregate_metrics(function, group_by, from_timestamp, to_timestamp).await
+                backend.aggregate_metrics(aggregates, group_by, window, from_timestamp, to_timestamp, filter, options).await
             },
         }
     }
@@ -274,10 +1007,36 @@ impl StorageBackend for StorageBackendType {
         }
     }
 
-    async fn query_table(&self, table_name: &str, projection: Option<Vec<String>>) -> Result<RecordBatch, Status> {
+    async fn insert_into_tables(&self, batches: Vec<(String, RecordBatch)>) -> Result<(), Status> {
         match self {
-            StorageBackendType::Adbc(backend) => backend.query_table(table_name, projection).await,
-            StorageBackendType::DuckDb(backend) => backend.query_table(table_name, projection).await,
+            StorageBackendType::Adbc(backend) => backend.insert_into_tables(batches).await,
+            StorageBackendType::DuckDb(backend) => backend.insert_into_tables(batches).await,
+        }
+    }
+
+    async fn query_table(
+        &self,
+        table_name: &str,
+        projection: Option<Vec<String>>,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.query_table(table_name, projection, filter).await,
+            StorageBackendType::DuckDb(backend) => backend.query_table(table_name, projection, filter).await,
+        }
+    }
+
+    async fn delete_from_table(&self, table_name: &str, filter: &Filter) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.delete_from_table(table_name, filter).await,
+            StorageBackendType::DuckDb(backend) => backend.delete_from_table(table_name, filter).await,
+        }
+    }
+
+    async fn replace_in_tables(&self, replacements: Vec<(String, Filter, RecordBatch)>) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.replace_in_tables(replacements).await,
+            StorageBackendType::DuckDb(backend) => backend.replace_in_tables(replacements).await,
         }
     }
 
@@ -295,6 +1054,130 @@ impl StorageBackend for StorageBackendType {
         }
     }
 
+    async fn refresh_aggregation_view(&self, view_name: &str) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.refresh_aggregation_view(view_name).await,
+            StorageBackendType::DuckDb(backend) => backend.refresh_aggregation_view(view_name).await,
+        }
+    }
+
+    async fn set_rollup_policy(&self, table_name: &str, policy: RollupPolicy) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.set_rollup_policy(table_name, policy).await,
+            StorageBackendType::DuckDb(backend) => backend.set_rollup_policy(table_name, policy).await,
+        }
+    }
+
+    async fn run_rollups(&self, table_name: &str) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.run_rollups(table_name).await,
+            StorageBackendType::DuckDb(backend) => backend.run_rollups(table_name).await,
+        }
+    }
+
+    async fn create_alert_rule(&self, rule: AlertRule) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.create_alert_rule(rule).await,
+            StorageBackendType::DuckDb(backend) => backend.create_alert_rule(rule).await,
+        }
+    }
+
+    async fn drop_alert_rule(&self, name: &str) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.drop_alert_rule(name).await,
+            StorageBackendType::DuckDb(backend) => backend.drop_alert_rule(name).await,
+        }
+    }
+
+    async fn evaluate_alert_rule(&self, name: &str) -> Result<Vec<Alert>, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.evaluate_alert_rule(name).await,
+            StorageBackendType::DuckDb(backend) => backend.evaluate_alert_rule(name).await,
+        }
+    }
+
+    async fn create_anomaly_monitor(&self, monitor: AnomalyMonitor) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.create_anomaly_monitor(monitor).await,
+            StorageBackendType::DuckDb(backend) => backend.create_anomaly_monitor(monitor).await,
+        }
+    }
+
+    async fn drop_anomaly_monitor(&self, name: &str) -> Result<(), Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.drop_anomaly_monitor(name).await,
+            StorageBackendType::DuckDb(backend) => backend.drop_anomaly_monitor(name).await,
+        }
+    }
+
+    async fn run_anomaly_monitor(&self, name: &str) -> Result<Vec<AnomalyScore>, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.run_anomaly_monitor(name).await,
+            StorageBackendType::DuckDb(backend) => backend.run_anomaly_monitor(name).await,
+        }
+    }
+
+    async fn forecast_metrics(
+        &self,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+        filter: Option<&Filter>,
+        options: &ResultOptions,
+        spec: &ForecastSpec,
+    ) -> Result<RecordBatch, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => {
+                backend.forecast_metrics(aggregates, group_by, window, from_timestamp, to_timestamp, filter, options, spec).await
+            }
+            StorageBackendType::DuckDb(backend) => {
+                backend.forecast_metrics(aggregates, group_by, window, from_timestamp, to_timestamp, filter, options, spec).await
+            }
+        }
+    }
+
+    async fn align_metrics(
+        &self,
+        alignment: &SeriesAlignment,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<RecordBatch, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.align_metrics(alignment, window, from_timestamp, to_timestamp).await,
+            StorageBackendType::DuckDb(backend) => backend.align_metrics(alignment, window, from_timestamp, to_timestamp).await,
+        }
+    }
+
+    async fn range_metrics(&self, query: &RangeQuery) -> Result<RecordBatch, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.range_metrics(query).await,
+            StorageBackendType::DuckDb(backend) => backend.range_metrics(query).await,
+        }
+    }
+
+    async fn aggregate_range(
+        &self,
+        table_name: &str,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        window: TimeWindow,
+        from_timestamp: i64,
+        to_timestamp: i64,
+        filter: Option<&Filter>,
+    ) -> Result<RecordBatch, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => {
+                backend.aggregate_range(table_name, aggregates, group_by, window, from_timestamp, to_timestamp, filter).await
+            }
+            StorageBackendType::DuckDb(backend) => {
+                backend.aggregate_range(table_name, aggregates, group_by, window, from_timestamp, to_timestamp, filter).await
+            }
+        }
+    }
+
     async fn drop_table(&self, table_name: &str) -> Result<(), Status> {
         match self {
             StorageBackendType::Adbc(backend) => backend.drop_table(table_name).await,
@@ -327,6 +1210,17 @@ impl StorageBackend for StorageBackendType {
         }
     }
 
+    async fn query_batch_aggregations(
+        &self,
+        from_timestamp: i64,
+        to_timestamp: Option<i64>,
+    ) -> Result<Vec<BatchAggregation>, Status> {
+        match self {
+            StorageBackendType::Adbc(backend) => backend.query_batch_aggregations(from_timestamp, to_timestamp).await,
+            StorageBackendType::DuckDb(backend) => backend.query_batch_aggregations(from_timestamp, to_timestamp).await,
+        }
+    }
+
     async fn insert_batch_aggregations(
         &self,
         aggregations: Vec<BatchAggregation>,
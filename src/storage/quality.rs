@@ -0,0 +1,359 @@
+//! Data quality gates for ingested record batches.
+//!
+//! A `QualityGate` checks every row of an (already schema-adapted) batch
+//! against the validation rules configured for its target table:
+//! - Range checks on numeric columns
+//! - Handling of NaN and infinite values in floating point columns
+//! - A maximum clock skew for timestamps in the future
+//! - A required pattern for metric identifiers
+//!
+//! Rows that fail a rule are split off from the batch together with the
+//! reasons they failed, and are written to the `dead_letters` table instead
+//! of their target table. The remaining rows are ingested as usual.
+
+use arrow::compute::{cast, filter_record_batch, nullif, take};
+use arrow::json::LineDelimitedWriter;
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+    UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+/// Name of the table receiving rows rejected by quality gates.
+pub const DEAD_LETTER_TABLE: &str = "dead_letters";
+
+/// Inclusive bounds for the values of a numeric column.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValueRange {
+    /// Smallest accepted value
+    #[serde(default)]
+    pub min: Option<f64>,
+    /// Largest accepted value
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+/// How NaN and infinite values in floating point columns are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFinitePolicy {
+    /// Send the row to the dead-letter table
+    #[default]
+    Reject,
+    /// Replace the value with null; rejected if the column is not nullable
+    Null,
+    /// Ingest the value unchanged
+    Accept,
+}
+
+/// Validation rules for a single table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityRules {
+    /// Accepted value ranges keyed by column name
+    #[serde(default)]
+    pub ranges: HashMap<String, ValueRange>,
+    /// Handling of NaN and infinite values
+    #[serde(default)]
+    pub non_finite: NonFinitePolicy,
+    /// Maximum number of seconds a timestamp may lie in the future
+    #[serde(default)]
+    pub max_clock_skew_secs: Option<u64>,
+    /// Column holding the row timestamp (Unix seconds or an Arrow timestamp)
+    #[serde(default = "default_timestamp_column")]
+    pub timestamp_column: String,
+    /// Pattern every metric identifier must match in full
+    #[serde(default)]
+    pub metric_id_pattern: Option<String>,
+    /// Column holding the metric identifier
+    #[serde(default = "default_metric_id_column")]
+    pub metric_id_column: String,
+}
+
+fn default_timestamp_column() -> String {
+    "timestamp".to_string()
+}
+
+fn default_metric_id_column() -> String {
+    "metric_id".to_string()
+}
+
+impl Default for QualityRules {
+    fn default() -> Self {
+        Self {
+            ranges: HashMap::new(),
+            non_finite: NonFinitePolicy::default(),
+            max_clock_skew_secs: None,
+            timestamp_column: default_timestamp_column(),
+            metric_id_pattern: None,
+            metric_id_column: default_metric_id_column(),
+        }
+    }
+}
+
+/// A row rejected by a quality gate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedRow {
+    /// Index of the row in the evaluated batch
+    pub row: usize,
+    /// Every rule the row violated
+    pub reason: String,
+}
+
+/// Result of running a batch through a quality gate.
+#[derive(Debug, Clone)]
+pub struct QualityReport {
+    /// Rows that passed every rule
+    pub accepted: RecordBatch,
+    /// Rows that failed at least one rule, in batch order
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// Compiled validation rules for a table.
+#[derive(Debug, Clone)]
+pub struct QualityGate {
+    rules: QualityRules,
+    metric_id_pattern: Option<Regex>,
+}
+
+impl QualityGate {
+    /// Compiles the rules of a table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if the metric ID pattern is not a
+    /// valid regular expression.
+    pub fn new(rules: QualityRules) -> Result<Self, Status> {
+        let metric_id_pattern = rules
+            .metric_id_pattern
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    Status::invalid_argument(format!("Invalid metric_id pattern '{}': {}", pattern, e))
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            rules,
+            metric_id_pattern,
+        })
+    }
+
+    /// Returns the rules enforced by this gate.
+    pub fn rules(&self) -> &QualityRules {
+        &self.rules
+    }
+
+    /// Splits a batch into accepted and rejected rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::failed_precondition` if a rule refers to a column the
+    /// batch does not have or that has an unsuitable type.
+    pub fn evaluate(&self, batch: &RecordBatch) -> Result<QualityReport, Status> {
+        self.evaluate_at(batch, unix_now())
+    }
+
+    /// Like [`evaluate`](Self::evaluate), with an explicit current time in
+    /// Unix seconds for the clock skew check.
+    pub fn evaluate_at(&self, batch: &RecordBatch, now: i64) -> Result<QualityReport, Status> {
+        let mut reasons: Vec<Vec<String>> = vec![Vec::new(); batch.num_rows()];
+        let mut columns = batch.columns().to_vec();
+        let schema = batch.schema();
+
+        // Non-finite values in any floating point column
+        if self.rules.non_finite != NonFinitePolicy::Accept {
+            for (index, field) in schema.fields().iter().enumerate() {
+                if !field.data_type().is_floating() {
+                    continue;
+                }
+                let values = as_float64(field.name(), &columns[index])?;
+                let non_finite: BooleanArray = values
+                    .iter()
+                    .map(|v| Some(v.is_some_and(|v| !v.is_finite())))
+                    .collect();
+                if non_finite.true_count() == 0 {
+                    continue;
+                }
+
+                if self.rules.non_finite == NonFinitePolicy::Null && field.is_nullable() {
+                    columns[index] = nullif(&columns[index], &non_finite)
+                        .map_err(|e| Status::internal(format!("Failed to null non-finite values: {}", e)))?;
+                    continue;
+                }
+                for (row, value) in values.iter().enumerate() {
+                    if let Some(v) = value.filter(|v| !v.is_finite()) {
+                        reasons[row].push(format!("{} is not finite ({})", field.name(), v));
+                    }
+                }
+            }
+        }
+
+        // Value ranges
+        let mut ranges: Vec<_> = self.rules.ranges.iter().collect();
+        ranges.sort_by(|a, b| a.0.cmp(b.0));
+        for (column, range) in ranges {
+            let values = as_float64(column, self.column(batch, column)?)?;
+            for (row, value) in values.iter().enumerate() {
+                let Some(v) = value.filter(|v| v.is_finite()) else { continue };
+                if let Some(min) = range.min.filter(|min| v < *min) {
+                    reasons[row].push(format!("{} {} is below minimum {}", column, v, min));
+                }
+                if let Some(max) = range.max.filter(|max| v > *max) {
+                    reasons[row].push(format!("{} {} is above maximum {}", column, v, max));
+                }
+            }
+        }
+
+        // Timestamps too far in the future
+        if let Some(skew) = self.rules.max_clock_skew_secs {
+            let column = &self.rules.timestamp_column;
+            let timestamps = as_unix_seconds(column, self.column(batch, column)?)?;
+            let limit = now.saturating_add(skew as i64);
+            for (row, value) in timestamps.iter().enumerate() {
+                if let Some(ts) = value.filter(|ts| *ts > limit) {
+                    reasons[row].push(format!(
+                        "{} {} is {}s in the future (max clock skew {}s)",
+                        column, ts, ts - now, skew
+                    ));
+                }
+            }
+        }
+
+        // Metric identifier pattern
+        if let Some(pattern) = &self.metric_id_pattern {
+            let column = &self.rules.metric_id_column;
+            let ids = cast(self.column(batch, column)?, &DataType::Utf8).map_err(|e| {
+                Status::failed_precondition(format!("Column '{}' is not a string column: {}", column, e))
+            })?;
+            let ids = ids.as_any().downcast_ref::<StringArray>()
+                .ok_or_else(|| Status::internal("Failed to read metric IDs"))?;
+            for (row, id) in ids.iter().enumerate() {
+                match id {
+                    Some(id) if pattern.is_match(id) => {}
+                    Some(id) => reasons[row].push(format!(
+                        "{} '{}' does not match pattern '{}'",
+                        column,
+                        id,
+                        self.rules.metric_id_pattern.as_deref().unwrap_or_default()
+                    )),
+                    None => reasons[row].push(format!("{} is null", column)),
+                }
+            }
+        }
+
+        let keep: BooleanArray = reasons.iter().map(|r| Some(r.is_empty())).collect();
+        let batch = RecordBatch::try_new(schema, columns)
+            .map_err(|e| Status::internal(format!("Failed to rebuild batch: {}", e)))?;
+        let accepted = filter_record_batch(&batch, &keep)
+            .map_err(|e| Status::internal(format!("Failed to filter batch: {}", e)))?;
+        let rejected = reasons
+            .into_iter()
+            .enumerate()
+            .filter(|(_, reasons)| !reasons.is_empty())
+            .map(|(row, reasons)| RejectedRow {
+                row,
+                reason: reasons.join("; "),
+            })
+            .collect();
+
+        Ok(QualityReport { accepted, rejected })
+    }
+
+    fn column<'a>(&self, batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef, Status> {
+        batch.column_by_name(name).ok_or_else(|| {
+            Status::failed_precondition(format!("Quality rule refers to unknown column '{}'", name))
+        })
+    }
+}
+
+/// Returns the schema of the dead-letter table.
+pub fn dead_letter_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("table_name", DataType::Utf8, false),
+        Field::new("source", DataType::Utf8, false),
+        Field::new("reason", DataType::Utf8, false),
+        Field::new("row", DataType::Utf8, false),
+        Field::new("rejected_at", DataType::Int64, false),
+    ])
+}
+
+/// Builds the dead-letter records for the rejected rows of `batch`.
+///
+/// Each rejected row is stored as a JSON object alongside the table it was
+/// destined for, the source that sent it and the reason it was rejected.
+pub fn dead_letter_batch(
+    table: &str,
+    source: &str,
+    batch: &RecordBatch,
+    rejected: &[RejectedRow],
+) -> Result<RecordBatch, Status> {
+    let indices = UInt32Array::from_iter_values(rejected.iter().map(|r| r.row as u32));
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| take(column, &indices, None))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Status::internal(format!("Failed to select rejected rows: {}", e)))?;
+    let rows = RecordBatch::try_new(batch.schema(), columns)
+        .map_err(|e| Status::internal(format!("Failed to select rejected rows: {}", e)))?;
+
+    let mut writer = LineDelimitedWriter::new(Vec::new());
+    writer
+        .write(&rows)
+        .and_then(|_| writer.finish())
+        .map_err(|e| Status::internal(format!("Failed to encode rejected rows: {}", e)))?;
+    let json = String::from_utf8(writer.into_inner())
+        .map_err(|e| Status::internal(format!("Failed to encode rejected rows: {}", e)))?;
+
+    let len = rejected.len();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(std::iter::repeat_n(table, len))),
+        Arc::new(StringArray::from_iter_values(std::iter::repeat_n(source, len))),
+        Arc::new(StringArray::from_iter_values(rejected.iter().map(|r| r.reason.as_str()))),
+        Arc::new(StringArray::from(json.lines().collect::<Vec<_>>())),
+        Arc::new(Int64Array::from_value(unix_now(), len)),
+    ];
+    RecordBatch::try_new(Arc::new(dead_letter_schema()), columns)
+        .map_err(|e| Status::internal(format!("Failed to build dead-letter batch: {}", e)))
+}
+
+fn as_float64(name: &str, column: &ArrayRef) -> Result<Float64Array, Status> {
+    let values = cast(column, &DataType::Float64).map_err(|e| {
+        Status::failed_precondition(format!("Column '{}' is not numeric: {}", name, e))
+    })?;
+    values
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .cloned()
+        .ok_or_else(|| Status::internal(format!("Failed to read column '{}'", name)))
+}
+
+/// Reads a timestamp column as Unix seconds.
+///
+/// Integer columns are taken to hold seconds, matching `MetricRecord`.
+fn as_unix_seconds(name: &str, column: &ArrayRef) -> Result<Int64Array, Status> {
+    let column = match column.data_type() {
+        DataType::Timestamp(_, _) => cast(column, &DataType::Timestamp(TimeUnit::Second, None)),
+        _ => Ok(column.clone()),
+    }
+    .and_then(|column| cast(&column, &DataType::Int64))
+    .map_err(|e| Status::failed_precondition(format!("Column '{}' is not a timestamp: {}", name, e)))?;
+    column
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .cloned()
+        .ok_or_else(|| Status::internal(format!("Failed to read column '{}'", name)))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
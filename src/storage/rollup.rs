@@ -0,0 +1,450 @@
+//! Multi-resolution rollups with per-tier retention.
+//!
+//! A [`RollupPolicy`] declares tiers of increasingly coarse windows for a
+//! table. Tier 0 is populated from the rows of the table and every further
+//! tier from the tier below it, so each run only reads the windows rolled
+//! up since the previous one. Tiers hold mergeable [`BatchAggregation`]
+//! state per group, aggregated column and window in `<table>_rollup_<tier>`,
+//! keyed like the state of materialized views, see
+//! [`materialized`](super::materialized).
+//!
+//! Only complete windows are rolled up, and each tier records the end of
+//! the last window it rolled up; rows arriving later for such a window are
+//! not added to the rollups. Rows of the table older than the raw retention
+//! and tier windows older than the tier retention are deleted, but only
+//! once they were rolled into the next tier.
+//!
+//! Rollups run on a schedule once a window of the finest tier completed,
+//! see [`run_due`] and [`spawn_scheduler`], or on demand with [`run`]. Range queries read the
+//! coarsest tier that satisfies them and the rows not rolled up yet, see
+//! [`aggregate`]; so do step-aligned range and PromQL queries of `metrics`,
+//! see [`StorageBackend::aggregate_metrics_between`].
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use arrow::compute::max;
+use arrow_array::{Int64Array, RecordBatch};
+use arrow_schema::Schema;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tonic::Status;
+use crate::aggregation::{aggregate_output_schema, validate_aggregates, AggregateExpr, GroupBy, TimeWindow};
+use crate::aggregation::filter::{CompareOp, Filter, Literal};
+use crate::storage::materialized::{
+    evaluate_states, parse_state_key, partial_states, state_key, unix_now, validate_state_columns,
+};
+use crate::storage::{BatchAggregation, StorageBackend};
+
+/// Schema metadata key of the coarsest tier read by [`aggregate`], absent
+/// if only rows of the table were read.
+pub const ROLLUP_TIER_KEY: &str = "hyprstream.rollup_tier";
+
+/// One resolution of a rollup policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollupTier {
+    /// Fixed or aligned window of the tier
+    pub window: TimeWindow,
+    /// Aggregates the tier is queried for; its state supports every
+    /// function over the same columns
+    pub aggregates: Vec<AggregateExpr>,
+    /// How long windows of the tier are kept, by window end
+    pub retention: Duration,
+}
+
+/// Rollup tiers of a table, from the finest to the coarsest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollupPolicy {
+    /// Columns identifying a series, e.g. `metric_id`
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    /// How long rows of the table are kept once they are rolled up
+    pub raw_retention: Duration,
+    pub tiers: Vec<RollupTier>,
+}
+
+impl RollupPolicy {
+    /// Checks the policy against the schema of its table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if there are no tiers, a tier
+    /// window is not fixed or aligned, a tier window does not consist of
+    /// whole windows of the tier below, a tier keeps its windows for less
+    /// than one window, or a tier aggregates a column the tier below does
+    /// not keep. The aggregates must be valid for the table, see
+    /// [`validate_aggregates`].
+    pub fn validate(&self, schema: &Schema) -> Result<(), Status> {
+        if self.tiers.is_empty() {
+            return Err(Status::invalid_argument("A rollup policy needs at least one tier"));
+        }
+        let group_by = GroupBy { columns: self.group_by.clone(), time_column: None };
+        let mut below: Option<(&RollupTier, i64, i64)> = None;
+        for (i, tier) in self.tiers.iter().enumerate() {
+            let (size, origin) = span(tier.window).ok_or_else(|| {
+                Status::invalid_argument(format!("Rollup tier {} must use a fixed or aligned window", i))
+            })?;
+            validate_aggregates(&tier.aggregates, &group_by, schema)?;
+            validate_state_columns(&self.group_by, &tier.aggregates, schema)?;
+            if (tier.retention.as_secs() as i64) < size {
+                return Err(Status::invalid_argument(format!(
+                    "Rollup tier {} keeps its windows for less than one window",
+                    i
+                )));
+            }
+            if let Some((below, below_size, below_origin)) = below {
+                if size % below_size != 0 || (origin - below_origin).rem_euclid(below_size) != 0 {
+                    return Err(Status::invalid_argument(format!(
+                        "Windows of rollup tier {} do not align with the windows of tier {}",
+                        i,
+                        i - 1
+                    )));
+                }
+                let kept = |column: &str| below.aggregates.iter().any(|a| a.column == column);
+                if let Some(aggregate) = tier.aggregates.iter().find(|a| !kept(&a.column)) {
+                    return Err(Status::invalid_argument(format!(
+                        "Rollup tier {} aggregates column {}, which tier {} does not keep",
+                        i,
+                        aggregate.column,
+                        i - 1
+                    )));
+                }
+            }
+            below = Some((tier, size, origin));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the size and origin in seconds of a fixed or aligned window.
+fn span(window: TimeWindow) -> Option<(i64, i64)> {
+    match window {
+        TimeWindow::Fixed(size) => Some((size.as_secs().max(1) as i64, 0)),
+        TimeWindow::Aligned { size, origin } => Some((size.as_secs().max(1) as i64, origin)),
+        _ => None,
+    }
+}
+
+/// Returns the name of the table holding a tier of the rollups of a table.
+pub fn tier_table(table: &str, tier: usize) -> String {
+    format!("{}_rollup_{}", table, tier)
+}
+
+/// Validates and registers the rollup policy of a table and creates the
+/// tables of its tiers.
+pub async fn configure<B: StorageBackend + ?Sized>(
+    backend: &B,
+    table: &str,
+    policy: RollupPolicy,
+) -> Result<(), Status> {
+    let schema = backend.table_manager().get_table_schema(table).await?;
+    policy.validate(&schema)?;
+    if backend.table_manager().get_rollup_policy(table).await.is_some() {
+        return Err(Status::already_exists(format!("Table {} already has a rollup policy", table)));
+    }
+    for tier in 0..policy.tiers.len() {
+        backend.create_table(&tier_table(table, tier), &BatchAggregation::schema()).await?;
+    }
+    backend.table_manager().set_rollup_policy(table, policy).await
+}
+
+/// Rolls up the windows of every tier of a table completed by `now`, then
+/// deletes rows and windows past their retention.
+pub async fn run<B: StorageBackend + ?Sized>(backend: &B, table: &str, now: i64) -> Result<(), Status> {
+    let policy = backend.table_manager().get_rollup_policy(table).await
+        .ok_or_else(|| Status::not_found(format!("Table {} has no rollup policy", table)))?;
+    let lock = backend.table_manager().rollup_lock(table).await;
+    let _running = lock.write().await;
+    let mut progress = match backend.table_manager().rollup_progress(table).await {
+        Some(progress) => progress,
+        None => stored_progress(backend, table, policy.tiers.len()).await?,
+    };
+
+    // A tier is complete up to where the tier below was rolled up
+    let mut complete_until = now;
+    for (i, tier) in policy.tiers.iter().enumerate() {
+        let cutoff = tier.window.window_bounds(complete_until).0;
+        if cutoff > progress[i] {
+            let mut states = if i == 0 {
+                let rows = backend.query_table(table, None, Some(&range("timestamp", "timestamp", progress[i], cutoff))).await?;
+                partial_states(&rows, &policy.group_by, &tier.aggregates, tier.window)?.states.into_values().collect()
+            } else {
+                let below = tier_table(table, i - 1);
+                let rows = backend.query_table(&below, None, Some(&range("window_start", "window_end", progress[i], cutoff))).await?;
+                roll_up(&BatchAggregation::from_record_batch(&rows)?, tier)?
+            };
+            if !states.is_empty() {
+                states.sort_by(|a, b| (&a.metric_id, a.window_start).cmp(&(&b.metric_id, b.window_start)));
+                backend.insert_into_table(&tier_table(table, i), BatchAggregation::to_record_batch(&states)?).await?;
+            }
+            progress[i] = cutoff;
+            backend.table_manager().set_rollup_progress(table, progress.clone()).await;
+        }
+        complete_until = progress[i];
+    }
+
+    expire(backend, table, &policy, &progress, now).await
+}
+
+/// Runs the rollups of the tables where a window of the finest tier
+/// completed since the last run.
+///
+/// Failures are logged and retried by the next call, so one table cannot
+/// keep others from rolling up.
+pub async fn run_due<B: StorageBackend + ?Sized>(backend: &B, now: i64) {
+    for table in backend.table_manager().rollup_tables().await {
+        let Some(policy) = backend.table_manager().get_rollup_policy(&table).await else {
+            continue;
+        };
+        let due = match backend.table_manager().rollup_progress(&table).await {
+            Some(progress) => policy.tiers[0].window.window_bounds(now).0 > progress[0],
+            None => true,
+        };
+        if due {
+            if let Err(e) = run(backend, &table, now).await {
+                tracing::error!("Failed to roll up table {}: {}", table, e);
+            }
+        }
+    }
+}
+
+/// Spawns a task calling [`run_due`] every `tick`.
+pub fn spawn_scheduler<B: StorageBackend>(backend: Arc<B>, tick: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tick);
+        loop {
+            interval.tick().await;
+            run_due(backend.as_ref(), unix_now()).await;
+        }
+    })
+}
+
+/// Aggregates the rows of a table with a timestamp in `[from, to)` into
+/// `window`s, reading rollup tiers instead of rows where they satisfy the
+/// query.
+///
+/// A tier satisfies the query if `window` consists of whole tier windows,
+/// `from` is the start of a tier window, the tier keeps every aggregated
+/// column and the query groups by a subset of the policy groups. A filter
+/// only allows reading tiers if it only uses group by columns. The coarsest
+/// such tier is read up to where it was rolled up, the rest of the range
+/// from the finer tiers and finally from the rows of the table, and the
+/// pieces are merged per window; tier windows past their retention are
+/// missing from the result.
+///
+/// The result has the columns of an aggregation view, see
+/// [`aggregate_output_schema`], with the coarsest tier read under
+/// [`ROLLUP_TIER_KEY`].
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if `from` is after `to`, the window
+/// is a session window, none or invalid, the query groups by a time column, or the
+/// aggregates, filter or columns are invalid for the table.
+#[allow(clippy::too_many_arguments)]
+pub async fn aggregate<B: StorageBackend + ?Sized>(
+    backend: &B,
+    table: &str,
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    window: TimeWindow,
+    from: i64,
+    to: i64,
+    filter: Option<&Filter>,
+) -> Result<RecordBatch, Status> {
+    let manager = backend.table_manager();
+    manager.validate_aggregates(table, aggregates, group_by).await?;
+    window.validate()?;
+    if let Some(filter) = filter {
+        manager.validate_filter(table, filter).await?;
+    }
+    if matches!(window, TimeWindow::None | TimeWindow::Session { .. }) || group_by.time_column.is_some() {
+        return Err(Status::invalid_argument(
+            "Range aggregation requires a time window other than a session window and no time column",
+        ));
+    }
+    if from > to {
+        return Err(Status::invalid_argument("The start of the range must not be after its end"));
+    }
+    let schema = manager.get_table_schema(table).await?;
+    validate_state_columns(&group_by.columns, aggregates, &schema)?;
+    let output = aggregate_output_schema(aggregates, group_by, true, &schema)?;
+
+    // Rollups of the table must not move rows between the pieces while they
+    // are read; tables without a policy are never rolled up
+    let policy = manager.get_rollup_policy(table).await;
+    let lock = match policy {
+        Some(_) => Some(manager.rollup_lock(table).await),
+        None => None,
+    };
+    let _reading = match &lock {
+        Some(lock) => Some(lock.read().await),
+        None => None,
+    };
+    let coarsest = policy.as_ref().and_then(|policy| {
+        coarsest_tier(policy, aggregates, group_by, window, from, filter, &schema)
+    });
+
+    let mut states: HashMap<(String, i64, i64), BatchAggregation> = HashMap::new();
+    let mut merge = |state: &BatchAggregation, key: String, (start, end): (i64, i64)| {
+        states
+            .entry((key.clone(), start, end))
+            .or_insert_with(|| BatchAggregation::new(key, start, end))
+            .merge(state)
+    };
+    let mut rows_from = from;
+    if let (Some(policy), Some(coarsest)) = (&policy, coarsest) {
+        let progress = match manager.rollup_progress(table).await {
+            Some(progress) => progress,
+            None => stored_progress(backend, table, policy.tiers.len()).await?,
+        };
+        let columns: HashSet<&str> = aggregates.iter().map(|a| a.column.as_str()).collect();
+        let indices: Vec<usize> = group_by.columns.iter()
+            .filter_map(|column| policy.group_by.iter().position(|c| c == column))
+            .collect();
+        for i in (0..=coarsest).rev() {
+            let until = progress[i].min(policy.tiers[i].window.window_bounds(to).0);
+            if until <= rows_from {
+                continue;
+            }
+            let tier = backend.query_table(&tier_table(table, i), None, Some(&range("window_start", "window_end", rows_from, until))).await?;
+            for state in BatchAggregation::from_record_batch(&tier)? {
+                let (group, column) = parse_state_key(&state.metric_id)?;
+                if !columns.contains(column.as_str()) {
+                    continue;
+                }
+                let group: Vec<Literal> = indices.iter().map(|&index| group[index].clone()).collect();
+                merge(&state, state_key(&group, &column)?, window.window_bounds(state.window_start))?;
+            }
+            rows_from = until;
+        }
+    }
+
+    let mut selected = range("timestamp", "timestamp", rows_from, to);
+    if let Some(filter) = filter {
+        selected = selected.and(filter.clone());
+    }
+    let rows = backend.query_table(table, None, Some(&selected)).await?;
+    for ((key, start, end), state) in partial_states(&rows, &group_by.columns, aggregates, window)?.states {
+        merge(&state, key, (start, end))?;
+    }
+
+    let states: Vec<BatchAggregation> = states.into_values().collect();
+    let mut batch = evaluate_states(&states, &group_by.columns, aggregates, output)?;
+    if let Some(coarsest) = coarsest {
+        // Tier state is only grouped, so the filter applies to the groups
+        if let Some(filter) = filter {
+            batch = filter.apply(&batch)?;
+        }
+        let mut metadata = batch.schema().metadata().clone();
+        metadata.insert(ROLLUP_TIER_KEY.to_string(), coarsest.to_string());
+        let schema = batch.schema().as_ref().clone().with_metadata(metadata);
+        batch = RecordBatch::try_new(Arc::new(schema), batch.columns().to_vec())
+            .map_err(|e| Status::internal(format!("Failed to create record batch: {}", e)))?;
+    }
+    Ok(batch)
+}
+
+/// Returns the coarsest tier satisfying a range query, see [`aggregate`].
+fn coarsest_tier(
+    policy: &RollupPolicy,
+    aggregates: &[AggregateExpr],
+    group_by: &GroupBy,
+    window: TimeWindow,
+    from: i64,
+    filter: Option<&Filter>,
+    schema: &Schema,
+) -> Option<usize> {
+    let (size, origin) = span(window)?;
+    if !group_by.columns.iter().all(|column| policy.group_by.contains(column)) {
+        return None;
+    }
+    if let Some(filter) = filter {
+        let groups: Vec<_> = group_by.columns.iter()
+            .filter_map(|column| schema.field_with_name(column).ok().cloned())
+            .collect();
+        filter.validate(&Schema::new(groups)).ok()?;
+    }
+    policy.tiers.iter().rposition(|tier| {
+        let Some((tier_size, tier_origin)) = span(tier.window) else {
+            return false;
+        };
+        size % tier_size == 0
+            && (origin - tier_origin).rem_euclid(tier_size) == 0
+            && (from - tier_origin).rem_euclid(tier_size) == 0
+            && aggregates.iter().all(|a| tier.aggregates.iter().any(|kept| kept.column == a.column))
+    })
+}
+
+/// Selects the rows in `[from, to)`, by `start_column` and `end_column`;
+/// `i64::MIN` leaves the range open.
+fn range(start_column: &str, end_column: &str, from: i64, to: i64) -> Filter {
+    let before = if start_column == end_column { CompareOp::Lt } else { CompareOp::Le };
+    let mut filters = vec![Filter::compare(end_column, before, Literal::Int(to))];
+    if from > i64::MIN {
+        filters.push(Filter::compare(start_column, CompareOp::Ge, Literal::Int(from)));
+    }
+    Filter::And(filters)
+}
+
+/// Merges the windows of the tier below into the windows of `tier`,
+/// keeping the state of the columns `tier` aggregates.
+fn roll_up(states: &[BatchAggregation], tier: &RollupTier) -> Result<Vec<BatchAggregation>, Status> {
+    let columns: HashSet<&str> = tier.aggregates.iter().map(|a| a.column.as_str()).collect();
+    let mut rolled: HashMap<(String, i64), BatchAggregation> = HashMap::new();
+    for state in states {
+        let (_, column) = parse_state_key(&state.metric_id)?;
+        if !columns.contains(column.as_str()) {
+            continue;
+        }
+        let (window_start, window_end) = tier.window.window_bounds(state.window_start);
+        rolled
+            .entry((state.metric_id.clone(), window_start))
+            .or_insert_with(|| BatchAggregation::new(state.metric_id.clone(), window_start, window_end))
+            .merge(state)?;
+    }
+    Ok(rolled.into_values().collect())
+}
+
+/// Reads the end of the last rolled up window of each tier from the tier
+/// tables, e.g. after a restart.
+async fn stored_progress<B: StorageBackend + ?Sized>(
+    backend: &B,
+    table: &str,
+    tiers: usize,
+) -> Result<Vec<i64>, Status> {
+    let mut progress = Vec::with_capacity(tiers);
+    for tier in 0..tiers {
+        let states = backend.query_table(&tier_table(table, tier), None, None).await?;
+        let ends = states.column_by_name("window_end").and_then(|c| c.as_any().downcast_ref::<Int64Array>());
+        progress.push(ends.and_then(max).unwrap_or(i64::MIN));
+    }
+    Ok(progress)
+}
+
+/// Deletes rows and tier windows past their retention that were rolled
+/// into the next tier.
+async fn expire<B: StorageBackend + ?Sized>(
+    backend: &B,
+    table: &str,
+    policy: &RollupPolicy,
+    progress: &[i64],
+    now: i64,
+) -> Result<(), Status> {
+    let raw_cutoff = now.saturating_sub(policy.raw_retention.as_secs() as i64).min(progress[0]);
+    if raw_cutoff > i64::MIN {
+        let expired = Filter::compare("timestamp", CompareOp::Lt, Literal::Int(raw_cutoff));
+        backend.delete_from_table(table, &expired).await?;
+    }
+    for (i, tier) in policy.tiers.iter().enumerate() {
+        let mut cutoff = now.saturating_sub(tier.retention.as_secs() as i64);
+        if let Some(next) = progress.get(i + 1) {
+            cutoff = cutoff.min(*next);
+        }
+        if cutoff > i64::MIN {
+            let expired = Filter::compare("window_end", CompareOp::Le, Literal::Int(cutoff));
+            backend.delete_from_table(&tier_table(table, i), &expired).await?;
+        }
+    }
+    Ok(())
+}
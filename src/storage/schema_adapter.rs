@@ -0,0 +1,217 @@
+//! Ingest-side schema adaptation for incoming record batches.
+//!
+//! Producers rarely agree on exact Arrow types: one client sends `Float32`
+//! values, another `Int32` counts or dictionary-encoded identifiers. The
+//! `SchemaAdapter` reconciles an incoming batch with the declared schema of
+//! its target table by:
+//! - Casting columns whose type is a lossless widening of the declared type
+//! - Filling missing optional columns with defaults or nulls
+//! - Dropping columns the target does not declare, with a warning
+//! - Rejecting the batch with an error naming the offending column, the
+//!   expected type and the actual type
+//!
+//! The adapter is used for the `metrics` table and for every table registered
+//! with the `TableManager`.
+
+use arrow::compute::{cast_with_options, CastOptions};
+use arrow_array::{
+    new_null_array, Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch,
+    RecordBatchOptions, StringArray,
+};
+use arrow_schema::{DataType, SchemaRef};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::Status;
+
+/// Value used to fill a column that is missing from an incoming batch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ColumnDefault {
+    /// Fill the column with nulls
+    Null,
+    /// Fill the column with a constant boolean
+    Boolean(bool),
+    /// Fill the column with a constant integer
+    Int64(i64),
+    /// Fill the column with a constant float
+    Float64(f64),
+    /// Fill the column with a constant string
+    Utf8(String),
+    /// Copy the values of another (already adapted) column
+    CopyOf(String),
+}
+
+/// Adapts incoming record batches to a declared target schema.
+#[derive(Debug, Clone)]
+pub struct SchemaAdapter {
+    schema: SchemaRef,
+    defaults: HashMap<String, ColumnDefault>,
+}
+
+impl SchemaAdapter {
+    /// Creates an adapter for the given target schema.
+    ///
+    /// Without explicit defaults, only nullable columns may be omitted by
+    /// producers; they are filled with nulls.
+    pub fn new(schema: SchemaRef) -> Self {
+        Self {
+            schema,
+            defaults: HashMap::new(),
+        }
+    }
+
+    /// Declares the value used when `column` is missing from a batch.
+    pub fn with_default(mut self, column: &str, default: ColumnDefault) -> Self {
+        self.defaults.insert(column.to_string(), default);
+        self
+    }
+
+    /// Returns the target schema.
+    pub fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    /// Adapts a batch to the target schema.
+    ///
+    /// The returned batch has exactly the target schema, with columns in
+    /// declaration order. Columns the target does not declare are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Status::invalid_argument` if a column has an incompatible
+    /// type, a required column is missing, or a non-nullable column contains
+    /// nulls.
+    pub fn adapt(&self, batch: &RecordBatch) -> Result<RecordBatch, Status> {
+        let incoming = batch.schema();
+        let unknown: Vec<&str> = incoming
+            .fields()
+            .iter()
+            .filter(|f| self.schema.field_with_name(f.name()).is_err())
+            .map(|f| f.name().as_str())
+            .collect();
+        if !unknown.is_empty() {
+            tracing::warn!("Dropping undeclared columns {:?} from ingested batch", unknown);
+        }
+
+        let num_rows = batch.num_rows();
+        let mut columns: Vec<ArrayRef> = Vec::with_capacity(self.schema.fields().len());
+
+        for field in self.schema.fields() {
+            let expected = field.data_type();
+            let column = match batch.column_by_name(field.name()) {
+                Some(column) => cast_column(field.name(), column, expected)?,
+                None => match self.defaults.get(field.name()) {
+                    Some(default) => self.default_column(field.name(), default, expected, num_rows, &columns)?,
+                    None if field.is_nullable() => new_null_array(expected, num_rows),
+                    None => {
+                        return Err(Status::invalid_argument(format!(
+                            "Missing required column '{}': expected {}",
+                            field.name(),
+                            expected
+                        )))
+                    }
+                },
+            };
+
+            if !field.is_nullable() && column.null_count() > 0 {
+                return Err(Status::invalid_argument(format!(
+                    "Column '{}' contains {} null values but is not nullable",
+                    field.name(),
+                    column.null_count()
+                )));
+            }
+
+            columns.push(column);
+        }
+
+        let options = RecordBatchOptions::new().with_row_count(Some(num_rows));
+        RecordBatch::try_new_with_options(self.schema.clone(), columns, &options)
+            .map_err(|e| Status::invalid_argument(format!("Failed to adapt record batch: {}", e)))
+    }
+
+    /// Builds a column of `len` rows from a declared default.
+    fn default_column(
+        &self,
+        column: &str,
+        default: &ColumnDefault,
+        data_type: &DataType,
+        len: usize,
+        adapted: &[ArrayRef],
+    ) -> Result<ArrayRef, Status> {
+        let array: ArrayRef = match default {
+            ColumnDefault::Null => return Ok(new_null_array(data_type, len)),
+            ColumnDefault::Boolean(v) => Arc::new(BooleanArray::from(vec![*v; len])),
+            ColumnDefault::Int64(v) => Arc::new(Int64Array::from_value(*v, len)),
+            ColumnDefault::Float64(v) => Arc::new(Float64Array::from_value(*v, len)),
+            ColumnDefault::Utf8(v) => Arc::new(StringArray::from_iter_values(std::iter::repeat_n(v, len))),
+            ColumnDefault::CopyOf(source) => {
+                let index = self.schema.index_of(source).ok()
+                    .filter(|i| *i < adapted.len())
+                    .ok_or_else(|| Status::internal(format!(
+                        "Default for column '{}' refers to unknown or later column '{}'",
+                        column, source
+                    )))?;
+                adapted[index].clone()
+            }
+        };
+        cast_column(column, &array, data_type)
+    }
+}
+
+/// Casts `column` to `expected` if the conversion is a compatible widening.
+fn cast_column(name: &str, column: &ArrayRef, expected: &DataType) -> Result<ArrayRef, Status> {
+    let actual = column.data_type();
+    if actual == expected {
+        return Ok(column.clone());
+    }
+
+    if !is_compatible(actual, expected) {
+        return Err(Status::invalid_argument(format!(
+            "Column '{}' has incompatible type: expected {}, got {}",
+            name, expected, actual
+        )));
+    }
+
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    cast_with_options(column, expected, &options).map_err(|e| {
+        Status::invalid_argument(format!(
+            "Column '{}' could not be cast from {} to {}: {}",
+            name, actual, expected, e
+        ))
+    })
+}
+
+/// Returns true if values of type `from` can be ingested into a column of type `to`.
+///
+/// Accepted are integer and float widening, string and binary representation
+/// changes, and dictionary decoding. Integers may also be ingested into
+/// `Float64` columns, which rounds `Int64` and `UInt64` values above 2^53 to
+/// the nearest representable float. Values out of the range of the target,
+/// such as a `UInt64` above `i64::MAX` into `Int64`, are rejected when the
+/// cast is performed.
+pub fn is_compatible(from: &DataType, to: &DataType) -> bool {
+    use DataType::*;
+
+    if from == to {
+        return true;
+    }
+
+    match (from, to) {
+        (Null, _) => true,
+        (Dictionary(_, value), to) => is_compatible(value, to),
+        (Int8 | Int16 | Int32 | Int64 | UInt8 | UInt16 | UInt32 | UInt64, Int64) => true,
+        (Int8 | Int16 | Int32 | UInt8 | UInt16 | UInt32, Int32) => true,
+        (Int8 | Int16 | UInt8 | UInt16, Int16) => true,
+        (
+            Int8 | Int16 | Int32 | Int64 | UInt8 | UInt16 | UInt32 | UInt64 | Float16 | Float32,
+            Float64,
+        ) => true,
+        (Int8 | Int16 | UInt8 | UInt16 | Float16, Float32) => true,
+        (Utf8 | LargeUtf8 | Utf8View, Utf8 | LargeUtf8) => true,
+        (Binary | LargeBinary | BinaryView, Binary | LargeBinary) => true,
+        _ => false,
+    }
+}
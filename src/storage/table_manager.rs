@@ -1,25 +1,78 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
+use arrow_array::RecordBatch;
 use arrow_schema::Schema;
 use tonic::Status;
 use serde::{Serialize, Deserialize};
-use crate::aggregation::{TimeWindow, AggregateFunction, GroupBy};
+use crate::aggregation::{aggregate_output_schema, validate_aggregates, TimeWindow, AggregateExpr, GroupBy, ResultOptions};
+use crate::aggregation::filter::Filter;
+use crate::storage::anomaly::{AnomalyMonitor, MonitorStates};
+use crate::storage::alerts::{self, Alert, AlertRule, Delivery, Notifier, RuleStates, ALERT_SUBSCRIPTION_CAPACITY};
+use crate::storage::rollup::RollupPolicy;
+use crate::storage::schema_adapter::SchemaAdapter;
 
 /// Configuration for an aggregation view
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AggregationView {
     pub source_table: String,
-    pub function: AggregateFunction,
+    /// Aggregate expressions computed per group and window
+    pub aggregates: Vec<AggregateExpr>,
     pub group_by: GroupBy,
     pub window: TimeWindow,
-    pub aggregate_columns: Vec<String>,
+    /// Optional filter on the rows of the source table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    /// Store the result in a table that is updated incrementally on ingest,
+    /// instead of re-aggregating the source table on every read
+    #[serde(default)]
+    pub materialized: bool,
+}
+
+/// Freshness of a materialized aggregation view
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaterializationStatus {
+    /// Unix seconds of the last full rebuild
+    pub refreshed_at: i64,
+    /// Unix seconds of the last incremental update, or the rebuild
+    pub updated_at: i64,
+    /// Latest source timestamp included in the view
+    pub watermark: Option<i64>,
+    /// Set when the view missed changes of the source table, e.g. deleted
+    /// rows or a failed update; cleared by a refresh
+    pub stale: bool,
 }
 
 #[derive(Debug)]
 pub struct TableManager {
     tables: Arc<RwLock<HashMap<String, Schema>>>,
     views: Arc<RwLock<HashMap<String, AggregationView>>>,
+    materializations: Arc<RwLock<HashMap<String, MaterializationStatus>>>,
+    /// Held per materialized view while it is updated, so concurrent ingests
+    /// do not overwrite each other's state
+    view_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    /// Held per table, for reading from the ingest of a batch until its
+    /// materialized views are updated, and for writing while a view over
+    /// the table is rebuilt
+    source_locks: Arc<Mutex<HashMap<String, Arc<RwLock<()>>>>>,
+    rollups: Arc<RwLock<HashMap<String, RollupPolicy>>>,
+    /// End of the last rolled up window per table and tier
+    rollup_progress: Arc<RwLock<HashMap<String, Vec<i64>>>>,
+    /// Held per table with a rollup policy, for writing while rollups run,
+    /// so scheduled and on demand runs do not overlap, and for reading while
+    /// range queries read its tiers
+    rollup_locks: Arc<Mutex<HashMap<String, Arc<RwLock<()>>>>>,
+    alert_rules: Arc<RwLock<HashMap<String, AlertRule>>>,
+    /// Held while rules are evaluated, so each evaluation sees the alerts
+    /// left by the previous one
+    alert_states: Arc<Mutex<RuleStates>>,
+    alert_notifiers: Arc<RwLock<Vec<Arc<dyn Notifier>>>>,
+    alert_sender: broadcast::Sender<Alert>,
+    /// Queue of alerts to send, started with the first alerts
+    alert_deliveries: Arc<OnceLock<mpsc::Sender<Delivery>>>,
+    anomaly_monitors: Arc<RwLock<HashMap<String, AnomalyMonitor>>>,
+    /// Held while monitors score windows, so each window is scored once
+    anomaly_states: Arc<Mutex<MonitorStates>>,
 }
 
 impl Clone for TableManager {
@@ -27,6 +80,19 @@ impl Clone for TableManager {
         Self {
             tables: self.tables.clone(),
             views: self.views.clone(),
+            materializations: self.materializations.clone(),
+            view_locks: self.view_locks.clone(),
+            source_locks: self.source_locks.clone(),
+            rollups: self.rollups.clone(),
+            rollup_progress: self.rollup_progress.clone(),
+            rollup_locks: self.rollup_locks.clone(),
+            alert_rules: self.alert_rules.clone(),
+            alert_states: self.alert_states.clone(),
+            alert_notifiers: self.alert_notifiers.clone(),
+            alert_sender: self.alert_sender.clone(),
+            alert_deliveries: self.alert_deliveries.clone(),
+            anomaly_monitors: self.anomaly_monitors.clone(),
+            anomaly_states: self.anomaly_states.clone(),
         }
     }
 }
@@ -42,6 +108,19 @@ impl TableManager {
         Self {
             tables: Arc::new(RwLock::new(HashMap::new())),
             views: Arc::new(RwLock::new(HashMap::new())),
+            materializations: Arc::new(RwLock::new(HashMap::new())),
+            view_locks: Arc::new(Mutex::new(HashMap::new())),
+            source_locks: Arc::new(Mutex::new(HashMap::new())),
+            rollups: Arc::new(RwLock::new(HashMap::new())),
+            rollup_progress: Arc::new(RwLock::new(HashMap::new())),
+            rollup_locks: Arc::new(Mutex::new(HashMap::new())),
+            alert_rules: Arc::new(RwLock::new(HashMap::new())),
+            alert_states: Arc::new(Mutex::new(HashMap::new())),
+            alert_notifiers: Arc::new(RwLock::new(Vec::new())),
+            alert_sender: broadcast::channel(ALERT_SUBSCRIPTION_CAPACITY).0,
+            alert_deliveries: Arc::new(OnceLock::new()),
+            anomaly_monitors: Arc::new(RwLock::new(HashMap::new())),
+            anomaly_states: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -54,6 +133,13 @@ impl TableManager {
         Ok(())
     }
 
+    /// Registers the schema of a table created by the backend itself, such
+    /// as the `metrics` table. An existing registration is kept.
+    pub async fn register_table(&self, name: &str, schema: Schema) {
+        let mut tables = self.tables.write().await;
+        tables.entry(name.to_string()).or_insert(schema);
+    }
+
     pub async fn get_table_schema(&self, name: &str) -> Result<Schema, Status> {
         let tables = self.tables.read().await;
         tables.get(name)
@@ -61,42 +147,64 @@ impl TableManager {
             .ok_or_else(|| Status::not_found(format!("Table {} not found", name)))
     }
 
-    pub async fn create_aggregation_view(
+    /// Adapts an incoming batch to the declared schema of a table.
+    ///
+    /// Compatible column types are cast and omitted nullable columns are
+    /// filled with nulls. See [`SchemaAdapter`] for the exact rules.
+    pub async fn adapt_batch(&self, name: &str, batch: &RecordBatch) -> Result<RecordBatch, Status> {
+        let schema = self.get_table_schema(name).await?;
+        SchemaAdapter::new(Arc::new(schema)).adapt(batch)
+    }
+
+    /// Checks an aggregation spec against the schema of a registered table.
+    ///
+    /// See [`validate_aggregates`] for the rules.
+    pub async fn validate_aggregates(
+        &self,
+        table: &str,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+    ) -> Result<(), Status> {
+        let schema = self.get_table_schema(table).await?;
+        validate_aggregates(aggregates, group_by, &schema)
+    }
+
+    /// Checks the result clauses of an aggregation over a registered table
+    /// against the output columns of the aggregation.
+    ///
+    /// See [`ResultOptions::validate`] for the rules.
+    pub async fn validate_result_options(
         &self,
-        name: String,
-        source_table: String,
-        function: AggregateFunction,
-        group_by: GroupBy,
-        window: TimeWindow,
-        aggregate_columns: Vec<String>,
+        table: &str,
+        aggregates: &[AggregateExpr],
+        group_by: &GroupBy,
+        windowed: bool,
+        options: &ResultOptions,
     ) -> Result<(), Status> {
-        // Verify source table exists
+        let schema = self.get_table_schema(table).await?;
+        options.validate(&aggregate_output_schema(aggregates, group_by, windowed, &schema)?)
+    }
+
+    /// Checks a filter against the schema of a registered table.
+    ///
+    /// See [`Filter::validate`] for the rules.
+    pub async fn validate_filter(&self, table: &str, filter: &Filter) -> Result<(), Status> {
+        let schema = self.get_table_schema(table).await?;
+        filter.validate(&schema)
+    }
+
+    pub async fn create_aggregation_view(&self, name: String, view: AggregationView) -> Result<(), Status> {
+        // Verify source table exists and the spec matches its columns
         {
             let tables = self.tables.read().await;
-            if !tables.contains_key(&source_table) {
-                return Err(Status::not_found(format!("Source table {} not found", source_table)));
-            }
-
-            // Verify aggregate columns exist in source table
-            let schema = tables.get(&source_table).unwrap();
-            for col in &aggregate_columns {
-                if !schema.fields().iter().any(|f| f.name() == col) {
-                    return Err(Status::invalid_argument(format!(
-                        "Column {} not found in source table {}",
-                        col, source_table
-                    )));
-                }
+            let schema = tables.get(&view.source_table)
+                .ok_or_else(|| Status::not_found(format!("Source table {} not found", view.source_table)))?;
+            validate_aggregates(&view.aggregates, &view.group_by, schema)?;
+            if let Some(filter) = &view.filter {
+                filter.validate(schema)?;
             }
         }
 
-        let view = AggregationView {
-            source_table,
-            function,
-            group_by,
-            window,
-            aggregate_columns,
-        };
-
         let mut views = self.views.write().await;
         if views.contains_key(&name) {
             return Err(Status::already_exists(format!("View {} already exists", name)));
@@ -135,6 +243,162 @@ impl TableManager {
         if views.remove(name).is_none() {
             return Err(Status::not_found(format!("View {} not found", name)));
         }
+        self.materializations.write().await.remove(name);
+        self.view_locks.lock().await.remove(name);
+        Ok(())
+    }
+
+    /// Returns the materialized views reading from a table.
+    pub async fn materialized_views(&self, source_table: &str) -> Vec<(String, AggregationView)> {
+        let views = self.views.read().await;
+        views.iter()
+            .filter(|(_, view)| view.materialized && view.source_table == source_table)
+            .map(|(name, view)| (name.clone(), view.clone()))
+            .collect()
+    }
+
+    /// Returns the freshness of a materialized view, if it was built.
+    pub async fn materialization_status(&self, name: &str) -> Option<MaterializationStatus> {
+        self.materializations.read().await.get(name).cloned()
+    }
+
+    /// Updates the freshness of a materialized view, starting from the
+    /// default status.
+    pub async fn update_materialization_status<F>(&self, name: &str, update: F)
+    where
+        F: FnOnce(&mut MaterializationStatus),
+    {
+        let mut statuses = self.materializations.write().await;
+        update(statuses.entry(name.to_string()).or_default());
+    }
+
+    /// Returns the lock held while the materialized view `name` is updated.
+    pub(crate) async fn view_lock(&self, name: &str) -> Arc<Mutex<()>> {
+        self.view_locks.lock().await.entry(name.to_string()).or_default().clone()
+    }
+
+    pub(crate) async fn source_lock(&self, table: &str) -> Arc<RwLock<()>> {
+        self.source_locks.lock().await.entry(table.to_string()).or_default().clone()
+    }
+
+    /// Registers the rollup policy of a table.
+    pub async fn set_rollup_policy(&self, table: &str, policy: RollupPolicy) -> Result<(), Status> {
+        let mut rollups = self.rollups.write().await;
+        if rollups.contains_key(table) {
+            return Err(Status::already_exists(format!("Table {} already has a rollup policy", table)));
+        }
+        rollups.insert(table.to_string(), policy);
+        Ok(())
+    }
+
+    pub async fn get_rollup_policy(&self, table: &str) -> Option<RollupPolicy> {
+        self.rollups.read().await.get(table).cloned()
+    }
+
+    /// Returns the tables with a rollup policy, by name.
+    pub async fn rollup_tables(&self) -> Vec<String> {
+        let mut tables: Vec<String> = self.rollups.read().await.keys().cloned().collect();
+        tables.sort();
+        tables
+    }
+
+    /// Returns the end of the last rolled up window of each tier of a
+    /// table, if rollups ran since startup.
+    pub async fn rollup_progress(&self, table: &str) -> Option<Vec<i64>> {
+        self.rollup_progress.read().await.get(table).cloned()
+    }
+
+    pub async fn set_rollup_progress(&self, table: &str, progress: Vec<i64>) {
+        self.rollup_progress.write().await.insert(table.to_string(), progress);
+    }
+
+    pub(crate) async fn rollup_lock(&self, table: &str) -> Arc<RwLock<()>> {
+        self.rollup_locks.lock().await.entry(table.to_string()).or_default().clone()
+    }
+
+    pub async fn create_alert_rule(&self, rule: AlertRule) -> Result<(), Status> {
+        let mut rules = self.alert_rules.write().await;
+        if rules.contains_key(&rule.name) {
+            return Err(Status::already_exists(format!("Alert rule {} already exists", rule.name)));
+        }
+        rules.insert(rule.name.clone(), rule);
+        Ok(())
+    }
+
+    pub async fn get_alert_rule(&self, name: &str) -> Result<AlertRule, Status> {
+        self.alert_rules.read().await.get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("Alert rule {} not found", name)))
+    }
+
+    pub async fn alert_rules(&self) -> Vec<AlertRule> {
+        let mut rules: Vec<AlertRule> = self.alert_rules.read().await.values().cloned().collect();
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        rules
+    }
+
+    pub async fn drop_alert_rule(&self, name: &str) -> Result<(), Status> {
+        self.alert_rules.write().await.remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Status::not_found(format!("Alert rule {} not found", name)))
+    }
+
+    pub(crate) fn alert_states(&self) -> &Mutex<RuleStates> {
+        &self.alert_states
+    }
+
+    /// Registers a notifier receiving the alerts of every rule.
+    pub async fn add_alert_notifier(&self, notifier: Arc<dyn Notifier>) {
+        self.alert_notifiers.write().await.push(notifier);
+    }
+
+    pub async fn alert_notifiers(&self) -> Vec<Arc<dyn Notifier>> {
+        self.alert_notifiers.read().await.clone()
+    }
+
+    pub fn alert_sender(&self) -> broadcast::Sender<Alert> {
+        self.alert_sender.clone()
+    }
+
+    /// Returns the queue of alerts to send to notifiers, spawning the task
+    /// sending them on first use.
+    pub(crate) fn alert_deliveries(&self) -> &mpsc::Sender<Delivery> {
+        self.alert_deliveries.get_or_init(alerts::spawn_delivery)
+    }
+
+    /// Subscribes to the alerts of the rules notifying subscriptions.
+    pub fn subscribe_alerts(&self) -> broadcast::Receiver<Alert> {
+        self.alert_sender.subscribe()
+    }
+
+    pub async fn create_anomaly_monitor(&self, monitor: AnomalyMonitor) -> Result<(), Status> {
+        let mut monitors = self.anomaly_monitors.write().await;
+        if monitors.contains_key(&monitor.name) {
+            return Err(Status::already_exists(format!("Anomaly monitor {} already exists", monitor.name)));
+        }
+        monitors.insert(monitor.name.clone(), monitor);
         Ok(())
     }
-} 
\ No newline at end of file
+
+    pub async fn get_anomaly_monitor(&self, name: &str) -> Result<AnomalyMonitor, Status> {
+        self.anomaly_monitors.read().await.get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("Anomaly monitor {} not found", name)))
+    }
+
+    pub async fn anomaly_monitor_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.anomaly_monitors.read().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub async fn drop_anomaly_monitor(&self, name: &str) -> Result<(), Status> {
+        self.anomaly_monitors.write().await.remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Status::not_found(format!("Anomaly monitor {} not found", name)))
+    }
+
+    pub(crate) fn anomaly_states(&self) -> &Mutex<MonitorStates> {
+        &self.anomaly_states
+    }
+}
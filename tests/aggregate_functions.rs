@@ -0,0 +1,228 @@
+use hyprstream_core::aggregation::calendar::CalendarUnit;
+use hyprstream_core::aggregation::fill::FillStrategy;
+use hyprstream_core::aggregation::filter::{Filter, MatchOp, SqlDialect};
+use hyprstream_core::aggregation::hll::HyperLogLog;
+use hyprstream_core::aggregation::{
+    build_aggregate_query, build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, OrderBy,
+    ResultOptions, TimeWindow, TopK,
+};
+use hyprstream_core::metrics::aggregation::apply_function;
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{merge_aggregations, BatchAggregation, StorageBackend};
+use std::time::Duration;
+
+const FUNCTIONS: [AggregateFunction; 7] = [
+    AggregateFunction::StdDev,
+    AggregateFunction::Variance,
+    AggregateFunction::First,
+    AggregateFunction::Last,
+    AggregateFunction::CountDistinct,
+    AggregateFunction::Rate,
+    AggregateFunction::Delta,
+];
+
+fn metrics(samples: &[(i64, f64)]) -> Vec<MetricRecord> {
+    samples
+        .iter()
+        .map(|(timestamp, value)| MetricRecord {
+            metric_id: "requests".to_string(),
+            timestamp: *timestamp,
+            value_running_window_sum: *value,
+            value_running_window_avg: *value,
+            value_running_window_count: 1,
+        })
+        .collect()
+}
+
+fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "expected {} within {} of {}",
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+#[test]
+fn test_hyperloglog_estimates_and_merges() {
+    let mut whole = HyperLogLog::new();
+    let mut left = HyperLogLog::new();
+    let mut right = HyperLogLog::new();
+    for i in 0..50_000 {
+        let v = (i % 20_000) as f64;
+        whole.insert(v);
+        if i % 2 == 0 { left.insert(v) } else { right.insert(v) }
+    }
+    assert_close(whole.estimate(), 20_000.0, 20_000.0 * 0.05);
+
+    left.merge(&right).unwrap();
+    assert_eq!(left, whole);
+    assert!(left.merge(&HyperLogLog::with_precision(10)).is_err());
+
+    let mut small = HyperLogLog::new();
+    for v in [1.0, 2.0, 2.0, 0.0, -0.0, f64::NAN] {
+        small.insert(v);
+    }
+    assert_eq!(small.estimate().round(), 3.0);
+
+    let bytes = small.to_bytes().unwrap();
+    assert_eq!(HyperLogLog::from_bytes(&bytes).unwrap(), small);
+
+    // The precision follows the format version; registers must match it
+    for precision in [11, 20] {
+        let mut invalid = bytes.clone();
+        invalid[1] = precision;
+        assert!(HyperLogLog::from_bytes(&invalid).is_err(), "{}", precision);
+    }
+}
+
+#[test]
+fn test_apply_functions() {
+    // Deliberately out of order: first and last follow the timestamps
+    let metrics = metrics(&[(30, 8.0), (10, 2.0), (20, 4.0), (40, 4.0)]);
+
+    assert_close(apply_function(AggregateFunction::Variance, &metrics).unwrap(), 19.0 / 3.0, 1e-9);
+    assert_close(apply_function(AggregateFunction::StdDev, &metrics).unwrap(), (19.0f64 / 3.0).sqrt(), 1e-9);
+    assert_eq!(apply_function(AggregateFunction::First, &metrics).unwrap(), 2.0);
+    assert_eq!(apply_function(AggregateFunction::Last, &metrics).unwrap(), 4.0);
+    assert_eq!(apply_function(AggregateFunction::CountDistinct, &metrics).unwrap(), 3.0);
+    assert_eq!(apply_function(AggregateFunction::Delta, &metrics).unwrap(), 2.0);
+    assert_close(apply_function(AggregateFunction::Rate, &metrics).unwrap(), 2.0 / 30.0, 1e-9);
+
+    // Undefined for a single sample
+    let single = &metrics[..1];
+    assert!(apply_function(AggregateFunction::Variance, single).unwrap().is_nan());
+    assert!(apply_function(AggregateFunction::Rate, single).unwrap().is_nan());
+}
+
+#[test]
+fn test_sql_matches_in_memory() {
+    let samples: Vec<(i64, f64)> = (0..500)
+        .map(|i| (1000 + i * 7, ((i * 7919) % 263) as f64 * 0.5))
+        .collect();
+    let expected = metrics(&samples);
+
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE samples (timestamp BIGINT, value DOUBLE)").unwrap();
+    for (timestamp, value) in &samples {
+        conn.execute("INSERT INTO samples VALUES (?, ?)", duckdb::params![timestamp, value]).unwrap();
+    }
+
+    for function in FUNCTIONS {
+        let sql = format!("SELECT CAST({} AS DOUBLE) FROM samples", function.to_sql("value"));
+        let actual: f64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        let in_memory = apply_function(function, &expected).unwrap();
+        // Both distinct counts are approximate, DuckDB's with fewer registers
+        let tolerance = match function {
+            AggregateFunction::CountDistinct => {
+                assert_close(in_memory, 263.0, 263.0 * 0.02);
+                263.0 * 0.15
+            }
+            _ => 1e-9 * in_memory.abs().max(1.0),
+        };
+        assert_close(actual, in_memory, tolerance);
+
+        // The Postgres rendering, which DuckDB runs as well, counts distinct
+        // values exactly
+        let postgres = function.to_sql_with_time("value", "timestamp", SqlDialect::Postgres);
+        let sql = format!("SELECT CAST({} AS DOUBLE) FROM samples", postgres);
+        let actual: f64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        let expected = match function {
+            AggregateFunction::CountDistinct => 263.0,
+            _ => in_memory,
+        };
+        assert_close(actual, expected, 1e-9 * expected.abs().max(1.0));
+    }
+
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let sql = build_aggregate_query("metrics", &[AggregateExpr::new(AggregateFunction::Rate, "value")], &group_by, None, None, None, &ResultOptions::default()).unwrap();
+    assert!(sql.contains("ARG_MAX(value, timestamp) - ARG_MIN(value, timestamp)"));
+    assert_eq!(
+        AggregateFunction::First.to_sql_with_time("latency", "ts", SqlDialect::DuckDb),
+        "ARG_MIN(latency, ts)"
+    );
+}
+
+#[test]
+fn test_postgres_rendering() {
+    let postgres = |function: AggregateFunction| function.to_sql_with_time("latency", "ts", SqlDialect::Postgres);
+    assert_eq!(
+        postgres(AggregateFunction::First),
+        "(ARRAY_AGG(latency ORDER BY ts) FILTER (WHERE latency IS NOT NULL AND ts IS NOT NULL))[1]"
+    );
+    assert_eq!(
+        postgres(AggregateFunction::Last),
+        "(ARRAY_AGG(latency ORDER BY ts DESC) FILTER (WHERE latency IS NOT NULL AND ts IS NOT NULL))[1]"
+    );
+    assert_eq!(postgres(AggregateFunction::Quantile(0.95)), "PERCENTILE_CONT(0.95) WITHIN GROUP (ORDER BY latency)");
+    assert_eq!(postgres(AggregateFunction::CountDistinct), "COUNT(DISTINCT latency)");
+
+    // A query using every window, fill and clause renders none of the DuckDB
+    // extensions
+    let functions = FUNCTIONS.iter().chain(&[AggregateFunction::Sum, AggregateFunction::Quantile(0.5)]);
+    let aggregates: Vec<AggregateExpr> = functions.map(|f| AggregateExpr::new(*f, "value")).collect();
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let options = ResultOptions {
+        fill: Some(FillStrategy::Linear),
+        having: Some(Filter::matches("metric_id", MatchOp::Regex, "cpu.*")),
+        top_k: Some(TopK { k: 3, by: OrderBy::desc("sum_value") }),
+        order_by: vec![OrderBy::asc("window_start")],
+        limit: Some(10),
+    };
+    let windows = [
+        TimeWindow::Sliding { window: Duration::from_secs(300), slide: Duration::from_secs(60) },
+        TimeWindow::Calendar { unit: CalendarUnit::Month, timezone: chrono_tz::Europe::Berlin, offset: Duration::ZERO },
+    ];
+    for window in windows {
+        let sql = build_windowed_aggregate_query(
+            "metrics", &aggregates, &group_by, window, Some(0), Some(3600), None, &options, SqlDialect::Postgres,
+        )
+        .unwrap();
+        for duckdb_only in [
+            "QUALIFY", "ARG_M", "UNNEST", "generate_series", "list_transform", "APPROX_COUNT_DISTINCT",
+            "QUANTILE_CONT", "IGNORE NULLS", "regexp_full_match", "make_timestamp", "epoch(", "AS DOUBLE)",
+        ] {
+            assert!(!sql.contains(duckdb_only), "{:?} renders {}", window, duckdb_only);
+        }
+        assert!(sql.contains("ROW_NUMBER() OVER (PARTITION BY window_start, window_end ORDER BY sum_value DESC NULLS LAST)"));
+    }
+}
+
+#[tokio::test]
+async fn test_window_state_merges_and_persists() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    let window = |start: i64, samples: &[(i64, f64)]| {
+        let mut agg = BatchAggregation::new("requests".to_string(), start, start + 60);
+        for (timestamp, value) in samples {
+            agg.update(*timestamp, *value, 1);
+        }
+        agg
+    };
+    let all: Vec<(i64, f64)> = vec![(5, 1.0), (20, 3.0), (70, 6.0), (100, 10.0), (50, 3.0)];
+
+    backend
+        .insert_batch_aggregations(vec![window(0, &all[..2]), window(60, &all[2..4])])
+        .await
+        .unwrap();
+    // A late sample for the first window is merged into the stored state
+    backend.insert_batch_aggregations(vec![window(0, &all[4..])]).await.unwrap();
+
+    let stored = backend.query_batch_aggregations(0, None).await.unwrap();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[0].sample_count, 3);
+    assert_eq!(stored[0].evaluate(AggregateFunction::Last).unwrap(), 3.0);
+
+    let merged = merge_aggregations(&stored, |_| ()).unwrap().remove(&()).unwrap();
+    let reference = metrics(&all);
+    for function in FUNCTIONS {
+        assert_close(
+            merged.evaluate(function).unwrap(),
+            apply_function(function, &reference).unwrap(),
+            1e-9,
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use hyprstream_core::aggregation::calendar::CalendarUnit;
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal, SqlDialect};
+use hyprstream_core::aggregation::operator::AggregateOperator;
+use hyprstream_core::aggregation::{
+    build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow,
+};
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{ExchangeAggregation, FlightSqlService};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow::compute::cast;
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::flight_service_client::FlightServiceClient;
+use arrow_flight::flight_service_server::FlightServiceServer;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, FlightDescriptor};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use futures::{StreamExt, TryStreamExt};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Code;
+
+/// Group by strings and the other columns as doubles.
+type Row = (Vec<Option<String>>, Vec<Option<f64>>);
+
+/// Deterministic xorshift generator for the randomized data.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// Returns `None` in about one of ten calls.
+    fn maybe<T>(&mut self, value: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        (self.below(10) != 0).then(|| value(self))
+    }
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, true),
+        Field::new("region", DataType::Int64, true),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("value", DataType::Float64, true),
+        Field::new("requests", DataType::Int64, true),
+    ])
+}
+
+/// Random rows with unique timestamps in `[0, span)`, so first and last
+/// values are well defined.
+fn samples(seed: u64, rows: usize, span: u64) -> RecordBatch {
+    let mut rng = Rng(seed);
+    // In the order drawn, so batches are the same in every run
+    let mut seen = HashSet::new();
+    let mut timestamps = Vec::with_capacity(rows);
+    while timestamps.len() < rows {
+        let timestamp = rng.below(span) as i64;
+        if seen.insert(timestamp) {
+            timestamps.push(timestamp);
+        }
+    }
+    let hosts: Vec<Option<&str>> = (0..rows).map(|_| rng.maybe(|r| ["a", "b", "c"][r.below(3) as usize])).collect();
+    let regions: Vec<Option<i64>> = (0..rows).map(|_| rng.maybe(|r| r.below(3) as i64)).collect();
+    let values: Vec<Option<f64>> = (0..rows).map(|_| rng.maybe(|r| r.below(10_000) as f64 / 100.0 - 20.0)).collect();
+    let requests: Vec<Option<i64>> = (0..rows).map(|_| rng.maybe(|r| r.below(50) as i64)).collect();
+    RecordBatch::try_new(
+        Arc::new(schema()),
+        vec![
+            Arc::new(StringArray::from(hosts)),
+            Arc::new(Int64Array::from(regions)),
+            Arc::new(Int64Array::from(timestamps)),
+            Arc::new(Float64Array::from(values)),
+            Arc::new(Int64Array::from(requests)),
+        ],
+    )
+    .unwrap()
+}
+
+fn load(batch: &RecordBatch) -> duckdb::Connection {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE samples (host VARCHAR, region BIGINT, timestamp BIGINT, value DOUBLE, requests BIGINT)",
+    )
+    .unwrap();
+    let column = |i: usize| batch.column(i).clone();
+    let (hosts, regions, timestamps, values, requests) = (column(0), column(1), column(2), column(3), column(4));
+    let hosts = hosts.as_any().downcast_ref::<StringArray>().unwrap();
+    let ints = |array: &Arc<dyn Array>| array.as_any().downcast_ref::<Int64Array>().unwrap().clone();
+    let (regions, timestamps, requests) = (ints(&regions), ints(&timestamps), ints(&requests));
+    let values = values.as_any().downcast_ref::<Float64Array>().unwrap();
+    for i in 0..batch.num_rows() {
+        conn.execute(
+            "INSERT INTO samples VALUES (?, ?, ?, ?, ?)",
+            duckdb::params![
+                hosts.is_valid(i).then(|| hosts.value(i)),
+                regions.is_valid(i).then(|| regions.value(i)),
+                timestamps.value(i),
+                values.is_valid(i).then(|| values.value(i)),
+                requests.is_valid(i).then(|| requests.value(i)),
+            ],
+        )
+        .unwrap();
+    }
+    conn
+}
+
+fn aggregates() -> Vec<AggregateExpr> {
+    let value = |function| AggregateExpr::new(function, "value");
+    vec![
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+        value(AggregateFunction::Count),
+        value(AggregateFunction::Sum),
+        value(AggregateFunction::Avg),
+        value(AggregateFunction::Min),
+        value(AggregateFunction::Max),
+        value(AggregateFunction::Quantile(0.9)),
+        value(AggregateFunction::StdDev),
+        value(AggregateFunction::Variance),
+        value(AggregateFunction::First),
+        value(AggregateFunction::Last),
+        value(AggregateFunction::Rate),
+        value(AggregateFunction::Delta),
+        AggregateExpr::new(AggregateFunction::Count, "host"),
+        AggregateExpr::new(AggregateFunction::CountDistinct, "host"),
+        AggregateExpr::new(AggregateFunction::Sum, "requests"),
+        AggregateExpr::new(AggregateFunction::Quantile(0.5), "requests").with_alias("median_requests"),
+        AggregateExpr::new(AggregateFunction::CountDistinct, "requests"),
+    ]
+}
+
+fn group_bys() -> Vec<GroupBy> {
+    let columns = |columns: &[&str]| columns.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    vec![
+        GroupBy { columns: vec![], time_column: None },
+        GroupBy { columns: columns(&["host"]), time_column: None },
+        GroupBy { columns: columns(&["host", "region"]), time_column: None },
+        GroupBy { columns: columns(&["region"]), time_column: Some("timestamp".to_string()) },
+    ]
+}
+
+/// Runs the operator over `batch`, pushed in three slices.
+fn aggregate(
+    batch: &RecordBatch,
+    group_by: &GroupBy,
+    window: TimeWindow,
+    range: (Option<i64>, Option<i64>),
+    filter: Option<&Filter>,
+) -> RecordBatch {
+    let mut operator = AggregateOperator::try_new(batch.schema(), &aggregates(), group_by, window)
+        .unwrap()
+        .with_range(range.0, range.1);
+    if let Some(filter) = filter {
+        operator = operator.with_filter(filter.clone()).unwrap();
+    }
+    let third = batch.num_rows() / 3;
+    for (offset, length) in [(0, third), (third, third), (2 * third, batch.num_rows() - 2 * third)] {
+        operator.push(&batch.slice(offset, length)).unwrap();
+    }
+    operator.finish().unwrap()
+}
+
+fn operator_rows(batch: &RecordBatch) -> Vec<Row> {
+    let mut rows: Vec<Row> = vec![(vec![], vec![]); batch.num_rows()];
+    for column in batch.columns() {
+        if let Some(strings) = column.as_any().downcast_ref::<StringArray>() {
+            for (row, value) in rows.iter_mut().zip(strings.iter()) {
+                row.0.push(value.map(str::to_string));
+            }
+        } else {
+            let floats = cast(column, &DataType::Float64).unwrap();
+            let floats = floats.as_any().downcast_ref::<Float64Array>().unwrap();
+            for (row, value) in rows.iter_mut().zip(floats.iter()) {
+                row.1.push(value);
+            }
+        }
+    }
+    rows
+}
+
+fn sql_rows(conn: &duckdb::Connection, sql: &str, output: &Schema) -> Vec<Row> {
+    let (strings, numbers): (Vec<&Field>, Vec<&Field>) =
+        output.fields().iter().map(|f| f.as_ref()).partition(|f| f.data_type() == &DataType::Utf8);
+    let columns: Vec<String> = strings.iter().map(|f| f.name().clone())
+        .chain(numbers.iter().map(|f| format!("CAST({} AS DOUBLE)", f.name())))
+        .collect();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM ({}) AS result", columns.join(", "), sql)).unwrap();
+    stmt.query_map([], |row| {
+        let text = (0..strings.len()).map(|i| row.get(i)).collect::<Result<Vec<Option<String>>, _>>()?;
+        let values = (0..numbers.len()).map(|i| row.get(strings.len() + i)).collect::<Result<Vec<Option<f64>>, _>>()?;
+        Ok((text, values))
+    })
+    .unwrap()
+    .map(|row| row.unwrap())
+    .collect()
+}
+
+/// Sorts rows by their group and window, the first `keys` numbers.
+fn sorted(mut rows: Vec<Row>, keys: usize) -> Vec<Row> {
+    rows.sort_by(|a, b| {
+        a.0.cmp(&b.0).then_with(|| a.1[..keys].partial_cmp(&b.1[..keys]).unwrap())
+    });
+    rows
+}
+
+/// Compares the operator with the SQL query builder for every grouping,
+/// range, filter and dialect; DuckDB runs the Postgres rendering as well.
+fn assert_matches_sql(batch: &RecordBatch, window: TimeWindow, from: i64, to: i64) {
+    let conn = load(batch);
+    let aggregates = aggregates();
+    let positive = Filter::compare("value", CompareOp::Gt, Literal::Float(0.0));
+    let ranges = [(None, None), (Some(from), None), (Some(from), Some(to)), (Some(i64::MAX - 1), None)];
+    let dialects = [SqlDialect::DuckDb, SqlDialect::Postgres];
+    for group_by in group_bys() {
+        for (range, dialect) in ranges.iter().flat_map(|range| dialects.map(|dialect| (*range, dialect))) {
+            for filter in [None, Some(&positive)] {
+                let case = format!("{:?} {:?} {:?} {:?} {:?}", window, group_by, range, filter, dialect);
+                // Distinct counts are compared with the exact count
+                let sql = build_windowed_aggregate_query(
+                    "samples", &aggregates, &group_by, window, range.0, range.1, filter, &ResultOptions::default(), dialect,
+                )
+                .unwrap()
+                .replace("APPROX_COUNT_DISTINCT(", "COUNT(DISTINCT ");
+                let actual = aggregate(batch, &group_by, window, range, filter);
+                let keys = actual.num_columns() - aggregates.len()
+                    - group_by.columns.iter().filter(|c| *c == "host").count();
+                let expected = sorted(sql_rows(&conn, &sql, &actual.schema()), keys);
+                let actual = sorted(operator_rows(&actual), keys);
+                assert_eq!(actual.len(), expected.len(), "{}", case);
+
+                for (actual, expected) in actual.iter().zip(&expected) {
+                    assert_eq!(actual.0, expected.0, "{}", case);
+                    assert_eq!(actual.1[..keys], expected.1[..keys], "{}", case);
+                    for (i, aggregate) in aggregates.iter().enumerate() {
+                        let (a, e) = (actual.1[keys + i], expected.1[keys + i]);
+                        let name = aggregate.output_name();
+                        let tolerance = 1e-9 * e.unwrap_or_default().abs().max(1.0);
+                        match (a, e) {
+                            (Some(a), Some(e)) => assert!((a - e).abs() <= tolerance, "{} {}: {} != {}", case, name, a, e),
+                            _ => assert_eq!(a, e, "{} {}", case, name),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_operator_matches_sql() {
+    let windows = [
+        TimeWindow::None,
+        TimeWindow::Fixed(Duration::from_secs(60)),
+        TimeWindow::Sliding { window: Duration::from_secs(90), slide: Duration::from_secs(30) },
+        TimeWindow::Aligned { size: Duration::from_secs(45), origin: 7 },
+        TimeWindow::Session { gap: Duration::from_secs(25) },
+    ];
+    for seed in [7, 1234] {
+        let batch = samples(seed, 200, 1500);
+        for window in windows {
+            assert_matches_sql(&batch, window, 300, 1000);
+        }
+    }
+}
+
+#[test]
+fn test_operator_matches_sql_calendar_windows() {
+    let day = TimeWindow::Calendar {
+        unit: CalendarUnit::Day,
+        timezone: chrono_tz::Europe::Berlin,
+        offset: Duration::from_secs(6 * 3600),
+    };
+    let batch = samples(99, 200, 10 * 86400);
+    assert_matches_sql(&batch, day, 2 * 86400, 7 * 86400);
+}
+
+#[test]
+fn test_operator_validation() {
+    let schema = Arc::new(schema());
+    let no_grouping = GroupBy { columns: vec![], time_column: None };
+    let invalid = [
+        AggregateExpr::new(AggregateFunction::Sum, "host"),
+        AggregateExpr::new(AggregateFunction::Avg, "latency"),
+        AggregateExpr::new(AggregateFunction::Max, "*"),
+    ];
+    for aggregate in invalid {
+        let result = AggregateOperator::try_new(schema.clone(), &[aggregate], &no_grouping, TimeWindow::None);
+        assert_eq!(result.err().unwrap().code(), Code::InvalidArgument);
+    }
+
+    let count = [AggregateExpr::new(AggregateFunction::CountDistinct, "host")];
+    let operator = AggregateOperator::try_new(schema, &count, &no_grouping, TimeWindow::None).unwrap();
+    let unknown = Filter::compare("latency", CompareOp::Gt, Literal::Int(1));
+    assert_eq!(operator.with_filter(unknown).err().unwrap().code(), Code::InvalidArgument);
+}
+
+#[test]
+fn test_sketched_operator_estimates() {
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Sum, "value"),
+        AggregateExpr::new(AggregateFunction::Quantile(0.9), "value"),
+        AggregateExpr::new(AggregateFunction::CountDistinct, "requests"),
+        AggregateExpr::new(AggregateFunction::CountDistinct, "host"),
+    ];
+    let group_by = GroupBy { columns: vec!["region".to_string()], time_column: None };
+    let batch = samples(11, 20_000, 1_000_000);
+    // Sessions merge the state of every timestamp, and with it the sketches
+    let windows = [TimeWindow::Fixed(Duration::from_secs(250_000)), TimeWindow::Session { gap: Duration::from_secs(50_000) }];
+    for window in windows {
+        let aggregate = |sketches: bool| {
+            let mut operator = AggregateOperator::try_new(batch.schema(), &aggregates, &group_by, window).unwrap();
+            if sketches {
+                operator = operator.with_sketches();
+            }
+            for offset in (0..batch.num_rows()).step_by(1000) {
+                operator.push(&batch.slice(offset, 1000)).unwrap();
+            }
+            operator.finish().unwrap()
+        };
+        let (exact, sketched) = (aggregate(false), aggregate(true));
+        assert_eq!(exact.num_rows(), sketched.num_rows());
+
+        // Only quantiles and distinct counts are estimated
+        let floats = |batch: &RecordBatch, i: usize| {
+            let column = cast(batch.column(i), &DataType::Float64).unwrap();
+            column.as_any().downcast_ref::<Float64Array>().unwrap().clone()
+        };
+        assert_eq!(floats(&exact, 3), floats(&sketched, 3));
+        for (i, tolerance) in [(4, 0.01), (5, 0.02), (6, 0.0)] {
+            for (exact, sketched) in floats(&exact, i).iter().zip(floats(&sketched, i).iter()) {
+                let (exact, sketched) = (exact.unwrap(), sketched.unwrap());
+                assert!((exact - sketched).abs() <= tolerance * exact.abs() + 0.05, "{} != {}", exact, sketched);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_aggregate_over_flight_exchange() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(FlightServiceServer::new(service))
+            .serve_with_incoming(incoming),
+    );
+
+    let aggregation = ExchangeAggregation {
+        aggregates: vec![
+            AggregateExpr::new(AggregateFunction::Count, "*"),
+            AggregateExpr::new(AggregateFunction::Avg, "value"),
+            AggregateExpr::new(AggregateFunction::Quantile(0.5), "value"),
+            AggregateExpr::new(AggregateFunction::CountDistinct, "requests"),
+        ],
+        group_by: GroupBy { columns: vec!["host".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(500)),
+        from_timestamp: None,
+        to_timestamp: None,
+        filter: None,
+        partial: false,
+        combine: false,
+    };
+    let batch = samples(5, 60, 1000);
+    let slices = [batch.slice(0, 30), batch.slice(30, 30)];
+    // Streams are aggregated with sketches
+    let operator = AggregateOperator::try_new(
+        batch.schema(), &aggregation.aggregates, &aggregation.group_by, aggregation.window,
+    )
+    .unwrap()
+    .with_sketches();
+    let expected = operator.aggregate_stream(futures::stream::iter(slices.clone().map(Ok))).await.unwrap();
+    assert_eq!(expected.num_rows(), 8);
+
+    let descriptor = FlightDescriptor::new_cmd(serde_json::to_vec(&aggregation).unwrap());
+    let batches = futures::stream::iter(slices.map(Ok));
+    let flight_data: Vec<FlightData> = FlightDataEncoderBuilder::new()
+        .with_flight_descriptor(Some(descriptor))
+        .build(batches)
+        .map(|data| data.unwrap())
+        .collect()
+        .await;
+
+    let mut client = FlightServiceClient::connect(format!("http://{}", address)).await.unwrap();
+    let response = client.do_exchange(futures::stream::iter(flight_data)).await.unwrap();
+    let flight_data: Vec<FlightData> = response.into_inner().try_collect().await.unwrap();
+    let result = flight_data_to_batches(&flight_data).unwrap();
+    assert_eq!(result, vec![expected]);
+}
@@ -0,0 +1,269 @@
+use hyprstream_core::aggregation::filter::SqlDialect;
+use hyprstream_core::aggregation::{
+    build_windowed_aggregate_query, validate_aggregates, AggregateExpr, AggregateFunction, GroupBy, ResultOptions,
+    TimeWindow,
+};
+use hyprstream_core::metrics::get_metrics_schema;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::table_manager::AggregationView;
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{AggregateMetricsQuery, FlightSqlService, QueryTicket};
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_flight::flight_service_server::FlightService;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, Ticket};
+use futures::TryStreamExt;
+use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Request};
+
+mod common;
+use common::metric;
+
+fn ints(batch: &RecordBatch, name: &str) -> Vec<i64> {
+    batch.column_by_name(name).unwrap().as_any().downcast_ref::<Int64Array>().unwrap().values().to_vec()
+}
+
+fn floats(batch: &RecordBatch, name: &str) -> Vec<f64> {
+    batch.column_by_name(name).unwrap().as_any().downcast_ref::<Float64Array>().unwrap().values().to_vec()
+}
+
+fn no_grouping() -> GroupBy {
+    GroupBy { columns: vec![], time_column: None }
+}
+
+#[test]
+fn test_output_names() {
+    assert_eq!(AggregateExpr::new(AggregateFunction::Sum, "value").output_name(), "sum_value");
+    assert_eq!(AggregateExpr::new(AggregateFunction::Count, "*").output_name(), "count");
+    assert_eq!(
+        AggregateExpr::new(AggregateFunction::Quantile(0.99), "latency").output_name(),
+        "quantile_0_99_latency"
+    );
+    assert_eq!(
+        AggregateExpr::new(AggregateFunction::CountDistinct, "user").with_alias("users").output_name(),
+        "users"
+    );
+}
+
+#[test]
+fn test_validate_aggregates() {
+    let schema = get_metrics_schema();
+    let sum = AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum");
+    let by_metric = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    assert!(validate_aggregates(std::slice::from_ref(&sum), &by_metric, &schema).is_ok());
+
+    let invalid: Vec<(Vec<AggregateExpr>, GroupBy)> = vec![
+        (vec![], no_grouping()),
+        (vec![AggregateExpr::new(AggregateFunction::Sum, "value")], no_grouping()),
+        (vec![AggregateExpr::new(AggregateFunction::Sum, "*")], no_grouping()),
+        (vec![sum.clone(), sum.clone()], no_grouping()),
+        (vec![sum.clone().with_alias("metric_id")], by_metric.clone()),
+        (vec![sum.clone().with_alias("total; DROP TABLE metrics")], no_grouping()),
+        (vec![AggregateExpr::new(AggregateFunction::Quantile(1.5), "value_running_window_sum")], no_grouping()),
+        (vec![sum.clone()], GroupBy { columns: vec!["host".to_string()], time_column: None }),
+    ];
+    for (aggregates, group_by) in invalid {
+        let status = validate_aggregates(&aggregates, &group_by, &schema).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument, "{:?}", aggregates);
+    }
+}
+
+#[test]
+fn test_multiple_expressions_in_one_query() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE requests (host VARCHAR, timestamp BIGINT, latency DOUBLE)").unwrap();
+    for (host, timestamp, latency) in [("a", 0, 10.0), ("a", 30, 30.0), ("b", 10, 5.0), ("a", 70, 7.0)] {
+        conn.execute("INSERT INTO requests VALUES (?, ?, ?)", duckdb::params![host, timestamp, latency]).unwrap();
+    }
+
+    let aggregates = vec![
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+        AggregateExpr::new(AggregateFunction::Avg, "latency").with_alias("mean"),
+        AggregateExpr::new(AggregateFunction::Max, "latency"),
+        AggregateExpr::new(AggregateFunction::Last, "latency"),
+    ];
+    let group_by = GroupBy { columns: vec!["host".to_string()], time_column: None };
+    let sql = build_windowed_aggregate_query(
+        "requests",
+        &aggregates,
+        &group_by,
+        TimeWindow::Fixed(Duration::from_secs(60)),
+        None,
+        None,
+        None,
+        &ResultOptions::default(),
+        SqlDialect::DuckDb,
+    )
+    .unwrap();
+    assert!(sql.contains("COUNT(*) AS count, AVG(latency) AS mean, MAX(latency) AS max_latency"));
+
+    let mut stmt = conn.prepare(&format!("{} ORDER BY host, window_start", sql)).unwrap();
+    let rows: Vec<(String, i64, i64, f64, f64, f64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    assert_eq!(rows, vec![
+        ("a".to_string(), 0, 2, 20.0, 30.0, 30.0),
+        ("a".to_string(), 60, 1, 7.0, 7.0, 7.0),
+        ("b".to_string(), 0, 1, 5.0, 5.0, 5.0),
+    ]);
+}
+
+#[tokio::test]
+async fn test_aggregate_metrics_with_spec() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend
+        .insert_metrics(vec![metric("cpu", 10, 1.0), metric("cpu", 20, 4.0), metric("mem", 30, 2.0)])
+        .await
+        .unwrap();
+
+    let aggregates = vec![
+        AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum"),
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+        AggregateExpr::new(AggregateFunction::Max, "value_running_window_sum").with_alias("peak"),
+    ];
+    let batch = backend
+        .aggregate_metrics(&aggregates, &no_grouping(), TimeWindow::None, 0, None, None, &ResultOptions::default())
+        .await
+        .unwrap();
+    let schema = batch.schema();
+    let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+    assert_eq!(names, vec!["window_start", "window_end", "sum_value_running_window_sum", "count", "peak", "row_count"]);
+    // Without a window the queried range is the window
+    assert_eq!(ints(&batch, "window_start"), vec![0]);
+    assert_eq!(ints(&batch, "window_end"), vec![i64::MAX]);
+    assert_eq!(floats(&batch, "sum_value_running_window_sum"), vec![7.0]);
+    assert_eq!(ints(&batch, "count"), vec![3]);
+    assert_eq!(floats(&batch, "peak"), vec![4.0]);
+    assert_eq!(ints(&batch, "row_count"), vec![3]);
+
+    let by_time = GroupBy { columns: vec![], time_column: Some("timestamp".to_string()) };
+    let batch = backend
+        .aggregate_metrics(&aggregates[..1], &by_time, TimeWindow::None, 15, Some(40), None, &ResultOptions::default())
+        .await
+        .unwrap();
+    let mut rows: Vec<(i64, i64, f64)> = (0..batch.num_rows())
+        .map(|i| (ints(&batch, "timestamp")[i], ints(&batch, "window_end")[i], floats(&batch, "sum_value_running_window_sum")[i]))
+        .collect();
+    rows.sort_by_key(|r| r.0);
+    assert_eq!(rows, vec![(20, 40, 4.0), (30, 40, 2.0)]);
+    // The end of the range is exclusive
+    let batch = backend
+        .aggregate_metrics(&aggregates[..1], &by_time, TimeWindow::None, 15, Some(30), None, &ResultOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(ints(&batch, "timestamp"), vec![20]);
+
+    let by_metric = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let window = TimeWindow::Fixed(Duration::from_secs(20));
+    let batch = backend
+        .aggregate_metrics(&aggregates, &by_metric, window, 0, None, None, &ResultOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(batch.schema().field(0).data_type(), &DataType::Utf8);
+    let metric_ids = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+    let mut rows: Vec<(String, i64, i64, f64, i64)> = (0..batch.num_rows())
+        .map(|i| {
+            (
+                metric_ids.value(i).to_string(),
+                ints(&batch, "window_start")[i],
+                ints(&batch, "window_end")[i],
+                floats(&batch, "peak")[i],
+                ints(&batch, "row_count")[i],
+            )
+        })
+        .collect();
+    rows.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    assert_eq!(rows, vec![
+        ("cpu".to_string(), 0, 20, 1.0, 1),
+        ("cpu".to_string(), 20, 40, 4.0, 1),
+        ("mem".to_string(), 20, 40, 2.0, 1),
+    ]);
+
+    // The row count takes the name row_count
+    let clash = [AggregateExpr::new(AggregateFunction::Count, "*").with_alias("row_count")];
+    let status = backend
+        .aggregate_metrics(&clash, &no_grouping(), TimeWindow::None, 0, None, None, &ResultOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let unknown = [AggregateExpr::new(AggregateFunction::Sum, "value")];
+    let status = backend
+        .aggregate_metrics(&unknown, &no_grouping(), TimeWindow::None, 0, None, None, &ResultOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_aggregate_metrics_over_flight() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend
+        .insert_metrics(vec![metric("cpu", 10, 1.0), metric("cpu", 20, 4.0), metric("mem", 30, 2.0)])
+        .await
+        .unwrap();
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+
+    let query = AggregateMetricsQuery {
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Avg, "value_running_window_sum")],
+        group_by: GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(20)),
+        from_timestamp: 0,
+        to_timestamp: None,
+        filter: None,
+        options: ResultOptions::default(),
+    };
+    let expected = backend
+        .aggregate_metrics(&query.aggregates, &query.group_by, query.window, 0, None, None, &query.options)
+        .await
+        .unwrap();
+    let ticket = Ticket { ticket: serde_json::to_vec(&QueryTicket::AggregateMetrics(query)).unwrap().into() };
+    let flight_data: Vec<FlightData> = service.do_get(Request::new(ticket)).await.unwrap().into_inner().try_collect().await.unwrap();
+    let batches = flight_data_to_batches(&flight_data).unwrap();
+    assert_eq!(batches, vec![expected]);
+    assert_eq!(batches[0].num_rows(), 3);
+
+    let ticket = Ticket { ticket: br#"{"AggregateMetrics": {"aggregates": []}}"#.to_vec().into() };
+    let status = service.do_get(Request::new(ticket)).await.err().unwrap();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_aggregation_view_validates_spec() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    let schema = Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("latency", DataType::Float64, false),
+    ]);
+    backend.create_table("requests", &schema).await.unwrap();
+
+    let mut view = AggregationView {
+        source_table: "requests".to_string(),
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Avg, "latence")],
+        group_by: GroupBy { columns: vec!["host".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(60)),
+        filter: None,
+        materialized: false,
+    };
+    let status = backend.create_aggregation_view(&view).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    view.aggregates = vec![
+        AggregateExpr::new(AggregateFunction::Avg, "latency"),
+        AggregateExpr::new(AggregateFunction::Quantile(0.99), "latency").with_alias("p99"),
+    ];
+    backend.create_aggregation_view(&view).await.unwrap();
+    let registered = backend.table_manager().get_aggregation_view("agg_view_requests").await.unwrap();
+    let names: Vec<String> = registered.aggregates.iter().map(|a| a.output_name()).collect();
+    assert_eq!(names, vec!["avg_latency", "p99"]);
+}
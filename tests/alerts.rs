@@ -0,0 +1,366 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{AlertSubscription, FlightSqlService, QueryTicket};
+use hyprstream_core::storage::alerts::{
+    active_alerts, evaluate, Alert, AlertRule, AlertSchedule, AlertSource, AlertState, Notifier, NotifierConfig,
+};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::table_manager::AggregationView;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_flight::decode::FlightRecordBatchStream;
+use arrow_flight::flight_service_client::FlightServiceClient;
+use arrow_flight::flight_service_server::FlightServiceServer;
+use arrow_flight::Ticket;
+use arrow_schema::{DataType, Field, Schema};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tonic::{Code, Status};
+
+mod common;
+use common::metric;
+
+/// Records the alerts it is notified of.
+#[derive(Debug, Default)]
+struct Recorder {
+    alerts: Mutex<Vec<Alert>>,
+}
+
+#[async_trait]
+impl Notifier for Recorder {
+    async fn notify(&self, alerts: &[Alert]) -> Result<(), Status> {
+        self.alerts.lock().unwrap().extend_from_slice(alerts);
+        Ok(())
+    }
+}
+
+impl Recorder {
+    /// Waits for `count` alerts, which are sent in the background, and
+    /// takes all alerts received.
+    async fn take(&self, count: usize) -> Vec<(AlertState, String)> {
+        for _ in 0..500 {
+            if self.alerts.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.alerts.lock().unwrap().drain(..).map(|alert| (alert.state, alert.labels["metric_id"].clone())).collect()
+    }
+}
+
+/// Never finishes sending alerts.
+#[derive(Debug)]
+struct Stalled;
+
+#[async_trait]
+impl Notifier for Stalled {
+    async fn notify(&self, _alerts: &[Alert]) -> Result<(), Status> {
+        std::future::pending().await
+    }
+}
+
+fn over(column: &str, threshold: f64) -> Filter {
+    Filter::compare(column, CompareOp::Gt, Literal::Float(threshold))
+}
+
+fn metrics_rule(for_secs: u64) -> AlertRule {
+    AlertRule {
+        name: "hot".to_string(),
+        source: AlertSource::Metrics {
+            aggregates: vec![AggregateExpr::new(AggregateFunction::Avg, "value_running_window_sum")],
+            group_by: GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+            window: TimeWindow::Fixed(Duration::from_secs(60)),
+            range: Duration::from_secs(120),
+            filter: None,
+        },
+        condition: over("avg_value_running_window_sum", 10.0),
+        for_duration: Duration::from_secs(for_secs),
+        labels: BTreeMap::from([("severity".to_string(), "page".to_string())]),
+        schedule: AlertSchedule::Interval(Duration::from_secs(60)),
+        notifiers: vec![NotifierConfig::Log],
+    }
+}
+
+#[tokio::test]
+async fn test_pending_firing_resolved() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    // `cpu` runs hot until 1140, `mem` never does
+    let rows = (1000..1300).step_by(10)
+        .flat_map(|t| [metric("cpu", t, if t < 1140 { 20.0 } else { 1.0 }), metric("mem", t, 1.0)])
+        .collect();
+    backend.insert_metrics(rows).await.unwrap();
+    let recorder = Arc::new(Recorder::default());
+    backend.table_manager().add_alert_notifier(recorder.clone()).await;
+    backend.create_alert_rule(metrics_rule(60)).await.unwrap();
+
+    // Matching in [960, 1020), pending for less than a minute
+    let changed = evaluate(&backend, "hot", 1020).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    let pending = &changed[0];
+    assert_eq!(pending.state, AlertState::Pending);
+    assert_eq!(pending.labels, BTreeMap::from([
+        ("metric_id".to_string(), "cpu".to_string()),
+        ("severity".to_string(), "page".to_string()),
+    ]));
+    assert_eq!(pending.values["avg_value_running_window_sum"], 20.0);
+    assert_eq!((pending.window_start, pending.window_end), (Some(960), Some(1020)));
+    assert_eq!(active_alerts(&backend).await, changed);
+    assert!(recorder.take(0).await.is_empty());
+
+    // Fires once it matched for the `for` duration, and only notifies once
+    let changed = evaluate(&backend, "hot", 1080).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!((changed[0].state, changed[0].active_since), (AlertState::Firing, 1020));
+    assert!(evaluate(&backend, "hot", 1140).await.unwrap().is_empty());
+    assert_eq!(recorder.take(1).await, vec![(AlertState::Firing, "cpu".to_string())]);
+
+    // [1140, 1200) is below the threshold
+    let changed = evaluate(&backend, "hot", 1200).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].state, AlertState::Resolved);
+    assert_eq!(changed[0].values["avg_value_running_window_sum"], 1.0);
+    assert!(active_alerts(&backend).await.is_empty());
+    assert_eq!(recorder.take(1).await, vec![(AlertState::Resolved, "cpu".to_string())]);
+
+    // Without a `for` duration alerts fire right away, and series missing
+    // from the source resolve
+    backend.drop_alert_rule("hot").await.unwrap();
+    backend.create_alert_rule(metrics_rule(0)).await.unwrap();
+    let changed = evaluate(&backend, "hot", 1080).await.unwrap();
+    assert_eq!(changed.iter().map(|a| a.state).collect::<Vec<_>>(), vec![AlertState::Firing]);
+    let changed = evaluate(&backend, "hot", 5000).await.unwrap();
+    assert_eq!(changed.iter().map(|a| a.state).collect::<Vec<_>>(), vec![AlertState::Resolved]);
+    assert_eq!(changed[0].window_end, Some(1080));
+    assert_eq!(recorder.take(2).await.len(), 2);
+
+    // A pending series that stops matching is dropped silently
+    backend.drop_alert_rule("hot").await.unwrap();
+    backend.create_alert_rule(metrics_rule(600)).await.unwrap();
+    assert_eq!(evaluate(&backend, "hot", 1140).await.unwrap()[0].state, AlertState::Pending);
+    assert!(evaluate(&backend, "hot", 1200).await.unwrap().is_empty());
+    assert!(active_alerts(&backend).await.is_empty());
+
+    // Alerts are sent in order, so only the alert firing afterwards arrives
+    backend.drop_alert_rule("hot").await.unwrap();
+    backend.create_alert_rule(metrics_rule(0)).await.unwrap();
+    evaluate(&backend, "hot", 1080).await.unwrap();
+    assert_eq!(recorder.take(1).await, vec![(AlertState::Firing, "cpu".to_string())]);
+}
+
+#[tokio::test]
+async fn test_last_second_of_window() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.insert_metrics(vec![metric("cpu", 1079, 20.0)]).await.unwrap();
+    backend.create_alert_rule(metrics_rule(0)).await.unwrap();
+
+    // [1020, 1080) closes at 1080 and includes the sample at 1079
+    let changed = evaluate(&backend, "hot", 1080).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].state, AlertState::Firing);
+    assert_eq!((changed[0].window_start, changed[0].window_end), (Some(1020), Some(1080)));
+}
+
+#[tokio::test]
+async fn test_stalled_notifiers_do_not_block_ingest() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    let recorder = Arc::new(Recorder::default());
+    backend.table_manager().add_alert_notifier(Arc::new(Stalled)).await;
+    backend.table_manager().add_alert_notifier(recorder.clone()).await;
+    let mut rule = metrics_rule(0);
+    rule.schedule = AlertSchedule::WindowClose;
+    backend.create_alert_rule(rule).await.unwrap();
+
+    // Each ingest closes a window where `cpu` runs hot or cools down
+    for (i, t) in [1000, 1060, 1120, 1180].into_iter().enumerate() {
+        let value = if i % 2 == 0 { 20.0 } else { 1.0 };
+        let ingest = backend.insert_metrics(vec![metric("cpu", t, value)]);
+        tokio::time::timeout(Duration::from_secs(5), ingest).await.unwrap().unwrap();
+    }
+    assert_eq!(active_alerts(&backend).await.len(), 1);
+    // The notifiers after the stalled one wait for it
+    assert!(recorder.take(0).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_rule_validation() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_alert_rule(metrics_rule(0)).await.unwrap();
+    assert_eq!(backend.create_alert_rule(metrics_rule(0)).await.unwrap_err().code(), Code::AlreadyExists);
+
+    let mut unknown_column = metrics_rule(0);
+    unknown_column.condition = over("max_value_running_window_sum", 1.0);
+    let mut window_close = metrics_rule(0);
+    window_close.source = AlertSource::Metrics {
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Count, "*")],
+        group_by: GroupBy { columns: vec![], time_column: None },
+        window: TimeWindow::Sliding { window: Duration::from_secs(60), slide: Duration::from_secs(10) },
+        range: Duration::from_secs(60),
+        filter: None,
+    };
+    window_close.condition = over("count", 1.0);
+    window_close.schedule = AlertSchedule::WindowClose;
+    let mut https = metrics_rule(0);
+    https.notifiers = vec![NotifierConfig::Webhook { url: "https://example.com/alerts".to_string() }];
+    let mut no_interval = metrics_rule(0);
+    no_interval.schedule = AlertSchedule::Interval(Duration::ZERO);
+    for mut invalid in [unknown_column, window_close, https, no_interval] {
+        invalid.name = "invalid".to_string();
+        assert_eq!(backend.create_alert_rule(invalid).await.unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    let mut missing_view = metrics_rule(0);
+    missing_view.name = "missing".to_string();
+    missing_view.source = AlertSource::View("missing".to_string());
+    assert_eq!(backend.create_alert_rule(missing_view).await.unwrap_err().code(), Code::NotFound);
+    assert_eq!(backend.drop_alert_rule("missing").await.unwrap_err().code(), Code::NotFound);
+    assert_eq!(backend.evaluate_alert_rule("missing").await.unwrap_err().code(), Code::NotFound);
+
+    // Rules are declared in JSON, with the `for` duration under `for`
+    let json = serde_json::to_value(metrics_rule(30)).unwrap();
+    assert_eq!(json["for"]["secs"], 30);
+    let mut rule: AlertRule = serde_json::from_value(json).unwrap();
+    assert_eq!(rule.for_duration, Duration::from_secs(30));
+    rule.name = "parsed".to_string();
+    backend.create_alert_rule(rule).await.unwrap();
+}
+
+fn requests_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("latency", DataType::Float64, true),
+    ])
+}
+
+fn requests(rows: &[(&str, i64, f64)]) -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(requests_schema()),
+        vec![
+            Arc::new(StringArray::from(rows.iter().map(|r| r.0).collect::<Vec<_>>())),
+            Arc::new(Int64Array::from(rows.iter().map(|r| r.1).collect::<Vec<_>>())),
+            Arc::new(Float64Array::from(rows.iter().map(|r| r.2).collect::<Vec<_>>())),
+        ],
+    )
+    .unwrap()
+}
+
+/// Serves HTTP requests on a local port, sending their bodies to the
+/// returned channel and answering 200.
+async fn webhook_stub() -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks/alerts", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            let body = loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    continue;
+                };
+                let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                assert!(head.starts_with("post /hooks/alerts http/1.1"), "{}", head);
+                assert!(head.contains("content-type: application/json"));
+                let length: usize = head.lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if request.len() >= end + 4 + length {
+                    break request[end + 4..end + 4 + length].to_vec();
+                }
+            };
+            socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+            sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+        }
+    });
+    (url, receiver)
+}
+
+#[tokio::test]
+async fn test_window_close_webhook_and_subscription() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend.create_table("requests", &requests_schema()).await.unwrap();
+    backend.create_aggregation_view(&AggregationView {
+        source_table: "requests".to_string(),
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Max, "latency")],
+        group_by: GroupBy { columns: vec!["host".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(60)),
+        filter: None,
+        materialized: true,
+    }).await.unwrap();
+    let (url, mut webhook) = webhook_stub().await;
+    backend.create_alert_rule(AlertRule {
+        name: "slow".to_string(),
+        source: AlertSource::View("agg_view_requests".to_string()),
+        condition: over("max_latency", 500.0),
+        for_duration: Duration::ZERO,
+        labels: BTreeMap::from([("team".to_string(), "edge".to_string())]),
+        schedule: AlertSchedule::WindowClose,
+        notifiers: vec![NotifierConfig::Webhook { url }, NotifierConfig::Subscription],
+    }).await.unwrap();
+
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(FlightServiceServer::new(service))
+            .serve_with_incoming(incoming),
+    );
+    let mut client = FlightServiceClient::connect(address).await.unwrap();
+    let ticket = serde_json::to_vec(&QueryTicket::SubscribeAlerts(AlertSubscription::default())).unwrap();
+    let response = client.do_get(Ticket::new(ticket)).await.unwrap();
+    let mut subscription = FlightRecordBatchStream::new_from_flight_data(response.into_inner().map_err(Into::into));
+
+    // Rows of an open window are not evaluated
+    backend.insert_into_table("requests", requests(&[("a", 10, 900.0), ("b", 20, 100.0)])).await.unwrap();
+    assert!(active_alerts(backend.as_ref()).await.is_empty());
+
+    // A row past the window closes it
+    backend.insert_into_table("requests", requests(&[("a", 70, 50.0), ("b", 75, 100.0)])).await.unwrap();
+    let payload = webhook.recv().await.unwrap();
+    let alerts: Vec<Alert> = serde_json::from_value(payload["alerts"].clone()).unwrap();
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].state, AlertState::Firing);
+    assert_eq!(alerts[0].evaluated_at, 60);
+    assert_eq!(alerts[0].labels["host"], "a");
+    assert_eq!(alerts[0].labels["team"], "edge");
+    assert_eq!(alerts[0].values["max_latency"], 900.0);
+
+    let batch = subscription.next().await.unwrap().unwrap();
+    assert_eq!(batch.schema().as_ref(), &Alert::schema());
+    assert_eq!(batch, Alert::to_batch(&alerts).unwrap());
+    let states = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(states.value(0), "firing");
+
+    // Later rows of the same window do not evaluate the rule again
+    backend.insert_into_table("requests", requests(&[("b", 80, 1000.0)])).await.unwrap();
+    backend.insert_into_table("requests", requests(&[("b", 130, 1.0)])).await.unwrap();
+    let payload = webhook.recv().await.unwrap();
+    let mut alerts: Vec<Alert> = serde_json::from_value(payload["alerts"].clone()).unwrap();
+    alerts.sort_by_key(|alert| alert.labels["host"].clone());
+    let states: Vec<(&str, AlertState)> = alerts.iter().map(|a| (a.labels["host"].as_str(), a.state)).collect();
+    assert_eq!(states, vec![("a", AlertState::Resolved), ("b", AlertState::Firing)]);
+    assert!(alerts.iter().all(|alert| alert.evaluated_at == 120));
+    for _ in 0..2 {
+        assert_eq!(subscription.next().await.unwrap().unwrap().num_rows(), 1);
+    }
+    assert!(webhook.try_recv().is_err());
+}
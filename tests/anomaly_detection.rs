@@ -0,0 +1,220 @@
+use hyprstream_core::aggregation::anomaly::AnomalyDetector;
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal, MatchOp};
+use hyprstream_core::aggregation::smoothing::{HoltWinters, HoltWintersParams};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::alerts::{evaluate, AlertRule, AlertSchedule, AlertSource, AlertState, NotifierConfig};
+use hyprstream_core::storage::anomaly::{flag_metric_id, run, score_metric_id, AnomalyMonitor};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::StorageBackend;
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+/// Returns the indices of the flagged values and how many were scored.
+fn flagged(detector: AnomalyDetector, values: &[f64]) -> (Vec<usize>, usize) {
+    let mut state = detector.start().unwrap();
+    let observations: Vec<_> = values.iter().map(|&value| state.observe(value)).collect();
+    let scored = observations.iter().filter(|o| o.is_some()).count();
+    let flagged = observations.iter()
+        .enumerate()
+        .filter(|(_, o)| o.is_some_and(|o| o.anomaly))
+        .map(|(i, _)| i)
+        .collect();
+    (flagged, scored)
+}
+
+fn seasonal(t: usize) -> f64 {
+    [0.0, 10.0, 0.0, -10.0][t % 4] + 0.1 * t as f64
+}
+
+fn holt_winters() -> HoltWintersParams {
+    HoltWintersParams { alpha: 0.5, beta: 0.1, gamma: 0.5, season: 4 }
+}
+
+#[test]
+fn test_detectors() {
+    let mut noisy: Vec<f64> = (0..40).map(|i| 10.0 + ((i * 7) % 5) as f64 * 0.5).collect();
+    noisy[25] = 30.0;
+    let (zscore, scored) = flagged(AnomalyDetector::ZScore { window: 10, threshold: 4.0 }, &noisy);
+    assert_eq!((zscore, scored), (vec![25], 38));
+    let (ewma, scored) = flagged(AnomalyDetector::Ewma { alpha: 0.2, threshold: 6.0 }, &noisy);
+    assert_eq!((ewma, scored), (vec![25], 38));
+
+    // A trend plus season is forecast without error once the model settled
+    let model = HoltWinters::fit(holt_winters(), &(0..160).map(seasonal).collect::<Vec<_>>()).unwrap();
+    for steps in 1..=8 {
+        let forecast = model.forecast(steps).unwrap();
+        assert!((forecast - seasonal(159 + steps)).abs() < 1e-3, "{} != {}", forecast, seasonal(159 + steps));
+    }
+    assert_eq!(HoltWinters::new(holt_winters()).unwrap().forecast(1), None);
+
+    // Seasonal swings are not anomalies for Holt-Winters, only the spike
+    let mut series: Vec<f64> = (0..60).map(|t| seasonal(t) + [0.3, -0.2, 0.1, -0.4, 0.2][t % 5]).collect();
+    series[41] += 15.0;
+    let detector = AnomalyDetector::HoltWinters { params: holt_winters(), threshold: 5.0 };
+    let (seasonal_flags, scored) = flagged(detector, &series);
+    assert_eq!(seasonal_flags.first(), Some(&41));
+    assert_eq!(scored, 60 - 8);
+    let (zscore, _) = flagged(AnomalyDetector::ZScore { window: 8, threshold: 5.0 }, &series[..41]);
+    assert!(zscore.is_empty());
+
+    let invalid = [
+        AnomalyDetector::ZScore { window: 1, threshold: 3.0 },
+        AnomalyDetector::Ewma { alpha: 0.0, threshold: 3.0 },
+        AnomalyDetector::Ewma { alpha: 0.5, threshold: 0.0 },
+        AnomalyDetector::HoltWinters { params: HoltWintersParams { season: 0, ..holt_winters() }, threshold: 3.0 },
+        AnomalyDetector::HoltWinters { params: HoltWintersParams { beta: 1.5, ..holt_winters() }, threshold: 3.0 },
+    ];
+    for detector in invalid {
+        assert_eq!(detector.validate().unwrap_err().code(), Code::InvalidArgument);
+    }
+}
+
+/// Two rows per minute of `cpu`, spiking in minute 9, and of `mem`.
+fn minutes(minutes: std::ops::Range<i64>) -> Vec<MetricRecord> {
+    minutes
+        .flat_map(|k| {
+            let cpu = if k == 9 { 100.0 } else { 10.0 + (k % 3) as f64 };
+            let mem = 5.0 + (k % 2) as f64;
+            [metric("cpu", 60 * k + 5, cpu), metric("cpu", 60 * k + 35, cpu), metric("mem", 60 * k + 20, mem)]
+        })
+        .collect()
+}
+
+fn monitor() -> AnomalyMonitor {
+    AnomalyMonitor {
+        name: "spikes".to_string(),
+        aggregate: AggregateExpr::new(AggregateFunction::Avg, "value_running_window_sum"),
+        window: TimeWindow::Fixed(Duration::from_secs(60)),
+        filter: None,
+        detector: AnomalyDetector::ZScore { window: 10, threshold: 4.0 },
+    }
+}
+
+/// Returns the derived series of the monitor as (metric id, minute, value).
+async fn derived(backend: &DuckDbBackend) -> Vec<(String, i64, f64)> {
+    let batch: RecordBatch = backend.aggregate_metrics(
+        &[AggregateExpr::new(AggregateFunction::Max, "value_running_window_sum")],
+        &GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+        TimeWindow::Fixed(Duration::from_secs(60)),
+        0,
+        None,
+        Some(&Filter::matches("metric_id", MatchOp::Regex, ".*:spikes:.*")),
+        &ResultOptions::default(),
+    ).await.unwrap();
+    let ids = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+    let starts = batch.column_by_name("window_start").unwrap().as_any().downcast_ref::<Int64Array>().unwrap();
+    let values = batch.column_by_name("max_value_running_window_sum").unwrap();
+    let values = values.as_any().downcast_ref::<Float64Array>().unwrap();
+    let mut rows: Vec<_> = (0..batch.num_rows())
+        .map(|i| (ids.value(i).to_string(), starts.value(i) / 60, values.value(i)))
+        .collect();
+    rows.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    rows
+}
+
+#[tokio::test]
+async fn test_monitor_writes_derived_series() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_anomaly_monitor(monitor()).await.unwrap();
+
+    // Minutes 0 to 11 close on ingest; the first two only build history
+    backend.insert_metrics(minutes(0..13)).await.unwrap();
+    let rows = derived(&backend).await;
+    let ids: Vec<&str> = rows.iter().map(|row| row.0.as_str()).collect();
+    assert_eq!(ids.len(), 4 * 10);
+    for metric_id in ["cpu", "mem"] {
+        assert_eq!(ids.iter().filter(|id| **id == score_metric_id(metric_id, "spikes")).count(), 10);
+        assert_eq!(ids.iter().filter(|id| **id == flag_metric_id(metric_id, "spikes")).count(), 10);
+    }
+    let flags: Vec<(&str, i64)> = rows.iter()
+        .filter(|row| row.0.ends_with(":anomaly") && row.2 == 1.0)
+        .map(|row| (row.0.as_str(), row.1))
+        .collect();
+    assert_eq!(flags, vec![("cpu:spikes:anomaly", 9)]);
+    let spike = rows.iter().find(|row| row.0 == "cpu:spikes:score" && row.1 == 9).unwrap();
+    assert!(spike.2 > 50.0);
+
+    // Each window is scored once; minute 12 closes with the next minute
+    assert!(run(&backend, "spikes", 755).await.unwrap().is_empty());
+    backend.insert_metrics(minutes(13..14)).await.unwrap();
+    let rows = derived(&backend).await;
+    assert_eq!(rows.len(), 4 * 11);
+    assert!(rows.iter().all(|row| row.1 <= 12));
+    let scores = run(&backend, "spikes", 845).await.unwrap();
+    let scored: Vec<(&str, i64)> = scores.iter().map(|s| (s.metric_id.as_str(), s.window_start)).collect();
+    assert_eq!(scored, vec![("cpu", 780), ("mem", 780)]);
+
+    // Alert rules watch the flags like any other metric
+    let rule = AlertRule {
+        name: "anomalous".to_string(),
+        source: AlertSource::Metrics {
+            aggregates: vec![AggregateExpr::new(AggregateFunction::Max, "value_running_window_sum")],
+            group_by: GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+            window: TimeWindow::Fixed(Duration::from_secs(60)),
+            range: Duration::from_secs(60),
+            filter: Some(Filter::matches("metric_id", MatchOp::Regex, ".*:spikes:anomaly")),
+        },
+        condition: Filter::compare("max_value_running_window_sum", CompareOp::Ge, Literal::Float(1.0)),
+        for_duration: Duration::ZERO,
+        labels: BTreeMap::new(),
+        schedule: AlertSchedule::Interval(Duration::from_secs(60)),
+        notifiers: vec![NotifierConfig::Log],
+    };
+    backend.create_alert_rule(rule).await.unwrap();
+    let alerts = evaluate(&backend, "anomalous", 600).await.unwrap();
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].state, AlertState::Firing);
+    assert_eq!(alerts[0].labels["metric_id"], "cpu:spikes:anomaly");
+    let alerts = evaluate(&backend, "anomalous", 660).await.unwrap();
+    assert_eq!(alerts[0].state, AlertState::Resolved);
+}
+
+#[tokio::test]
+async fn test_monitor_scores_last_second_of_window() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_anomaly_monitor(monitor()).await.unwrap();
+
+    // Minute 10 spikes only in its last second
+    let mut rows: Vec<_> = (0..11).map(|k| metric("cpu", 60 * k + 5, 10.0 + (k % 3) as f64)).collect();
+    rows.push(metric("cpu", 659, 1000.0));
+    backend.insert_metrics(rows).await.unwrap();
+
+    let scores = run(&backend, "spikes", 660).await.unwrap();
+    assert_eq!(scores.len(), 1);
+    assert_eq!((scores[0].window_start, scores[0].anomaly), (600, true));
+}
+
+#[tokio::test]
+async fn test_monitor_validation() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_anomaly_monitor(monitor()).await.unwrap();
+    assert_eq!(backend.create_anomaly_monitor(monitor()).await.unwrap_err().code(), Code::AlreadyExists);
+
+    let mut name = monitor();
+    name.name = "cpu:spikes".to_string();
+    let mut window = monitor();
+    window.window = TimeWindow::Sliding { window: Duration::from_secs(60), slide: Duration::from_secs(30) };
+    let mut column = monitor();
+    column.aggregate = AggregateExpr::new(AggregateFunction::Avg, "missing");
+    let mut detector = monitor();
+    detector.detector = AnomalyDetector::ZScore { window: 10, threshold: -1.0 };
+    for mut invalid in [name, window, column, detector] {
+        if invalid.name == "spikes" {
+            invalid.name = "other".to_string();
+        }
+        assert_eq!(backend.create_anomaly_monitor(invalid).await.unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    assert_eq!(backend.run_anomaly_monitor("missing").await.unwrap_err().code(), Code::NotFound);
+    backend.drop_anomaly_monitor("spikes").await.unwrap();
+    assert_eq!(backend.drop_anomaly_monitor("spikes").await.unwrap_err().code(), Code::NotFound);
+}
@@ -0,0 +1,102 @@
+use hyprstream_core::aggregation::AggregateFunction;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{upsert_state, BatchAggregation, StorageBackend};
+
+mod common;
+use common::metric;
+
+fn window(metric_id: &str, start: i64, samples: &[(i64, f64)]) -> BatchAggregation {
+    let mut agg = BatchAggregation::new(metric_id.to_string(), start, start + 60);
+    for (timestamp, value) in samples {
+        agg.update(*timestamp, *value, 1);
+    }
+    agg
+}
+
+#[test]
+fn test_upsert_state_merges_repeated_windows() {
+    let new = vec![
+        window("mem", 0, &[(10, 4.0)]),
+        window("cpu", 60, &[(70, 1.0)]),
+        window("cpu", 0, &[(5, 1.0)]),
+        window("cpu", 60, &[(80, 2.0), (90, 3.0)]),
+    ];
+    let stored = vec![window("cpu", 60, &[(65, 10.0)]), window("disk", 0, &[(1, 1.0)])];
+
+    let upserted = upsert_state(&new, &stored).unwrap();
+    let keys: Vec<(&str, i64)> = upserted.iter().map(|a| (a.metric_id.as_str(), a.window_start)).collect();
+    assert_eq!(keys, vec![("cpu", 0), ("cpu", 60), ("mem", 0)]);
+    // Both new windows and the stored state, each merged once
+    assert_eq!(upserted[1].sample_count, 4);
+    assert_eq!(upserted[1].evaluate(AggregateFunction::Sum).unwrap(), 16.0);
+    assert_eq!(upserted[1].evaluate(AggregateFunction::First).unwrap(), 10.0);
+    assert_eq!(upserted[1].evaluate(AggregateFunction::Last).unwrap(), 3.0);
+
+    let upserted = upsert_state(&new, &[]).unwrap();
+    assert_eq!(upserted.len(), 3);
+    assert_eq!(upserted[1].sample_count, 3);
+}
+
+#[tokio::test]
+async fn test_insert_batch_aggregations_with_repeated_windows() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    backend.insert_batch_aggregations(vec![window("cpu", 0, &[(5, 1.0)])]).await.unwrap();
+    backend
+        .insert_batch_aggregations(vec![
+            window("cpu", 0, &[(10, 2.0)]),
+            window("cpu", 0, &[(20, 3.0)]),
+            window("cpu", 60, &[(70, 4.0)]),
+        ])
+        .await
+        .unwrap();
+
+    let stored = backend.query_batch_aggregations(0, None).await.unwrap();
+    assert_eq!(stored.len(), 2);
+    assert_eq!(stored[0].sample_count, 3);
+    assert_eq!(stored[0].evaluate(AggregateFunction::Sum).unwrap(), 6.0);
+    assert_eq!(stored[1].sample_count, 1);
+}
+
+#[tokio::test]
+async fn test_ingest_state_keeps_sketches() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    // Samples 1..=50 spread over two 60s windows
+    let metrics: Vec<_> = (0..50).map(|i| metric("cpu", i * 2, (i + 1) as f64)).collect();
+    backend.insert_metrics(metrics[..25].to_vec()).await.unwrap();
+    backend.insert_metrics(metrics[25..].to_vec()).await.unwrap();
+
+    let stored = backend.query_batch_aggregations(0, None).await.unwrap();
+    let windows: Vec<_> = stored
+        .iter()
+        .map(|a| (a.window_start, a.window_end, a.quantile_sketch.as_ref().unwrap().count()))
+        .collect();
+    assert_eq!(windows, vec![(0, 60, 30), (60, 120, 20)]);
+
+    // The p95 of the whole range is read back from the merged sketches in
+    // `metric_aggregations`
+    let mut merged = stored[0].clone();
+    merged.merge(&stored[1]).unwrap();
+    let p95 = merged.evaluate(AggregateFunction::Quantile(0.95)).unwrap();
+    assert!((p95 - 47.0).abs() <= 47.0 * 0.011, "p95 {}", p95);
+    assert_eq!(merged.evaluate(AggregateFunction::CountDistinct).unwrap(), 50.0);
+
+    // State without sketches, as stored before they were kept, merged into
+    // it loses them
+    let mut merged = window("cpu", 0, &[(5, 4.0)]);
+    let mut legacy = BatchAggregation {
+        quantile_sketch: None,
+        distinct_sketch: None,
+        ..BatchAggregation::new("cpu".to_string(), 0, 60)
+    };
+    legacy.update(10, 1.0, 1);
+    merged.merge(&legacy).unwrap();
+    assert!(merged.quantile_sketch.is_none());
+    assert!(merged.evaluate(AggregateFunction::Quantile(0.5)).is_err());
+    let mut empty = BatchAggregation::new("cpu".to_string(), 0, 60);
+    empty.merge(&window("cpu", 0, &[(5, 4.0)])).unwrap();
+    assert_eq!(empty.quantile_sketch.unwrap().count(), 1);
+}
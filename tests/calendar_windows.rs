@@ -0,0 +1,210 @@
+use hyprstream_core::aggregation::calendar::CalendarUnit;
+use hyprstream_core::aggregation::filter::SqlDialect;
+use hyprstream_core::aggregation::{build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, TimeWindow, ResultOptions};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::table_manager::AggregationView;
+use hyprstream_core::storage::StorageBackend;
+use arrow_schema::{DataType, Field, Schema};
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use std::time::Duration;
+
+fn calendar(unit: CalendarUnit, timezone: Tz) -> TimeWindow {
+    TimeWindow::Calendar { unit, timezone, offset: Duration::ZERO }
+}
+
+fn local(timezone: Tz, y: i32, m: u32, d: u32, h: u32) -> i64 {
+    timezone.with_ymd_and_hms(y, m, d, h, 0, 0).earliest().unwrap().timestamp()
+}
+
+fn utc(y: i32, m: u32, d: u32, h: u32) -> i64 {
+    NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, 0, 0).unwrap().and_utc().timestamp()
+}
+
+#[test]
+fn test_calendar_bounds_follow_local_time() {
+    let berlin = chrono_tz::Europe::Berlin;
+
+    // The day daylight saving starts has 23 hours
+    let (start, end) = calendar(CalendarUnit::Day, berlin).window_bounds(local(berlin, 2024, 3, 31, 12));
+    assert_eq!((start, end), (utc(2024, 3, 30, 23), utc(2024, 3, 31, 22)));
+
+    // Weeks start on Monday
+    let (start, end) = calendar(CalendarUnit::Week, berlin).window_bounds(local(berlin, 2024, 1, 7, 23));
+    assert_eq!((start, end), (local(berlin, 2024, 1, 1, 0), local(berlin, 2024, 1, 8, 0)));
+
+    let (start, end) = calendar(CalendarUnit::Month, berlin).window_bounds(local(berlin, 2024, 2, 29, 23));
+    assert_eq!((start, end), (local(berlin, 2024, 2, 1, 0), local(berlin, 2024, 3, 1, 0)));
+
+    let (start, end) = calendar(CalendarUnit::Quarter, berlin).window_bounds(local(berlin, 2024, 11, 15, 8));
+    assert_eq!((start, end), (local(berlin, 2024, 10, 1, 0), local(berlin, 2025, 1, 1, 0)));
+
+    // Business days starting at 06:00
+    let business_day = TimeWindow::Calendar {
+        unit: CalendarUnit::Day,
+        timezone: berlin,
+        offset: Duration::from_secs(6 * 3600),
+    };
+    let (start, end) = business_day.window_bounds(local(berlin, 2024, 6, 2, 5));
+    assert_eq!((start, end), (local(berlin, 2024, 6, 1, 6), local(berlin, 2024, 6, 2, 6)));
+}
+
+#[test]
+fn test_calendar_bounds_in_gap_resolve_to_transition() {
+    // Daylight saving in Sao Paulo started at midnight on 2018-11-04
+    let sao_paulo = chrono_tz::America::Sao_Paulo;
+    let (start, end) = calendar(CalendarUnit::Day, sao_paulo).window_bounds(local(sao_paulo, 2018, 11, 4, 12));
+    assert_eq!(start, utc(2018, 11, 4, 3));
+    assert_eq!(end, local(sao_paulo, 2018, 11, 5, 0));
+}
+
+#[test]
+fn test_aligned_bounds() {
+    let window = TimeWindow::Aligned { size: Duration::from_secs(3600), origin: 900 };
+    assert_eq!(window.window_bounds(5000), (4500, 8100));
+    assert_eq!(window.window_bounds(900), (900, 4500));
+    assert_eq!(window.window_bounds(-100), (-2700, 900));
+}
+
+#[test]
+fn test_sql_matches_window_bounds() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE events (timestamp BIGINT)").unwrap();
+    // Deterministic spread over 1980..2090, plus instants around a transition
+    let mut timestamps: Vec<i64> = (0..400).map(|i: i64| 315_532_800 + (i * 8_640_013_577) % 3_471_292_800).collect();
+    let transition = utc(2024, 10, 27, 1);
+    timestamps.extend((-3..=3).map(|h| transition + h * 1800));
+    for timestamp in &timestamps {
+        conn.execute("INSERT INTO events VALUES (?)", duckdb::params![timestamp]).unwrap();
+    }
+
+    let zones = [
+        chrono_tz::UTC,
+        chrono_tz::Europe::Berlin,
+        chrono_tz::America::Sao_Paulo,
+        chrono_tz::Australia::Lord_Howe,
+        chrono_tz::Asia::Kolkata,
+    ];
+    let units = [CalendarUnit::Day, CalendarUnit::Week, CalendarUnit::Month, CalendarUnit::Quarter];
+    let mut windows = vec![TimeWindow::Aligned { size: Duration::from_secs(7 * 86400), origin: 345_600 }];
+    for timezone in zones {
+        for unit in units {
+            for offset in [0, 6 * 3600] {
+                windows.push(TimeWindow::Calendar { unit, timezone, offset: Duration::from_secs(offset) });
+            }
+        }
+    }
+
+    // The Postgres rendering only uses SQL that DuckDB runs as well
+    for window in windows {
+        for dialect in [SqlDialect::DuckDb, SqlDialect::Postgres] {
+            let sql = format!("SELECT timestamp, {} FROM events", window.to_sql_between(None, None, dialect).unwrap());
+            let mut stmt = conn.prepare(&sql).unwrap();
+            let mut rows = stmt.query([]).unwrap();
+            while let Some(row) = rows.next().unwrap() {
+                let timestamp: i64 = row.get(0).unwrap();
+                let bounds: (i64, i64) = (row.get(1).unwrap(), row.get(2).unwrap());
+                assert_eq!(bounds, window.window_bounds(timestamp), "{:?} {:?} at {}", window, dialect, timestamp);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sql_between_renders_transitions_of_the_range() {
+    let berlin = chrono_tz::Europe::Berlin;
+    let window = calendar(CalendarUnit::Day, berlin);
+    let full = window.to_sql().unwrap();
+    let (from, to) = (utc(2024, 10, 20, 0), utc(2024, 11, 3, 0));
+    let clamped = window.to_sql_between(Some(from), Some(to), SqlDialect::DuckDb).unwrap();
+    assert!(clamped.len() * 20 < full.len(), "{} of {}", clamped.len(), full.len());
+
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE events (timestamp BIGINT)").unwrap();
+    let timestamps: Vec<i64> = (from..to).step_by(1800).collect();
+    for timestamp in &timestamps {
+        conn.execute("INSERT INTO events VALUES (?)", duckdb::params![timestamp]).unwrap();
+    }
+    let mut stmt = conn.prepare(&format!("SELECT timestamp, {} FROM events", clamped)).unwrap();
+    let mut rows = stmt.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let timestamp: i64 = row.get(0).unwrap();
+        let bounds: (i64, i64) = (row.get(1).unwrap(), row.get(2).unwrap());
+        assert_eq!(bounds, window.window_bounds(timestamp), "at {}", timestamp);
+    }
+}
+
+#[tokio::test]
+async fn test_calendar_aggregation_view() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    let schema = Schema::new(vec![
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("value", DataType::Float64, false),
+    ]);
+    backend.create_table("events", &schema).await.unwrap();
+
+    let new_york = chrono_tz::America::New_York;
+    let window = calendar(CalendarUnit::Day, new_york);
+    let view = AggregationView {
+        source_table: "events".to_string(),
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Sum, "value")],
+        group_by: GroupBy { columns: vec![], time_column: None },
+        window,
+        filter: None,
+        materialized: false,
+    };
+    backend.create_aggregation_view(&view).await.unwrap();
+    let registered = backend.table_manager().get_aggregation_view("agg_view_events").await.unwrap();
+    assert!(matches!(registered.window, TimeWindow::Calendar { unit: CalendarUnit::Day, .. }));
+
+    // 23:00 and 01:00 local fall on different days, although both are
+    // within the same UTC day
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE events (timestamp BIGINT, value DOUBLE)").unwrap();
+    for (hour, value) in [(20, 1.0), (23, 2.0), (25, 4.0)] {
+        let timestamp = local(new_york, 2024, 5, 1, 0) + hour * 3600;
+        conn.execute("INSERT INTO events VALUES (?, ?)", duckdb::params![timestamp, value]).unwrap();
+    }
+    let sql = build_windowed_aggregate_query("events", &view.aggregates, &view.group_by, window, None, None, None, &ResultOptions::default(), SqlDialect::DuckDb).unwrap();
+    let mut stmt = conn.prepare(&format!("{} ORDER BY window_start", sql)).unwrap();
+    let rows: Vec<(i64, f64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(2)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    assert_eq!(rows, vec![(local(new_york, 2024, 5, 1, 0), 3.0), (local(new_york, 2024, 5, 2, 0), 4.0)]);
+}
+
+#[tokio::test]
+async fn test_batch_aggregations_use_calendar_windows() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    let tokyo = chrono_tz::Asia::Tokyo;
+    let metrics: Vec<MetricRecord> = [(2024, 3, 31, 23), (2024, 4, 1, 1), (2024, 4, 30, 9)]
+        .into_iter()
+        .map(|(y, m, d, h)| MetricRecord {
+            metric_id: "orders".to_string(),
+            timestamp: local(tokyo, y, m, d, h),
+            value_running_window_sum: 1.0,
+            value_running_window_avg: 1.0,
+            value_running_window_count: 1,
+        })
+        .collect();
+
+    let mut windows: Vec<(i64, i64, i64)> = backend
+        .update_batch_aggregations(&metrics, calendar(CalendarUnit::Month, tokyo))
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|a| (a.window_start, a.window_end, a.running_count))
+        .collect();
+    windows.sort();
+    assert_eq!(windows, vec![
+        (local(tokyo, 2024, 3, 1, 0), local(tokyo, 2024, 4, 1, 0), 1),
+        (local(tokyo, 2024, 4, 1, 0), local(tokyo, 2024, 5, 1, 0), 2),
+    ]);
+}
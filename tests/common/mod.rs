@@ -0,0 +1,21 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use arrow_array::RecordBatch;
+use hyprstream_core::metrics::MetricRecord;
+
+/// A metric row representing a single sample of `value`.
+pub fn metric(metric_id: &str, timestamp: i64, value: f64) -> MetricRecord {
+    MetricRecord {
+        metric_id: metric_id.to_string(),
+        timestamp,
+        value_running_window_sum: value,
+        value_running_window_avg: value,
+        value_running_window_count: 1,
+    }
+}
+
+/// The column `name` of a batch, downcast to `T`.
+pub fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+    batch.column_by_name(name).unwrap().as_any().downcast_ref::<T>().unwrap()
+}
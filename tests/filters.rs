@@ -0,0 +1,219 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal, MatchOp, SqlDialect};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::StorageBackend;
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+fn samples_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("metric_id", DataType::Utf8, false),
+        Field::new("host", DataType::Utf8, true),
+        Field::new("value", DataType::Float64, true),
+    ])
+}
+
+fn samples() -> Vec<(i64, &'static str, Option<&'static str>, Option<f64>)> {
+    vec![
+        (0, "cpu.user", Some("a"), Some(1.0)),
+        (1, "cpu.system", Some("b"), Some(5.0)),
+        (2, "mem.used", None, Some(3.0)),
+        (3, "cpu.idle", Some("a"), None),
+        (4, "disk.io", Some("it's"), Some(-2.5)),
+    ]
+}
+
+fn samples_batch() -> RecordBatch {
+    let rows = samples();
+    RecordBatch::try_new(
+        Arc::new(samples_schema()),
+        vec![
+            Arc::new(Int64Array::from(rows.iter().map(|r| r.0).collect::<Vec<_>>())),
+            Arc::new(StringArray::from(rows.iter().map(|r| r.1).collect::<Vec<_>>())),
+            Arc::new(StringArray::from(rows.iter().map(|r| r.2).collect::<Vec<_>>())),
+            Arc::new(Float64Array::from(rows.iter().map(|r| r.3).collect::<Vec<_>>())),
+        ],
+    )
+    .unwrap()
+}
+
+fn example_filters() -> Vec<Filter> {
+    vec![
+        Filter::compare("value", CompareOp::Gt, Literal::Int(2)),
+        Filter::compare("value", CompareOp::Le, Literal::Float(1.0)),
+        Filter::compare("host", CompareOp::Ne, Literal::String("a".to_string())),
+        Filter::compare("host", CompareOp::Eq, Literal::String("it's".to_string())),
+        Filter::In {
+            column: "host".to_string(),
+            values: vec![Literal::String("b".to_string()), Literal::String("it's".to_string())],
+        },
+        Filter::In { column: "value".to_string(), values: vec![] },
+        Filter::metric_id_regex("cpu\\..*"),
+        Filter::metric_id_regex("cpu"),
+        Filter::matches("metric_id", MatchOp::NotRegex, "cpu\\.(user|idle)"),
+        Filter::matches("host", MatchOp::Regex, "a|b"),
+        Filter::Not(Box::new(Filter::compare("value", CompareOp::Gt, Literal::Int(2)))),
+        Filter::Or(vec![
+            Filter::compare("host", CompareOp::Eq, Literal::String("a".to_string())),
+            Filter::compare("value", CompareOp::Lt, Literal::Int(0)),
+        ]),
+        Filter::metric_id_regex("cpu.*").and(Filter::compare("value", CompareOp::Ge, Literal::Int(1))),
+        Filter::And(vec![]),
+        Filter::Or(vec![]),
+    ]
+}
+
+#[test]
+fn test_serde_round_trip() {
+    for filter in example_filters() {
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(serde_json::from_str::<Filter>(&json).unwrap(), filter, "{}", json);
+    }
+
+    let filter: Filter = serde_json::from_str(
+        r#"{"and": [
+            {"match": {"label": "metric_id", "op": "regex", "value": "cpu.*"}},
+            {"compare": {"column": "value", "op": "ge", "value": 1.5}}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        filter,
+        Filter::metric_id_regex("cpu.*").and(Filter::compare("value", CompareOp::Ge, Literal::Float(1.5)))
+    );
+}
+
+#[test]
+fn test_sql_matches_in_memory_evaluation() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE samples (id BIGINT, metric_id VARCHAR, host VARCHAR, value DOUBLE)").unwrap();
+    for (id, metric_id, host, value) in samples() {
+        conn.execute("INSERT INTO samples VALUES (?, ?, ?, ?)", duckdb::params![id, metric_id, host, value]).unwrap();
+    }
+    let batch = samples_batch();
+    let schema = samples_schema();
+
+    for filter in example_filters() {
+        filter.validate(&schema).unwrap();
+        let sql = format!(
+            "SELECT id FROM samples WHERE {} ORDER BY id",
+            filter.to_sql(SqlDialect::DuckDb).unwrap()
+        );
+        let mut stmt = conn.prepare(&sql).unwrap();
+        let expected: Vec<i64> = stmt.query_map([], |row| row.get(0)).unwrap().map(|id| id.unwrap()).collect();
+
+        let filtered = filter.apply(&batch).unwrap();
+        let ids = filtered.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+        let actual: Vec<i64> = ids.values().to_vec();
+        assert_eq!(actual, expected, "{}", sql);
+    }
+
+    // Comparisons with null are unknown rather than false
+    let filter = Filter::compare("value", CompareOp::Gt, Literal::Int(2));
+    let mask = filter.evaluate(&batch).unwrap();
+    assert!(mask.is_null(3));
+    assert!(Filter::Not(Box::new(filter)).evaluate(&batch).unwrap().is_null(3));
+}
+
+#[test]
+fn test_sql_rendering_is_injection_safe() {
+    let filter = Filter::compare("host", CompareOp::Eq, Literal::String("x'; DROP TABLE metrics; --".to_string()));
+    assert_eq!(filter.to_sql(SqlDialect::DuckDb).unwrap(), "host = 'x''; DROP TABLE metrics; --'");
+
+    let filter = Filter::metric_id_regex("cpu'.*");
+    assert_eq!(filter.to_sql(SqlDialect::DuckDb).unwrap(), "regexp_full_match(metric_id, 'cpu''.*')");
+    assert_eq!(filter.to_sql(SqlDialect::Postgres).unwrap(), "metric_id ~ '^(?:cpu''.*)$'");
+
+    for column in ["host = host OR 1", "value)", "\"host\"", ""] {
+        let filter = Filter::compare(column, CompareOp::Eq, Literal::Int(1));
+        assert_eq!(filter.to_sql(SqlDialect::DuckDb).unwrap_err().code(), Code::InvalidArgument);
+    }
+    let filter = Filter::compare("value", CompareOp::Lt, Literal::Float(f64::INFINITY));
+    assert_eq!(filter.to_sql(SqlDialect::DuckDb).unwrap_err().code(), Code::InvalidArgument);
+}
+
+#[test]
+fn test_validate_against_schema() {
+    let schema = samples_schema();
+    let invalid = vec![
+        Filter::compare("latency", CompareOp::Gt, Literal::Int(1)),
+        Filter::compare("value", CompareOp::Eq, Literal::String("1".to_string())),
+        Filter::compare("host", CompareOp::Eq, Literal::Bool(true)),
+        Filter::In { column: "id".to_string(), values: vec![Literal::Int(1), Literal::String("2".to_string())] },
+        Filter::matches("value", MatchOp::Eq, "1"),
+        Filter::metric_id_regex("cpu("),
+        Filter::Not(Box::new(Filter::And(vec![Filter::compare("missing", CompareOp::Eq, Literal::Int(0))]))),
+    ];
+    for filter in invalid {
+        let status = filter.validate(&schema).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument, "{:?}", filter);
+    }
+}
+
+#[tokio::test]
+async fn test_aggregate_metrics_with_filter() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend
+        .insert_metrics(vec![
+            metric("cpu.user", 10, 1.0),
+            metric("cpu.system", 20, 4.0),
+            metric("mem.used", 30, 2.0),
+        ])
+        .await
+        .unwrap();
+
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum"),
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+    ];
+    let group_by = GroupBy { columns: vec![], time_column: None };
+    let filter = Filter::metric_id_regex("cpu\\..*");
+    let batch = backend
+        .aggregate_metrics(&aggregates, &group_by, TimeWindow::None, 0, None, Some(&filter), &ResultOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(batch.num_rows(), 1);
+    let sums = batch.column_by_name("sum_value_running_window_sum").unwrap();
+    assert_eq!(sums.as_any().downcast_ref::<Float64Array>().unwrap().value(0), 5.0);
+    let counts = batch.column_by_name("count").unwrap();
+    assert_eq!(counts.as_any().downcast_ref::<Int64Array>().unwrap().value(0), 2);
+
+    let unknown = Filter::compare("host", CompareOp::Eq, Literal::String("a".to_string()));
+    let status = backend
+        .aggregate_metrics(&aggregates, &group_by, TimeWindow::None, 0, None, Some(&unknown), &ResultOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_query_and_delete_with_filter() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_table("samples", &samples_schema()).await.unwrap();
+    backend.insert_into_table("samples", samples_batch()).await.unwrap();
+
+    let filter = Filter::In {
+        column: "host".to_string(),
+        values: vec![Literal::String("a".to_string()), Literal::String("it's".to_string())],
+    };
+    let result = backend.query_table("samples", None, Some(&filter)).await.unwrap();
+    let ids = result.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(ids.values().to_vec(), vec![0, 3, 4]);
+
+    backend.delete_from_table("samples", &filter).await.unwrap();
+    let result = backend.query_table("samples", None, None).await.unwrap();
+    let ids = result.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(ids.values().to_vec(), vec![1, 2]);
+
+    let invalid = Filter::compare("latency", CompareOp::Gt, Literal::Int(1));
+    let status = backend.query_table("samples", None, Some(&invalid)).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
@@ -0,0 +1,182 @@
+use hyprstream_core::aggregation::forecast::{forecast_batch, ForecastModel, ForecastSpec};
+use hyprstream_core::aggregation::smoothing::HoltWintersParams;
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{AggregateMetricsQuery, FlightSqlService, ForecastMetricsQuery, QueryTicket};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_flight::flight_service_server::FlightService;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, Ticket};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use futures::TryStreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Request};
+
+mod common;
+use common::{column, metric};
+
+fn seasonal(t: usize) -> f64 {
+    [0.0, 10.0, 0.0, -10.0][t % 4] + 0.1 * t as f64
+}
+
+fn spec(horizon: usize, model: ForecastModel) -> ForecastSpec {
+    ForecastSpec { horizon, model, confidence: 0.95 }
+}
+
+fn holt_winters() -> ForecastModel {
+    ForecastModel::HoltWinters(HoltWintersParams { alpha: 0.5, beta: 0.1, gamma: 0.5, season: 4 })
+}
+
+#[test]
+fn test_forecast_models() {
+    // An exact line is extrapolated with an empty interval
+    let line: Vec<f64> = (0..10).map(|x| 1.0 + 2.0 * x as f64).collect();
+    let points = spec(3, ForecastModel::LinearTrend).forecast(&line).unwrap();
+    for (step, point) in points.iter().enumerate() {
+        let expected = 1.0 + 2.0 * (10 + step) as f64;
+        assert!((point.value - expected).abs() < 1e-9);
+        assert!((point.upper - point.lower).abs() < 1e-9);
+    }
+
+    // Intervals widen away from the data and with the confidence
+    let noisy: Vec<f64> = (0..20).map(|x| x as f64 + [0.5, -0.5, 0.3, -0.3][x % 4]).collect();
+    let points = spec(5, ForecastModel::LinearTrend).forecast(&noisy).unwrap();
+    assert!(points.windows(2).all(|p| p[1].upper - p[1].lower > p[0].upper - p[0].lower));
+    assert!(points.iter().all(|p| p.lower < p.value && p.value < p.upper));
+    let wide = ForecastSpec { confidence: 0.99, ..spec(5, ForecastModel::LinearTrend) }.forecast(&noisy).unwrap();
+    let ratio = (wide[0].upper - wide[0].lower) / (points[0].upper - points[0].lower);
+    assert!((ratio - 2.575829 / 1.959964).abs() < 1e-5, "{}", ratio);
+
+    // Holt-Winters follows the season, with widening intervals
+    let history: Vec<f64> = (0..40).map(|t| seasonal(t) + [0.2, -0.1, 0.1, -0.3, 0.1][t % 5]).collect();
+    let points = spec(8, holt_winters()).forecast(&history).unwrap();
+    for (step, point) in points.iter().enumerate() {
+        assert!((point.value - seasonal(40 + step)).abs() < 1.0, "{:?} != {}", point, seasonal(40 + step));
+        assert!(point.lower <= seasonal(40 + step) && seasonal(40 + step) <= point.upper);
+    }
+    assert!(points.windows(2).all(|p| p[1].upper - p[1].lower >= p[0].upper - p[0].lower));
+    assert!(points[7].upper - points[7].lower > points[0].upper - points[0].lower);
+
+    assert_eq!(spec(1, ForecastModel::LinearTrend).forecast(&[1.0, 2.0]), None);
+    assert_eq!(spec(1, holt_winters()).forecast(&history[..7]), None);
+
+    let invalid = [
+        spec(0, ForecastModel::LinearTrend),
+        ForecastSpec { confidence: 1.0, ..spec(1, ForecastModel::LinearTrend) },
+        spec(1, ForecastModel::HoltWinters(HoltWintersParams { alpha: 0.5, beta: 0.1, gamma: 0.5, season: 0 })),
+    ];
+    for spec in invalid {
+        assert_eq!(spec.validate().unwrap_err().code(), Code::InvalidArgument);
+    }
+}
+
+/// Ten minutes of `cpu` growing by 2 a minute, and two minutes of `mem`.
+fn history() -> Vec<MetricRecord> {
+    let cpu = (0..10).flat_map(|k| {
+        let value = 10.0 + 2.0 * k as f64;
+        [metric("cpu", 60 * k + 10, value - 1.0), metric("cpu", 60 * k + 40, value + 1.0)]
+    });
+    cpu.chain([metric("mem", 5, 3.0), metric("mem", 65, 4.0)]).collect()
+}
+
+fn query() -> AggregateMetricsQuery {
+    AggregateMetricsQuery {
+        aggregates: vec![
+            AggregateExpr::new(AggregateFunction::Avg, "value_running_window_sum"),
+            AggregateExpr::new(AggregateFunction::Count, "*"),
+        ],
+        group_by: GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(60)),
+        from_timestamp: 0,
+        to_timestamp: None,
+        filter: None,
+        options: ResultOptions::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_forecast_metrics() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend.insert_metrics(history()).await.unwrap();
+
+    let query = query();
+    let forecast = spec(3, ForecastModel::LinearTrend);
+    let batch = backend.forecast_metrics(
+        &query.aggregates,
+        &query.group_by,
+        query.window,
+        query.from_timestamp,
+        query.to_timestamp,
+        None,
+        &query.options,
+        &forecast,
+    ).await.unwrap();
+    let schema = batch.schema();
+    let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+    assert_eq!(names, vec![
+        "metric_id", "window_start", "window_end", "step",
+        "avg_value_running_window_sum", "avg_value_running_window_sum_lower", "avg_value_running_window_sum_upper",
+        "count", "count_lower", "count_upper",
+    ]);
+    let ids: Vec<&str> = column::<StringArray>(&batch, "metric_id").iter().map(Option::unwrap).collect();
+    assert_eq!(ids, vec!["cpu", "cpu", "cpu", "mem", "mem", "mem"]);
+    assert_eq!(column::<Int64Array>(&batch, "window_start").values(), &[600, 660, 720, 120, 180, 240]);
+    assert_eq!(column::<Int64Array>(&batch, "window_end").values(), &[660, 720, 780, 180, 240, 300]);
+    assert_eq!(column::<Int64Array>(&batch, "step").values(), &[1, 2, 3, 1, 2, 3]);
+    let values = column::<Float64Array>(&batch, "avg_value_running_window_sum");
+    let counts = column::<Float64Array>(&batch, "count");
+    for (row, expected) in [30.0, 32.0, 34.0].into_iter().enumerate() {
+        assert!((values.value(row) - expected).abs() < 1e-9);
+        assert!((counts.value(row) - 2.0).abs() < 1e-9);
+    }
+    // Two windows of `mem` are too few for a trend
+    assert_eq!(values.null_count(), 3);
+    assert!(values.is_null(3));
+
+    // Forecasts need windows that follow each other
+    let status = backend.forecast_metrics(
+        &query.aggregates,
+        &query.group_by,
+        TimeWindow::None,
+        0,
+        None,
+        None,
+        &query.options,
+        &forecast,
+    ).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    // Same result through Flight
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let ticket = QueryTicket::ForecastMetrics(ForecastMetricsQuery { query: query.clone(), forecast });
+    let ticket = Ticket { ticket: serde_json::to_vec(&ticket).unwrap().into() };
+    let flight_data: Vec<FlightData> = service.do_get(Request::new(ticket)).await.unwrap().into_inner().try_collect().await.unwrap();
+    assert_eq!(flight_data_to_batches(&flight_data).unwrap(), vec![batch]);
+
+    let ticket = QueryTicket::ForecastMetrics(ForecastMetricsQuery { query, forecast: spec(0, ForecastModel::LinearTrend) });
+    let ticket = Ticket { ticket: serde_json::to_vec(&ticket).unwrap().into() };
+    let status = service.do_get(Request::new(ticket)).await.err().unwrap();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[test]
+fn test_forecast_batch_without_group_by() {
+    let history = RecordBatch::try_from_iter([
+        ("window_start", Arc::new(Int64Array::from(vec![120, 0, 60])) as _),
+        ("window_end", Arc::new(Int64Array::from(vec![180, 60, 120])) as _),
+        ("sum_value", Arc::new(Float64Array::from(vec![Some(5.0), Some(1.0), None])) as _),
+    ]).unwrap();
+    let aggregates = [AggregateExpr::new(AggregateFunction::Sum, "value")];
+    let window = TimeWindow::Fixed(Duration::from_secs(60));
+    let forecast = forecast_batch(&history, &aggregates, window, &spec(2, ForecastModel::LinearTrend)).unwrap();
+    assert_eq!(column::<Int64Array>(&forecast, "window_start").values(), &[180, 240]);
+    // The null window is skipped, leaving two values
+    assert_eq!(column::<Float64Array>(&forecast, "sum_value").null_count(), 2);
+
+    let empty = history.slice(0, 0);
+    assert_eq!(forecast_batch(&empty, &aggregates, window, &spec(2, ForecastModel::LinearTrend)).unwrap().num_rows(), 0);
+}
@@ -0,0 +1,216 @@
+use hyprstream_core::aggregation::calendar::CalendarUnit;
+use hyprstream_core::aggregation::fill::{fill_series, FillStrategy, FilledWindow};
+use hyprstream_core::aggregation::filter::SqlDialect;
+use hyprstream_core::aggregation::{
+    build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, OrderBy, ResultOptions, TimeWindow,
+    MAX_FILL_WINDOWS, ROW_COUNT_COLUMN,
+};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::{aggregate_windows, fill_aggregations};
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+const STRATEGIES: [FillStrategy; 4] =
+    [FillStrategy::Null, FillStrategy::Constant(-1.0), FillStrategy::Previous, FillStrategy::Linear];
+
+fn fixed(secs: u64) -> TimeWindow {
+    TimeWindow::Fixed(Duration::from_secs(secs))
+}
+
+fn samples() -> Vec<MetricRecord> {
+    vec![
+        metric("cpu", 12, 1.0),
+        metric("cpu", 15, 2.0),
+        metric("cpu", 45, 6.0),
+        metric("cpu", 71, 3.0),
+        metric("mem", 30, 10.0),
+    ]
+}
+
+#[test]
+fn test_windows_between() {
+    assert_eq!(fixed(10).windows_between(5, 30).unwrap(), vec![(0, 10), (10, 20), (20, 30)]);
+    assert_eq!(fixed(10).windows_between(30, 30).unwrap(), vec![]);
+
+    let sliding = TimeWindow::Sliding { window: Duration::from_secs(20), slide: Duration::from_secs(10) };
+    assert_eq!(sliding.windows_between(10, 30).unwrap(), vec![(0, 20), (10, 30), (20, 40)]);
+
+    let aligned = TimeWindow::Aligned { size: Duration::from_secs(10), origin: 3 };
+    assert_eq!(aligned.windows_between(0, 20).unwrap(), vec![(-7, 3), (3, 13), (13, 23)]);
+
+    // One calendar day per window, starting at UTC midnight
+    let day = TimeWindow::Calendar { unit: CalendarUnit::Day, timezone: chrono_tz::UTC, offset: Duration::ZERO };
+    assert_eq!(day.windows_between(3600, 2 * 86400).unwrap(), vec![(0, 86400), (86400, 2 * 86400)]);
+
+    let session = TimeWindow::Session { gap: Duration::from_secs(10) };
+    for window in [session, TimeWindow::None] {
+        assert_eq!(window.windows_between(0, 10).unwrap_err().code(), Code::InvalidArgument);
+    }
+    let status = fixed(1).windows_between(0, MAX_FILL_WINDOWS as i64 + 1).unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[test]
+fn test_fill_series() {
+    let windows = fixed(10).windows_between(0, 60).unwrap();
+    let values = HashMap::from([(10, 1.0), (40, 7.0), (20, f64::NAN)]);
+    let fill = |strategy| -> Vec<Option<f64>> {
+        fill_series(&values, &windows, strategy).into_iter().map(|w| w.value).collect()
+    };
+
+    assert_eq!(fill(FillStrategy::Null), vec![None, Some(1.0), None, None, Some(7.0), None]);
+    assert_eq!(
+        fill(FillStrategy::Constant(0.0)),
+        vec![Some(0.0), Some(1.0), Some(0.0), Some(0.0), Some(7.0), Some(0.0)]
+    );
+    assert_eq!(fill(FillStrategy::Previous), vec![None, Some(1.0), Some(1.0), Some(1.0), Some(7.0), Some(7.0)]);
+    assert_eq!(fill(FillStrategy::Linear), vec![None, Some(1.0), Some(3.0), Some(5.0), Some(7.0), None]);
+
+    let filled = fill_series(&values, &windows, FillStrategy::Null);
+    assert_eq!(filled[1], FilledWindow { window_start: 10, window_end: 20, value: Some(1.0) });
+}
+
+#[test]
+fn test_sql_and_in_memory_fill_agree() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE samples (metric_id VARCHAR, timestamp BIGINT, value DOUBLE)").unwrap();
+    for sample in samples() {
+        conn.execute(
+            "INSERT INTO samples VALUES (?, ?, ?)",
+            duckdb::params![sample.metric_id, sample.timestamp, sample.value_running_window_sum],
+        )
+        .unwrap();
+    }
+
+    let aggregates = [AggregateExpr::new(AggregateFunction::Sum, "value")];
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let window = fixed(10);
+    let (from, to) = (0, 80);
+    let aggregations = aggregate_windows(&samples(), window, |_| 1);
+
+    // DuckDB runs the Postgres rendering as well
+    let cases = STRATEGIES.iter().flat_map(|strategy| [SqlDialect::DuckDb, SqlDialect::Postgres].map(|dialect| (*strategy, dialect)));
+    for (strategy, dialect) in cases {
+        let options = ResultOptions {
+            fill: Some(strategy),
+            order_by: vec![OrderBy::asc("metric_id"), OrderBy::asc("window_start")],
+            ..Default::default()
+        };
+        let sql = build_windowed_aggregate_query(
+            "samples", &aggregates, &group_by, window, Some(from), Some(to), None, &options, dialect,
+        )
+        .unwrap();
+        let mut stmt = conn.prepare(&sql).unwrap();
+        let from_sql: Vec<(String, i64, i64, Option<f64>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+
+        let in_memory: Vec<(String, i64, i64, Option<f64>)> =
+            fill_aggregations(&aggregations, AggregateFunction::Sum, window, from, to, strategy)
+                .unwrap()
+                .into_iter()
+                .flat_map(|(metric_id, series)| {
+                    series.into_iter().map(move |w| (metric_id.clone(), w.window_start, w.window_end, w.value))
+                })
+                .collect();
+
+        assert_eq!(from_sql.len(), 16, "{:?} {:?}", strategy, dialect);
+        assert_eq!(from_sql, in_memory, "{:?} {:?}", strategy, dialect);
+    }
+
+    // cpu has values in windows 10, 40 and 70
+    let linear = fill_aggregations(&aggregations, AggregateFunction::Sum, window, from, to, FillStrategy::Linear).unwrap();
+    let cpu: Vec<Option<f64>> = linear[0].1.iter().map(|w| w.value).collect();
+    assert_eq!(cpu, vec![None, Some(3.0), Some(4.0), Some(5.0), Some(6.0), Some(5.0), Some(4.0), Some(3.0)]);
+}
+
+#[test]
+fn test_fill_keeps_values_of_null_groups() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE samples (host VARCHAR, timestamp BIGINT, value DOUBLE);
+        INSERT INTO samples VALUES ('a', 5, 1.0), (NULL, 15, 2.0), (NULL, 16, 3.0)",
+    )
+    .unwrap();
+
+    let aggregates = [AggregateExpr::new(AggregateFunction::Sum, "value")];
+    let group_by = GroupBy { columns: vec!["host".to_string()], time_column: None };
+    let options = ResultOptions {
+        fill: Some(FillStrategy::Null),
+        order_by: vec![OrderBy::asc("host"), OrderBy::asc("window_start")],
+        ..Default::default()
+    };
+    let sql = build_windowed_aggregate_query("samples", &aggregates, &group_by, fixed(10), Some(0), Some(20), None, &options, SqlDialect::DuckDb)
+        .unwrap();
+    let mut stmt = conn.prepare(&sql).unwrap();
+    let rows: Vec<(Option<String>, i64, Option<f64>)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(3)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    assert_eq!(rows, vec![
+        (Some("a".to_string()), 0, Some(1.0)),
+        (Some("a".to_string()), 10, None),
+        (None, 0, None),
+        (None, 10, Some(5.0)),
+    ]);
+}
+
+#[test]
+fn test_fill_counts_no_rows_in_missing_windows() {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE samples (timestamp BIGINT, value DOUBLE);
+        INSERT INTO samples VALUES (5, 1.0), (6, 2.0), (25, 4.0)",
+    )
+    .unwrap();
+
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Sum, "value"),
+        AggregateExpr::new(AggregateFunction::Count, "*").with_alias(ROW_COUNT_COLUMN),
+    ];
+    let no_grouping = GroupBy { columns: vec![], time_column: None };
+    for strategy in STRATEGIES {
+        let options = ResultOptions {
+            fill: Some(strategy),
+            order_by: vec![OrderBy::asc("window_start")],
+            ..Default::default()
+        };
+        let sql = build_windowed_aggregate_query(
+            "samples", &aggregates, &no_grouping, fixed(10), Some(0), Some(40), None, &options, SqlDialect::DuckDb,
+        )
+        .unwrap();
+        let mut stmt = conn.prepare(&sql).unwrap();
+        let counts: Vec<i64> = stmt.query_map([], |row| row.get(ROW_COUNT_COLUMN)).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(counts, vec![2, 0, 1, 0], "{:?}", strategy);
+    }
+}
+
+#[test]
+fn test_fill_requires_bounded_windows() {
+    let no_grouping = GroupBy { columns: vec![], time_column: None };
+    build(fixed(10), Some(100), &no_grouping).unwrap();
+    let by_time = GroupBy { columns: vec![], time_column: Some("timestamp".to_string()) };
+    let invalid = [
+        build(fixed(10), None, &no_grouping),
+        build(TimeWindow::None, Some(100), &no_grouping),
+        build(TimeWindow::Session { gap: Duration::from_secs(5) }, Some(100), &no_grouping),
+        build(fixed(10), Some(100), &by_time),
+    ];
+    for result in invalid {
+        assert_eq!(result.unwrap_err(), Code::InvalidArgument);
+    }
+}
+
+fn build(window: TimeWindow, to: Option<i64>, group_by: &GroupBy) -> Result<String, Code> {
+    let aggregates = [AggregateExpr::new(AggregateFunction::Count, "*")];
+    let options = ResultOptions { fill: Some(FillStrategy::Previous), ..Default::default() };
+    build_windowed_aggregate_query("samples", &aggregates, group_by, window, Some(0), to, None, &options, SqlDialect::DuckDb)
+        .map_err(|status| status.code())
+}
@@ -0,0 +1,251 @@
+use hyprstream_core::config::IdempotencyConfig;
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::FlightSqlService;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::idempotency::{
+    ack_batch, ack_schema, producer_acks, IdempotencyCache, IdempotencyKey, KeyState, ACK_TABLE,
+};
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("value", DataType::Float64, false),
+    ])
+}
+
+fn samples(hosts: Vec<&str>) -> RecordBatch {
+    let values: Vec<f64> = (0..hosts.len()).map(|i| i as f64).collect();
+    RecordBatch::try_new(
+        Arc::new(schema()),
+        vec![
+            Arc::new(StringArray::from(hosts)),
+            Arc::new(Float64Array::from(values)),
+        ],
+    )
+    .unwrap()
+}
+
+fn key(producer_id: &str, sequence: u64) -> IdempotencyKey {
+    IdempotencyKey { producer_id: producer_id.to_string(), sequence }
+}
+
+async fn setup() -> Arc<StorageBackendType> {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend.create_table("samples", &schema()).await.unwrap();
+    backend
+}
+
+fn service(backend: &Arc<StorageBackendType>, window: u64) -> FlightSqlService {
+    let model_storage = Box::new(TimeSeriesModelStorage::new(backend.clone()));
+    FlightSqlService::new(backend.clone(), model_storage)
+        .with_idempotency(IdempotencyConfig { window, ..Default::default() })
+}
+
+#[test]
+fn test_producer_state_window() {
+    let cache = IdempotencyCache::new(IdempotencyConfig { window: 3, ..Default::default() });
+    let empty = RecordBatch::new_empty(Arc::new(ack_schema()));
+    let mut state = cache.load_producer("p", &empty).unwrap();
+
+    assert_eq!(state.check("p", 1).unwrap(), KeyState::New);
+    for sequence in 1..=5 {
+        state.record(sequence, format!("ack-{}", sequence));
+    }
+
+    assert_eq!(state.check("p", 5).unwrap(), KeyState::Applied("ack-5".to_string()));
+    assert_eq!(state.check("p", 3).unwrap(), KeyState::Applied("ack-3".to_string()));
+    assert_eq!(state.check("p", 6).unwrap(), KeyState::New);
+    let err = state.check("p", 2).unwrap_err();
+    assert_eq!(err.code(), tonic::Code::AlreadyExists);
+}
+
+#[test]
+fn test_load_producer_ignores_other_producers() {
+    let cache = IdempotencyCache::default();
+    let rows = [ack_batch(&key("a", 7), "a7").unwrap(), ack_batch(&key("b", 1), "b1").unwrap()];
+    let persisted = arrow::compute::concat_batches(&rows[0].schema(), &rows).unwrap();
+
+    let state = cache.load_producer("a", &persisted).unwrap();
+    assert_eq!(state.check("a", 7).unwrap(), KeyState::Applied("a7".to_string()));
+    assert_eq!(state.check("a", 1).unwrap(), KeyState::New);
+}
+
+#[tokio::test]
+async fn test_idle_producers_are_evicted_and_reloaded() {
+    let cache = IdempotencyCache::new(IdempotencyConfig { window: 16, idle_secs: 0.2 });
+    let persisted = ack_batch(&key("a", 1), "a1").unwrap();
+
+    let slot = cache.producer("a").unwrap();
+    *slot.lock().await = Some(cache.load_producer("a", &persisted).unwrap());
+    drop(slot);
+    let held = cache.producer("b").unwrap();
+    assert_eq!(cache.tracked_producers(), 2);
+
+    // `a` is idle, `b` is still held by a batch
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    cache.evict_idle();
+    assert_eq!(cache.tracked_producers(), 1);
+    drop(held);
+
+    // The evicted producer starts unloaded and is rebuilt from its acks
+    let slot = cache.producer("a").unwrap();
+    assert!(slot.lock().await.is_none());
+    let state = cache.load_producer("a", &persisted).unwrap();
+    assert_eq!(state.check("a", 1).unwrap(), KeyState::Applied("a1".to_string()));
+}
+
+#[tokio::test]
+async fn test_retry_after_eviction_is_deduplicated() {
+    let backend = setup().await;
+    let model_storage = Box::new(TimeSeriesModelStorage::new(backend.clone()));
+    let service = FlightSqlService::new(backend.clone(), model_storage)
+        .with_idempotency(IdempotencyConfig { window: 16, idle_secs: 0.2 });
+
+    let first = service
+        .ingest_batch_once("samples", samples(vec!["a", "b"]), "test", &key("p1", 1))
+        .await
+        .unwrap();
+
+    // Another producer's batch evicts the idle `p1`
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    service
+        .ingest_batch_once("samples", samples(vec!["c"]), "test", &key("p2", 1))
+        .await
+        .unwrap();
+
+    let retry = service
+        .ingest_batch_once("samples", samples(vec!["x", "y", "z"]), "test", &key("p1", 1))
+        .await
+        .unwrap();
+    assert_eq!(retry.rows, first.rows);
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 3);
+}
+
+#[tokio::test]
+async fn test_retry_returns_original_ack() {
+    let backend = setup().await;
+    let service = service(&backend, 16);
+
+    let first = service
+        .ingest_batch_once("samples", samples(vec!["a", "b"]), "test", &key("p1", 1))
+        .await
+        .unwrap();
+    assert_eq!(first.rows, 2);
+    assert_eq!(first.sequence, Some(1));
+
+    // The retry carries different data, but must not be applied
+    let retry = service
+        .ingest_batch_once("samples", samples(vec!["x", "y", "z"]), "test", &key("p1", 1))
+        .await
+        .unwrap();
+    assert_eq!(retry.rows, 2);
+    assert_eq!(retry.producer_id.as_deref(), Some("p1"));
+
+    service
+        .ingest_batch_once("samples", samples(vec!["c"]), "test", &key("p1", 2))
+        .await
+        .unwrap();
+    service
+        .ingest_batch_once("samples", samples(vec!["d"]), "test", &key("p2", 1))
+        .await
+        .unwrap();
+
+    let stored = backend.query_table("samples", None, None).await.unwrap();
+    assert_eq!(stored.num_rows(), 4);
+}
+
+#[tokio::test]
+async fn test_acks_survive_restart() {
+    let backend = setup().await;
+    service(&backend, 16)
+        .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p1", 10))
+        .await
+        .unwrap();
+
+    // A new service instance on the same storage loads the persisted acks
+    let restarted = service(&backend, 16);
+    let retry = restarted
+        .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p1", 10))
+        .await
+        .unwrap();
+    assert_eq!(retry.sequence, Some(10));
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 1);
+}
+
+#[tokio::test]
+async fn test_sequence_outside_window_rejected() {
+    let backend = setup().await;
+    let service = service(&backend, 2);
+
+    for sequence in [1, 2, 3] {
+        service
+            .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p1", sequence))
+            .await
+            .unwrap();
+    }
+    let err = service
+        .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p1", 1))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::AlreadyExists);
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 3);
+}
+
+#[tokio::test]
+async fn test_batches_are_written_atomically() {
+    let backend = setup().await;
+    // Registered, but missing from the database, so its insert fails
+    backend.table_manager().create_table("missing".to_string(), schema()).await.unwrap();
+
+    let writes = vec![
+        ("samples".to_string(), samples(vec!["a", "b"])),
+        ("missing".to_string(), samples(vec!["c"])),
+    ];
+    assert!(backend.insert_into_tables(writes).await.is_err());
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 0);
+
+    let writes = vec![
+        ("samples".to_string(), samples(vec!["a", "b"])),
+        (ACK_TABLE.to_string(), ack_batch(&key("p1", 1), "ack").unwrap()),
+    ];
+    backend.create_table(ACK_TABLE, &ack_schema()).await.unwrap();
+    backend.insert_into_tables(writes).await.unwrap();
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 2);
+    assert_eq!(backend.query_table(ACK_TABLE, None, None).await.unwrap().num_rows(), 1);
+}
+
+#[tokio::test]
+async fn test_expired_acks_are_deleted() {
+    let backend = setup().await;
+    let producer = service(&backend, 2);
+
+    for sequence in 1..=7 {
+        producer
+            .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p1", sequence))
+            .await
+            .unwrap();
+    }
+    producer
+        .ingest_batch_once("samples", samples(vec!["a"]), "test", &key("p2", 1))
+        .await
+        .unwrap();
+
+    // At most two windows of acks are kept per producer
+    let acks = backend.query_table(ACK_TABLE, None, Some(&producer_acks("p1"))).await.unwrap();
+    let sequences = acks.column_by_name("sequence").unwrap().as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(sequences.values(), &[5, 6, 7]);
+    assert_eq!(backend.query_table(ACK_TABLE, None, Some(&producer_acks("p2"))).await.unwrap().num_rows(), 1);
+
+    // The remembered acks are still answered after a restart
+    let retry = service(&backend, 2)
+        .ingest_batch_once("samples", samples(vec!["x"]), "test", &key("p1", 7))
+        .await
+        .unwrap();
+    assert_eq!(retry.sequence, Some(7));
+    assert_eq!(backend.query_table("samples", None, None).await.unwrap().num_rows(), 8);
+}
@@ -0,0 +1,272 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal, SqlDialect};
+use hyprstream_core::aggregation::{
+    build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, ResultOptions, TimeWindow,
+};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::materialized::{state_table, STALE_KEY, WATERMARK_KEY};
+use hyprstream_core::storage::table_manager::AggregationView;
+use hyprstream_core::storage::StorageBackend;
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Code;
+
+type Row = (String, i64, i64, Option<i64>, Option<f64>, Option<f64>, Option<i64>);
+
+fn requests_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("latency", DataType::Float64, true),
+    ])
+}
+
+fn requests(rows: &[(&str, i64, Option<f64>)]) -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(requests_schema()),
+        vec![
+            Arc::new(StringArray::from(rows.iter().map(|r| r.0).collect::<Vec<_>>())),
+            Arc::new(Int64Array::from(rows.iter().map(|r| r.1).collect::<Vec<_>>())),
+            Arc::new(Float64Array::from(rows.iter().map(|r| r.2).collect::<Vec<_>>())),
+        ],
+    )
+    .unwrap()
+}
+
+fn first_batch() -> Vec<(&'static str, i64, Option<f64>)> {
+    vec![("a", 0, Some(10.0)), ("a", 30, Some(20.0)), ("b", 10, Some(5.0)), ("b", 70, None)]
+}
+
+fn second_batch() -> Vec<(&'static str, i64, Option<f64>)> {
+    vec![("a", 50, Some(30.0)), ("b", 75, Some(7.0)), ("c", 130, Some(1.0)), ("c", 200, Some(-4.0))]
+}
+
+fn view(materialized: bool) -> AggregationView {
+    AggregationView {
+        source_table: "requests".to_string(),
+        aggregates: vec![
+            AggregateExpr::new(AggregateFunction::Count, "*"),
+            AggregateExpr::new(AggregateFunction::Sum, "latency"),
+            AggregateExpr::new(AggregateFunction::Max, "latency"),
+            AggregateExpr::new(AggregateFunction::Count, "latency").with_alias("samples"),
+        ],
+        group_by: GroupBy { columns: vec!["host".to_string()], time_column: None },
+        window: TimeWindow::Fixed(Duration::from_secs(60)),
+        filter: Some(Filter::compare("timestamp", CompareOp::Lt, Literal::Int(180))),
+        materialized,
+    }
+}
+
+async fn backend() -> DuckDbBackend {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_table("requests", &requests_schema()).await.unwrap();
+    backend
+}
+
+fn view_rows(batch: &RecordBatch) -> Vec<Row> {
+    let strings = |i: usize| batch.column(i).as_any().downcast_ref::<StringArray>().unwrap().clone();
+    let ints = |i: usize| batch.column(i).as_any().downcast_ref::<Int64Array>().unwrap().clone();
+    let floats = |i: usize| batch.column(i).as_any().downcast_ref::<Float64Array>().unwrap().clone();
+    let (hosts, starts, ends, counts, sums, maxs, samples) =
+        (strings(0), ints(1), ints(2), ints(3), floats(4), floats(5), ints(6));
+    let mut rows: Vec<Row> = (0..batch.num_rows())
+        .map(|i| {
+            (
+                hosts.value(i).to_string(),
+                starts.value(i),
+                ends.value(i),
+                counts.is_valid(i).then(|| counts.value(i)),
+                sums.is_valid(i).then(|| sums.value(i)),
+                maxs.is_valid(i).then(|| maxs.value(i)),
+                samples.is_valid(i).then(|| samples.value(i)),
+            )
+        })
+        .collect();
+    rows.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    rows
+}
+
+/// Evaluates the plain SQL view over `rows` in a separate database.
+fn expected_rows(rows: &[(&str, i64, Option<f64>)]) -> Vec<Row> {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE requests (host VARCHAR, timestamp BIGINT, latency DOUBLE)").unwrap();
+    for (host, timestamp, latency) in rows {
+        conn.execute("INSERT INTO requests VALUES (?, ?, ?)", duckdb::params![host, timestamp, latency]).unwrap();
+    }
+    let view = view(false);
+    let sql = build_windowed_aggregate_query(
+        "requests",
+        &view.aggregates,
+        &view.group_by,
+        view.window,
+        None,
+        None,
+        view.filter.as_ref(),
+        &ResultOptions::default(),
+        SqlDialect::DuckDb,
+    )
+    .unwrap();
+    let mut stmt = conn.prepare(&format!("{} ORDER BY host, window_start", sql)).unwrap();
+    stmt.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+    })
+    .unwrap()
+    .map(|row| row.unwrap())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_incremental_updates_match_full_aggregation() {
+    let backend = backend().await;
+    backend.insert_into_table("requests", requests(&first_batch())).await.unwrap();
+    backend.create_aggregation_view(&view(true)).await.unwrap();
+
+    let initial = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(view_rows(&initial), expected_rows(&first_batch()));
+
+    // The second batch touches existing windows, adds new ones and has a
+    // row excluded by the view filter
+    backend.insert_into_table("requests", requests(&second_batch())).await.unwrap();
+    let all: Vec<_> = first_batch().into_iter().chain(second_batch()).collect();
+    let updated = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(view_rows(&updated), expected_rows(&all));
+    assert!(view_rows(&updated).contains(&("b".to_string(), 60, 120, Some(2), Some(7.0), Some(7.0), Some(1))));
+
+    let metadata = updated.schema().metadata().clone();
+    assert_eq!(metadata[STALE_KEY], "false");
+    assert_eq!(metadata[WATERMARK_KEY], "130");
+
+    // The state table holds one row per group, column and window
+    let state = backend.query_table(&state_table("agg_view_requests"), None, None).await.unwrap();
+    assert_eq!(state.num_rows(), 2 * updated.num_rows());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_updates_are_serialized() {
+    let backend = Arc::new(backend().await);
+    backend.create_aggregation_view(&view(true)).await.unwrap();
+
+    // Every batch touches the same window and its state
+    let rows: Vec<(&str, i64, Option<f64>)> = (0..8).map(|i| ("a", i, Some(i as f64))).collect();
+    let inserts = rows.iter().map(|row| {
+        let backend = backend.clone();
+        let batch = requests(&[*row]);
+        tokio::spawn(async move { backend.insert_into_table("requests", batch).await })
+    });
+    for insert in futures::future::join_all(inserts).await {
+        insert.unwrap().unwrap();
+    }
+
+    let updated = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(view_rows(&updated), expected_rows(&rows));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_refresh_during_ingest() {
+    let backend = Arc::new(backend().await);
+    backend.create_aggregation_view(&view(true)).await.unwrap();
+
+    // Each batch is either read by a refresh or applied after it, not both
+    let rows: Vec<(&str, i64, Option<f64>)> = (0..8).map(|i| ("a", i, Some(i as f64))).collect();
+    let inserts = rows.iter().map(|row| {
+        let backend = backend.clone();
+        let batch = requests(&[*row]);
+        tokio::spawn(async move { backend.insert_into_table("requests", batch).await })
+    });
+    let refreshes = (0..4).map(|_| {
+        let backend = backend.clone();
+        tokio::spawn(async move { backend.refresh_aggregation_view("agg_view_requests").await })
+    });
+    let (inserts, refreshes) = futures::future::join(
+        futures::future::join_all(inserts),
+        futures::future::join_all(refreshes),
+    ).await;
+    for result in inserts.into_iter().chain(refreshes) {
+        result.unwrap().unwrap();
+    }
+
+    let updated = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(updated.schema().metadata()[STALE_KEY], "false");
+    assert_eq!(view_rows(&updated), expected_rows(&rows));
+}
+
+#[tokio::test]
+async fn test_refresh_after_delete() {
+    let backend = backend().await;
+    backend.insert_into_table("requests", requests(&first_batch())).await.unwrap();
+    backend.insert_into_table("requests", requests(&second_batch())).await.unwrap();
+    backend.create_aggregation_view(&view(true)).await.unwrap();
+
+    let a = Filter::compare("host", CompareOp::Eq, Literal::String("a".to_string()));
+    backend.delete_from_table("requests", &a).await.unwrap();
+    let stale = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(stale.schema().metadata()[STALE_KEY], "true");
+    assert!(view_rows(&stale).iter().any(|row| row.0 == "a"));
+
+    backend.refresh_aggregation_view("agg_view_requests").await.unwrap();
+    let refreshed = backend.query_aggregation_view("agg_view_requests").await.unwrap();
+    assert_eq!(refreshed.schema().metadata()[STALE_KEY], "false");
+    let remaining: Vec<_> = first_batch().into_iter().chain(second_batch()).filter(|r| r.0 != "a").collect();
+    assert_eq!(view_rows(&refreshed), expected_rows(&remaining));
+
+    backend.drop_aggregation_view("agg_view_requests").await.unwrap();
+    let tables = backend.table_manager().list_tables().await;
+    assert!(!tables.iter().any(|table| table.starts_with("agg_view_requests")));
+}
+
+#[tokio::test]
+async fn test_sliding_view_over_metrics() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    let view = AggregationView {
+        source_table: "metrics".to_string(),
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Avg, "value_running_window_sum")],
+        group_by: GroupBy { columns: vec!["metric_id".to_string()], time_column: None },
+        window: TimeWindow::Sliding { window: Duration::from_secs(20), slide: Duration::from_secs(10) },
+        filter: None,
+        materialized: true,
+    };
+    backend.create_aggregation_view(&view).await.unwrap();
+
+    let metric = |timestamp: i64, value: f64| MetricRecord {
+        metric_id: "cpu".to_string(),
+        timestamp,
+        value_running_window_sum: value,
+        value_running_window_avg: value,
+        value_running_window_count: 1,
+    };
+    backend.insert_metrics(vec![metric(5, 1.0), metric(15, 3.0)]).await.unwrap();
+    backend.insert_metrics(vec![metric(12, 5.0)]).await.unwrap();
+
+    let batch = backend.query_aggregation_view("agg_view_metrics").await.unwrap();
+    let starts = batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+    let avgs = batch.column(3).as_any().downcast_ref::<Float64Array>().unwrap();
+    let mut windows: Vec<(i64, f64)> = (0..batch.num_rows()).map(|i| (starts.value(i), avgs.value(i))).collect();
+    windows.sort_by_key(|w| w.0);
+    assert_eq!(windows, vec![(-10, 1.0), (0, 3.0), (10, 4.0)]);
+}
+
+#[tokio::test]
+async fn test_materialized_view_validation() {
+    let backend = backend().await;
+    let mut session = view(true);
+    session.window = TimeWindow::Session { gap: Duration::from_secs(30) };
+    let mut by_time = view(true);
+    by_time.group_by.time_column = Some("timestamp".to_string());
+    for invalid in [session, by_time] {
+        let status = backend.create_aggregation_view(&invalid).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    backend.create_aggregation_view(&view(true)).await.unwrap();
+    let status = backend.create_aggregation_view(&view(true)).await.unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
+
+    backend.drop_aggregation_view("agg_view_requests").await.unwrap();
+    backend.create_aggregation_view(&view(false)).await.unwrap();
+    let status = backend.refresh_aggregation_view("agg_view_requests").await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+}
@@ -0,0 +1,331 @@
+use hyprstream_core::aggregation::hll::HyperLogLog;
+use hyprstream_core::aggregation::operator::AggregateOperator;
+use hyprstream_core::aggregation::partial::{
+    partial_schema, PartialCombiner, PartialState, PARTIAL_STATE_VERSION, PARTIAL_STATE_VERSION_KEY,
+};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{ExchangeAggregation, FlightSqlService};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::flight_service_client::FlightServiceClient;
+use arrow_flight::flight_service_server::FlightServiceServer;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, FlightDescriptor};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Code;
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("value", DataType::Float64, true),
+        Field::new("path", DataType::Utf8, true),
+    ])
+}
+
+/// Rows of three hosts every 7 seconds, with some null values.
+fn samples(from: usize, to: usize) -> RecordBatch {
+    let rows = from..to;
+    let hosts: Vec<&str> = rows.clone().map(|i| ["a", "b", "c"][i % 3]).collect();
+    let timestamps: Vec<i64> = rows.clone().map(|i| i as i64 * 7).collect();
+    let values: Vec<Option<f64>> = rows.clone()
+        .map(|i| (i % 5 != 0).then(|| ((i * 37) % 100) as f64 / 3.0 - 10.0))
+        .collect();
+    let paths: Vec<Option<String>> = rows.map(|i| (i % 4 != 0).then(|| format!("/{}", i % 9))).collect();
+    RecordBatch::try_new(
+        Arc::new(schema()),
+        vec![
+            Arc::new(StringArray::from(hosts)),
+            Arc::new(Int64Array::from(timestamps)),
+            Arc::new(Float64Array::from(values)),
+            Arc::new(StringArray::from(paths)),
+        ],
+    )
+    .unwrap()
+}
+
+fn aggregates() -> Vec<AggregateExpr> {
+    let value = |function| AggregateExpr::new(function, "value");
+    vec![
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+        value(AggregateFunction::Count),
+        value(AggregateFunction::Sum),
+        value(AggregateFunction::Avg),
+        value(AggregateFunction::Min),
+        value(AggregateFunction::Max),
+        value(AggregateFunction::Quantile(0.5)),
+        value(AggregateFunction::StdDev),
+        value(AggregateFunction::Variance),
+        value(AggregateFunction::First),
+        value(AggregateFunction::Last),
+        value(AggregateFunction::Rate),
+        value(AggregateFunction::Delta),
+        value(AggregateFunction::CountDistinct),
+        AggregateExpr::new(AggregateFunction::CountDistinct, "path"),
+    ]
+}
+
+fn by_host() -> GroupBy {
+    GroupBy { columns: vec!["host".to_string()], time_column: None }
+}
+
+fn window() -> TimeWindow {
+    TimeWindow::Fixed(Duration::from_secs(100))
+}
+
+fn operator() -> AggregateOperator {
+    AggregateOperator::try_new(Arc::new(schema()), &aggregates(), &by_host(), window()).unwrap()
+}
+
+/// Pre-aggregates the rows `[from, to)` like an edge node.
+fn edge(from: usize, to: usize) -> RecordBatch {
+    let mut operator = operator();
+    operator.push(&samples(from, to)).unwrap();
+    operator.finish_partial().unwrap()
+}
+
+fn floats(batch: &RecordBatch, i: usize) -> Vec<Option<f64>> {
+    let column = arrow::compute::cast(batch.column(i), &DataType::Float64).unwrap();
+    column.as_any().downcast_ref::<Float64Array>().unwrap().iter().collect()
+}
+
+/// Returns the exact results over the rows `[0, to)` and those evaluated
+/// from the partial state of a single operator over them.
+fn references(to: usize) -> (RecordBatch, RecordBatch) {
+    let mut operator = operator();
+    operator.push(&samples(0, to)).unwrap();
+    let exact = operator.finish().unwrap();
+    let mut combiner = PartialCombiner::try_new(&aggregates(), &by_host(), window()).unwrap();
+    combiner.push(&edge(0, to)).unwrap();
+    (exact, combiner.finish().unwrap())
+}
+
+/// Compares combined results with the exact results over all rows, and
+/// sketch based functions with the sketches of a single operator, as
+/// sketches merge losslessly.
+fn assert_combined_matches(combined: &RecordBatch, (exact, sketched): &(RecordBatch, RecordBatch)) {
+    assert_eq!(combined.schema(), exact.schema());
+    assert_eq!(combined.num_rows(), exact.num_rows());
+    for i in 0..3 {
+        assert_eq!(combined.column(i), exact.column(i));
+    }
+    for (i, aggregate) in aggregates().iter().enumerate() {
+        let column = 3 + i;
+        let expected = match aggregate.function {
+            AggregateFunction::Quantile(_) | AggregateFunction::CountDistinct => floats(sketched, column),
+            _ => floats(exact, column),
+        };
+        for (actual, expected) in floats(combined, column).into_iter().zip(expected) {
+            let (Some(actual), Some(expected)) = (actual, expected) else {
+                assert_eq!(actual, expected, "{}", aggregate.output_name());
+                continue;
+            };
+            assert!(
+                (actual - expected).abs() <= 1e-9 * expected.abs().max(1.0),
+                "{}: {} != {}",
+                aggregate.output_name(),
+                actual,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_combined_partial_state_matches_single_aggregation() {
+    let references = references(150);
+
+    // Edges see overlapping windows and groups
+    let edges = [edge(0, 40), edge(40, 95), edge(95, 150)];
+    let expected_schema = partial_schema(&aggregates(), &by_host(), true, &schema()).unwrap();
+    assert_eq!(edges[0].schema().as_ref(), &expected_schema);
+    assert_eq!(edges[0].schema().metadata()[PARTIAL_STATE_VERSION_KEY], PARTIAL_STATE_VERSION.to_string());
+
+    let mut combiner = PartialCombiner::try_new(&aggregates(), &by_host(), window()).unwrap();
+    for batch in &edges {
+        combiner.push(batch).unwrap();
+    }
+    assert_combined_matches(&combiner.finish().unwrap(), &references);
+
+    // Combined state can be combined again further upstream
+    let mut left = PartialCombiner::try_new(&aggregates(), &by_host(), window()).unwrap();
+    left.push(&edges[0]).unwrap();
+    left.push(&edges[1]).unwrap();
+    let mut root = PartialCombiner::try_new(&aggregates(), &by_host(), window()).unwrap();
+    root.push(&left.finish_partial().unwrap()).unwrap();
+    root.push(&edges[2]).unwrap();
+    assert_combined_matches(&root.finish().unwrap(), &references);
+}
+
+/// Partial state of values observed at their index as timestamp.
+fn state(values: &[f64]) -> PartialState {
+    let mut state = PartialState::empty();
+    for (timestamp, value) in values.iter().enumerate() {
+        state.0.update(timestamp as i64, *value, 1);
+    }
+    state.0.window_start = 0;
+    state.0.window_end = values.len() as i64 - 1;
+    state
+}
+
+#[test]
+fn test_partial_state_serialization() {
+    let mut merged = state(&[1.0, 2.0]);
+    merged.merge(&state(&[6.0])).unwrap();
+    assert_eq!(merged.evaluate(AggregateFunction::Variance).unwrap(), Some(7.0));
+    assert_eq!(merged.evaluate(AggregateFunction::Count).unwrap(), Some(3.0));
+    assert_eq!(merged.evaluate(AggregateFunction::Rate).unwrap(), Some(1.0));
+
+    let bytes = merged.to_bytes().unwrap();
+    assert_eq!(bytes[0], PARTIAL_STATE_VERSION);
+    assert_eq!(PartialState::from_bytes(&bytes).unwrap(), merged);
+
+    let mut newer = bytes.clone();
+    newer[0] = PARTIAL_STATE_VERSION + 1;
+    for invalid in [&newer[..], &[][..], &bytes[..3]] {
+        assert_eq!(PartialState::from_bytes(invalid).unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    // Sketches are validated like stored sketches: the last bytes are the
+    // precision, register count and registers of the distinct sketch
+    let mut distinct = state(&[1.0]);
+    distinct.0.distinct_sketch = Some(HyperLogLog::with_precision(4));
+    let mut bytes = distinct.to_bytes().unwrap();
+    assert_eq!(PartialState::from_bytes(&bytes).unwrap(), distinct);
+    let precision = bytes.len() - 16 - 8 - 1;
+    bytes[precision] = 5;
+    assert_eq!(PartialState::from_bytes(&bytes).unwrap_err().code(), Code::InvalidArgument);
+
+    // Empty state evaluates like SQL over no rows
+    for function in [AggregateFunction::Sum, AggregateFunction::Rate, AggregateFunction::Quantile(0.9)] {
+        assert_eq!(PartialState::empty().evaluate(function).unwrap(), None);
+    }
+    for function in [AggregateFunction::Count, AggregateFunction::CountDistinct] {
+        assert_eq!(PartialState::empty().evaluate(function).unwrap(), Some(0.0));
+    }
+
+    let status = merged.evaluate(AggregateFunction::Quantile(1.5)).unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    // The state of other functions has no sketches
+    let status = merged.evaluate(AggregateFunction::Quantile(0.5)).unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+}
+
+#[test]
+fn test_partial_state_format() {
+    // Version, window bounds, sums, counts, extremes, first and last values,
+    // then the optional sketches
+    let mut expected = vec![PARTIAL_STATE_VERSION];
+    expected.extend(0i64.to_le_bytes());
+    expected.extend(1i64.to_le_bytes());
+    expected.extend(3.0f64.to_le_bytes());
+    expected.extend(2i64.to_le_bytes());
+    expected.extend(1.0f64.to_le_bytes());
+    expected.extend(2.0f64.to_le_bytes());
+    expected.extend(2i64.to_le_bytes());
+    expected.extend(0.5f64.to_le_bytes());
+    expected.extend(0i64.to_le_bytes());
+    expected.extend(1.0f64.to_le_bytes());
+    expected.extend(1i64.to_le_bytes());
+    expected.extend(2.0f64.to_le_bytes());
+    let mut without_sketches = expected.clone();
+    without_sketches.extend([0, 0]);
+    assert_eq!(state(&[1.0, 2.0]).to_bytes().unwrap(), without_sketches);
+
+    // Sketches are length prefixed in their own versioned format
+    let mut distinct = state(&[1.0, 2.0]);
+    distinct.0.distinct_sketch = Some(HyperLogLog::with_precision(4));
+    let mut with_distinct = expected;
+    with_distinct.push(0);
+    with_distinct.push(1);
+    with_distinct.extend(26u64.to_le_bytes());
+    with_distinct.extend([1, 4]);
+    with_distinct.extend(16u64.to_le_bytes());
+    with_distinct.extend([0; 16]);
+    assert_eq!(distinct.to_bytes().unwrap(), with_distinct);
+
+    // The metric id is not part of the state
+    let mut named = state(&[1.0, 2.0]);
+    named.0.metric_id = "cpu".to_string();
+    assert_eq!(named.to_bytes().unwrap(), without_sketches);
+}
+
+#[test]
+fn test_combiner_validation() {
+    let session = TimeWindow::Session { gap: Duration::from_secs(10) };
+    let status = PartialCombiner::try_new(&aggregates(), &by_host(), session).err().unwrap();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let mut operator = AggregateOperator::try_new(Arc::new(schema()), &aggregates(), &by_host(), session).unwrap();
+    operator.push(&samples(0, 10)).unwrap();
+    assert_eq!(operator.finish_partial().unwrap_err().code(), Code::InvalidArgument);
+
+    let mut combiner = PartialCombiner::try_new(&aggregates(), &by_host(), window()).unwrap();
+    let partial = edge(0, 10);
+    let unversioned = RecordBatch::try_new(
+        Arc::new(partial.schema().as_ref().clone().with_metadata(Default::default())),
+        partial.columns().to_vec(),
+    )
+    .unwrap();
+    let raw = samples(0, 10);
+    let fewer = partial.project(&[0, 1, 2, 3]).unwrap();
+    for invalid in [unversioned, raw, fewer] {
+        assert_eq!(combiner.push(&invalid).unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    // A grouped combiner without input does not know its key types
+    assert_eq!(combiner.finish().unwrap_err().code(), Code::FailedPrecondition);
+}
+
+async fn exchange(address: &str, aggregation: &ExchangeAggregation, batches: Vec<RecordBatch>) -> RecordBatch {
+    let descriptor = FlightDescriptor::new_cmd(serde_json::to_vec(aggregation).unwrap());
+    let flight_data: Vec<FlightData> = FlightDataEncoderBuilder::new()
+        .with_flight_descriptor(Some(descriptor))
+        .build(futures::stream::iter(batches.into_iter().map(Ok)))
+        .map(|data| data.unwrap())
+        .collect()
+        .await;
+    let mut client = FlightServiceClient::connect(address.to_string()).await.unwrap();
+    let response = client.do_exchange(futures::stream::iter(flight_data)).await.unwrap();
+    let flight_data: Vec<FlightData> = response.into_inner().try_collect().await.unwrap();
+    flight_data_to_batches(&flight_data).unwrap().remove(0)
+}
+
+#[tokio::test]
+async fn test_edge_and_upstream_over_flight_exchange() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(FlightServiceServer::new(service))
+            .serve_with_incoming(incoming),
+    );
+
+    let mut aggregation = ExchangeAggregation {
+        aggregates: aggregates(),
+        group_by: by_host(),
+        window: window(),
+        from_timestamp: None,
+        to_timestamp: None,
+        filter: None,
+        partial: true,
+        combine: false,
+    };
+    let left = exchange(&address, &aggregation, vec![samples(0, 70)]).await;
+    let right = exchange(&address, &aggregation, vec![samples(70, 150)]).await;
+    assert_eq!(left, edge(0, 70));
+
+    aggregation.partial = false;
+    aggregation.combine = true;
+    let combined = exchange(&address, &aggregation, vec![left, right]).await;
+    assert_combined_matches(&combined, &references(150));
+}
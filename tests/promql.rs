@@ -0,0 +1,297 @@
+use hyprstream_core::aggregation::filter::MatchOp;
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::promql::api::{serve, MAX_BODY_BYTES};
+use hyprstream_core::promql::eval::{evaluate, EvalRange, QueryValue, Series};
+use hyprstream_core::promql::parser::{parse, AggregateOp, Expr, Grouping, LabelMatcher, RangeFunction, Selector};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+#[test]
+fn test_parse() {
+    let expr = parse(r#"sum by (job) (rate(http_requests{job=~"api.*", code!="500"}[5m]))"#).unwrap();
+    let selector = Selector {
+        name: Some("http_requests".to_string()),
+        matchers: vec![
+            LabelMatcher { label: "job".to_string(), op: MatchOp::Regex, value: "api.*".to_string() },
+            LabelMatcher { label: "code".to_string(), op: MatchOp::Ne, value: "500".to_string() },
+        ],
+    };
+    let rate = Expr::RangeFunction { function: RangeFunction::Rate, selector, range: 300 };
+    let sum = Expr::Aggregate {
+        op: AggregateOp::Sum,
+        grouping: Some(Grouping::By(vec!["job".to_string()])),
+        expr: Box::new(rate),
+    };
+    assert_eq!(expr, sum);
+    // The grouping may follow the argument
+    assert_eq!(parse(r#"sum(rate(http_requests{job=~"api.*",code!="500"}[5m])) by (job)"#).unwrap(), sum);
+    assert!(matches!(parse("avg_over_time(cpu[1h30m])").unwrap(), Expr::RangeFunction { range: 5400, .. }));
+
+    let invalid = [
+        "rate(",
+        "cpu offset 5m",
+        "rate(cpu[5m:1m])",
+        "cpu[5m]",
+        "rate(cpu)",
+        "label_replace(cpu)",
+        "cpu and mem",
+        r#"{job="api"}"#,
+        r#""text""#,
+    ];
+    for query in invalid {
+        assert_eq!(parse(query).unwrap_err().code(), Code::InvalidArgument, "{}", query);
+    }
+}
+
+fn http_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("job", DataType::Utf8, false),
+        Field::new("instance", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("requests", DataType::Float64, true),
+    ])
+}
+
+/// Request counters sampled every 15s for ten minutes, growing by 1, 2
+/// and 0.5 a second.
+fn http_requests() -> RecordBatch {
+    let mut rows = Vec::new();
+    for t in (0..=600).step_by(15) {
+        rows.push(("api", "a", t, t as f64));
+        rows.push(("api", "b", t, 2.0 * t as f64));
+        rows.push(("web", "c", t, 0.5 * t as f64));
+    }
+    RecordBatch::try_new(
+        Arc::new(http_schema()),
+        vec![
+            Arc::new(StringArray::from(rows.iter().map(|r| r.0).collect::<Vec<_>>())),
+            Arc::new(StringArray::from(rows.iter().map(|r| r.1).collect::<Vec<_>>())),
+            Arc::new(Int64Array::from(rows.iter().map(|r| r.2).collect::<Vec<_>>())),
+            Arc::new(Float64Array::from(rows.iter().map(|r| r.3).collect::<Vec<_>>())),
+        ],
+    )
+    .unwrap()
+}
+
+/// Cumulative latency buckets at 600.
+fn latency_buckets() -> RecordBatch {
+    let buckets = [("0.1", 10.0), ("0.5", 30.0), ("+Inf", 40.0)];
+    RecordBatch::try_from_iter([
+        ("le", Arc::new(StringArray::from(buckets.iter().map(|b| b.0).collect::<Vec<_>>())) as _),
+        ("timestamp", Arc::new(Int64Array::from(vec![600; 3])) as _),
+        ("bucket", Arc::new(Float64Array::from(buckets.iter().map(|b| b.1).collect::<Vec<_>>())) as _),
+    ])
+    .unwrap()
+}
+
+async fn backend() -> Arc<StorageBackendType> {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend.create_table("http", &http_schema()).await.unwrap();
+    backend.insert_into_table("http", http_requests()).await.unwrap();
+    let latency = latency_buckets();
+    backend.create_table("latency", &latency.schema()).await.unwrap();
+    backend.insert_into_table("latency", latency).await.unwrap();
+    // `cpu` every 30s, and a counter `resets` restarting after 300
+    let mut records: Vec<MetricRecord> = (0..=20).map(|k| metric("cpu", 30 * k, k as f64)).collect();
+    records.extend((0..=40).map(|k| {
+        let t = 15 * k;
+        metric("resets", t, if t <= 300 { t as f64 } else { (t - 300) as f64 })
+    }));
+    backend.insert_metrics(records).await.unwrap();
+    backend
+}
+
+fn series(value: QueryValue) -> Vec<Series> {
+    match value {
+        QueryValue::Vector(series) => series,
+        QueryValue::Scalar(values) => panic!("Expected a vector, got {:?}", values),
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+/// Labels and values of a vector, without absent values.
+fn samples(value: QueryValue) -> Vec<(Labels, Vec<f64>)> {
+    series(value).into_iter()
+        .map(|series| (series.labels.into_iter().collect(), series.values.into_iter().flatten().collect()))
+        .collect()
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    pairs.iter().map(|(label, value)| (label.to_string(), value.to_string())).collect()
+}
+
+async fn query(backend: &StorageBackendType, query: &str, eval: EvalRange) -> Result<QueryValue, tonic::Status> {
+    evaluate(backend, &parse(query).unwrap(), eval).await
+}
+
+#[tokio::test]
+async fn test_evaluate() {
+    let backend = backend().await;
+    let at = EvalRange::instant(600);
+    let range = EvalRange::new(300, 600, 60).unwrap();
+
+    let rates = query(&backend, "rate(http:requests[1m])", at).await.unwrap();
+    assert_eq!(samples(rates), vec![
+        (labels(&[("instance", "a"), ("job", "api")]), vec![1.0]),
+        (labels(&[("instance", "b"), ("job", "api")]), vec![2.0]),
+        (labels(&[("instance", "c"), ("job", "web")]), vec![0.5]),
+    ]);
+    let by_job = query(&backend, "sum by (job) (rate(http:requests[1m]))", range).await.unwrap();
+    assert_eq!(samples(by_job), vec![
+        (labels(&[("job", "api")]), vec![3.0; 6]),
+        (labels(&[("job", "web")]), vec![0.5; 6]),
+    ]);
+    let increase = query(&backend, r#"increase(http:requests{instance=~"a|c"}[2m])"#, at).await.unwrap();
+    assert_eq!(samples(increase).iter().map(|s| s.1[0]).collect::<Vec<_>>(), vec![120.0, 60.0]);
+    let instant = query(&backend, r#"http:requests{job="web"}"#, range).await.unwrap();
+    assert_eq!(samples(instant), vec![(
+        labels(&[("__name__", "http:requests"), ("instance", "c"), ("job", "web")]),
+        vec![150.0, 180.0, 210.0, 240.0, 270.0, 300.0],
+    )]);
+    assert!(series(query(&backend, r#"http:requests{zone="eu"}"#, at).await.unwrap()).is_empty());
+
+    // The metrics table by metric_id
+    let cpu = query(&backend, "avg_over_time(cpu[2m])", at).await.unwrap();
+    assert_eq!(samples(cpu), vec![(Vec::new(), vec![18.5])]);
+    let cpu = query(&backend, "max_over_time(cpu[2m]) - min_over_time(cpu[2m])", at).await.unwrap();
+    assert_eq!(samples(cpu), vec![(Vec::new(), vec![3.0])]);
+    let cpu = query(&backend, r#"{__name__=~"cp.*"}"#, at).await.unwrap();
+    assert_eq!(samples(cpu), vec![(labels(&[("__name__", "cpu")]), vec![20.0])]);
+    // The counter reset after 300 is counted from zero
+    let resets = query(&backend, "increase(resets[2m])", EvalRange::new(240, 360, 60).unwrap()).await.unwrap();
+    assert_eq!(samples(resets)[0].1, vec![120.0; 3]);
+    let resets = query(&backend, "increase(resets[2m])", EvalRange::instant(360)).await.unwrap();
+    assert_eq!(samples(resets)[0].1, vec![120.0]);
+
+    let quantile = query(&backend, "histogram_quantile(0.75, latency:bucket)", at).await.unwrap();
+    assert_eq!(samples(quantile), vec![(Vec::new(), vec![0.5])]);
+    let quantile = query(&backend, "histogram_quantile(0.25, sum by (le) (latency:bucket))", at).await.unwrap();
+    assert_eq!(samples(quantile), vec![(Vec::new(), vec![0.1])]);
+    // Above the highest finite bucket
+    let quantile = query(&backend, "histogram_quantile(0.99, latency:bucket)", at).await.unwrap();
+    assert_eq!(samples(quantile), vec![(Vec::new(), vec![0.5])]);
+}
+
+#[tokio::test]
+async fn test_binary_operators() {
+    let backend = backend().await;
+    let at = EvalRange::instant(600);
+
+    let filtered = query(&backend, "sum by (job) (rate(http:requests[1m])) * 100 > 100", at).await.unwrap();
+    assert_eq!(samples(filtered), vec![(labels(&[("job", "api")]), vec![300.0])]);
+    let flags = query(&backend, "sum by (job) (rate(http:requests[1m])) > bool 1", at).await.unwrap();
+    assert_eq!(samples(flags).iter().map(|s| s.1[0]).collect::<Vec<_>>(), vec![1.0, 0.0]);
+    let difference = query(
+        &backend,
+        r#"rate(http:requests{instance="b"}[1m]) - ignoring(instance) rate(http:requests{instance="a"}[1m])"#,
+        at,
+    ).await.unwrap();
+    assert_eq!(samples(difference), vec![(labels(&[("job", "api")]), vec![1.0])]);
+    let share = query(
+        &backend,
+        "rate(http:requests[1m]) / on(job) group_total",
+        at,
+    ).await;
+    assert!(series(share.unwrap()).is_empty());
+    let negated = query(&backend, r#"-http:requests{instance="a"} + 1"#, at).await.unwrap();
+    assert_eq!(samples(negated), vec![(labels(&[("instance", "a"), ("job", "api")]), vec![-599.0])]);
+
+    match query(&backend, "2 ^ 3 ^ 2 - 1 + 2 * 3 % 4 > bool 500", at).await.unwrap() {
+        QueryValue::Scalar(values) => assert_eq!(values, vec![1.0]),
+        value => panic!("Expected a scalar, got {:?}", value),
+    }
+    let status = query(&backend, "1 > 2", at).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    // Several api series match on job
+    let status = query(&backend, "rate(http:requests[1m]) + on(job) rate(http:requests[1m])", at).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = query(&backend, "rate(http:requests[7s])", EvalRange::new(0, 600_000, 60).unwrap()).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(EvalRange::new(0, 600_000, 1).unwrap_err().code(), Code::InvalidArgument);
+}
+
+/// Sends a request and returns the status code and JSON body.
+async fn request(addr: &str, request: &str) -> (u16, serde_json::Value) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let code = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (code, serde_json::from_str(body).unwrap())
+}
+
+fn get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n", path)
+}
+
+#[tokio::test]
+async fn test_http_api() {
+    let backend = backend().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(serve(backend, listener));
+
+    let (code, body) = request(&addr, &get("/api/v1/query?query=rate(http%3Arequests%7Bjob%3D%22web%22%7D%5B1m%5D)&time=600")).await;
+    assert_eq!(code, 200);
+    assert_eq!(body, serde_json::json!({
+        "status": "success",
+        "data": {
+            "resultType": "vector",
+            "result": [{ "metric": { "instance": "c", "job": "web" }, "value": [600, "0.5"] }],
+        },
+    }));
+
+    let form = "query=sum+by+(job)+(rate(http:requests[1m]))&start=2024-01-01T00:00:00Z&end=1704067200&step=1m";
+    let (code, body) = request(&addr, &format!(
+        "POST /api/v1/query_range HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-type: application/x-www-form-urlencoded\r\ncontent-length: {}\r\n\r\n{}",
+        form.len(),
+        form
+    )).await;
+    assert_eq!(code, 200);
+    assert_eq!(body["data"], serde_json::json!({ "resultType": "matrix", "result": [] }));
+    let (code, body) = request(&addr, &get("/api/v1/query_range?query=sum(rate(http:requests[1m]))&start=540&end=600&step=60")).await;
+    assert_eq!(code, 200);
+    assert_eq!(body["data"]["result"], serde_json::json!([{ "metric": {}, "values": [[540, "3.5"], [600, "3.5"]] }]));
+    let (_, body) = request(&addr, &get("/api/v1/query?query=1%2F0&time=600")).await;
+    assert_eq!(body["data"], serde_json::json!({ "resultType": "scalar", "result": [600, "+Inf"] }));
+
+    let (code, body) = request(&addr, &get("/api/v1/label/__name__/values")).await;
+    assert_eq!(code, 200);
+    assert_eq!(body["data"], serde_json::json!(["cpu", "resets"]));
+
+    let (code, body) = request(&addr, &get("/api/v1/query?query=rate(")).await;
+    assert_eq!(code, 400);
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["errorType"], "bad_data");
+    let (code, _) = request(&addr, &get("/api/v1/query_range?query=cpu&start=600&end=0&step=60")).await;
+    assert_eq!(code, 400);
+    let (code, _) = request(&addr, &get("/api/v1/unknown")).await;
+    assert_eq!(code, 404);
+
+    // Timestamps whose difference overflows are rejected
+    let (code, _) = request(&addr, &get("/api/v1/query_range?query=cpu&start=-1e300&end=1e300&step=1e18")).await;
+    assert_eq!(code, 400);
+    assert_eq!(EvalRange::new(i64::MIN, i64::MAX, i64::MAX).unwrap_err().code(), Code::InvalidArgument);
+
+    let form = format!("query=cpu&time=600&padding={}", "x".repeat(MAX_BODY_BYTES));
+    let (code, body) = request(&addr, &format!(
+        "POST /api/v1/query HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-type: application/x-www-form-urlencoded\r\ncontent-length: {}\r\n\r\n{}",
+        form.len(),
+        form
+    )).await;
+    assert_eq!(code, 413);
+    assert_eq!(body["status"], "error");
+}
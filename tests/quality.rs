@@ -0,0 +1,155 @@
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::FlightSqlService;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::quality::{
+    dead_letter_batch, NonFinitePolicy, QualityGate, QualityRules, ValueRange, DEAD_LETTER_TABLE,
+};
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const NOW: i64 = 1_700_000_000;
+
+fn metrics_batch(ids: Vec<&str>, timestamps: Vec<i64>, values: Vec<f64>, counts: Vec<i64>) -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("metric_id", DataType::Utf8, false),
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("value_running_window_sum", DataType::Float64, false),
+            Field::new("value_running_window_avg", DataType::Float64, false),
+            Field::new("value_running_window_count", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(StringArray::from(ids)),
+            Arc::new(Int64Array::from(timestamps)),
+            Arc::new(Float64Array::from(values.clone())),
+            Arc::new(Float64Array::from(values)),
+            Arc::new(Int64Array::from(counts)),
+        ],
+    )
+    .unwrap()
+}
+
+fn metrics_rules() -> QualityRules {
+    let mut rules = QualityRules {
+        max_clock_skew_secs: Some(60),
+        metric_id_pattern: Some("[a-z_.]+".to_string()),
+        ..Default::default()
+    };
+    rules.ranges.insert(
+        "value_running_window_count".to_string(),
+        ValueRange { min: Some(0.0), max: None },
+    );
+    rules
+}
+
+#[test]
+fn test_gate_splits_batch() {
+    let gate = QualityGate::new(metrics_rules()).unwrap();
+    let batch = metrics_batch(
+        vec!["cpu.usage", "cpu.usage", "mem", "BAD-ID", "disk"],
+        vec![NOW, NOW + 30, NOW + 3600, NOW, NOW],
+        vec![1.0, f64::NAN, 3.0, 4.0, f64::INFINITY],
+        vec![1, 1, 1, 1, -2],
+    );
+
+    let report = gate.evaluate_at(&batch, NOW).unwrap();
+    assert_eq!(report.accepted.num_rows(), 1);
+    let rejected_rows: Vec<usize> = report.rejected.iter().map(|r| r.row).collect();
+    assert_eq!(rejected_rows, vec![1, 2, 3, 4]);
+
+    assert!(report.rejected[0].reason.contains("value_running_window_sum is not finite (NaN)"));
+    assert!(report.rejected[1].reason.contains("3600s in the future"));
+    assert!(report.rejected[2].reason.contains("'BAD-ID' does not match pattern"));
+    // Every violated rule is reported
+    assert!(report.rejected[3].reason.contains("not finite"));
+    assert!(report.rejected[3].reason.contains("-2 is below minimum 0"));
+}
+
+#[test]
+fn test_non_finite_policies() {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("required", DataType::Float64, false),
+        Field::new("optional", DataType::Float64, true),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(Float64Array::from(vec![1.0, 2.0])) as ArrayRef,
+            Arc::new(Float64Array::from(vec![f64::NAN, 2.0])),
+        ],
+    )
+    .unwrap();
+
+    let accept = QualityGate::new(QualityRules { non_finite: NonFinitePolicy::Accept, ..Default::default() }).unwrap();
+    assert_eq!(accept.evaluate(&batch).unwrap().accepted.num_rows(), 2);
+
+    let null = QualityGate::new(QualityRules { non_finite: NonFinitePolicy::Null, ..Default::default() }).unwrap();
+    let report = null.evaluate(&batch).unwrap();
+    assert!(report.rejected.is_empty());
+    assert!(report.accepted.column(1).is_null(0));
+
+    let reject = QualityGate::new(QualityRules::default()).unwrap();
+    assert_eq!(reject.evaluate(&batch).unwrap().rejected.len(), 1);
+}
+
+#[test]
+fn test_invalid_rules() {
+    let rules = QualityRules { metric_id_pattern: Some("(".to_string()), ..Default::default() };
+    assert_eq!(QualityGate::new(rules).unwrap_err().code(), tonic::Code::InvalidArgument);
+
+    let mut rules = QualityRules::default();
+    rules.ranges.insert("missing".to_string(), ValueRange::default());
+    let batch = metrics_batch(vec!["a"], vec![NOW], vec![1.0], vec![1]);
+    let err = QualityGate::new(rules).unwrap().evaluate(&batch).unwrap_err();
+    assert!(err.message().contains("'missing'"));
+}
+
+#[test]
+fn test_dead_letter_batch() {
+    let gate = QualityGate::new(metrics_rules()).unwrap();
+    let batch = metrics_batch(vec!["cpu", "cpu"], vec![NOW, NOW], vec![1.0, 2.0], vec![1, -1]);
+    let report = gate.evaluate_at(&batch, NOW).unwrap();
+
+    let letters = dead_letter_batch("metrics", "10.0.0.1:5000", &batch, &report.rejected).unwrap();
+    assert_eq!(letters.num_rows(), 1);
+    let column = |name: &str| {
+        letters.column_by_name(name).unwrap().as_any().downcast_ref::<StringArray>().unwrap().value(0).to_string()
+    };
+    assert_eq!(column("table_name"), "metrics");
+    assert_eq!(column("source"), "10.0.0.1:5000");
+    assert!(column("reason").contains("below minimum"));
+    assert!(column("row").contains("\"value_running_window_count\":-1"));
+}
+
+#[tokio::test]
+async fn test_service_ingests_valid_rows_and_dead_letters_the_rest() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    let model_storage = Box::new(TimeSeriesModelStorage::new(backend.clone()));
+    let mut rules = HashMap::new();
+    rules.insert("metrics".to_string(), metrics_rules());
+    let service = FlightSqlService::new(backend.clone(), model_storage)
+        .with_quality_rules(&rules)
+        .unwrap();
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let batch = metrics_batch(
+        vec!["cpu", "mem", "disk"],
+        vec![now, now, now],
+        vec![1.0, f64::NAN, 3.0],
+        vec![1, 1, 1],
+    );
+
+    let ack = service.ingest_batch("metrics", batch, "test-client").await.unwrap();
+    assert_eq!(ack.rows, 2);
+    assert_eq!(ack.rejected, 1);
+
+    let letters = backend.query_table(DEAD_LETTER_TABLE, None, None).await.unwrap();
+    assert_eq!(letters.num_rows(), 1);
+}
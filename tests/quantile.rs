@@ -0,0 +1,164 @@
+use hyprstream_core::aggregation::sketch::QuantileSketch;
+use hyprstream_core::aggregation::{build_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, ResultOptions};
+use hyprstream_core::metrics::aggregation::apply_function;
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{merge_aggregations, BatchAggregation, StorageBackend};
+
+fn exact_quantile(values: &[f64], q: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted[(q * (sorted.len() - 1) as f64).floor() as usize]
+}
+
+fn assert_close(actual: f64, expected: f64, relative: f64) {
+    assert!(
+        (actual - expected).abs() <= expected.abs() * relative + 1e-9,
+        "expected {} within {} of {}",
+        actual,
+        relative,
+        expected
+    );
+}
+
+#[test]
+fn test_sketch_relative_accuracy() {
+    let values: Vec<f64> = (1..=10_000).map(|i| (i as f64) * 0.37).collect();
+    let mut sketch = QuantileSketch::new();
+    for v in &values {
+        sketch.insert(*v);
+    }
+
+    assert_eq!(sketch.count(), 10_000);
+    for q in [0.01, 0.25, 0.5, 0.9, 0.99, 0.999] {
+        assert_close(sketch.quantile(q).unwrap(), exact_quantile(&values, q), 0.011);
+    }
+    assert_eq!(sketch.quantile(0.0), Some(0.37));
+    assert_eq!(sketch.quantile(1.0), Some(3700.0));
+    assert_eq!(sketch.quantile(1.5), None);
+}
+
+#[test]
+fn test_sketch_negative_and_zero_values() {
+    let values: Vec<f64> = (-500..=500).map(|i| i as f64).collect();
+    let mut sketch = QuantileSketch::new();
+    for v in &values {
+        sketch.insert(*v);
+    }
+    sketch.insert(f64::NAN);
+
+    assert_eq!(sketch.count(), 1001);
+    assert_eq!(sketch.quantile(0.5), Some(0.0));
+    assert_close(sketch.quantile(0.1).unwrap(), -400.0, 0.011);
+    assert_close(sketch.quantile(0.9).unwrap(), 400.0, 0.011);
+}
+
+#[test]
+fn test_sketch_merge_matches_single_sketch() {
+    let mut whole = QuantileSketch::new();
+    let mut left = QuantileSketch::new();
+    let mut right = QuantileSketch::new();
+    for i in 0..2000 {
+        let v = ((i * 7919) % 1000) as f64 + 0.5;
+        whole.insert(v);
+        if i % 3 == 0 { left.insert(v) } else { right.insert(v) }
+    }
+
+    left.merge(&right).unwrap();
+    assert_eq!(left.count(), whole.count());
+    for q in [0.1, 0.5, 0.95] {
+        assert_eq!(left.quantile(q), whole.quantile(q));
+    }
+
+    let coarse = QuantileSketch::with_relative_accuracy(0.05);
+    assert!(left.merge(&coarse).is_ok(), "empty sketches always merge");
+    let mut coarse = coarse;
+    coarse.insert(1.0);
+    assert!(left.merge(&coarse).is_err());
+}
+
+#[test]
+fn test_sketch_serialization() {
+    let mut sketch = QuantileSketch::new();
+    for v in [1.0, 2.0, 3.0, -4.0, 0.0] {
+        sketch.insert(v);
+    }
+    let bytes = sketch.to_bytes().unwrap();
+    assert_eq!(QuantileSketch::from_bytes(&bytes).unwrap(), sketch);
+
+    let mut unknown_version = bytes.clone();
+    unknown_version[0] = 99;
+    assert!(QuantileSketch::from_bytes(&unknown_version).is_err());
+
+    // The relative accuracy follows the format version
+    for accuracy in [0.9, 0.0, f64::NAN] {
+        let mut invalid = bytes.clone();
+        invalid[1..9].copy_from_slice(&accuracy.to_le_bytes());
+        assert!(QuantileSketch::from_bytes(&invalid).is_err(), "{}", accuracy);
+    }
+}
+
+#[test]
+fn test_quantile_function() {
+    let metrics: Vec<MetricRecord> = (1..=100)
+        .map(|i| MetricRecord {
+            metric_id: "latency".to_string(),
+            timestamp: i,
+            value_running_window_sum: i as f64,
+            value_running_window_avg: i as f64,
+            value_running_window_count: 1,
+        })
+        .collect();
+
+    let p99 = apply_function(AggregateFunction::Quantile(0.99), &metrics).unwrap();
+    assert_close(p99, 99.0, 0.011);
+    assert!(apply_function(AggregateFunction::Quantile(-0.1), &metrics).is_err());
+
+    assert_eq!(AggregateFunction::Quantile(0.95).to_sql("latency"), "QUANTILE_CONT(latency, 0.95)");
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let sql = build_aggregate_query("metrics", &[AggregateExpr::new(AggregateFunction::Quantile(0.5), "value")], &group_by, None, None, None, &ResultOptions::default()).unwrap();
+    assert!(sql.contains("QUANTILE_CONT(value, 0.5)"));
+}
+
+#[tokio::test]
+async fn test_sketches_persisted_and_merged_per_window() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+
+    let window = |metric_id: &str, start: i64, values: &[f64]| {
+        let mut agg = BatchAggregation::new(metric_id.to_string(), start, start + 60);
+        for v in values {
+            agg.update(start, *v, 1);
+        }
+        agg
+    };
+
+    backend.insert_batch_aggregations(vec![
+        window("api", 0, &[10.0, 20.0]),
+        window("api", 60, &[30.0]),
+        window("db", 0, &[100.0]),
+    ]).await.unwrap();
+    // A later batch for an existing window is merged into it
+    backend.insert_batch_aggregations(vec![window("api", 0, &[40.0, 50.0])]).await.unwrap();
+
+    let stored = backend.query_batch_aggregations(0, None).await.unwrap();
+    assert_eq!(stored.len(), 3);
+    let api_first = &stored[0];
+    assert_eq!((api_first.metric_id.as_str(), api_first.window_start), ("api", 0));
+    assert_eq!(api_first.running_count, 4);
+    assert_eq!(api_first.quantile_sketch.as_ref().unwrap().count(), 4);
+    assert_eq!(api_first.evaluate(AggregateFunction::Max).unwrap(), 50.0);
+
+    // Merge windows per metric, then across metrics
+    let per_metric = merge_aggregations(&stored, |a| a.metric_id.clone()).unwrap();
+    let api = &per_metric["api"];
+    assert_eq!((api.window_start, api.window_end), (0, 120));
+    assert_eq!(api.quantile_sketch.as_ref().unwrap().count(), 5);
+    assert_close(api.evaluate(AggregateFunction::Quantile(0.5)).unwrap(), 30.0, 0.011);
+
+    let all = merge_aggregations(&stored, |_| ()).unwrap();
+    assert_eq!(all[&()].evaluate(AggregateFunction::Quantile(1.0)).unwrap(), 100.0);
+
+    let restricted = backend.query_batch_aggregations(60, Some(120)).await.unwrap();
+    assert_eq!(restricted.len(), 1);
+}
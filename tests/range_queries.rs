@@ -0,0 +1,187 @@
+use hyprstream_core::aggregation::matrix::{MatrixLayout, RangeQuery, StepGrid, DEFAULT_MAX_POINTS};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, TimeWindow};
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{FlightSqlService, QueryTicket};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::rollup::{run, RollupPolicy, RollupTier};
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_flight::flight_service_server::FlightService;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, Ticket};
+use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+use futures::TryStreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Request};
+
+mod common;
+use common::{column, metric};
+
+fn query(start: i64, end: i64, step: u64, layout: MatrixLayout) -> RangeQuery {
+    RangeQuery {
+        aggregates: vec![AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum").with_alias("value")],
+        labels: vec!["metric_id".to_string()],
+        filter: None,
+        start,
+        end,
+        step: Duration::from_secs(step),
+        max_points: DEFAULT_MAX_POINTS,
+        layout,
+    }
+}
+
+fn values(batch: &RecordBatch, name: &str) -> Vec<Option<f64>> {
+    column::<Float64Array>(batch, name).iter().collect()
+}
+
+#[test]
+fn test_step_grid() {
+    // The start is aligned down to the step, and the window holding the end
+    // is the last one
+    let grid = query(30, 299, 60, MatrixLayout::Long).grid();
+    assert_eq!(grid, StepGrid { start: 0, step: 60, points: 5 });
+    assert_eq!(grid.end(), 300);
+    assert_eq!(query(-30, 0, 60, MatrixLayout::Long).grid(), StepGrid { start: -60, step: 60, points: 2 });
+
+    // Widened to a multiple of the step
+    let mut widened = query(0, 299, 60, MatrixLayout::Long);
+    widened.max_points = 2;
+    assert_eq!(widened.grid(), StepGrid { start: 0, step: 180, points: 2 });
+    widened.end = 86_400 * 365;
+    widened.max_points = 1000;
+    let grid = widened.grid();
+    assert!(grid.points <= 1000 && grid.step % 60 == 0, "{:?}", grid);
+
+    let invalid = [
+        query(0, 299, 0, MatrixLayout::Long),
+        query(300, 299, 60, MatrixLayout::Long),
+        RangeQuery { max_points: 0, ..query(0, 299, 60, MatrixLayout::Long) },
+        RangeQuery { aggregates: Vec::new(), ..query(0, 299, 60, MatrixLayout::Long) },
+        // Out of range values would overflow the grid
+        query(i64::MIN, 299, 60, MatrixLayout::Long),
+        query(0, i64::MAX, 60, MatrixLayout::Long),
+        query(0, 299, u64::MAX, MatrixLayout::Long),
+        query(0, 299, i64::MAX as u64 + 1, MatrixLayout::Long),
+    ];
+    for query in invalid {
+        assert_eq!(query.validate().unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    // The widest valid range and step align without overflowing
+    let max = chrono::DateTime::<chrono::Utc>::MAX_UTC.timestamp();
+    let min = chrono::DateTime::<chrono::Utc>::MIN_UTC.timestamp();
+    for (start, end, step) in [(min, max, 1), (min, max, max as u64), (max, max, max as u64), (min, min, 7)] {
+        let query = query(start, end, step, MatrixLayout::Long);
+        query.validate().unwrap();
+        let grid = query.grid();
+        assert!(grid.start <= start && grid.end() > end, "{:?}", grid);
+    }
+}
+
+#[tokio::test]
+async fn test_range_metrics() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    // `cpu` in minutes 0, 1 and 4, `mem` in minutes 0 and 3, and rows
+    // outside the range
+    backend.insert_metrics(vec![
+        metric("cpu", 10, 1.0),
+        metric("cpu", 20, 2.0),
+        metric("cpu", 70, 4.0),
+        metric("cpu", 250, 8.0),
+        metric("cpu", 300, 100.0),
+        metric("mem", 20, 5.0),
+        metric("mem", 200, 6.0),
+        metric("disk", 400, 1.0),
+    ]).await.unwrap();
+
+    let long = backend.range_metrics(&query(30, 299, 60, MatrixLayout::Long)).await.unwrap();
+    let names: Vec<String> = long.schema().fields().iter().map(|f| f.name().clone()).collect();
+    assert_eq!(names, vec!["metric_id", "window_start", "window_end", "value", "row_count"]);
+    let ids: Vec<&str> = column::<StringArray>(&long, "metric_id").iter().map(Option::unwrap).collect();
+    assert_eq!(ids, vec!["cpu"; 5].into_iter().chain(vec!["mem"; 5]).collect::<Vec<_>>());
+    assert_eq!(column::<Int64Array>(&long, "window_start").values(), &[0, 60, 120, 180, 240, 0, 60, 120, 180, 240]);
+    assert_eq!(column::<Int64Array>(&long, "window_end").values(), &[60, 120, 180, 240, 300, 60, 120, 180, 240, 300]);
+    assert_eq!(values(&long, "value"), vec![
+        Some(3.0), Some(4.0), None, None, Some(8.0),
+        Some(5.0), None, None, Some(6.0), None,
+    ]);
+    assert_eq!(column::<Int64Array>(&long, "row_count").values(), &[2, 1, 0, 0, 1, 1, 0, 0, 1, 0]);
+
+    let wide = backend.range_metrics(&query(30, 299, 60, MatrixLayout::Wide)).await.unwrap();
+    let names: Vec<String> = wide.schema().fields().iter().map(|f| f.name().clone()).collect();
+    assert_eq!(names, vec!["window_start", "window_end", r#"value{metric_id="cpu"}"#, r#"value{metric_id="mem"}"#]);
+    assert_eq!(column::<Int64Array>(&wide, "window_start").values(), &[0, 60, 120, 180, 240]);
+    assert_eq!(values(&wide, r#"value{metric_id="cpu"}"#), vec![Some(3.0), Some(4.0), None, None, Some(8.0)]);
+    assert_eq!(values(&wide, r#"value{metric_id="mem"}"#), vec![Some(5.0), None, None, Some(6.0), None]);
+
+    // Widened to windows of three minutes, over all metrics as one series
+    let mut widened = query(0, 299, 60, MatrixLayout::Wide);
+    widened.labels.clear();
+    widened.max_points = 2;
+    let widened = backend.range_metrics(&widened).await.unwrap();
+    assert_eq!(column::<Int64Array>(&widened, "window_start").values(), &[0, 180]);
+    assert_eq!(column::<Int64Array>(&widened, "window_end").values(), &[180, 360]);
+    assert_eq!(values(&widened, "value"), vec![Some(12.0), Some(114.0)]);
+
+    // A range without data keeps its steps in the wide layout only
+    let empty = backend.range_metrics(&query(1000, 1100, 60, MatrixLayout::Long)).await.unwrap();
+    assert_eq!(empty.num_rows(), 0);
+    let empty = backend.range_metrics(&query(1000, 1100, 60, MatrixLayout::Wide)).await.unwrap();
+    assert_eq!(empty.num_rows(), 3);
+    assert_eq!(empty.num_columns(), 2);
+
+    // Same result through Flight
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let ticket = QueryTicket::RangeMetrics(query(30, 299, 60, MatrixLayout::Wide));
+    let ticket = Ticket { ticket: serde_json::to_vec(&ticket).unwrap().into() };
+    let flight_data: Vec<FlightData> = service.do_get(Request::new(ticket)).await.unwrap().into_inner().try_collect().await.unwrap();
+    assert_eq!(flight_data_to_batches(&flight_data).unwrap(), vec![wide]);
+
+    let ticket = QueryTicket::RangeMetrics(query(0, 299, 0, MatrixLayout::Long));
+    let ticket = Ticket { ticket: serde_json::to_vec(&ticket).unwrap().into() };
+    let status = service.do_get(Request::new(ticket)).await.err().unwrap();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_range_metrics_read_rollups_past_raw_retention() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    backend.insert_metrics(vec![
+        metric("cpu", 10, 1.0),
+        metric("cpu", 20, 2.0),
+        metric("cpu", 70, 4.0),
+        metric("mem", 200, 6.0),
+    ]).await.unwrap();
+    let policy = RollupPolicy {
+        group_by: vec!["metric_id".to_string()],
+        raw_retention: Duration::ZERO,
+        tiers: vec![RollupTier {
+            window: TimeWindow::Fixed(Duration::from_secs(60)),
+            aggregates: vec![
+                AggregateExpr::new(AggregateFunction::Count, "*"),
+                AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum"),
+            ],
+            retention: Duration::from_secs(86_400),
+        }],
+    };
+    backend.set_rollup_policy("metrics", policy).await.unwrap();
+
+    // The rows are rolled up and deleted
+    run(backend.as_ref(), "metrics", 600).await.unwrap();
+    assert_eq!(backend.query_table("metrics", None, None).await.unwrap().num_rows(), 0);
+
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let ticket = QueryTicket::RangeMetrics(query(0, 299, 60, MatrixLayout::Long));
+    let ticket = Ticket { ticket: serde_json::to_vec(&ticket).unwrap().into() };
+    let flight_data: Vec<FlightData> = service.do_get(Request::new(ticket)).await.unwrap().into_inner().try_collect().await.unwrap();
+    let batches = flight_data_to_batches(&flight_data).unwrap();
+    assert_eq!(batches.len(), 1);
+    let long = &batches[0];
+    assert_eq!(values(long, "value"), vec![
+        Some(3.0), Some(4.0), None, None, None,
+        None, None, None, Some(6.0), None,
+    ]);
+    assert_eq!(column::<Int64Array>(long, "row_count").values(), &[2, 1, 0, 0, 0, 0, 0, 0, 1, 0]);
+}
@@ -0,0 +1,147 @@
+use hyprstream_core::config::{ClientRateLimit, RateLimitConfig};
+use hyprstream_core::rate_limit::{client_key, token_key, RateLimiter};
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::{Code, Request};
+
+fn config(rows_per_sec: Option<u64>, bytes_per_sec: Option<u64>) -> RateLimitConfig {
+    RateLimitConfig {
+        enabled: true,
+        rows_per_sec,
+        bytes_per_sec,
+        burst_secs: 1.0,
+        clients: HashMap::new(),
+    }
+}
+
+#[test]
+fn test_burst_then_resource_exhausted() {
+    let limiter = RateLimiter::new(config(Some(100), None)).unwrap();
+
+    limiter.check("token:a", 60, 0).unwrap();
+    limiter.check("token:a", 40, 0).unwrap();
+
+    let err = limiter.check("token:a", 50, 0).unwrap_err();
+    assert_eq!(err.code(), Code::ResourceExhausted);
+    assert!(err.message().contains("rows/s"));
+
+    let retry_ms: u64 = err.metadata().get("retry-after-ms").unwrap()
+        .to_str().unwrap().parse().unwrap();
+    assert!(retry_ms > 0 && retry_ms <= 500);
+    assert_eq!(err.metadata().get("retry-after").unwrap(), "1");
+}
+
+#[test]
+fn test_byte_quota_and_rejection_consumes_nothing() {
+    let limiter = RateLimiter::new(config(Some(1_000), Some(1_000))).unwrap();
+
+    limiter.check("peer:10.0.0.1", 10, 900).unwrap();
+    let err = limiter.check("peer:10.0.0.1", 10, 500).unwrap_err();
+    assert!(err.message().contains("bytes/s"));
+    assert!(!err.message().contains("rows/s"));
+
+    // The rejected request did not charge the row bucket
+    limiter.check("peer:10.0.0.1", 990, 0).unwrap();
+}
+
+#[test]
+fn test_clients_are_independent() {
+    let limiter = RateLimiter::new(config(Some(10), None)).unwrap();
+
+    limiter.check("token:a", 10, 0).unwrap();
+    assert!(limiter.check("token:a", 1, 0).is_err());
+    limiter.check("token:b", 10, 0).unwrap();
+}
+
+#[test]
+fn test_disabled_admits_everything() {
+    let limiter = RateLimiter::disabled();
+    assert!(!limiter.is_enabled());
+    for _ in 0..10 {
+        limiter.check("anonymous", u64::MAX, u64::MAX).unwrap();
+    }
+}
+
+#[test]
+fn test_client_override() {
+    let mut config = config(Some(10), None);
+    config.clients.insert(
+        token_key("bulk"),
+        ClientRateLimit { rows_per_sec: Some(1_000), bytes_per_sec: None },
+    );
+    let limiter = RateLimiter::new(config).unwrap();
+
+    limiter.check(&token_key("bulk"), 1_000, 0).unwrap();
+    limiter.check("token:other", 10, 0).unwrap();
+    assert!(limiter.check("token:other", 1, 0).is_err());
+}
+
+#[test]
+fn test_oversized_request_admitted_when_bucket_full() {
+    let limiter = RateLimiter::new(config(Some(10), None)).unwrap();
+
+    // Larger than the bucket, but the bucket is full so it goes into debt
+    limiter.check("token:a", 50, 0).unwrap();
+    let err = limiter.check("token:a", 1, 0).unwrap_err();
+    assert_eq!(err.metadata().get("retry-after").unwrap(), "5");
+}
+
+#[test]
+fn test_bucket_refills() {
+    let limiter = RateLimiter::new(config(Some(100), None)).unwrap();
+
+    limiter.check("token:a", 100, 0).unwrap();
+    assert!(limiter.check("token:a", 20, 0).is_err());
+    std::thread::sleep(Duration::from_millis(300));
+    limiter.check("token:a", 20, 0).unwrap();
+}
+
+#[test]
+fn test_idle_buckets_are_evicted() {
+    let limiter = RateLimiter::new(RateLimitConfig { burst_secs: 0.5, ..config(Some(100), None) }).unwrap();
+
+    limiter.check("token:a", 50, 0).unwrap();
+    std::thread::sleep(Duration::from_millis(250));
+    limiter.check("token:b", 50, 0).unwrap();
+    assert_eq!(limiter.tracked_clients(), 2);
+
+    // `a` has refilled, `b` has not
+    std::thread::sleep(Duration::from_millis(300));
+    limiter.evict_idle();
+    assert_eq!(limiter.tracked_clients(), 1);
+    // An evicted client starts over with a full bucket
+    limiter.check("token:a", 50, 0).unwrap();
+    assert!(limiter.check("token:a", 10, 0).is_err());
+}
+
+#[test]
+fn test_client_key() {
+    let mut request = Request::new(());
+    assert_eq!(client_key(&request), "anonymous");
+
+    request.metadata_mut().insert("authorization", "Bearer secret".parse().unwrap());
+    assert_eq!(client_key(&request), token_key("secret"));
+    assert_eq!(
+        token_key("secret"),
+        "token:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+    );
+}
+
+#[test]
+fn test_invalid_rates() {
+    let mut zero_override = config(Some(10), None);
+    zero_override.clients.insert(
+        token_key("bulk"),
+        ClientRateLimit { rows_per_sec: None, bytes_per_sec: Some(0) },
+    );
+    let invalid = [
+        config(Some(0), None),
+        config(None, Some(0)),
+        zero_override,
+        RateLimitConfig { burst_secs: 0.0, ..config(Some(10), None) },
+        RateLimitConfig { burst_secs: f64::NAN, ..config(Some(10), None) },
+    ];
+    for config in invalid {
+        assert_eq!(RateLimiter::new(config).unwrap_err().code(), Code::InvalidArgument);
+    }
+}
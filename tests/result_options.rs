@@ -0,0 +1,205 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal, SqlDialect};
+use hyprstream_core::aggregation::{
+    aggregate_output_schema, build_aggregate_query, build_windowed_aggregate_query, AggregateExpr,
+    AggregateFunction, GroupBy, OrderBy, ResultOptions, TimeWindow, TopK,
+};
+use hyprstream_core::metrics::get_metrics_schema;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::StorageBackend;
+use arrow_array::{Float64Array, Int64Array, StringArray};
+use arrow_schema::DataType;
+use std::time::Duration;
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+fn requests() -> duckdb::Connection {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE requests (host VARCHAR, timestamp BIGINT, latency DOUBLE)").unwrap();
+    let rows = [
+        ("a", 0, 10.0), ("a", 10, 20.0), ("b", 5, 50.0), ("c", 20, 30.0), ("c", 25, 5.0),
+        ("a", 60, 1.0), ("b", 70, 2.0), ("b", 75, 4.0), ("c", 80, 8.0),
+    ];
+    for (host, timestamp, latency) in rows {
+        conn.execute("INSERT INTO requests VALUES (?, ?, ?)", duckdb::params![host, timestamp, latency]).unwrap();
+    }
+    conn
+}
+
+fn query_rows(conn: &duckdb::Connection, sql: &str) -> Vec<(String, i64, f64)> {
+    let mut stmt = conn.prepare(sql).unwrap();
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(3)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+fn by_host() -> GroupBy {
+    GroupBy { columns: vec!["host".to_string()], time_column: None }
+}
+
+#[test]
+fn test_top_k_per_window() {
+    let conn = requests();
+    let aggregates = [AggregateExpr::new(AggregateFunction::Max, "latency").with_alias("peak")];
+    let options = ResultOptions {
+        top_k: Some(TopK { k: 2, by: OrderBy::desc("peak") }),
+        order_by: vec![OrderBy::asc("window_start"), OrderBy::desc("peak")],
+        ..Default::default()
+    };
+    let sql = build_windowed_aggregate_query(
+        "requests",
+        &aggregates,
+        &by_host(),
+        TimeWindow::Fixed(Duration::from_secs(60)),
+        None,
+        None,
+        None,
+        &options,
+        SqlDialect::DuckDb,
+    )
+    .unwrap();
+    assert!(sql.contains("QUALIFY ROW_NUMBER() OVER (PARTITION BY window_start, window_end ORDER BY peak DESC NULLS LAST) <= 2"));
+
+    let expected = vec![
+        ("b".to_string(), 0, 50.0),
+        ("c".to_string(), 0, 30.0),
+        ("c".to_string(), 60, 8.0),
+        ("b".to_string(), 60, 4.0),
+    ];
+    assert_eq!(query_rows(&conn, &sql), expected);
+
+    // Postgres has no QUALIFY: the rank is filtered in a subquery, and left
+    // out of the output columns
+    let sql = build_windowed_aggregate_query(
+        "requests",
+        &aggregates,
+        &by_host(),
+        TimeWindow::Fixed(Duration::from_secs(60)),
+        None,
+        None,
+        None,
+        &options,
+        SqlDialect::Postgres,
+    )
+    .unwrap();
+    assert!(!sql.contains("QUALIFY"));
+    assert!(sql.starts_with("SELECT host, window_start, window_end, peak FROM"));
+    assert_eq!(query_rows(&conn, &sql), expected);
+}
+
+#[test]
+fn test_having_order_and_limit() {
+    let conn = requests();
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+        AggregateExpr::new(AggregateFunction::Sum, "latency"),
+    ];
+    let options = ResultOptions {
+        having: Some(Filter::compare("sum_latency", CompareOp::Lt, Literal::Int(50))),
+        order_by: vec![OrderBy::desc("sum_latency")],
+        limit: Some(1),
+        ..Default::default()
+    };
+    let sql = build_aggregate_query("requests", &aggregates, &by_host(), None, None, None, &options).unwrap();
+    let mut stmt = conn.prepare(&sql).unwrap();
+    let rows: Vec<(String, i64, f64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    // b is filtered out with a sum of 56, c has the larger sum of a and c
+    assert_eq!(rows, vec![("c".to_string(), 3, 43.0)]);
+
+    // Without clauses the query is not wrapped
+    let plain = build_aggregate_query("requests", &aggregates, &by_host(), None, None, None, &ResultOptions::default());
+    assert!(!plain.unwrap().contains("aggregated"));
+}
+
+#[test]
+fn test_validate_result_options() {
+    let aggregates = [AggregateExpr::new(AggregateFunction::Quantile(0.99), "value_running_window_avg").with_alias("p99")];
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let output = aggregate_output_schema(&aggregates, &group_by, true, &get_metrics_schema()).unwrap();
+    let names: Vec<&str> = output.fields().iter().map(|f| f.name().as_str()).collect();
+    assert_eq!(names, vec!["metric_id", "window_start", "window_end", "p99"]);
+    assert_eq!(output.field(3).data_type(), &DataType::Float64);
+
+    let valid = ResultOptions {
+        having: Some(Filter::compare("p99", CompareOp::Ge, Literal::Float(0.5))),
+        order_by: vec![OrderBy::desc("p99"), OrderBy::asc("metric_id")],
+        limit: Some(10),
+        top_k: Some(TopK { k: 3, by: OrderBy::desc("p99") }),
+        ..Default::default()
+    };
+    valid.validate(&output).unwrap();
+
+    let invalid = vec![
+        ResultOptions { order_by: vec![OrderBy::asc("timestamp")], ..Default::default() },
+        ResultOptions { top_k: Some(TopK { k: 0, by: OrderBy::desc("p99") }), ..Default::default() },
+        ResultOptions { top_k: Some(TopK { k: 1, by: OrderBy::desc("value") }), ..Default::default() },
+        ResultOptions {
+            having: Some(Filter::compare("value_running_window_avg", CompareOp::Gt, Literal::Int(1))),
+            ..Default::default()
+        },
+        ResultOptions {
+            having: Some(Filter::compare("p99", CompareOp::Gt, Literal::String("1".to_string()))),
+            ..Default::default()
+        },
+    ];
+    for options in invalid {
+        let status = options.validate(&output).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument, "{:?}", options);
+    }
+}
+
+#[test]
+fn test_serde() {
+    let options: ResultOptions = serde_json::from_str(
+        r#"{"order_by": [{"column": "p99", "descending": true}], "limit": 10}"#,
+    )
+    .unwrap();
+    assert_eq!(options, ResultOptions { order_by: vec![OrderBy::desc("p99")], limit: Some(10), ..Default::default() });
+    assert_eq!(serde_json::to_string(&ResultOptions::default()).unwrap(), "{}");
+}
+
+#[tokio::test]
+async fn test_aggregate_metrics_top_metrics() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    let mut metrics = Vec::new();
+    for (i, metric_id) in ["cpu", "mem", "disk", "net"].into_iter().enumerate() {
+        for t in 0..10 {
+            metrics.push(metric(metric_id, t, (i as f64 + 1.0) * t as f64));
+        }
+    }
+    backend.insert_metrics(metrics).await.unwrap();
+
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Max, "value_running_window_sum").with_alias("peak"),
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+    ];
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let options = ResultOptions {
+        having: Some(Filter::compare("count", CompareOp::Ge, Literal::Int(10))),
+        order_by: vec![OrderBy::desc("peak")],
+        limit: Some(2),
+        ..Default::default()
+    };
+    let batch = backend.aggregate_metrics(&aggregates, &group_by, TimeWindow::None, 0, None, None, &options).await.unwrap();
+    let metric_ids = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+    let peaks = batch.column_by_name("peak").unwrap().as_any().downcast_ref::<Float64Array>().unwrap();
+    let rows: Vec<(&str, f64)> = (0..batch.num_rows()).map(|i| (metric_ids.value(i), peaks.value(i))).collect();
+    assert_eq!(rows, vec![("net", 36.0), ("disk", 27.0)]);
+
+    // The row count can be used in the result clauses
+    let busiest = ResultOptions { order_by: vec![OrderBy::desc("row_count")], limit: Some(1), ..Default::default() };
+    let batch = backend.aggregate_metrics(&aggregates, &group_by, TimeWindow::None, 5, None, None, &busiest).await.unwrap();
+    let row_counts = batch.column_by_name("row_count").unwrap().as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(row_counts.values().to_vec(), vec![5]);
+
+    let unknown = ResultOptions { order_by: vec![OrderBy::desc("p99")], ..Default::default() };
+    let status = backend.aggregate_metrics(&aggregates, &group_by, TimeWindow::None, 0, None, None, &unknown).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
@@ -0,0 +1,278 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, GroupBy, TimeWindow};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::materialized::state_key;
+use hyprstream_core::storage::rollup::{
+    run, run_due, spawn_scheduler, tier_table, RollupPolicy, RollupTier, ROLLUP_TIER_KEY,
+};
+use hyprstream_core::storage::{BatchAggregation, StorageBackend};
+use arrow_array::{Array, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::Code;
+
+fn requests_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("latency", DataType::Float64, true),
+    ])
+}
+
+fn requests(rows: &[(&str, i64, f64)]) -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(requests_schema()),
+        vec![
+            Arc::new(StringArray::from(rows.iter().map(|r| r.0).collect::<Vec<_>>())),
+            Arc::new(Int64Array::from(rows.iter().map(|r| r.1).collect::<Vec<_>>())),
+            Arc::new(Float64Array::from(rows.iter().map(|r| r.2).collect::<Vec<_>>())),
+        ],
+    )
+    .unwrap()
+}
+
+fn tier(secs: u64, retention: u64) -> RollupTier {
+    RollupTier {
+        window: TimeWindow::Fixed(Duration::from_secs(secs)),
+        aggregates: vec![
+            AggregateExpr::new(AggregateFunction::Count, "*"),
+            AggregateExpr::new(AggregateFunction::Sum, "latency"),
+            AggregateExpr::new(AggregateFunction::Max, "latency"),
+        ],
+        retention: Duration::from_secs(retention),
+    }
+}
+
+fn policy() -> RollupPolicy {
+    RollupPolicy {
+        group_by: vec!["host".to_string()],
+        raw_retention: Duration::from_secs(120),
+        tiers: vec![tier(60, 600), tier(300, 3600)],
+    }
+}
+
+/// Creates the table with rows and a rollup policy; the rollups only run
+/// when the tests call [`run`].
+async fn backend(rows: &[(&str, i64, f64)], policy: RollupPolicy) -> DuckDbBackend {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    backend.create_table("requests", &requests_schema()).await.unwrap();
+    backend.insert_into_table("requests", requests(rows)).await.unwrap();
+    backend.set_rollup_policy("requests", policy).await.unwrap();
+    backend
+}
+
+/// Returns (window start, count, sum) of the latency state of a host.
+async fn tier_windows(backend: &DuckDbBackend, tier: usize, host: &str) -> Vec<(i64, i64, f64)> {
+    let batch = backend.query_table(&tier_table("requests", tier), None, None).await.unwrap();
+    let key = state_key(&[Literal::String(host.to_string())], "latency").unwrap();
+    let mut windows: Vec<_> = BatchAggregation::from_record_batch(&batch)
+        .unwrap()
+        .into_iter()
+        .filter(|state| state.metric_id == key)
+        .map(|state| (state.window_start, state.sample_count, state.running_sum))
+        .collect();
+    windows.sort_by_key(|w| w.0);
+    windows
+}
+
+async fn raw_timestamps(backend: &DuckDbBackend) -> Vec<i64> {
+    let batch = backend.query_table("requests", None, None).await.unwrap();
+    let timestamps = batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+    let mut timestamps: Vec<i64> = timestamps.values().to_vec();
+    timestamps.sort();
+    timestamps
+}
+
+#[tokio::test]
+async fn test_tiers_are_rolled_up_from_below() {
+    let rows = [
+        ("a", 0, 10.0), ("a", 30, 20.0), ("b", 10, 5.0), ("a", 70, 30.0),
+        ("b", 130, 7.0), ("a", 250, 1.0), ("a", 310, 2.0), ("a", 420, 4.0),
+    ];
+    let backend = backend(&rows, policy()).await;
+
+    // Only complete windows are rolled up
+    run(&backend, "requests", 330).await.unwrap();
+    assert_eq!(tier_windows(&backend, 0, "a").await, vec![(0, 2, 30.0), (60, 1, 30.0), (240, 1, 1.0)]);
+    assert_eq!(tier_windows(&backend, 0, "b").await, vec![(0, 1, 5.0), (120, 1, 7.0)]);
+    assert_eq!(tier_windows(&backend, 1, "a").await, vec![(0, 4, 61.0)]);
+    assert_eq!(tier_windows(&backend, 1, "b").await, vec![(0, 2, 12.0)]);
+
+    // Rolling up again does not count windows twice
+    run(&backend, "requests", 430).await.unwrap();
+    assert_eq!(tier_windows(&backend, 0, "a").await.last(), Some(&(300, 1, 2.0)));
+    assert_eq!(tier_windows(&backend, 1, "a").await, vec![(0, 4, 61.0)]);
+
+    run(&backend, "requests", 620).await.unwrap();
+    assert_eq!(tier_windows(&backend, 1, "a").await, vec![(0, 4, 61.0), (300, 2, 6.0)]);
+}
+
+#[tokio::test]
+async fn test_expiry_waits_for_rollup() {
+    let rows = [("a", 0, 1.0), ("a", 100, 2.0), ("a", 250, 3.0), ("a", 290, 4.0)];
+    let policy = RollupPolicy { raw_retention: Duration::ZERO, tiers: vec![tier(60, 60), tier(300, 3600)], ..policy() };
+    let backend = backend(&rows, policy).await;
+
+    // Raw rows are kept until their window is rolled up
+    run(&backend, "requests", 280).await.unwrap();
+    assert_eq!(raw_timestamps(&backend).await, vec![250, 290]);
+
+    // Tier 0 windows are past their retention, but kept until tier 1 has
+    // its first window
+    run(&backend, "requests", 290).await.unwrap();
+    assert_eq!(tier_windows(&backend, 0, "a").await.len(), 2);
+    assert!(tier_windows(&backend, 1, "a").await.is_empty());
+
+    run(&backend, "requests", 1000).await.unwrap();
+    assert_eq!(tier_windows(&backend, 1, "a").await, vec![(0, 4, 10.0)]);
+    assert!(tier_windows(&backend, 0, "a").await.is_empty());
+    assert!(raw_timestamps(&backend).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_scheduled_rollups() {
+    let backend = Arc::new(DuckDbBackend::new_in_memory().unwrap());
+    backend.init().await.unwrap();
+    backend.create_table("requests", &requests_schema()).await.unwrap();
+    backend.set_rollup_policy("requests", policy()).await.unwrap();
+
+    // Ingest leaves the rows of complete windows to the scheduler, which
+    // rolls them up and expires them
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let start = now / 300 * 300 - 600;
+    backend.insert_into_table("requests", requests(&[("a", start, 1.0), ("a", start + 70, 2.0)])).await.unwrap();
+    assert_eq!(raw_timestamps(&backend).await.len(), 2);
+    let scheduler = spawn_scheduler(backend.clone(), Duration::from_millis(10));
+    for _ in 0..500 {
+        if raw_timestamps(&backend).await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    scheduler.abort();
+    assert!(raw_timestamps(&backend).await.is_empty());
+    assert_eq!(tier_windows(&backend, 1, "a").await, vec![(start, 2, 3.0)]);
+
+    // Nothing is due until another window of the finest tier completes
+    backend.insert_into_table("requests", requests(&[("a", start + 80, 4.0)])).await.unwrap();
+    run_due(backend.as_ref(), now).await;
+    assert_eq!(raw_timestamps(&backend).await.len(), 1);
+    run_due(backend.as_ref(), now + 120).await;
+    assert!(raw_timestamps(&backend).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_range_queries_read_coarsest_tier() {
+    let rows: Vec<(&str, i64, f64)> = (0..1200)
+        .step_by(7)
+        .map(|t| (if t % 2 == 0 { "a" } else { "b" }, t, (t % 13) as f64))
+        .collect();
+    let raw = DuckDbBackend::new_in_memory().unwrap();
+    raw.init().await.unwrap();
+    raw.create_table("requests", &requests_schema()).await.unwrap();
+    raw.insert_into_table("requests", requests(&rows)).await.unwrap();
+
+    let policy = RollupPolicy {
+        raw_retention: Duration::from_secs(3600),
+        tiers: vec![tier(60, 3600), tier(300, 3600)],
+        ..policy()
+    };
+    let rolled = backend(&rows, policy).await;
+    run(&rolled, "requests", 1000).await.unwrap();
+    // Rows of rolled up windows can only be read from the tiers
+    let rolled_up = Filter::compare("timestamp", CompareOp::Lt, Literal::Int(960));
+    rolled.delete_from_table("requests", &rolled_up).await.unwrap();
+
+    let aggregates = [
+        AggregateExpr::new(AggregateFunction::Sum, "latency"),
+        AggregateExpr::new(AggregateFunction::Max, "latency").with_alias("peak"),
+        AggregateExpr::new(AggregateFunction::Count, "*"),
+    ];
+    let by_host = GroupBy { columns: vec!["host".to_string()], time_column: None };
+    let total = GroupBy { columns: vec![], time_column: None };
+    let host_a = Filter::compare("host", CompareOp::Eq, Literal::String("a".to_string()));
+    let fixed = |secs| TimeWindow::Fixed(Duration::from_secs(secs));
+    let queries = [
+        (&by_host, fixed(600), 0, None, Some("1")),
+        (&by_host, fixed(60), 0, None, Some("0")),
+        (&total, fixed(600), 0, None, Some("1")),
+        (&by_host, fixed(600), 0, Some(&host_a), Some("1")),
+        (&by_host, fixed(300), 600, None, Some("1")),
+        (&by_host, fixed(120), 900, None, Some("0")),
+    ];
+    for (group_by, window, from, filter, tier) in queries {
+        let expected = raw.aggregate_range("requests", &aggregates, group_by, window, from, 1200, filter).await.unwrap();
+        let result = rolled.aggregate_range("requests", &aggregates, group_by, window, from, 1200, filter).await.unwrap();
+        assert_eq!(result.schema().metadata().get(ROLLUP_TIER_KEY).map(String::as_str), tier, "{:?}", window);
+        assert_eq!(result.columns(), expected.columns(), "{:?}", window);
+        assert!(result.num_rows() > 0);
+    }
+
+    // The last window is stitched from both tiers and the recent rows
+    let result = rolled.aggregate_range("requests", &aggregates, &total, fixed(600), 0, 1200, None).await.unwrap();
+    let counts = result.column(4).as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(counts.values().to_vec(), vec![86, 86]);
+
+    // Without a satisfying tier only the remaining rows are read
+    let unsatisfied = [
+        (fixed(90), 0, None),
+        (fixed(600), 30, None),
+        (fixed(600), 0, Some(Filter::compare("latency", CompareOp::Gt, Literal::Float(3.0)))),
+    ];
+    for (window, from, filter) in unsatisfied {
+        let result = rolled.aggregate_range("requests", &aggregates, &by_host, window, from, 1200, filter.as_ref()).await.unwrap();
+        assert!(!result.schema().metadata().contains_key(ROLLUP_TIER_KEY), "{:?}", window);
+        let starts = result.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert!(starts.values().iter().all(|start| *start + 600 > 960), "{:?}", window);
+    }
+
+    let session = TimeWindow::Session { gap: Duration::from_secs(10) };
+    let status = rolled.aggregate_range("requests", &aggregates, &by_host, session, 0, 1200, None).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = rolled.aggregate_range("requests", &aggregates, &by_host, fixed(60), 1200, 0, None).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_rollup_policy_validation() {
+    let backend = backend(&[("a", 0, 1.0)], policy()).await;
+    let status = backend.set_rollup_policy("requests", policy()).await.unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
+    let status = backend.run_rollups("missing").await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let mut misaligned = policy();
+    misaligned.tiers[1].window = TimeWindow::Fixed(Duration::from_secs(90));
+    let mut shifted = policy();
+    shifted.tiers[1].window = TimeWindow::Aligned { size: Duration::from_secs(300), origin: 30 };
+    let mut sliding = policy();
+    sliding.tiers[0].window = TimeWindow::Sliding { window: Duration::from_secs(60), slide: Duration::from_secs(30) };
+    let mut short_retention = policy();
+    short_retention.tiers[1].retention = Duration::from_secs(60);
+    let mut dropped_column = policy();
+    dropped_column.tiers[0].aggregates = vec![AggregateExpr::new(AggregateFunction::Count, "*")];
+    let mut no_tiers = policy();
+    no_tiers.tiers.clear();
+    let mut unknown_group = policy();
+    unknown_group.group_by = vec!["region".to_string()];
+
+    let schema = requests_schema();
+    for invalid in [misaligned, shifted, sliding, short_retention, dropped_column, no_tiers, unknown_group] {
+        let status = invalid.validate(&schema).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument, "{:?}", invalid);
+    }
+
+    let json = r#"{
+        "group_by": ["host"],
+        "raw_retention": {"secs": 3600, "nanos": 0},
+        "tiers": [{
+            "window": {"Fixed": {"secs": 60, "nanos": 0}},
+            "aggregates": [{"function": "Avg", "column": "latency"}],
+            "retention": {"secs": 86400, "nanos": 0}
+        }]
+    }"#;
+    let parsed: RollupPolicy = serde_json::from_str(json).unwrap();
+    parsed.validate(&schema).unwrap();
+}
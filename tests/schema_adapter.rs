@@ -0,0 +1,236 @@
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::schema_adapter::{ColumnDefault, SchemaAdapter};
+use hyprstream_core::storage::StorageBackend;
+use arrow_array::types::Int32Type;
+use arrow_array::{
+    Array, ArrayRef, DictionaryArray, Float32Array, Float64Array, Int32Array, Int64Array,
+    LargeStringArray, RecordBatch, StringArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+fn batch(fields: Vec<Field>, columns: Vec<ArrayRef>) -> RecordBatch {
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+}
+
+#[test]
+fn test_metrics_accept_compatible_types() {
+    let ids: DictionaryArray<Int32Type> = vec!["cpu", "cpu", "mem"].into_iter().collect();
+    let batch = batch(
+        vec![
+            Field::new("metric_id", ids.data_type().clone(), false),
+            Field::new("timestamp", DataType::Int32, false),
+            Field::new("value_running_window_sum", DataType::Float32, false),
+            Field::new("value_running_window_avg", DataType::Float32, false),
+            Field::new("value_running_window_count", DataType::Int32, false),
+        ],
+        vec![
+            Arc::new(ids),
+            Arc::new(Int32Array::from(vec![1, 2, 3])),
+            Arc::new(Float32Array::from(vec![1.5, 2.5, 3.5])),
+            Arc::new(Float32Array::from(vec![1.5, 2.0, 3.5])),
+            Arc::new(Int32Array::from(vec![1, 2, 1])),
+        ],
+    );
+
+    let metrics = MetricRecord::try_from_record_batch(&batch).unwrap();
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(metrics[2].metric_id, "mem");
+    assert_eq!(metrics[1].timestamp, 2);
+    assert_eq!(metrics[1].value_running_window_sum, 2.5);
+    assert_eq!(metrics[1].value_running_window_count, 2);
+}
+
+#[test]
+fn test_metrics_fill_optional_columns() {
+    let batch = batch(
+        vec![
+            Field::new("metric_id", DataType::LargeUtf8, false),
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("value_running_window_sum", DataType::Float64, false),
+        ],
+        vec![
+            Arc::new(LargeStringArray::from(vec!["cpu", "mem"])),
+            Arc::new(Int64Array::from(vec![10, 20])),
+            Arc::new(Float64Array::from(vec![4.0, 8.0])),
+        ],
+    );
+
+    let metrics = MetricRecord::try_from_record_batch(&batch).unwrap();
+    assert_eq!(metrics[0].metric_id, "cpu");
+    assert_eq!(metrics[0].value_running_window_avg, 4.0);
+    assert_eq!(metrics[1].value_running_window_avg, 8.0);
+    assert_eq!(metrics[1].value_running_window_count, 1);
+}
+
+#[test]
+fn test_metrics_reject_names_column_and_types() {
+    let batch = batch(
+        vec![
+            Field::new("metric_id", DataType::Utf8, false),
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("value_running_window_sum", DataType::Utf8, false),
+        ],
+        vec![
+            Arc::new(StringArray::from(vec!["cpu"])),
+            Arc::new(Int64Array::from(vec![10])),
+            Arc::new(StringArray::from(vec!["1.0"])),
+        ],
+    );
+
+    let err = MetricRecord::try_from_record_batch(&batch).unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(err.message().contains("value_running_window_sum"));
+    assert!(err.message().contains("expected Float64"));
+    assert!(err.message().contains("got Utf8"));
+}
+
+#[test]
+fn test_missing_required_column() {
+    let batch = batch(
+        vec![Field::new("metric_id", DataType::Utf8, false)],
+        vec![Arc::new(StringArray::from(vec!["cpu"]))],
+    );
+
+    let err = MetricRecord::try_from_record_batch(&batch).unwrap_err();
+    assert!(err.message().contains("Missing required column 'timestamp'"));
+}
+
+#[test]
+fn test_adapter_defaults_nulls_and_order() {
+    let target = Arc::new(Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("value", DataType::Float64, false),
+        Field::new("region", DataType::Utf8, true),
+        Field::new("weight", DataType::Float64, false),
+    ]));
+    let adapter = SchemaAdapter::new(target.clone())
+        .with_default("weight", ColumnDefault::Float64(1.0));
+
+    // Columns arrive out of order and without the optional ones
+    let batch = batch(
+        vec![
+            Field::new("value", DataType::Int64, false),
+            Field::new("host", DataType::Utf8, false),
+        ],
+        vec![
+            Arc::new(Int64Array::from(vec![7, 9])),
+            Arc::new(StringArray::from(vec!["a", "b"])),
+        ],
+    );
+
+    let adapted = adapter.adapt(&batch).unwrap();
+    assert_eq!(adapted.schema(), target);
+    assert_eq!(adapted.column(2).null_count(), 2);
+    let values = adapted.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+    assert_eq!(values.value(1), 9.0);
+    let weights = adapted.column(3).as_any().downcast_ref::<Float64Array>().unwrap();
+    assert_eq!(weights.value(0), 1.0);
+}
+
+#[test]
+fn test_adapter_drops_unknown_columns_and_rejects_narrowing() {
+    let target = Arc::new(Schema::new(vec![Field::new("count", DataType::Int32, false)]));
+    let adapter = SchemaAdapter::new(target);
+
+    let unknown = batch(
+        vec![
+            Field::new("count", DataType::Int32, false),
+            Field::new("extra", DataType::Int32, false),
+        ],
+        vec![
+            Arc::new(Int32Array::from(vec![1])),
+            Arc::new(Int32Array::from(vec![2])),
+        ],
+    );
+    let adapted = adapter.adapt(&unknown).unwrap();
+    assert_eq!(adapted.schema(), adapter.schema().clone());
+    assert_eq!(adapted.column(0).as_any().downcast_ref::<Int32Array>().unwrap().value(0), 1);
+
+    let narrowing = batch(
+        vec![Field::new("count", DataType::Int64, false)],
+        vec![Arc::new(Int64Array::from(vec![1]))],
+    );
+    let err = adapter.adapt(&narrowing).unwrap_err();
+    assert!(err.message().contains("expected Int32, got Int64"));
+}
+
+#[test]
+fn test_adapter_rounds_large_integers_and_rejects_overflow() {
+    let target = Arc::new(Schema::new(vec![
+        Field::new("value", DataType::Float64, false),
+        Field::new("count", DataType::Int64, false),
+    ]));
+    let adapter = SchemaAdapter::new(target);
+
+    // Integers beyond 2^53 are accepted as the nearest float
+    let large = (1_i64 << 53) + 1;
+    let rounded = batch(
+        vec![
+            Field::new("value", DataType::Int64, false),
+            Field::new("count", DataType::UInt64, false),
+        ],
+        vec![
+            Arc::new(Int64Array::from(vec![large])),
+            Arc::new(UInt64Array::from(vec![7])),
+        ],
+    );
+    let adapted = adapter.adapt(&rounded).unwrap();
+    assert_eq!(adapted.column(0).as_any().downcast_ref::<Float64Array>().unwrap().value(0), (1_u64 << 53) as f64);
+    assert_eq!(adapted.column(1).as_any().downcast_ref::<Int64Array>().unwrap().value(0), 7);
+
+    // Unsigned values above i64::MAX do not fit
+    let overflow = batch(
+        vec![
+            Field::new("value", DataType::Float64, false),
+            Field::new("count", DataType::UInt64, false),
+        ],
+        vec![
+            Arc::new(Float64Array::from(vec![1.0])),
+            Arc::new(UInt64Array::from(vec![u64::MAX])),
+        ],
+    );
+    let err = adapter.adapt(&overflow).unwrap_err();
+    assert!(err.message().contains("could not be cast from UInt64 to Int64"));
+}
+
+#[tokio::test]
+async fn test_table_insert_is_adapted() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    let schema = Schema::new(vec![
+        Field::new("host", DataType::Utf8, false),
+        Field::new("value", DataType::Float64, false),
+    ]);
+    backend.create_table("samples", &schema).await.unwrap();
+
+    let incoming = batch(
+        vec![
+            Field::new("host", DataType::LargeUtf8, false),
+            Field::new("value", DataType::Float32, false),
+        ],
+        vec![
+            Arc::new(LargeStringArray::from(vec!["a", "b"])),
+            Arc::new(Float32Array::from(vec![1.5, 2.5])),
+        ],
+    );
+    backend.insert_into_table("samples", incoming).await.unwrap();
+
+    let result = backend.query_table("samples", None, None).await.unwrap();
+    assert_eq!(result.num_rows(), 2);
+    let values = result.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+    assert_eq!(values.value(1), 2.5);
+
+    let rejected = batch(
+        vec![
+            Field::new("host", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, false),
+        ],
+        vec![
+            Arc::new(StringArray::from(vec!["c"])),
+            Arc::new(StringArray::from(vec!["oops"])),
+        ],
+    );
+    let err = backend.insert_into_table("samples", rejected).await.unwrap_err();
+    assert!(err.message().contains("'value'"));
+}
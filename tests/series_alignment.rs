@@ -0,0 +1,153 @@
+use hyprstream_core::aggregation::filter::{CompareOp, Filter, Literal};
+use hyprstream_core::aggregation::series::{LabelMatching, SeriesAlignment, SeriesOp, SeriesOperand};
+use hyprstream_core::aggregation::{AggregateExpr, AggregateFunction, TimeWindow};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::models::storage::TimeSeriesModelStorage;
+use hyprstream_core::service::{AlignMetricsQuery, FlightSqlService, QueryTicket};
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{StorageBackend, StorageBackendType};
+use arrow_flight::flight_service_server::FlightService;
+use arrow_flight::utils::flight_data_to_batches;
+use arrow_flight::{FlightData, Ticket};
+use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+use futures::TryStreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Request};
+
+mod common;
+use common::{column, metric};
+
+fn operand(metric_id: &str) -> SeriesOperand {
+    SeriesOperand {
+        aggregate: AggregateExpr::new(AggregateFunction::Sum, "value_running_window_sum"),
+        labels: vec!["metric_id".to_string()],
+        filter: Some(Filter::compare("metric_id", CompareOp::Eq, Literal::String(metric_id.to_string()))),
+    }
+}
+
+fn alignment(op: SeriesOp) -> SeriesAlignment {
+    SeriesAlignment {
+        left: operand("errors"),
+        right: operand("requests"),
+        matching: LabelMatching::Ignoring(vec!["metric_id".to_string()]),
+        op,
+    }
+}
+
+fn values(batch: &RecordBatch) -> Vec<Option<f64>> {
+    column::<Float64Array>(batch, "value").iter().collect()
+}
+
+#[tokio::test]
+async fn test_align_metrics() {
+    let backend = Arc::new(StorageBackendType::DuckDb(DuckDbBackend::new_in_memory().unwrap()));
+    backend.init().await.unwrap();
+    // Errors have no rows in minute 2, and requests none in minute 4
+    let requests = [10.0, 20.0, 30.0, 40.0];
+    let errors = [(0, 1.0), (1, 2.0), (3, 0.5), (4, 9.0)];
+    let mut records: Vec<MetricRecord> = requests.iter()
+        .enumerate()
+        .flat_map(|(k, value)| [metric("requests", 60 * k as i64 + 5, value / 2.0), metric("requests", 60 * k as i64 + 50, value / 2.0)])
+        .collect();
+    records.extend(errors.iter().map(|(k, value)| metric("errors", 60 * k + 30, *value)));
+    backend.insert_metrics(records).await.unwrap();
+    let window = TimeWindow::Fixed(Duration::from_secs(60));
+
+    let ratio = backend.align_metrics(&alignment(SeriesOp::Div), window, 0, None).await.unwrap();
+    let names: Vec<String> = ratio.schema().fields().iter().map(|f| f.name().clone()).collect();
+    assert_eq!(names, vec!["window_start", "window_end", "value"]);
+    assert_eq!(column::<Int64Array>(&ratio, "window_start").values(), &[0, 60, 180]);
+    assert_eq!(column::<Int64Array>(&ratio, "window_end").values(), &[60, 120, 240]);
+    assert_eq!(values(&ratio), vec![Some(0.1), Some(0.1), Some(0.0125)]);
+
+    let difference = backend.align_metrics(&alignment(SeriesOp::Sub), window, 60, Some(239)).await.unwrap();
+    assert_eq!(values(&difference), vec![Some(-18.0), Some(-39.5)]);
+
+    // Errors grow with requests except for the last window
+    let covariance = backend.align_metrics(&alignment(SeriesOp::Covariance), window, 0, None).await.unwrap();
+    assert_eq!(covariance.num_rows(), 1);
+    assert_eq!(column::<Int64Array>(&covariance, "window_start").value(0), 0);
+    assert_eq!(column::<Int64Array>(&covariance, "window_end").value(0), 240);
+    // Pairs (1, 10), (2, 20), (0.5, 40)
+    let expected = ((1.0 - 7.0 / 6.0) * (10.0 - 70.0 / 3.0)
+        + (2.0 - 7.0 / 6.0) * (20.0 - 70.0 / 3.0)
+        + (0.5 - 7.0 / 6.0) * (40.0 - 70.0 / 3.0)) / 2.0;
+    assert!((values(&covariance)[0].unwrap() - expected).abs() < 1e-9);
+    let correlation = backend.align_metrics(&alignment(SeriesOp::Correlation), window, 0, Some(119)).await.unwrap();
+    assert!((values(&correlation)[0].unwrap() - 1.0).abs() < 1e-9);
+
+    let service = FlightSqlService::new(backend.clone(), Box::new(TimeSeriesModelStorage::new(backend.clone())));
+    let query = AlignMetricsQuery { alignment: alignment(SeriesOp::Div), window, from_timestamp: 0, to_timestamp: None };
+    let ticket = Ticket { ticket: serde_json::to_vec(&QueryTicket::AlignMetrics(query)).unwrap().into() };
+    let flight_data: Vec<FlightData> = service.do_get(Request::new(ticket)).await.unwrap().into_inner().try_collect().await.unwrap();
+    assert_eq!(flight_data_to_batches(&flight_data).unwrap(), vec![ratio]);
+
+    // Without ignoring metric_id no series pair
+    let mut unmatched = alignment(SeriesOp::Add);
+    unmatched.matching = LabelMatching::default();
+    assert_eq!(backend.align_metrics(&unmatched, window, 0, None).await.unwrap().num_rows(), 0);
+
+    let session = TimeWindow::Session { gap: Duration::from_secs(60) };
+    let status = backend.align_metrics(&alignment(SeriesOp::Add), session, 0, None).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+/// An aggregation result with `host` and `metric_id` labels.
+fn series(rows: &[(&str, &str, i64, f64)]) -> RecordBatch {
+    RecordBatch::try_from_iter([
+        ("host", Arc::new(rows.iter().map(|r| Some(r.0)).collect::<StringArray>()) as _),
+        ("metric_id", Arc::new(rows.iter().map(|r| Some(r.1)).collect::<StringArray>()) as _),
+        ("window_start", Arc::new(rows.iter().map(|r| r.2).collect::<Int64Array>()) as _),
+        ("window_end", Arc::new(rows.iter().map(|r| r.2 + 60).collect::<Int64Array>()) as _),
+        ("sum_value", Arc::new(rows.iter().map(|r| r.3).collect::<Float64Array>()) as _),
+    ]).unwrap()
+}
+
+fn host_operand() -> SeriesOperand {
+    SeriesOperand {
+        aggregate: AggregateExpr::new(AggregateFunction::Sum, "value"),
+        labels: vec!["host".to_string(), "metric_id".to_string()],
+        filter: None,
+    }
+}
+
+#[test]
+fn test_label_matching() {
+    let left = series(&[("a", "errors", 0, 1.0), ("b", "errors", 0, 3.0), ("c", "errors", 0, 5.0)]);
+    let right = series(&[("a", "requests", 0, 10.0), ("b", "requests", 0, 30.0), ("b", "requests", 60, 1.0)]);
+    let mut alignment = SeriesAlignment {
+        left: host_operand(),
+        right: host_operand(),
+        matching: LabelMatching::On(vec!["host".to_string()]),
+        op: SeriesOp::Mul,
+    };
+    let product = alignment.combine(&left, &right).unwrap();
+    let names: Vec<String> = product.schema().fields().iter().map(|f| f.name().clone()).collect();
+    assert_eq!(names, vec!["host", "window_start", "window_end", "value"]);
+    let hosts: Vec<&str> = column::<StringArray>(&product, "host").iter().map(Option::unwrap).collect();
+    assert_eq!(hosts, vec!["a", "b"]);
+    assert_eq!(values(&product), vec![Some(10.0), Some(90.0)]);
+
+    alignment.matching = LabelMatching::Ignoring(vec!["metric_id".to_string()]);
+    assert_eq!(alignment.combine(&left, &right).unwrap(), product);
+    assert_eq!(alignment.output_labels().unwrap(), vec!["host"]);
+
+    // Division by zero and single windows give nulls
+    alignment.op = SeriesOp::Div;
+    let zero = series(&[("a", "requests", 0, 0.0)]);
+    assert_eq!(values(&alignment.combine(&left, &zero).unwrap()), vec![None]);
+    alignment.op = SeriesOp::Correlation;
+    assert_eq!(values(&alignment.combine(&left, &right).unwrap()), vec![None, None]);
+
+    // Several right series per host
+    alignment.matching = LabelMatching::On(Vec::new());
+    let status = alignment.combine(&left, &right).unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    alignment.matching = LabelMatching::On(vec!["zone".to_string()]);
+    assert_eq!(alignment.validate(TimeWindow::None).unwrap_err().code(), Code::InvalidArgument);
+    alignment.matching = LabelMatching::Ignoring(Vec::new());
+    alignment.right.labels = vec!["host".to_string()];
+    assert_eq!(alignment.validate(TimeWindow::None).unwrap_err().code(), Code::InvalidArgument);
+}
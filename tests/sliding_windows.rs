@@ -0,0 +1,180 @@
+use hyprstream_core::aggregation::filter::SqlDialect;
+use hyprstream_core::aggregation::operator::AggregateOperator;
+use hyprstream_core::aggregation::{build_windowed_aggregate_query, AggregateExpr, AggregateFunction, GroupBy, TimeWindow, ResultOptions, MAX_SLIDING_WINDOWS};
+use hyprstream_core::metrics::MetricRecord;
+use hyprstream_core::storage::duckdb::DuckDbBackend;
+use hyprstream_core::storage::{aggregate_windows, merge_sessions, StorageBackend};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tonic::Code;
+
+mod common;
+use common::metric;
+
+fn sliding(window: u64, slide: u64) -> TimeWindow {
+    TimeWindow::Sliding { window: Duration::from_secs(window), slide: Duration::from_secs(slide) }
+}
+
+/// Row counts per `(metric_id, window_start, window_end)` computed by DuckDB.
+fn sql_counts(metrics: &[MetricRecord], window: TimeWindow) -> BTreeMap<(String, i64, i64), i64> {
+    sql_counts_between(metrics, window, None, None, SqlDialect::DuckDb)
+}
+
+fn sql_counts_between(
+    metrics: &[MetricRecord],
+    window: TimeWindow,
+    from: Option<i64>,
+    to: Option<i64>,
+    dialect: SqlDialect,
+) -> BTreeMap<(String, i64, i64), i64> {
+    let conn = duckdb::Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE metrics (metric_id VARCHAR, timestamp BIGINT, value DOUBLE)").unwrap();
+    for m in metrics {
+        conn.execute(
+            "INSERT INTO metrics VALUES (?, ?, ?)",
+            duckdb::params![m.metric_id, m.timestamp, m.value_running_window_sum],
+        )
+        .unwrap();
+    }
+
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let sql = build_windowed_aggregate_query("metrics", &[AggregateExpr::new(AggregateFunction::Count, "*")], &group_by, window, from, to, None, &ResultOptions::default(), dialect).unwrap();
+    let mut stmt = conn.prepare(&sql).unwrap();
+    let rows = stmt
+        .query_map([], |row| Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?)))
+        .unwrap();
+    rows.map(|row| row.unwrap()).collect()
+}
+
+fn memory_counts(metrics: &[MetricRecord], window: TimeWindow) -> BTreeMap<(String, i64, i64), i64> {
+    aggregate_windows(metrics, window, |_| 1)
+        .into_iter()
+        .map(|a| ((a.metric_id, a.window_start, a.window_end), a.running_count))
+        .collect()
+}
+
+#[test]
+fn test_sliding_windows_overlapping_event() {
+    let windows = sliding(300, 60).windows(125);
+    assert_eq!(windows, vec![(-120, 180), (-60, 240), (0, 300), (60, 360), (120, 420)]);
+
+    // A window that is not a multiple of the slide
+    assert_eq!(sliding(90, 60).windows(100), vec![(60, 150)]);
+    assert_eq!(sliding(90, 60).windows(50), vec![(0, 90)]);
+
+    // Other windows assign each event to a single window
+    let fixed = TimeWindow::Fixed(Duration::from_secs(60));
+    assert_eq!(fixed.windows(125), vec![fixed.window_bounds(125)]);
+}
+
+#[tokio::test]
+async fn test_sliding_batch_aggregations() {
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    let metrics: Vec<MetricRecord> = (0..20).map(|i| metric("cpu", i * 30, i as f64)).collect();
+
+    let aggregations = backend.update_batch_aggregations(&metrics, sliding(300, 60)).await.unwrap();
+    for agg in &aggregations {
+        let expected: Vec<&MetricRecord> = metrics
+            .iter()
+            .filter(|m| m.timestamp >= agg.window_start && m.timestamp < agg.window_end)
+            .collect();
+        assert_eq!(agg.running_count, expected.len() as i64, "window {}", agg.window_start);
+        assert_eq!(agg.running_sum, expected.iter().map(|m| m.value_running_window_sum).sum::<f64>());
+    }
+    // Every event is counted once per overlapping window
+    let total: i64 = aggregations.iter().map(|a| a.running_count).sum();
+    assert_eq!(total, 20 * 5);
+}
+
+#[test]
+fn test_sliding_sql_matches_in_memory() {
+    let metrics: Vec<MetricRecord> = (0..200)
+        .map(|i: i64| metric(if i % 3 == 0 { "a" } else { "b" }, (i * 7_919) % 5_000 - 1_000, 1.0))
+        .collect();
+    for window in [sliding(300, 60), sliding(90, 60), sliding(60, 60), TimeWindow::Fixed(Duration::from_secs(120))] {
+        assert_eq!(sql_counts(&metrics, window), memory_counts(&metrics, window), "{:?}", window);
+        // The join over window indices of the Postgres dialect, run by DuckDB
+        let postgres = sql_counts_between(&metrics, window, None, None, SqlDialect::Postgres);
+        assert_eq!(postgres, memory_counts(&metrics, window), "{:?}", window);
+    }
+}
+
+#[tokio::test]
+async fn test_sliding_window_limits() {
+    assert!(sliding(MAX_SLIDING_WINDOWS, 1).validate().is_ok());
+    assert_eq!(sliding(MAX_SLIDING_WINDOWS + 1, 1).validate().unwrap_err().code(), Code::InvalidArgument);
+    assert_eq!(sliding(60, 120).validate().unwrap_err().code(), Code::InvalidArgument);
+
+    // A day sliding by a second is rejected before any row is assigned
+    let day = sliding(86_400, 1);
+    let count = [AggregateExpr::new(AggregateFunction::Count, "*")];
+    let group_by = GroupBy { columns: vec!["metric_id".to_string()], time_column: None };
+    let backend = DuckDbBackend::new_in_memory().unwrap();
+    backend.init().await.unwrap();
+    let status = backend
+        .aggregate_metrics(&count, &group_by, day, 0, None, None, &ResultOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let schema = std::sync::Arc::new(hyprstream_core::metrics::get_metrics_schema());
+    let status = AggregateOperator::try_new(schema, &count, &group_by, day).err().unwrap();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[test]
+fn test_session_windows() {
+    let window = TimeWindow::Session { gap: Duration::from_secs(60) };
+    let metrics = vec![
+        metric("a", 0, 1.0),
+        metric("a", 59, 1.0),
+        metric("a", 130, 1.0),
+        metric("b", 30, 1.0),
+        metric("a", 100, 1.0),
+        metric("a", 250, 1.0),
+    ];
+
+    let expected: BTreeMap<(String, i64, i64), i64> = [
+        (("a".to_string(), 0, 190), 4),
+        (("a".to_string(), 250, 310), 1),
+        (("b".to_string(), 30, 90), 1),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(memory_counts(&metrics, window), expected);
+    assert_eq!(sql_counts(&metrics, window), expected);
+
+    // Sessions continuing in a later batch are stitched together
+    let mut sessions = aggregate_windows(&metrics[..3], window, |_| 1);
+    sessions.extend(aggregate_windows(&metrics[3..], window, |_| 1));
+    let stitched: Vec<(String, i64, i64, i64)> = merge_sessions(&sessions)
+        .unwrap()
+        .into_iter()
+        .map(|s| (s.metric_id, s.window_start, s.window_end, s.running_count))
+        .collect();
+    assert_eq!(stitched, vec![
+        ("a".to_string(), 0, 190, 4),
+        ("a".to_string(), 250, 310, 1),
+        ("b".to_string(), 30, 90, 1),
+    ]);
+}
+
+#[test]
+fn test_range_is_applied_before_windows() {
+    let metrics = vec![metric("a", 0, 1.0), metric("a", 59, 1.0), metric("a", 100, 1.0), metric("a", 130, 1.0)];
+
+    // The end of the range is excluded, and rows outside of it do not extend
+    // sessions
+    let session = TimeWindow::Session { gap: Duration::from_secs(60) };
+    let expected: BTreeMap<(String, i64, i64), i64> = [(("a".to_string(), 59, 160), 2)].into_iter().collect();
+    assert_eq!(sql_counts_between(&metrics, session, Some(59), Some(130), SqlDialect::DuckDb), expected);
+
+    let fixed = TimeWindow::Fixed(Duration::from_secs(60));
+    let expected: BTreeMap<(String, i64, i64), i64> = [
+        (("a".to_string(), 0, 60), 1),
+        (("a".to_string(), 60, 120), 1),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(sql_counts_between(&metrics, fixed, Some(59), Some(130), SqlDialect::DuckDb), expected);
+}